@@ -1,118 +1,1559 @@
 use std::any::{TypeId, Any};
+use std::cell::{RefCell, UnsafeCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // These Data are custom structs to distinguash the same inner types.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DataA(char);
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DataB(char);
 
-// Concrete data storage.
-// Assume that this is a storage that keeps your heterogeneous data.
-// It's super simple, but for practical usage, we should make this more flexible and safe.
-struct DataStorage {
-    data: HashMap<TypeId, Box<dyn Any>>,
+// Data that can be checked at compile time by `RunList`.
+// `TypeId`s can't be compared in constants, so that data are told apart by their names.
+// Names must be unique among all `Data`, otherwise `RunList` takes different types for the same data.
+// Implement it with `data!` rather than by hand, which names the type by its path.
+trait Data: 'static {
+    const NAME: &'static str;
+}
+
+// Implements `Data` for the given types, named like "crate::module::Type".
+macro_rules! data {
+    ($($ty:ident),+) => {
+        $(
+            impl Data for $ty {
+                const NAME: &'static str = concat!(module_path!(), "::", stringify!($ty));
+            }
+        )+
+    };
+}
+
+data!(DataA, DataB);
+
+// Identifies values in the storage by their type and the name of the collection.
+// So that there can be several collections of the same type, such as front and back buffers.
+// Unnamed collections have the empty name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DataKey {
+    name: &'static str,
+    ty: TypeId,
+    type_name: &'static str,
+}
+
+impl DataKey {
+    fn of<T: 'static>() -> Self {
+        Self::named::<T>("")
+    }
+
+    fn named<T: 'static>(name: &'static str) -> Self {
+        Self {
+            name,
+            ty: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+        }
+    }
+}
+
+impl fmt::Display for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.type_name)
+        } else {
+            write!(f, "{}: {}", self.name, self.type_name)
+        }
+    }
+}
+
+// Concrete data storage.
+// Assume that this is a storage that keeps your heterogeneous data.
+// It's super simple, but for practical usage, we should make this more flexible and safe.
+// Values are `Send` and `Sync`, so that functions can visit them on other threads.
+// And they're `Clone`, so that the storage can be rolled back, see `snapshot`.
+struct DataStorage {
+    data: HashMap<DataKey, Box<dyn Any + Send + Sync>>,
+    // How to clone the collection of the same key.
+    clones: HashMap<DataKey, CloneFn>,
+    // Queued by `Commands` while functions are running, see `apply_commands`.
+    // It's shared rather than borrowed, so that `Commands` stay valid while the storage is borrowed mutably.
+    commands: Arc<Mutex<Vec<Command>>>,
+}
+
+type CloneFn = fn(&(dyn Any + Send + Sync)) -> Box<dyn Any + Send + Sync>;
+
+fn clone_values<T>(values: &(dyn Any + Send + Sync)) -> Box<dyn Any + Send + Sync>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    Box::new(values.downcast_ref::<Vec<T>>().unwrap().clone())
+}
+
+// Copy of all data in a `DataStorage`, see `DataStorage::snapshot`.
+struct Snapshot {
+    data: HashMap<DataKey, Box<dyn Any + Send + Sync>>,
+    clones: HashMap<DataKey, CloneFn>,
+}
+
+impl DataStorage {
+    fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            clones: HashMap::new(),
+            commands: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Clones all data, so that drivers can run functions speculatively and roll back by `restore`.
+    // Call this between invocations, when no slices handed to `run` are alive.
+    fn snapshot(&self) -> Snapshot {
+        let data = (self.data.iter())
+            .map(|(key, values)| (*key, self.clones[key](values.as_ref())))
+            .collect();
+        Snapshot {
+            data,
+            clones: self.clones.clone(),
+        }
+    }
+
+    // Puts back all data as they were at the `snapshot`, and discards queued commands.
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.data = snapshot.data;
+        self.clones = snapshot.clones;
+        self.commands.lock().unwrap().clear();
+    }
+
+    // Applies queued commands in the order they're queued.
+    // Drivers call this after each invocation, when no slices handed to `run` are alive.
+    fn apply_commands(&mut self) {
+        let commands = std::mem::take(&mut *self.commands.lock().unwrap());
+        for command in commands {
+            command(self);
+        }
+    }
+
+    // Puts the `values` of type `T`, and returns the previous ones if any.
+    fn insert<T: Clone + Send + Sync + 'static>(&mut self, values: Vec<T>) -> Option<Vec<T>> {
+        self.insert_named("", values)
+    }
+
+    // Puts the `values` of type `T` in the collection `name`, and returns the previous ones if any.
+    // Collections of different names are independent of each other and of the unnamed one.
+    fn insert_named<T>(&mut self, name: &'static str, values: Vec<T>) -> Option<Vec<T>>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let key = DataKey::named::<T>(name);
+        self.clones.insert(key, clone_values::<T>);
+        self.data
+            .insert(key, Box::new(values))
+            .map(|old| *old.downcast::<Vec<T>>().unwrap())
+    }
+
+    // Takes out the values of type `T`.
+    fn remove<T: 'static>(&mut self) -> Option<Vec<T>> {
+        self.remove_named("")
+    }
+
+    // Takes out the values of type `T` in the collection `name`.
+    fn remove_named<T: 'static>(&mut self, name: &'static str) -> Option<Vec<T>> {
+        let key = DataKey::named::<T>(name);
+        self.clones.remove(&key);
+        self.data
+            .remove(&key)
+            .map(|old| *old.downcast::<Vec<T>>().unwrap())
+    }
+
+    fn contains<T: 'static>(&self) -> bool {
+        self.data.contains_key(&DataKey::of::<T>())
+    }
+
+    // Returns the number of values of type `T`, which is 0 if there're none.
+    fn len<T: 'static>(&self) -> usize {
+        self.data
+            .get(&DataKey::of::<T>())
+            .map_or(0, |values| values.downcast_ref::<Vec<T>>().unwrap().len())
+    }
+}
+
+// Slices are `None` if the storage doesn't have the type in the collection.
+trait Store {
+    fn as_named_slice<T: 'static>(&self, name: &'static str) -> Option<&[T]>;
+    fn as_named_mut_slice<T: 'static>(&mut self, name: &'static str) -> Option<&mut [T]>;
+    fn commands(&self) -> Commands<'_>;
+
+    // Moves the values out, and leaves the collection empty.
+    fn take_named<T: 'static>(&mut self, name: &'static str) -> Option<Vec<T>>;
+
+    fn as_slice<T: 'static>(&self) -> Option<&[T]> {
+        self.as_named_slice("")
+    }
+
+    fn as_mut_slice<T: 'static>(&mut self) -> Option<&mut [T]> {
+        self.as_named_mut_slice("")
+    }
+}
+
+impl Store for DataStorage {
+    fn as_named_slice<T: 'static>(&self, name: &'static str) -> Option<&[T]> {
+        let values = self.data.get(&DataKey::named::<T>(name))?;
+        Some(values.downcast_ref::<Vec<T>>().unwrap().as_slice())
+    }
+
+    fn as_named_mut_slice<T: 'static>(&mut self, name: &'static str) -> Option<&mut [T]> {
+        let values = self.data.get_mut(&DataKey::named::<T>(name))?;
+        Some(values.downcast_mut::<Vec<T>>().unwrap().as_mut_slice())
+    }
+
+    fn commands(&self) -> Commands<'_> {
+        Commands {
+            queue: Arc::clone(&self.commands),
+            _data: PhantomData,
+        }
+    }
+
+    fn take_named<T: 'static>(&mut self, name: &'static str) -> Option<Vec<T>> {
+        let values = self.data.get_mut(&DataKey::named::<T>(name))?;
+        Some(std::mem::take(values.downcast_mut::<Vec<T>>().unwrap()))
+    }
+}
+
+// Alternative storage that hands out slices without laundering lifetimes, see `FetchCell`.
+// Slices borrow the storage itself, and the storage tracks them at run-time like `RefCell`,
+// so that aliasing slices are refused instead of being prevented by `check_borrows` beforehand.
+// It doesn't have commands, so that functions taking `Commands` can't run on it.
+struct CellStorage {
+    data: HashMap<DataKey, UnsafeCell<Box<dyn Any>>>,
+    // Collections borrowed since the last `&mut self` call. Borrows live as long as `&self`,
+    // so that they're forgotten only when `&mut self` proves none of them are alive.
+    borrows: RefCell<HashMap<DataKey, Borrow>>,
+}
+
+#[derive(PartialEq)]
+enum Borrow {
+    Shared,
+    Unique,
+}
+
+// Why `CellStorage` couldn't hand out a slice.
+#[derive(Debug)]
+enum BorrowError {
+    Missing(DataKey),
+    Borrowed(DataKey),
+}
+
+impl CellStorage {
+    fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            borrows: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Puts the `values` of type `T`, and returns the previous ones if any.
+    fn insert<T: 'static>(&mut self, values: Vec<T>) -> Option<Vec<T>> {
+        self.insert_named("", values)
+    }
+
+    // Puts the `values` of type `T` in the collection `name`, and returns the previous ones if any.
+    fn insert_named<T: 'static>(&mut self, name: &'static str, values: Vec<T>) -> Option<Vec<T>> {
+        self.borrows.get_mut().clear();
+        self.data
+            .insert(DataKey::named::<T>(name), UnsafeCell::new(Box::new(values)))
+            .map(|old| *old.into_inner().downcast::<Vec<T>>().unwrap())
+    }
+
+    // Returns the values of type `T`, or `None` if there're none or they're borrowed mutably.
+    fn get<T: 'static>(&self) -> Option<&[T]> {
+        self.slice("").ok()
+    }
+
+    fn slice<T: 'static>(&self, name: &'static str) -> Result<&[T], BorrowError> {
+        let key = DataKey::named::<T>(name);
+        let cell = self.data.get(&key).ok_or(BorrowError::Missing(key))?;
+        if *self.borrows.borrow_mut().entry(key).or_insert(Borrow::Shared) == Borrow::Unique {
+            return Err(BorrowError::Borrowed(key));
+        }
+        // Safety: There's no mutable slice of the collection, and there won't be while this one is alive.
+        let values = unsafe { &*cell.get() };
+        Ok(values.downcast_ref::<Vec<T>>().unwrap())
+    }
+
+    // Like `RefCell::borrow_mut`, uniqueness is checked by `borrows` instead of `&mut self`.
+    #[allow(clippy::mut_from_ref)]
+    fn mut_slice<T: 'static>(&self, name: &'static str) -> Result<&mut [T], BorrowError> {
+        self.mut_values(name).map(Vec::as_mut_slice)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn mut_values<T: 'static>(&self, name: &'static str) -> Result<&mut Vec<T>, BorrowError> {
+        let key = DataKey::named::<T>(name);
+        let cell = self.data.get(&key).ok_or(BorrowError::Missing(key))?;
+        let mut borrows = self.borrows.borrow_mut();
+        if borrows.contains_key(&key) {
+            return Err(BorrowError::Borrowed(key));
+        }
+        borrows.insert(key, Borrow::Unique);
+        // Safety: There's no other slice of the collection, and there won't be while this one is alive.
+        let values = unsafe { &mut *cell.get() };
+        Ok(values.downcast_mut::<Vec<T>>().unwrap())
+    }
+
+    // Runs the function with slices borrowing this storage.
+    // It's the same `Runnable` as `DataStorage` runs, as long as its elements are `FetchCell`.
+    fn invoke<R, O>(&mut self, runnable: &mut R) -> Result<O, InvokeError>
+    where
+        R: for<'a> Runnable<'a, Output = O>,
+        for<'a> <R as Runnable<'a>>::Ref: VisitCell<'a>,
+        for<'a> <R as Runnable<'a>>::Mut: VisitCell<'a>,
+    {
+        self.borrows.get_mut().clear();
+        let function = std::any::type_name::<R>();
+        let output = run_cell(self, runnable).map_err(|e| match e {
+            BorrowError::Missing(ty) => InvokeError::Missing { function, ty },
+            BorrowError::Borrowed(ty) => InvokeError::Conflict(BorrowConflict {
+                function,
+                types: vec![ty],
+            }),
+        });
+        self.borrows.get_mut().clear();
+        output
+    }
+}
+
+fn run_cell<'a, R>(data: &'a CellStorage, runnable: &mut R) -> Result<R::Output, BorrowError>
+where
+    R: Runnable<'a>,
+    R::Ref: VisitCell<'a>,
+    R::Mut: VisitCell<'a>,
+{
+    let r = R::Ref::visit_cell(data)?;
+    let m = R::Mut::visit_cell(data)?;
+    Ok(runnable.run(r, m))
+}
+
+// Deferred change to the storage, see `Commands`.
+type Command = Box<dyn FnOnce(&mut DataStorage) + Send>;
+
+// Queues changes that slices can't make, such as pushing values.
+// They're applied after the function returns, so that slices handed to `run` are never invalidated.
+// Put this in the `Ref`, because it doesn't borrow any data.
+// Functions running at the same time share the queue, so that the order of their commands is not fixed.
+struct Commands<'a> {
+    queue: Arc<Mutex<Vec<Command>>>,
+    _data: PhantomData<&'a DataStorage>,
+}
+
+impl Commands<'_> {
+    fn add(&self, command: impl FnOnce(&mut DataStorage) + Send + 'static) {
+        self.queue.lock().unwrap().push(Box::new(command));
+    }
+
+    // Appends the `value`, and makes the slice of type `T` if there isn't.
+    fn push<T: Clone + Send + Sync + 'static>(&self, value: T) {
+        self.add(move |data| match data.data.get_mut(&DataKey::of::<T>()) {
+            Some(values) => values.downcast_mut::<Vec<T>>().unwrap().push(value),
+            None => {
+                data.insert(vec![value]);
+            }
+        });
+    }
+
+    // Removes the value at the `index` by shifting the following ones.
+    // It's ignored if the value doesn't exist.
+    fn remove_index<T: 'static>(&self, index: usize) {
+        self.add(move |data| {
+            if let Some(values) = data.data.get_mut(&DataKey::of::<T>()) {
+                let values = values.downcast_mut::<Vec<T>>().unwrap();
+                if index < values.len() {
+                    values.remove(index);
+                }
+            }
+        });
+    }
+}
+
+// Types borrowed by both `Ref` and `Mut`, or more than once by `Mut`, of a function.
+// Handing out views of them would make aliasing slices, so that `invoke` refuses it.
+#[derive(Debug)]
+struct BorrowConflict {
+    function: &'static str,
+    types: Vec<DataKey>,
+}
+
+impl fmt::Display for BorrowConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let types: Vec<String> = self.types.iter().map(|ty| ty.to_string()).collect();
+        write!(
+            f,
+            "{} borrows {} mutably and elsewhere at the same time",
+            self.function,
+            types.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for BorrowConflict {}
+
+// Error of `invoke`.
+#[derive(Debug)]
+enum InvokeError {
+    Conflict(BorrowConflict),
+    // The storage doesn't have the type `ty` that the `function` visits.
+    Missing {
+        function: &'static str,
+        ty: DataKey,
+    },
+}
+
+impl fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict(e) => e.fmt(f),
+            Self::Missing { function, ty } => {
+                write!(f, "{} visits {}, but there's no such data", function, ty)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvokeError {}
+
+impl From<BorrowConflict> for InvokeError {
+    fn from(e: BorrowConflict) -> Self {
+        Self::Conflict(e)
+    }
+}
+
+// `run` with its views, which can be sent to another thread. It returns the erased `Output`.
+type Job<'s> = Box<dyn FnOnce() -> Box<dyn Any + Send> + Send + 's>;
+
+// `Output` of `run` is erased as well, so that outputs of different functions are kept in the same list.
+trait Invokable: Send {
+    // Visits `data` and returns the job calling `run` with the views.
+    // Like `visit`, views don't borrow `data`, so that jobs of functions not conflicting can run at the same time.
+    // Depends on DataPool for object safety.
+    fn prepare<'s>(&'s mut self, data: &mut DataStorage) -> Result<Job<'s>, InvokeError>;
+    fn id(&self) -> TypeId;
+    fn name(&self) -> &'static str;
+    fn reads(&self) -> Vec<DataKey>; // For ordering by the `Scheduler`.
+    fn writes(&self) -> Vec<DataKey>; // For ordering by the `Scheduler`.
+
+    fn check_borrows(&self) -> Result<(), BorrowConflict> {
+        check_borrows(self.name(), &self.reads(), &self.writes())
+    }
+
+    // Runs the function and applies commands queued by it.
+    fn invoke(&mut self, data: &mut DataStorage) -> Result<Box<dyn Any>, InvokeError> {
+        let output = self.prepare(data)?();
+        data.apply_commands();
+        Ok(output)
+    }
+}
+
+// `Invokable` for functions only reading data, which only needs shared access to the storage.
+// So that they can be invoked at the same time, or where there's only `&DataStorage`.
+// Commands queued by them are applied by the next `apply_commands`.
+trait InvokableRef: Sync {
+    fn invoke(&self, data: &DataStorage) -> Result<Box<dyn Any + Send>, InvokeError>;
+}
+
+impl<T> InvokableRef for T
+where
+    T: for<'a> RunnableRef<'a> + Sync,
+    for<'a> <T as RunnableRef<'a>>::Output: Send,
+{
+    fn invoke(&self, data: &DataStorage) -> Result<Box<dyn Any + Send>, InvokeError> {
+        let r = <<T as RunnableRef<'_>>::Ref as Visit>::visit(data).map_err(|ty| InvokeError::Missing {
+            function: std::any::type_name::<T>(),
+            ty,
+        })?;
+        Ok(Box::new(self.run(r)))
+    }
+}
+
+impl<T> Invokable for T
+where
+    T: for<'a> Runnable<'a> + Send + 'static,
+    for<'a> <T as Runnable<'a>>::Ref: Send,
+    for<'a> <T as Runnable<'a>>::Mut: Send,
+    for<'a> <T as Runnable<'a>>::Output: Send,
+{
+    #[inline]
+    fn prepare<'s>(&'s mut self, data: &mut DataStorage) -> Result<Job<'s>, InvokeError> {
+        self.check_borrows()?;
+        let missing = |ty| InvokeError::Missing {
+            function: std::any::type_name::<T>(),
+            ty,
+        };
+        let r = <<T as Runnable<'s>>::Ref as Visit>::visit(data).map_err(missing)?;
+        let m = <<T as Runnable<'s>>::Mut as VisitMut>::visit_mut(data).map_err(missing)?;
+        Ok(Box::new(move || Box::new(self.run(r, m)) as Box<dyn Any + Send>))
+    }
+
+    fn id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    fn reads(&self) -> Vec<DataKey> {
+        <<T as Runnable<'static>>::Ref as Visit>::types()
+    }
+
+    fn writes(&self) -> Vec<DataKey> {
+        <<T as Runnable<'static>>::Mut as VisitMut>::types()
+    }
+}
+
+// Finds types in `muts` that appear in `refs` or elsewhere in `muts`.
+// The same type in differently named collections doesn't alias.
+fn check_borrows(function: &'static str, refs: &[DataKey], muts: &[DataKey]) -> Result<(), BorrowConflict> {
+    let mut types = Vec::new();
+    for (i, key) in muts.iter().enumerate() {
+        let is_aliased = refs.iter().chain(&muts[..i]).any(|other| other == key);
+        if is_aliased && !types.contains(key) {
+            types.push(*key);
+        }
+    }
+    if types.is_empty() {
+        Ok(())
+    } else {
+        Err(BorrowConflict { function, types })
+    }
+}
+
+// Functions that can't be ordered because their dependencies make a cycle.
+#[derive(Debug)]
+struct CycleError {
+    functions: Vec<&'static str>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dependencies make a cycle among {}", self.functions.join(", "))
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+// Error of running a `Scheduler`.
+#[derive(Debug)]
+enum ScheduleError {
+    Cycle(CycleError),
+    Invoke(InvokeError),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle(e) => e.fmt(f),
+            Self::Invoke(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+impl From<CycleError> for ScheduleError {
+    fn from(e: CycleError) -> Self {
+        Self::Cycle(e)
+    }
+}
+
+impl From<InvokeError> for ScheduleError {
+    fn from(e: InvokeError) -> Self {
+        Self::Invoke(e)
+    }
+}
+
+// Runs `Invokable`s in an order that respects their dependencies instead of insertion order.
+// Dependencies are declared by `add_dependency`, and inferred from the visited types as well:
+// if one writes what another visits, the one added first runs first unless declared otherwise.
+struct Scheduler {
+    list: Vec<Box<dyn Invokable>>,
+    // (Before, After) declared by `add_dependency`.
+    dependencies: Vec<(TypeId, TypeId)>,
+    // Statistics of the function at the same index in the `list`.
+    stats: Vec<Stats>,
+}
+
+// How many times a function has run and how long it took in total, see `Scheduler::report`.
+// Invocations refused for missing data or borrow conflicts are not counted, because the function didn't run.
+#[derive(Debug, Clone, Copy, Default)]
+struct Stats {
+    calls: u32,
+    total: Duration,
+}
+
+impl Stats {
+    fn record(&mut self, elapsed: Duration) {
+        self.calls += 1;
+        self.total += elapsed;
+    }
+
+    // Returns zero if it's never run.
+    fn average(&self) -> Duration {
+        self.total.checked_div(self.calls).unwrap_or_default()
+    }
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Self {
+            list: Vec::new(),
+            dependencies: Vec::new(),
+            stats: Vec::new(),
+        }
+    }
+
+    // Adds the function unless its `Ref` and `Mut` conflict, because it can't run anyway.
+    fn add(&mut self, item: impl Invokable + 'static) -> Result<&mut Self, BorrowConflict> {
+        item.check_borrows()?;
+        self.list.push(Box::new(item));
+        self.stats.push(Stats::default());
+        Ok(self)
+    }
+
+    // Returns names and statistics of the functions, the most time consuming first.
+    // Functions run by `run_parallel` are timed on their own threads,
+    // so that the sum can exceed the wall time.
+    fn report(&self) -> Vec<(&'static str, Stats)> {
+        let mut report: Vec<_> = (self.list.iter().zip(&self.stats))
+            .map(|(item, stats)| (item.name(), *stats))
+            .collect();
+        report.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.total));
+        report
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.fill(Stats::default());
+    }
+
+    // Makes `After` run after `Before`.
+    fn add_dependency<Before: 'static, After: 'static>(&mut self) -> &mut Self {
+        self.dependencies.push((TypeId::of::<Before>(), TypeId::of::<After>()));
+        self
+    }
+
+    // Returns (Before, After) indices of declared and inferred dependencies.
+    fn edges(&self) -> Vec<(usize, usize)> {
+        let n = self.list.len();
+        let index = |id: TypeId| self.list.iter().position(|item| item.id() == id);
+        let mut edges: Vec<(usize, usize)> = (self.dependencies.iter())
+            .filter_map(|(before, after)| Some((index(*before)?, index(*after)?)))
+            .collect();
+
+        // Inferred edges don't override declared ones, so that they're skipped if they'd make a cycle.
+        for i in 0..n {
+            for j in i + 1..n {
+                if conflicts(self.list[i].as_ref(), self.list[j].as_ref()) && !reaches(&edges, j, i) {
+                    edges.push((i, j));
+                }
+            }
+        }
+        edges
+    }
+
+    // Returns indices of the functions in topological order of the `edges`.
+    // Functions that don't depend on each other keep insertion order.
+    fn order(&self, edges: &[(usize, usize)]) -> Result<Vec<usize>, CycleError> {
+        let n = self.list.len();
+
+        // Kahn's algorithm picking the earliest added one among ready functions.
+        let mut in_degrees = vec![0; n];
+        for (_, after) in edges.iter() {
+            in_degrees[*after] += 1;
+        }
+        let mut order = Vec::new();
+        let mut done = vec![false; n];
+        while let Some(i) = (0..n).find(|i| !done[*i] && in_degrees[*i] == 0) {
+            done[i] = true;
+            order.push(i);
+            for (_, after) in edges.iter().filter(|(before, _)| *before == i) {
+                in_degrees[*after] -= 1;
+            }
+        }
+        if order.len() < n {
+            let functions = (0..n).filter(|i| !done[*i]).map(|i| self.list[i].name()).collect();
+            return Err(CycleError { functions });
+        }
+        Ok(order)
+    }
+
+    // Runs all functions in topological order, and returns their outputs in the same order.
+    // It stops at the first function that fails, e.g. because data it visits is missing.
+    fn run(&mut self, data: &mut DataStorage) -> Result<Vec<Box<dyn Any>>, ScheduleError> {
+        let order = self.order(&self.edges())?;
+        let mut outputs = Vec::new();
+        for i in order {
+            let (output, elapsed) = timed(|| self.list[i].invoke(data));
+            outputs.push(output?);
+            self.stats[i].record(elapsed);
+        }
+        Ok(outputs)
+    }
+
+    // Runs functions like `run`, but ones not conflicting with each other run at the same time on std threads.
+    // Functions are grouped into batches in topological order, where a function joins the last batch
+    // if it doesn't depend on any function in there. A batch of one function runs on this thread.
+    // Outputs are in the order of batches, and in insertion order within a batch.
+    fn run_parallel(&mut self, data: &mut DataStorage) -> Result<Vec<Box<dyn Any>>, ScheduleError> {
+        let edges = self.edges();
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        for i in self.order(&edges)? {
+            match batches.last_mut() {
+                Some(batch) if !batch.iter().any(|j| reaches(&edges, *j, i)) => batch.push(i),
+                _ => batches.push(vec![i]),
+            }
+        }
+
+        let mut outputs: Vec<Box<dyn Any>> = Vec::new();
+        for mut batch in batches {
+            batch.sort();
+            let jobs = (self.list.iter_mut().enumerate())
+                .filter(|(i, _)| batch.contains(i))
+                .map(|(_, item)| item.prepare(data))
+                .collect::<Result<Vec<Job>, _>>()?;
+            if jobs.len() == 1 {
+                for (i, job) in batch.iter().zip(jobs) {
+                    let (output, elapsed) = timed(job);
+                    self.stats[*i].record(elapsed);
+                    outputs.push(output);
+                }
+                data.apply_commands();
+                continue;
+            }
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = jobs.into_iter().map(|job| scope.spawn(|| timed(job))).collect();
+                for (i, handle) in batch.iter().zip(handles) {
+                    let (output, elapsed) = handle.join().unwrap();
+                    self.stats[*i].record(elapsed);
+                    outputs.push(output);
+                }
+            });
+            data.apply_commands();
+        }
+        Ok(outputs)
+    }
+}
+
+// Calls `f` and measures how long it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let output = f();
+    (output, start.elapsed())
+}
+
+// Returns true if either one writes what the other one visits, which means they can't run at the same time.
+fn conflicts(a: &dyn Invokable, b: &dyn Invokable) -> bool {
+    let visits = |item: &dyn Invokable| -> Vec<DataKey> {
+        item.reads().into_iter().chain(item.writes()).collect()
+    };
+    a.writes().iter().any(|key| visits(b).contains(key))
+        || b.writes().iter().any(|key| visits(a).contains(key))
+}
+
+// Returns true if `to` can be reached from `from` by following the `edges`.
+fn reaches(edges: &[(usize, usize)], from: usize, to: usize) -> bool {
+    let mut stack = vec![from];
+    let mut visited = vec![from];
+    while let Some(node) = stack.pop() {
+        if node == to {
+            return true;
+        }
+        for (_, next) in edges.iter().filter(|(before, _)| *before == node) {
+            if !visited.contains(next) {
+                visited.push(*next);
+                stack.push(*next);
+            }
+        }
+    }
+    false
+}
+
+// Element of the `Ref` tuple, which is `&[T]`, `Named` or `Commands`.
+// Compiler infers that lifetime of `data` is different with the `Self` because we casted to raw pointers.
+// This helps we to use `data` after calling this function so that we can call `visit_mut`.
+// But it's dangerous, so that `invoke` checks borrow rule manually by `ty`.
+trait Fetch {
+    // The same type with the lifetime `'a`.
+    type Item<'a>: Fetch;
+
+    // Returns the key of the slice if the storage doesn't have it.
+    fn fetch(data: &impl Store) -> Result<Self, DataKey>
+    where
+        Self: Sized;
+
+    // Key of the slice, or `None` if it doesn't borrow data.
+    fn ty() -> Option<DataKey>;
+}
+
+// Element of the `Mut` tuple, which is `&mut [T]` or `NamedMut`.
+trait FetchMut {
+    // The same type with the lifetime `'a`.
+    type Item<'a>: FetchMut;
+
+    // Returns the key of the slice if the storage doesn't have it.
+    fn fetch_mut(data: &mut impl Store) -> Result<Self, DataKey>
+    where
+        Self: Sized;
+
+    // Key of the slice.
+    fn ty() -> Option<DataKey>;
+}
+
+impl<T: 'static> Fetch for &[T] {
+    type Item<'a> = &'a [T];
+
+    #[inline]
+    fn fetch(data: &impl Store) -> Result<Self, DataKey> {
+        let slice = data.as_slice::<T>().ok_or(DataKey::of::<T>())?;
+        Ok(unsafe { &*(slice as *const [T]) })
+    }
+
+    fn ty() -> Option<DataKey> {
+        Some(DataKey::of::<T>())
+    }
+}
+
+impl<T: 'static> FetchMut for &mut [T] {
+    type Item<'a> = &'a mut [T];
+
+    #[inline]
+    fn fetch_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+        let slice = data.as_mut_slice::<T>().ok_or(DataKey::of::<T>())?;
+        Ok(unsafe { &mut *(slice as *mut [T]) })
+    }
+
+    fn ty() -> Option<DataKey> {
+        Some(DataKey::of::<T>())
+    }
+}
+
+impl Fetch for Commands<'_> {
+    type Item<'a> = Commands<'a>;
+
+    #[inline]
+    fn fetch(data: &impl Store) -> Result<Self, DataKey> {
+        Ok(Commands {
+            queue: data.commands().queue,
+            _data: PhantomData,
+        })
+    }
+
+    fn ty() -> Option<DataKey> {
+        None
+    }
+}
+
+// Name of a collection, which picks it for `Named` and `NamedMut`.
+trait Name: 'static {
+    const NAME: &'static str;
+}
+
+// Slice of the collection `N` of type `T`, which is used like `&[T]`.
+struct Named<'a, N, T> {
+    slice: &'a [T],
+    _name: PhantomData<fn() -> N>,
+}
+
+impl<N, T> Deref for Named<'_, N, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<N: Name, T: 'static> Fetch for Named<'_, N, T> {
+    type Item<'a> = Named<'a, N, T>;
+
+    #[inline]
+    fn fetch(data: &impl Store) -> Result<Self, DataKey> {
+        let slice = data.as_named_slice::<T>(N::NAME).ok_or(DataKey::named::<T>(N::NAME))?;
+        Ok(Named {
+            slice: unsafe { &*(slice as *const [T]) },
+            _name: PhantomData,
+        })
+    }
+
+    fn ty() -> Option<DataKey> {
+        Some(DataKey::named::<T>(N::NAME))
+    }
+}
+
+// Mutable slice of the collection `N` of type `T`, which is used like `&mut [T]`.
+struct NamedMut<'a, N, T> {
+    slice: &'a mut [T],
+    _name: PhantomData<fn() -> N>,
+}
+
+impl<N, T> Deref for NamedMut<'_, N, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<N, T> DerefMut for NamedMut<'_, N, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.slice
+    }
+}
+
+impl<N: Name, T: 'static> FetchMut for NamedMut<'_, N, T> {
+    type Item<'a> = NamedMut<'a, N, T>;
+
+    #[inline]
+    fn fetch_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+        let slice = data.as_named_mut_slice::<T>(N::NAME).ok_or(DataKey::named::<T>(N::NAME))?;
+        Ok(NamedMut {
+            slice: unsafe { &mut *(slice as *mut [T]) },
+            _name: PhantomData,
+        })
+    }
+
+    fn ty() -> Option<DataKey> {
+        Some(DataKey::named::<T>(N::NAME))
+    }
+}
+
+// Element of the `Mut` tuple owning all values of type `T`, such as for stages draining data.
+// Values are moved out of the storage, which keeps the collection empty so that others still visit it.
+struct Take<T> {
+    values: Vec<T>,
+}
+
+impl<T> Deref for Take<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.values
+    }
+}
+
+impl<T> DerefMut for Take<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.values
+    }
+}
+
+impl<T> IntoIterator for Take<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.into_iter()
+    }
+}
+
+impl<T: 'static> FetchMut for Take<T> {
+    type Item<'a> = Take<T>;
+
+    #[inline]
+    fn fetch_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+        let values = data.take_named::<T>("").ok_or(DataKey::of::<T>())?;
+        Ok(Take { values })
+    }
+
+    fn ty() -> Option<DataKey> {
+        Some(DataKey::of::<T>())
+    }
+}
+
+// Condition on values of type `T`, which picks them for `Filtered`.
+trait Predicate<T>: 'static {
+    fn test(value: &T) -> bool;
 }
 
-impl DataStorage {
-    // Makes sample data.
-    fn new() -> Self {
-        let mut data: HashMap<TypeId, Box<dyn Any>> = HashMap::new();
-        data.insert(TypeId::of::<DataA>(), Box::new(vec![DataA('a'), DataA('b')]));
-        data.insert(TypeId::of::<DataB>(), Box::new(vec![DataB('c'), DataB('d')]));
-        Self { data }
+// View of the slice `S` where only values matching `P` are visible, such as `Filtered<&[DataA], P>`.
+// It's filtered while iterating, so that nothing is copied into temporary vectors.
+// `S` can be any slice element, and it borrows the same data as `S` does.
+struct Filtered<S, P> {
+    slice: S,
+    _predicate: PhantomData<fn() -> P>,
+}
+
+impl<S, P> Filtered<S, P> {
+    fn new(slice: S) -> Self {
+        Self {
+            slice,
+            _predicate: PhantomData,
+        }
+    }
+
+    fn iter<T: 'static>(&self) -> impl Iterator<Item = &T>
+    where
+        S: Deref<Target = [T]>,
+        P: Predicate<T>,
+    {
+        self.slice.iter().filter(|value| P::test(value))
+    }
+
+    fn iter_mut<T: 'static>(&mut self) -> impl Iterator<Item = &mut T>
+    where
+        S: DerefMut<Target = [T]>,
+        P: Predicate<T>,
+    {
+        self.slice.iter_mut().filter(|value| P::test(value))
     }
 }
 
-trait Store {
-    fn as_slice<T: 'static>(&self) -> &[T];
-    fn as_mut_slice<T: 'static>(&mut self) -> &mut [T];
+impl<S: Fetch, P: 'static> Fetch for Filtered<S, P> {
+    type Item<'a> = Filtered<S::Item<'a>, P>;
+
+    #[inline]
+    fn fetch(data: &impl Store) -> Result<Self, DataKey> {
+        S::fetch(data).map(Self::new)
+    }
+
+    fn ty() -> Option<DataKey> {
+        S::ty()
+    }
 }
 
-impl Store for DataStorage {
-    fn as_slice<T: 'static>(&self) -> &[T] {
-        self.data
-            .get(&TypeId::of::<T>())
-            .unwrap()
-            .downcast_ref::<Vec<T>>()
-            .unwrap()
-            .as_slice()
+impl<S: FetchMut, P: 'static> FetchMut for Filtered<S, P> {
+    type Item<'a> = Filtered<S::Item<'a>, P>;
+
+    #[inline]
+    fn fetch_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+        S::fetch_mut(data).map(Self::new)
     }
-    
-    fn as_mut_slice<T: 'static>(&mut self) -> &mut [T] {
-        self.data
-            .get_mut(&TypeId::of::<T>())
-            .unwrap()
-            .downcast_mut::<Vec<T>>()
-            .unwrap()
-            .as_mut_slice()
+
+    fn ty() -> Option<DataKey> {
+        S::ty()
     }
 }
 
-trait Invokable {
-    fn invoke(&mut self, data: &mut DataStorage); // Depends on DataPool for object safety.
+// Element of `Ref` and `Mut` tuples that borrows `CellStorage` for `'a`.
+// Unlike `Fetch`, it's safe because the storage checks borrows of the elements by itself.
+trait FetchCell<'a>: Sized {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError>;
 }
 
-impl<'a, T: Runnable<'a>> Invokable for T {
-    #[inline]
-    fn invoke(&mut self, data: &mut DataStorage) {
-        self.run(
-            <T::Ref as Visit>::visit(data),
-            <T::Mut as VisitMut>::visit_mut(data)
-        );
+impl<'a, T: 'static> FetchCell<'a> for &'a [T] {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        data.slice("")
+    }
+}
+
+impl<'a, T: 'static> FetchCell<'a> for &'a mut [T] {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        data.mut_slice("")
+    }
+}
+
+impl<'a, N: Name, T: 'static> FetchCell<'a> for Named<'a, N, T> {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        Ok(Named {
+            slice: data.slice(N::NAME)?,
+            _name: PhantomData,
+        })
+    }
+}
+
+impl<'a, N: Name, T: 'static> FetchCell<'a> for NamedMut<'a, N, T> {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        Ok(NamedMut {
+            slice: data.mut_slice(N::NAME)?,
+            _name: PhantomData,
+        })
+    }
+}
+
+impl<T: 'static> FetchCell<'_> for Take<T> {
+    fn fetch_cell(data: &CellStorage) -> Result<Self, BorrowError> {
+        let values = std::mem::take(data.mut_values::<T>("")?);
+        Ok(Take { values })
+    }
+}
+
+impl<'a, S: FetchCell<'a>, P> FetchCell<'a> for Filtered<S, P> {
+    fn fetch_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        S::fetch_cell(data).map(Self::new)
     }
 }
 
 trait Visit {
-    fn visit(data: &impl Store) -> Self;
+    // The same type with the lifetime `'a`, see `into_invokable`.
+    type Item<'a>: Visit;
+
+    // Returns the key of the first slice that the storage doesn't have.
+    fn visit(data: &impl Store) -> Result<Self, DataKey>
+    where
+        Self: Sized;
+
+    // Keys of the slices, which are checked before visiting.
+    fn types() -> Vec<DataKey>;
 }
 
 trait VisitMut {
-    fn visit_mut(data: &mut impl Store) -> Self;
+    // The same type with the lifetime `'a`, see `into_invokable`.
+    type Item<'a>: VisitMut;
+
+    // Returns the key of the first slice that the storage doesn't have.
+    fn visit_mut(data: &mut impl Store) -> Result<Self, DataKey>
+    where
+        Self: Sized;
+
+    // Keys of the slices, which are checked before visiting.
+    fn types() -> Vec<DataKey>;
+}
+
+// `Ref` or `Mut` tuple of `FetchCell` elements, see `CellStorage`.
+trait VisitCell<'a>: Sized {
+    fn visit_cell(data: &'a CellStorage) -> Result<Self, BorrowError>;
 }
 
 trait Runnable<'a> {
     type Ref: Visit;
     type Mut: VisitMut;
-    
-    fn run(&mut self, r: Self::Ref, m: Self::Mut);
+    // What the function reports, such as metrics or data for later stages. It's `()` if nothing.
+    type Output: 'static;
+
+    fn run(&mut self, r: Self::Ref, m: Self::Mut) -> Self::Output;
 }
 
-// Please implement `Visit` and `VisitMut` for more tuples. (It's ordinary in Rust for now)
-// And be careful!
-// Compiler infers that lifetime of `data` is different with the `Self` because we casted to raw pointers.
-// This helps we to use `data` after calling this function so that we can call `visit_mut`.
-// But it's dangerous, so that we need to check borrow rule manually.
-impl<A: 'static, B: 'static> Visit for (&[A], &[B]) {
-    #[inline]
-    fn visit(data: &impl Store) -> Self {
-        unsafe {
-            (
-                &*(data.as_slice::<A>() as *const [A]),
-                &*(data.as_slice::<B>() as *const [B]),
-            )
+// `Runnable` that only reads, so that `&self` is enough to run it, see `InvokableRef`.
+// It's a `Runnable` as well, whose `Mut` is `()`.
+trait RunnableRef<'a> {
+    type Ref: Visit;
+    type Output: 'static;
+
+    fn run(&self, r: Self::Ref) -> Self::Output;
+}
+
+impl<'a, T: RunnableRef<'a>> Runnable<'a> for T {
+    type Ref = T::Ref;
+    type Mut = ();
+    type Output = T::Output;
+
+    fn run(&mut self, r: Self::Ref, _m: ()) -> Self::Output {
+        RunnableRef::run(self, r)
+    }
+}
+
+// Implements `Visit` and `VisitMut` for tuples of elements, because we can't be generic over tuple arity in Rust.
+// And be careful! Elements are fetched without borrowing `data`, see `Fetch`.
+macro_rules! impl_visit {
+    ($($id:ident),+) => {
+        impl<$($id: Fetch),+> Visit for ($($id,)+) {
+            type Item<'a> = ($($id::Item<'a>,)+);
+
+            #[inline]
+            fn visit(data: &impl Store) -> Result<Self, DataKey> {
+                Ok(($($id::fetch(data)?,)+))
+            }
+
+            fn types() -> Vec<DataKey> {
+                [$($id::ty()),+].into_iter().flatten().collect()
+            }
+        }
+
+        impl<$($id: FetchMut),+> VisitMut for ($($id,)+) {
+            type Item<'a> = ($($id::Item<'a>,)+);
+
+            #[inline]
+            fn visit_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+                Ok(($($id::fetch_mut(data)?,)+))
+            }
+
+            fn types() -> Vec<DataKey> {
+                [$($id::ty()),+].into_iter().flatten().collect()
+            }
+        }
+
+        impl<'a, $($id: FetchCell<'a>),+> VisitCell<'a> for ($($id,)+) {
+            fn visit_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+                Ok(($($id::fetch_cell(data)?,)+))
+            }
         }
+    };
+}
+
+// Invokes `impl_visit` for the given identifiers and all their suffixes, from 16 elements down to 1.
+macro_rules! impl_visit_all {
+    ($head:ident $(, $tail:ident)*) => {
+        impl_visit!($head $(, $tail)*);
+        impl_visit_all!($($tail),*);
+    };
+    () => {};
+}
+
+impl_visit_all!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+// `()` visits nothing, e.g. for functions that only read.
+impl Visit for () {
+    type Item<'a> = ();
+
+    fn visit(_data: &impl Store) -> Result<Self, DataKey> {
+        Ok(())
+    }
+
+    fn types() -> Vec<DataKey> {
+        Vec::new()
+    }
+}
+
+impl VisitMut for () {
+    type Item<'a> = ();
+
+    fn visit_mut(_data: &mut impl Store) -> Result<Self, DataKey> {
+        Ok(())
+    }
+
+    fn types() -> Vec<DataKey> {
+        Vec::new()
+    }
+}
+
+impl VisitCell<'_> for () {
+    fn visit_cell(_data: &CellStorage) -> Result<Self, BorrowError> {
+        Ok(())
     }
 }
 
-impl<A: 'static, B: 'static> VisitMut for (&mut [A], &mut [B]) {
+// A single slice visits itself, so that functions touching one type don't need a 1-tuple.
+impl<T: 'static> Visit for &[T] {
+    type Item<'a> = &'a [T];
+
+    #[inline]
+    fn visit(data: &impl Store) -> Result<Self, DataKey> {
+        Self::fetch(data)
+    }
+
+    fn types() -> Vec<DataKey> {
+        <Self as Fetch>::ty().into_iter().collect()
+    }
+}
+
+impl<T: 'static> VisitMut for &mut [T] {
+    type Item<'a> = &'a mut [T];
+
     #[inline]
-    fn visit_mut(data: &mut impl Store) -> Self {
-        unsafe {
-            (
-                &mut *(data.as_mut_slice::<A>() as *mut [A]),
-                &mut *(data.as_mut_slice::<B>() as *mut [B]),
-            )
+    fn visit_mut(data: &mut impl Store) -> Result<Self, DataKey> {
+        Self::fetch_mut(data)
+    }
+
+    fn types() -> Vec<DataKey> {
+        <Self as FetchMut>::ty().into_iter().collect()
+    }
+}
+
+impl<'a, T: 'static> VisitCell<'a> for &'a [T] {
+    fn visit_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        Self::fetch_cell(data)
+    }
+}
+
+impl<'a, T: 'static> VisitCell<'a> for &'a mut [T] {
+    fn visit_cell(data: &'a CellStorage) -> Result<Self, BorrowError> {
+        Self::fetch_cell(data)
+    }
+}
+
+// Set of (collection name, data name) evaluated at compile time, see `RunList`.
+#[derive(Clone, Copy)]
+struct KeySet {
+    keys: [(&'static str, &'static str); KeySet::CAPACITY],
+    len: usize,
+}
+
+impl KeySet {
+    const CAPACITY: usize = 32;
+    const EMPTY: Self = Self {
+        keys: [("", ""); Self::CAPACITY],
+        len: 0,
+    };
+
+    const fn of<T: Data>(name: &'static str) -> Self {
+        Self::EMPTY.with((name, T::NAME))
+    }
+
+    const fn with(mut self, key: (&'static str, &'static str)) -> Self {
+        if !self.contains(key) {
+            assert!(self.len < Self::CAPACITY, "too many data in a `RunList`");
+            self.keys[self.len] = key;
+            self.len += 1;
+        }
+        self
+    }
+
+    const fn union(mut self, other: Self) -> Self {
+        let mut i = 0;
+        while i < other.len {
+            self = self.with(other.keys[i]);
+            i += 1;
+        }
+        self
+    }
+
+    const fn contains(&self, key: (&'static str, &'static str)) -> bool {
+        let mut i = 0;
+        while i < self.len {
+            if str_eq(self.keys[i].0, key.0) && str_eq(self.keys[i].1, key.1) {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    const fn intersects(&self, other: &Self) -> bool {
+        let mut i = 0;
+        while i < other.len {
+            if self.contains(other.keys[i]) {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+}
+
+// `&str` can't be compared by `==` in constants.
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Keys that an element or a tuple of elements borrows, which are known at compile time.
+trait StaticKeys {
+    const KEYS: KeySet;
+    // Number of borrowed keys including duplicates, which is greater than `KEYS.len` if some are duplicated.
+    const COUNT: usize = Self::KEYS.len;
+}
+
+impl<T: Data> StaticKeys for &[T] {
+    const KEYS: KeySet = KeySet::of::<T>("");
+}
+
+impl<T: Data> StaticKeys for &mut [T] {
+    const KEYS: KeySet = KeySet::of::<T>("");
+}
+
+impl<N: Name, T: Data> StaticKeys for Named<'_, N, T> {
+    const KEYS: KeySet = KeySet::of::<T>(N::NAME);
+}
+
+impl<N: Name, T: Data> StaticKeys for NamedMut<'_, N, T> {
+    const KEYS: KeySet = KeySet::of::<T>(N::NAME);
+}
+
+impl<S: StaticKeys, P> StaticKeys for Filtered<S, P> {
+    const KEYS: KeySet = S::KEYS;
+}
+
+impl<T: Data> StaticKeys for Take<T> {
+    const KEYS: KeySet = KeySet::of::<T>("");
+}
+
+impl StaticKeys for Commands<'_> {
+    const KEYS: KeySet = KeySet::EMPTY;
+}
+
+impl StaticKeys for () {
+    const KEYS: KeySet = KeySet::EMPTY;
+}
+
+macro_rules! impl_static_keys {
+    ($($id:ident),+) => {
+        impl<$($id: StaticKeys),+> StaticKeys for ($($id,)+) {
+            const KEYS: KeySet = KeySet::EMPTY $(.union($id::KEYS))+;
+            const COUNT: usize = 0 $(+ $id::COUNT)+;
+        }
+    };
+}
+
+macro_rules! impl_static_keys_all {
+    ($head:ident $(, $tail:ident)*) => {
+        impl_static_keys!($head $(, $tail)*);
+        impl_static_keys_all!($($tail),*);
+    };
+    () => {};
+}
+
+impl_static_keys_all!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+// Keys that functions in a `RunList` read and write.
+trait StaticList {
+    const READS: KeySet;
+    const WRITES: KeySet;
+}
+
+impl StaticList for () {
+    const READS: KeySet = KeySet::EMPTY;
+    const WRITES: KeySet = KeySet::EMPTY;
+}
+
+impl<L, R> StaticList for (L, R)
+where
+    L: StaticList,
+    R: for<'a> Runnable<'a>,
+    <R as Runnable<'static>>::Ref: StaticKeys,
+    <R as Runnable<'static>>::Mut: StaticKeys,
+{
+    const READS: KeySet = L::READS.union(<<R as Runnable<'static>>::Ref as StaticKeys>::KEYS);
+    const WRITES: KeySet = L::WRITES.union(<<R as Runnable<'static>>::Mut as StaticKeys>::KEYS);
+}
+
+// Fails to compile if `R` conflicts with itself or with functions in `L`.
+struct Check<L, R>(PhantomData<(L, R)>);
+
+impl<L, R> Check<L, R>
+where
+    L: StaticList,
+    R: for<'a> Runnable<'a>,
+    <R as Runnable<'static>>::Ref: StaticKeys,
+    <R as Runnable<'static>>::Mut: StaticKeys,
+{
+    const OK: () = {
+        let reads = <<R as Runnable<'static>>::Ref as StaticKeys>::KEYS;
+        let writes = <<R as Runnable<'static>>::Mut as StaticKeys>::KEYS;
+        let count = <<R as Runnable<'static>>::Mut as StaticKeys>::COUNT;
+        assert!(
+            writes.len == count && !reads.intersects(&writes),
+            "a function borrows data mutably and elsewhere at the same time"
+        );
+        assert!(
+            !L::WRITES.intersects(&reads)
+                && !L::WRITES.intersects(&writes)
+                && !L::READS.intersects(&writes),
+            "a function writes data that another function in the list visits"
+        );
+    };
+}
+
+// List of functions that don't conflict with each other, which is checked at compile time
+// instead of by `Scheduler` at run-time.
+// `L` is the list of added functions like `((((), RunA), RunB), RunC)`.
+// Functions are run at the same time, so that they must not depend on each other.
+// A list can hold up to `KeySet::CAPACITY`(32) distinct data in total. More than that is a compile error,
+// "too many data in a `RunList`", and there's no way around it but to split the list.
+struct RunList<L> {
+    list: Vec<Box<dyn Invokable>>,
+    _list: PhantomData<fn() -> L>,
+}
+
+impl RunList<()> {
+    fn new() -> Self {
+        Self {
+            list: Vec::new(),
+            _list: PhantomData,
+        }
+    }
+}
+
+impl<L: StaticList> RunList<L> {
+    // Adds the function, and fails to compile if it conflicts with itself or with functions in the list.
+    // The check is a constant evaluated after monomorphization, so that only `cargo build` rejects conflicts,
+    // while `cargo check` accepts them.
+    fn add<R>(mut self, item: R) -> RunList<(L, R)>
+    where
+        R: Invokable + for<'a> Runnable<'a> + 'static,
+        <R as Runnable<'static>>::Ref: StaticKeys,
+        <R as Runnable<'static>>::Mut: StaticKeys,
+    {
+        let () = Check::<L, R>::OK;
+        self.list.push(Box::new(item));
+        RunList {
+            list: self.list,
+            _list: PhantomData,
+        }
+    }
+
+    // Runs all functions at the same time on std threads, and returns their outputs in insertion order.
+    fn run(&mut self, data: &mut DataStorage) -> Result<Vec<Box<dyn Any>>, InvokeError> {
+        let jobs = (self.list.iter_mut())
+            .map(|item| item.prepare(data))
+            .collect::<Result<Vec<Job>, _>>()?;
+        let outputs = std::thread::scope(|scope| {
+            let handles: Vec<_> = jobs.into_iter().map(|job| scope.spawn(job)).collect();
+            (handles.into_iter())
+                .map(|handle| handle.join().unwrap() as Box<dyn Any>)
+                .collect()
+        });
+        data.apply_commands();
+        Ok(outputs)
+    }
+}
+
+// `Runnable` calling a closure, see `into_invokable`.
+struct FnRunnable<F, R, M, O> {
+    f: F,
+    _marker: PhantomData<fn(R, M) -> O>,
+}
+
+// Closure that can be a `Runnable`, where `Marker` is `(R, M, O)` inferred from the closure's signature.
+// It's a trait rather than bounds of `into_invokable`, so that the compiler takes the signature from the annotations.
+trait IntoRunnable<Marker> {
+    type Runnable;
+
+    fn into_runnable(self) -> Self::Runnable;
+}
+
+impl<F, R, M, O> IntoRunnable<(R, M, O)> for F
+where
+    R: Visit,
+    M: VisitMut,
+    F: FnMut(R, M) -> O + for<'a> FnMut(R::Item<'a>, M::Item<'a>) -> O,
+{
+    type Runnable = FnRunnable<F, R, M, O>;
+
+    fn into_runnable(self) -> Self::Runnable {
+        FnRunnable {
+            f: self,
+            _marker: PhantomData,
         }
     }
 }
 
+// Turns a closure into a `Runnable`, so that quick one-off functions can join the list without defining a struct.
+// Parameters must be annotated like `|r: (&[DataA], Commands), m: &mut [DataB]| { ... }`.
+// The closure takes them in any lifetime, and `R` and `M` are inferred from the annotations.
+fn into_invokable<Marker, F: IntoRunnable<Marker>>(f: F) -> F::Runnable {
+    f.into_runnable()
+}
+
+impl<'a, F, R, M, O> Runnable<'a> for FnRunnable<F, R, M, O>
+where
+    R: Visit,
+    M: VisitMut,
+    O: 'static,
+    F: for<'b> FnMut(R::Item<'b>, M::Item<'b>) -> O,
+{
+    type Ref = R::Item<'a>;
+    type Mut = M::Item<'a>;
+    type Output = O;
+
+    fn run(&mut self, r: Self::Ref, m: Self::Mut) -> O {
+        (self.f)(r, m)
+    }
+}
+
 struct RunA;
 impl<'a> Runnable<'a> for RunA {
     type Ref = (&'a [DataA], &'a [DataB]);
     type Mut = (&'a mut [DataA], &'a mut [DataB]);
+    type Output = ();
 
-    // Data race occurs here on purpose.
+    // Data race would occur here, so that `invoke` refuses to run it.
     fn run(&mut self, r: Self::Ref, m: Self::Mut) {
         println!("RunA");
         println!("r: {:?}", r);
@@ -122,10 +1563,11 @@ impl<'a> Runnable<'a> for RunA {
 
 struct RunB;
 impl<'a> Runnable<'a> for RunB {
-    type Ref = (&'a [DataA], &'a [DataB]);
-    type Mut = (&'a mut [DataA], &'a mut [DataB]);
+    type Ref = (&'a [DataB],);
+    type Mut = (&'a mut [DataA],);
+    type Output = ();
 
-    // Data race occurs here on purpose.
+    // It reads and writes different data, so that it's fine.
     fn run(&mut self, r: Self::Ref, m: Self::Mut) {
         println!("RunB");
         println!("r: {:?}", r);
@@ -133,14 +1575,260 @@ impl<'a> Runnable<'a> for RunB {
     }
 }
 
+// Any tuple up to 16 slices can be declared, even a single one. A bare slice works as well, see `RunD`.
+struct RunC;
+impl<'a> Runnable<'a> for RunC {
+    type Ref = (&'a [DataA],);
+    type Mut = (&'a mut [DataB],);
+    type Output = usize;
+
+    // Copies `DataA` into `DataB` in upper case, and returns how many were copied.
+    fn run(&mut self, (a,): Self::Ref, (b,): Self::Mut) -> usize {
+        println!("RunC");
+        for (a, b) in a.iter().zip(b.iter_mut()) {
+            b.0 = a.0.to_ascii_uppercase();
+        }
+        println!("m: {:?}", b);
+        a.len().min(b.len())
+    }
+}
+
+// Functions only reading data don't conflict with each other.
+struct RunD;
+impl<'a> RunnableRef<'a> for RunD {
+    type Ref = &'a [DataA];
+    type Output = String;
+
+    fn run(&self, a: Self::Ref) -> String {
+        a.iter().map(|a| a.0).collect()
+    }
+}
+
+struct RunE;
+impl<'a> RunnableRef<'a> for RunE {
+    type Ref = &'a [DataB];
+    type Output = String;
+
+    fn run(&self, b: Self::Ref) -> String {
+        b.iter().map(|b| b.0).collect()
+    }
+}
+
+// Slices can't grow, so that new data is queued by `Commands`.
+struct RunF;
+impl<'a> Runnable<'a> for RunF {
+    type Ref = (&'a [DataB], Commands<'a>);
+    type Mut = ();
+    type Output = ();
+
+    // Moves the first `DataB` to the end in lower case.
+    fn run(&mut self, (b, commands): Self::Ref, _m: Self::Mut) {
+        if let Some(first) = b.first() {
+            commands.push(DataB(first.0.to_ascii_lowercase()));
+            commands.remove_index::<DataB>(0);
+        }
+    }
+}
+
+// Names of `DataB` collections for double buffering.
+struct Front;
+impl Name for Front {
+    const NAME: &'static str = "front";
+}
+
+struct Back;
+impl Name for Back {
+    const NAME: &'static str = "back";
+}
+
+// Reads and writes `DataB` at the same time, which is fine because they're different collections.
+struct RunG;
+impl<'a> Runnable<'a> for RunG {
+    type Ref = (Named<'a, Front, DataB>,);
+    type Mut = (NamedMut<'a, Back, DataB>,);
+    type Output = ();
+
+    // Draws the next frame to the back buffer from the front buffer.
+    fn run(&mut self, (front,): Self::Ref, (mut back,): Self::Mut) {
+        for (f, b) in front.iter().zip(back.iter_mut()) {
+            b.0 = f.0.to_ascii_uppercase();
+        }
+    }
+}
+
+struct Lowercase;
+impl Predicate<DataA> for Lowercase {
+    fn test(value: &DataA) -> bool {
+        value.0.is_ascii_lowercase()
+    }
+}
+impl Predicate<DataB> for Lowercase {
+    fn test(value: &DataB) -> bool {
+        value.0.is_ascii_lowercase()
+    }
+}
+
+// Sees only lower case data.
+struct RunH;
+impl<'a> Runnable<'a> for RunH {
+    type Ref = (Filtered<&'a [DataA], Lowercase>,);
+    type Mut = (Filtered<&'a mut [DataB], Lowercase>,);
+    type Output = String;
+
+    // Upper-cases lower case `DataB`, and returns lower case `DataA`.
+    fn run(&mut self, (a,): Self::Ref, (mut b,): Self::Mut) -> String {
+        b.iter_mut().for_each(|b| b.0 = b.0.to_ascii_uppercase());
+        a.iter().map(|a| a.0).collect()
+    }
+}
+
+// Consumes all `DataA`, and returns how many there were.
+struct RunI;
+impl<'a> Runnable<'a> for RunI {
+    type Ref = ();
+    type Mut = (Take<DataA>,);
+    type Output = usize;
+
+    fn run(&mut self, _r: Self::Ref, (a,): Self::Mut) -> usize {
+        a.into_iter().count()
+    }
+}
+
 fn main() {
+    // Makes sample data.
     let mut data = DataStorage::new();
+    data.insert(vec![DataA('a'), DataA('b')]);
+    data.insert(vec![DataB('c'), DataB('d')]);
+    assert!(data.contains::<DataA>() && data.len::<DataB>() == 2);
     
     // We can have a list including heterogeneous functions using object safe trait `Invokable`.
-    let list: Vec<Box<dyn Invokable>> = vec![Box::new(RunA), Box::new(RunB)];
+    // `Scheduler` keeps the list, and refuses functions that would make aliasing slices.
+    let mut scheduler = Scheduler::new();
+    if let Err(e) = scheduler.add(RunA) {
+        println!("{}", e);
+    }
+    scheduler.add(RunB).unwrap().add(RunC).unwrap();
+
+    // `RunB` writes `DataA` that `RunC` reads, so that `RunB` would run first as it's added first.
+    // But we want `RunB` to see what `RunC` made.
+    scheduler.add_dependency::<RunC, RunB>();
+
+    // Let's invoke each function in order, and collect their outputs.
+    let outputs = scheduler.run(&mut data).unwrap();
+
+    // Outputs can be taken back by their types.
+    let copied: usize = outputs.iter().filter_map(|output| output.downcast_ref::<usize>()).sum();
+    println!("Copied {} items", copied);
+
+    // Functions not conflicting with each other run at the same time.
+    // `RunD` and `RunE` only read, but `RunC` writes `DataB` that `RunE` reads, so that it runs after them.
+    let mut parallel = Scheduler::new();
+    parallel.add(RunD).unwrap().add(RunE).unwrap().add(RunC).unwrap();
+    let outputs = parallel.run_parallel(&mut data).unwrap();
+    let read: Vec<_> = outputs.iter().filter_map(|output| output.downcast_ref::<String>()).collect();
+    println!("Read {:?}", read);
+
+    // Let's find out which function takes the longest.
+    parallel.run_parallel(&mut data).unwrap();
+    for (name, stats) in parallel.report() {
+        let average = stats.average();
+        println!("{}: {} calls, {:?} in total, {:?} on average", name, stats.calls, stats.total, average);
+    }
+    parallel.reset_stats();
+    assert!(parallel.report().iter().all(|(_, stats)| stats.calls == 0));
 
-    // Let's invoke each function.
-    for mut item in list {
-        item.invoke(&mut data);
+    // Closures can be functions as well.
+    let mut shout = into_invokable(|b: &[DataB], _m: ()| {
+        b.iter().map(|b| b.0.to_ascii_uppercase()).collect::<String>()
+    });
+    let output = shout.invoke(&mut data).unwrap();
+    println!("Shouted {:?}", output.downcast_ref::<String>().unwrap());
+
+    // Commands are applied after the function returns.
+    RunF.invoke(&mut data).unwrap();
+    println!("Moved {:?}", data.as_slice::<DataB>().unwrap());
+
+    // Dependencies making a cycle can't be ordered.
+    scheduler.add_dependency::<RunB, RunC>();
+    if let Err(e) = scheduler.run(&mut data) {
+        println!("{}", e);
+    }
+
+    // Data can be taken out as well.
+    let a = data.remove::<DataA>().unwrap();
+    println!("Removed {:?}", a);
+    assert!(!data.contains::<DataA>() && data.len::<DataA>() == 0);
+
+    // Functions visiting missing data fail instead of panicking.
+    if let Err(e) = RunD.invoke(&data) {
+        println!("{}", e);
+    }
+    // Refused calls are left out of stats, so that they don't skew averages.
+    assert!(parallel.run(&mut data).is_err());
+    assert!(parallel.report().iter().all(|(_, stats)| stats.calls == 0));
+
+    // Several collections of the same type are told apart by their names.
+    data.insert_named("front", vec![DataB('x'), DataB('y')]);
+    data.insert_named("back", vec![DataB('-'), DataB('-')]);
+    RunG.invoke(&mut data).unwrap();
+    let back = data.remove_named::<DataB>("back").unwrap();
+    println!("Drew {:?}", back);
+    if let Err(e) = RunG.invoke(&mut data) {
+        println!("{}", e);
+    }
+
+    // The same functions run on `CellStorage` without unsafe lifetime casts in visiting.
+    let mut cells = CellStorage::new();
+    cells.insert(vec![DataA('k'), DataA('l')]);
+    cells.insert(vec![DataB('m'), DataB('n')]);
+    let copied = cells.invoke(&mut RunC).unwrap();
+    println!("Copied {} items to {:?} in cells", copied, cells.get::<DataB>().unwrap());
+    if let Err(e) = cells.invoke(&mut RunA) {
+        println!("{}", e);
+    }
+
+    // Filtered views skip values not matching their predicates.
+    data.insert(vec![DataA('a'), DataA('B'), DataA('c')]);
+    data.insert(vec![DataB('d'), DataB('E')]);
+    let output = RunH.invoke(&mut data).unwrap();
+    let b = data.as_slice::<DataB>().unwrap();
+    println!("Filtered {:?}, {:?}", output.downcast_ref::<String>().unwrap(), b);
+
+    // Values can be moved out to functions, leaving nothing behind.
+    let output = RunI.invoke(&mut data).unwrap();
+    println!("Drained {} items", output.downcast_ref::<usize>().unwrap());
+    assert!(data.contains::<DataA>() && data.len::<DataA>() == 0);
+
+    // Lists can be checked at compile time as well.
+    // Adding `RunC` here fails to compile, because it writes `DataB` that `RunE` reads.
+    let mut list = RunList::new().add(RunD).add(RunE);
+    let outputs = list.run(&mut data).unwrap();
+    let read: Vec<_> = outputs.iter().filter_map(|output| output.downcast_ref::<String>()).collect();
+    println!("Read {:?} from a static list", read);
+
+    // Functions only reading data can be invoked by shared references, even on other threads.
+    let shared = &data;
+    let read = std::thread::scope(|scope| {
+        let d = scope.spawn(|| InvokableRef::invoke(&RunD, shared));
+        let e = scope.spawn(|| InvokableRef::invoke(&RunE, shared));
+        [d.join().unwrap().unwrap(), e.join().unwrap().unwrap()]
+    });
+    let read: Vec<_> = read.iter().filter_map(|output| output.downcast_ref::<String>()).collect();
+    println!("Read {:?} through shared references", read);
+
+    // Functions can be run speculatively, and rolled back if any of them fails.
+    data.insert(vec![DataB('x'), DataB('Y')]);
+    let snapshot = data.snapshot();
+    let mut validate = into_invokable(|b: &[DataB], _m: ()| -> Result<(), String> {
+        match b.iter().find(|b| b.0.is_ascii_uppercase()) {
+            Some(b) => Err(format!("{:?} is not lower case", b)),
+            None => Ok(()),
+        }
+    });
+    RunH.invoke(&mut data).unwrap();
+    let output = validate.invoke(&mut data).unwrap();
+    if let Err(e) = output.downcast_ref::<Result<(), String>>().unwrap() {
+        data.restore(snapshot);
+        println!("Rolled back to {:?} because {}", data.as_slice::<DataB>().unwrap(), e);
     }
 }
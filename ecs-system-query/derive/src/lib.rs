@@ -0,0 +1,249 @@
+//! Derive macros of the `ecs-system-query` example.
+//! Real projects would use `syn` and `quote`, but this example doesn't depend on any crates.
+//! So that we read just enough tokens of the item by hand.
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+/// Implements `Component` for the type.
+///
+/// ```ignore
+/// #[derive(Component)]
+/// struct CompA(&'static str);
+/// ```
+///
+/// `#[component(require(A, B))]` makes adding the type insert `A::default()` and `B::default()` as well
+/// if the entity doesn't have them, see `Component::insert_required`.
+///
+/// ```ignore
+/// #[derive(Component)]
+/// #[component(require(Transform))]
+/// struct Sprite(&'static str);
+/// ```
+#[proc_macro_derive(Component, attributes(component))]
+pub fn derive_component(input: TokenStream) -> TokenStream {
+    match Item::parse(input) {
+        Ok(item) => {
+            let body = if item.required.is_empty() {
+                String::new()
+            } else {
+                let inserts = item
+                    .required
+                    .iter()
+                    .map(|ty| {
+                        format!(
+                            "if storage.get::<{0}>(entity).is_none() {{ \
+                                storage.insert::<{0}>(entity, <{0} as Default>::default()); \
+                            }}",
+                            ty
+                        )
+                    })
+                    .collect::<String>();
+                format!(
+                    "fn insert_required(storage: &mut crate::storage::ComponentStorage, entity: crate::entity::Entity) {{ {} }}",
+                    inserts
+                )
+            };
+            item.impl_trait("crate::storage::Component", &body)
+                .parse()
+                .unwrap()
+        }
+        Err(msg) => format!("compile_error!({:?});", msg).parse().unwrap(),
+    }
+}
+
+/// Header of a struct, enum, or union.
+struct Item {
+    name: String,
+    // Generic parameters without defaults, e.g. `T: Clone, const N: usize`.
+    params: Vec<String>,
+    // Names of the generic parameters, e.g. `T, N`.
+    args: Vec<String>,
+    // Names of the generic type parameters, e.g. `T`.
+    types: Vec<String>,
+    // Predicates of the where clause.
+    predicates: String,
+    // Types listed by `#[component(require(...))]`.
+    required: Vec<String>,
+}
+
+impl Item {
+    fn parse(input: TokenStream) -> Result<Self, String> {
+        let mut tokens = input.into_iter().peekable();
+
+        // Attributes and visibility.
+        let mut required = Vec::new();
+        let name = loop {
+            match tokens.next() {
+                Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                    if let Some(TokenTree::Group(attr)) = tokens.next() {
+                        parse_attr(attr.stream(), &mut required)?;
+                    }
+                }
+                Some(TokenTree::Ident(ident))
+                    if matches!(ident.to_string().as_str(), "struct" | "enum" | "union") =>
+                {
+                    match tokens.next() {
+                        Some(TokenTree::Ident(name)) => break name.to_string(),
+                        _ => return Err("expected type name".to_owned()),
+                    }
+                }
+                Some(_) => {}
+                None => return Err("expected struct, enum, or union".to_owned()),
+            }
+        };
+
+        // Generic parameters.
+        let mut params = Vec::new();
+        if matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == '<') {
+            tokens.next();
+            let mut depth = 0;
+            let mut param = Vec::new();
+            for tt in tokens.by_ref() {
+                // `>` of `->` doesn't close anything.
+                let is_arrow = matches!(param.last(), Some(TokenTree::Punct(p)) if p.as_char() == '-' && p.spacing() == Spacing::Joint);
+                if let TokenTree::Punct(p) = &tt {
+                    match p.as_char() {
+                        '>' if is_arrow => {}
+                        '<' => depth += 1,
+                        '>' if depth == 0 => break,
+                        '>' => depth -= 1,
+                        ',' if depth == 0 => {
+                            params.push(std::mem::take(&mut param));
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                param.push(tt);
+            }
+            if !param.is_empty() {
+                params.push(param);
+            }
+        }
+
+        let mut args = Vec::new();
+        let mut types = Vec::new();
+        for param in params.iter_mut() {
+            match param.first() {
+                Some(TokenTree::Punct(p)) if p.as_char() == '\'' => {
+                    return Err("Component can't have lifetime parameters".to_owned());
+                }
+                Some(TokenTree::Ident(ident)) if ident.to_string() == "const" => {
+                    args.push(param[1].to_string());
+                }
+                Some(TokenTree::Ident(ident)) => {
+                    args.push(ident.to_string());
+                    types.push(ident.to_string());
+                }
+                _ => return Err("unexpected generic parameter".to_owned()),
+            }
+            // Removes the default, which is not allowed in impl.
+            if let Some(pos) = param.iter().position(
+                |tt| matches!(tt, TokenTree::Punct(p) if p.as_char() == '=' && p.spacing() == Spacing::Alone),
+            ) {
+                param.truncate(pos);
+            }
+        }
+        let params = params.into_iter().map(to_string).collect();
+
+        // Where clause, which is before the body of a named struct or after the body of a tuple struct.
+        let mut predicates = Vec::new();
+        let mut in_where = false;
+        for tt in tokens {
+            match &tt {
+                TokenTree::Ident(ident) if ident.to_string() == "where" => in_where = true,
+                TokenTree::Group(g) if g.delimiter() == Delimiter::Brace => break,
+                TokenTree::Punct(p) if p.as_char() == ';' => break,
+                _ if in_where => predicates.push(tt),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            name,
+            params,
+            args,
+            types,
+            predicates: to_string(predicates),
+            required,
+        })
+    }
+
+    /// Makes an impl of the `trait_path` for the item, whose items are the `body`.
+    fn impl_trait(&self, trait_path: &str, body: &str) -> String {
+        // Type parameters must be `Send + Sync + 'static` as the `Component` is.
+        let mut predicates = self
+            .types
+            .iter()
+            .map(|ty| format!("{}: Send + Sync + 'static, ", ty))
+            .collect::<String>();
+        predicates.push_str(&self.predicates);
+        format!(
+            "impl<{}> {} for {}<{}> where {} {{ {} }}",
+            self.params.join(", "),
+            trait_path,
+            self.name,
+            self.args.join(", "),
+            predicates,
+            body,
+        )
+    }
+}
+
+/// Reads `#[component(require(...))]` into the `required`, and rejects other options.
+fn parse_attr(attr: TokenStream, required: &mut Vec<String>) -> Result<(), String> {
+    let mut tokens = attr.into_iter();
+    match tokens.next() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "component" => {}
+        _ => return Ok(()),
+    }
+    let Some(TokenTree::Group(options)) = tokens.next() else {
+        return Err("expected component options".to_owned());
+    };
+    let mut options = options.stream().into_iter();
+    while let Some(option) = options.next() {
+        match (option, options.next()) {
+            (TokenTree::Ident(ident), Some(TokenTree::Group(types)))
+                if ident.to_string() == "require" && types.delimiter() == Delimiter::Parenthesis =>
+            {
+                required.extend(split_commas(types.stream()).into_iter().map(to_string));
+            }
+            _ => return Err("unknown component attribute".to_owned()),
+        }
+        match options.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == ',' => {}
+            None => break,
+            _ => return Err("expected `,` between component options".to_owned()),
+        }
+    }
+    Ok(())
+}
+
+/// Splits the `tokens` by commas that are not inside of angle brackets, e.g. `A<B, C>, D` into `A<B, C>` and `D`.
+fn split_commas(tokens: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut items = Vec::new();
+    let mut item = Vec::new();
+    let mut depth = 0;
+    for tt in tokens {
+        if let TokenTree::Punct(p) = &tt {
+            match p.as_char() {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                ',' if depth == 0 => {
+                    items.push(std::mem::take(&mut item));
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        item.push(tt);
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+    items
+}
+
+fn to_string(tokens: Vec<TokenTree>) -> String {
+    tokens.into_iter().collect::<TokenStream>().to_string()
+}
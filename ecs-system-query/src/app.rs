@@ -0,0 +1,169 @@
+use super::{
+    std_clock, AnyTransitions, Clock, ComponentStorage, Event, ExclusiveSystem, IntoSystem, Schedule,
+    Stage, StateTransitions, StateValue, States,
+};
+
+/// Resource to stop the loop of `App::run` at the end of the current frame.
+/// Insert it from an `ExclusiveSystem`, or from anywhere having the storage.
+#[derive(Debug, Clone, Copy)]
+pub struct AppExit;
+
+/// A group of `Component`s, resources, events, and *System*s making up a feature, such as physics.
+/// It's added to an `App` by `App::add_plugin`.
+pub trait Plugin {
+    /// Registers everything of the feature to the `app`.
+    fn build(&self, app: &mut App);
+}
+
+/// A storage and a schedule running on it, which is the entry point of an application.
+///
+/// ```ignore
+/// App::new().add_plugin(PhysicsPlugin).add_system(SysA).add_system(SysB).run();
+/// ```
+pub struct App {
+    storage: ComponentStorage,
+    schedule: Schedule,
+    // Drives `update` until the application ends, see `App::set_runner`.
+    runner: Box<dyn FnOnce(App)>,
+    // Measures the `delta` of the `Time`, see `App::set_clock`.
+    clock: Clock,
+    // Time of the `clock` when the last frame started.
+    last: Option<std::time::Duration>,
+    // `StateTransitions` of state machines added by `App::add_state`.
+    transitions: Vec<Box<dyn AnyTransitions>>,
+}
+
+impl App {
+    pub fn new() -> Self {
+        Self {
+            storage: ComponentStorage::new(),
+            schedule: Schedule::new(),
+            runner: Box::new(run_loop),
+            clock: std_clock,
+            last: None,
+            transitions: Vec::new(),
+        }
+    }
+
+    /// Appends the `system` to the `Stage::Update`.
+    /// Use `schedule_mut` for other stages or configurations.
+    pub fn add_system<M>(&mut self, system: impl IntoSystem<M>) -> &mut Self {
+        self.schedule.add_system(Stage::Update, system);
+        self
+    }
+
+    /// Appends the exclusive `system` to the `Stage::Update`.
+    pub fn add_exclusive_system(&mut self, system: impl ExclusiveSystem) -> &mut Self {
+        self.schedule.add_exclusive_system(Stage::Update, system);
+        self
+    }
+
+    /// Builds the `plugin` into this application right away.
+    pub fn add_plugin(&mut self, plugin: impl Plugin) -> &mut Self {
+        plugin.build(self);
+        self
+    }
+
+    pub fn insert_resource<R: Send + Sync + 'static>(&mut self, value: R) -> &mut Self {
+        self.storage.insert_resource(value);
+        self
+    }
+
+    pub fn add_event<T: Event>(&mut self) -> &mut Self {
+        self.storage.add_event::<T>();
+        self
+    }
+
+    /// Adds the state machine `S` starting from the `initial` state.
+    /// Transitions are applied at the start of every frame, before the schedule runs.
+    pub fn add_state<S: StateValue>(&mut self, initial: S) -> &mut Self {
+        self.storage.insert_resource(States::new(initial));
+        self.transitions.push(Box::new(StateTransitions::<S>::new()));
+        self
+    }
+
+    /// Returns the `OnEnter` schedule of the `state`, see [`StateTransitions::on_enter_mut`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state machine hasn't been added by [`Self::add_state`].
+    pub fn on_enter_mut<S: StateValue>(&mut self, state: S) -> &mut Schedule {
+        self.transitions_mut::<S>().on_enter_mut(state)
+    }
+
+    /// Returns the `OnExit` schedule of the `state`, see [`StateTransitions::on_exit_mut`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state machine hasn't been added by [`Self::add_state`].
+    pub fn on_exit_mut<S: StateValue>(&mut self, state: S) -> &mut Schedule {
+        self.transitions_mut::<S>().on_exit_mut(state)
+    }
+
+    fn transitions_mut<S: StateValue>(&mut self) -> &mut StateTransitions<S> {
+        (self.transitions.iter_mut())
+            .find_map(|t| t.as_any_mut().downcast_mut::<StateTransitions<S>>())
+            .unwrap_or_else(|| panic!("state {} should be added by add_state", std::any::type_name::<S>()))
+    }
+
+    pub fn storage(&self) -> &ComponentStorage {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut ComponentStorage {
+        &mut self.storage
+    }
+
+    pub fn schedule_mut(&mut self) -> &mut Schedule {
+        &mut self.schedule
+    }
+
+    /// Replaces how `run` drives frames.
+    /// The default one calls `update` in a plain loop until `AppExit` is inserted.
+    /// On the web, the loop blocks the browser, so that set a runner calling `update` in `requestAnimationFrame` instead.
+    pub fn set_runner(&mut self, runner: impl FnOnce(App) + 'static) -> &mut Self {
+        self.runner = Box::new(runner);
+        self
+    }
+
+    /// Replaces the clock measuring the `delta` of the `Time`, which is `std_clock` by default.
+    /// `Instant` is not available on the web, see [`Clock`].
+    pub fn set_clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Runs a frame.
+    /// Before the schedule runs, the `Time` moves on by the time elapsed since the last frame,
+    /// and state transitions are applied.
+    pub fn update(&mut self) {
+        let now = (self.clock)();
+        let delta = self.last.map(|last| now.saturating_sub(last)).unwrap_or_default();
+        self.last = Some(now);
+        self.storage.advance_time(delta);
+        for transitions in self.transitions.iter_mut() {
+            transitions.apply(&mut self.storage);
+        }
+        self.schedule.run(&mut self.storage);
+    }
+
+    /// Returns true if `AppExit` has been inserted.
+    pub fn should_exit(&self) -> bool {
+        self.storage.get_resource::<AppExit>().is_some()
+    }
+
+    /// Hands the application over to the runner, see [`Self::set_runner`].
+    /// `self` is left empty, so that we can run it at the end of building.
+    pub fn run(&mut self) {
+        let mut app = std::mem::replace(self, App::new());
+        let runner = std::mem::replace(&mut app.runner, Box::new(run_loop));
+        runner(app);
+    }
+}
+
+/// Default runner of the `App`.
+fn run_loop(mut app: App) {
+    while !app.should_exit() {
+        app.update();
+    }
+}
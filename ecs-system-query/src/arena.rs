@@ -0,0 +1,98 @@
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+/// Bump allocator over a fixed region, which can be given to `ComponentStorage::set_column_alloc`.
+/// Allocating just moves an offset, and freeing does nothing, so that the whole region is released at once when the arena drops.
+/// Columns are kept close together in the region as well.
+/// Requests that don't fit fall back to the global allocator.
+pub struct BumpArena {
+    base: NonNull<u8>,
+    capacity: usize,
+    // Offset of the free space from the `base`.
+    offset: Mutex<usize>,
+}
+
+// Safety: The region is handed out only through the `offset`, which is guarded by the mutex.
+unsafe impl Send for BumpArena {}
+unsafe impl Sync for BumpArena {}
+
+impl BumpArena {
+    /// Alignment of the region.
+    const ALIGN: usize = 64;
+
+    pub fn new(capacity: usize) -> Self {
+        let layout = Layout::from_size_align(capacity.max(1), Self::ALIGN).unwrap();
+        // Safety: `layout` has non-zero size.
+        let base = NonNull::new(unsafe { alloc::alloc(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self {
+            base,
+            capacity: layout.size(),
+            offset: Mutex::new(0),
+        }
+    }
+
+    /// Returns how many bytes of the region have been handed out.
+    pub fn used(&self) -> usize {
+        *self.offset.lock().unwrap()
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let start = self.base.as_ptr() as usize;
+        (start..start + self.capacity).contains(&(ptr as usize))
+    }
+}
+
+impl Drop for BumpArena {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.capacity, Self::ALIGN).unwrap();
+        // Safety: `base` was allocated with this layout.
+        unsafe { alloc::dealloc(self.base.as_ptr(), layout) };
+    }
+}
+
+unsafe impl GlobalAlloc for BumpArena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut offset = self.offset.lock().unwrap();
+        let base = self.base.as_ptr() as usize;
+        let start = (base + *offset).next_multiple_of(layout.align()) - base;
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.capacity => {
+                *offset = end;
+                self.base.as_ptr().add(start)
+            }
+            _ => alloc::alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Memory in the region is released when the arena drops.
+        if !self.contains(ptr) {
+            alloc::dealloc(ptr, layout);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.contains(ptr) {
+            let mut offset = self.offset.lock().unwrap();
+            let start = ptr as usize - self.base.as_ptr() as usize;
+            // The last block can be resized in place.
+            if start + layout.size() == *offset && start + new_size <= self.capacity {
+                *offset = start + new_size;
+                return ptr;
+            }
+            if new_size <= layout.size() {
+                return ptr;
+            }
+        }
+        // Safety: Caller guarantees that the `new_size` with the alignment makes a valid layout.
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new = self.alloc(new_layout);
+        if !new.is_null() {
+            std::ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+}
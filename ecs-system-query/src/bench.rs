@@ -0,0 +1,134 @@
+//! Rough benchmarks of hot paths, run by `cargo run --release -- bench`.
+//! This example doesn't depend on any crates such as criterion, so that we just measure elapsed time.
+
+use super::{ComponentStorage, Entity, Query, Schedule, Stage, System, Target, With, Without};
+use ecs_system_query_derive::Component;
+use std::any::TypeId;
+use std::hint::black_box;
+use std::time::Instant;
+
+const ENTITIES: usize = 10_000;
+const RUNS: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, Component)]
+struct Position(f32, f32);
+
+#[derive(Debug, Clone, Copy, Component)]
+struct Velocity(f32, f32);
+
+/// Tag `Component`s to make many tables.
+#[derive(Debug, Clone, Copy, Component)]
+struct Tag<const N: usize>;
+
+/// Filter checking several `Component` types of each table.
+type Tagged = Target<Tag<0>, With<(Tag<1>, Tag<2>)>, Without<(Tag<3>, Tag<4>)>>;
+
+/// Moves entities, which is the typical query of a frame.
+struct Movement;
+impl System for Movement {
+    type Ref = Target<Velocity>;
+    type Mut = Target<Position>;
+    fn run(&self, r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut) {
+        for (v, p) in r.zip(m) {
+            for (v, p) in v.iter().zip(p.iter_mut()) {
+                p.0 += v.0;
+                p.1 += v.1;
+            }
+        }
+    }
+}
+
+/// Has many queries doing nothing, so that the cost of getting query results stands out.
+struct ManyQueries;
+impl System for ManyQueries {
+    #[allow(clippy::type_complexity)]
+    type Ref = (
+        Target<Tag<0>>,
+        Target<Tag<1>>,
+        Target<Tag<2>>,
+        Target<Tag<3>>,
+        Target<Tag<4>>,
+        Target<Tag<5>>,
+        Target<Tag<6>>,
+        Target<Tag<7>>,
+    );
+    type Mut = ();
+    fn run(&self, r: <Self::Ref as Query>::Output, _m: ()) {
+        black_box(r);
+    }
+}
+
+pub fn run() {
+    let mut storage = ComponentStorage::new();
+    let entities = measure("spawn", 1, || {
+        (0..ENTITIES)
+            .map(|i| storage.spawn((Position(i as f32, 0.0), Velocity(1.0, 1.0))))
+            .collect::<Vec<Entity>>()
+    });
+
+    let mut batch = ComponentStorage::new();
+    measure("spawn batch", 1, || {
+        batch.spawn_batch((0..ENTITIES).map(|i| (Position(i as f32, 0.0), Velocity(1.0, 1.0))))
+    });
+
+    let mut schedule = Schedule::new();
+    schedule.add_system(Stage::Update, Movement);
+    measure("schedule run", RUNS, || schedule.run(&mut storage));
+
+    measure("get", RUNS, || {
+        for entity in entities.iter() {
+            black_box(storage.get::<Position>(*entity));
+        }
+    });
+
+    measure("remove and insert", 1, || {
+        for entity in entities.iter() {
+            let v = storage.remove::<Velocity>(*entity).unwrap();
+            storage.insert(*entity, v);
+        }
+    });
+
+    // Every combination of tags makes its own table.
+    let mut tagged = ComponentStorage::new();
+    for bits in 0..1 << 8 {
+        spawn_tags(&mut tagged, bits);
+    }
+    let mut entity = tagged.spawn(());
+    measure("filter 256 tables", RUNS, || {
+        // Spawning invalidates cached query results, so that the filter is evaluated again.
+        tagged.despawn(entity);
+        entity = tagged.spawn(());
+        tagged.query::<Tagged>(TypeId::of::<Tagged>()).entity_count()
+    });
+
+    let mut schedule = Schedule::new();
+    schedule.add_system(Stage::Update, ManyQueries);
+    measure("8 queries", RUNS, || schedule.run(&mut tagged));
+}
+
+/// Spawns an entity having `Tag<N>`s where the `N`th bit of the `bits` is set.
+fn spawn_tags(storage: &mut ComponentStorage, bits: usize) -> Entity {
+    let entity = storage.spawn(());
+    let mut edit = storage.entity_mut(entity).unwrap();
+    macro_rules! insert_tags {
+        ($($n:literal)*) => {$(
+            if bits & (1 << $n) != 0 {
+                edit.insert(Tag::<$n>);
+            }
+        )*};
+    }
+    insert_tags!(0 1 2 3 4 5 6 7);
+    entity
+}
+
+/// Runs the `f` `runs` times, and prints the average time.
+fn measure<R>(name: &str, runs: usize, mut f: impl FnMut() -> R) -> R {
+    let start = Instant::now();
+    let mut result = None;
+    for _ in 0..runs {
+        result = Some(black_box(f()));
+    }
+    let elapsed = start.elapsed() / runs as u32;
+    println!("{:<20} {:>12?} / run ({} entities)", name, elapsed, ENTITIES);
+    result.unwrap()
+}
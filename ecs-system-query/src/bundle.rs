@@ -0,0 +1,28 @@
+use super::{AnyColumn, ComponentId, Components, Table};
+
+/// A set of `Component`s that is inserted at once.
+/// Tuples of `Component`s are `Bundle`s, see `impl_all_tuples!`.
+/// The entity moves to the table having all of them only once, instead of moving for each `Component`.
+pub trait Bundle: 'static {
+    /// Registers the `Component` types, and returns their ids in the order of the bundle.
+    fn register(components: &mut Components) -> Vec<ComponentId>;
+
+    /// Makes empty columns for the `Component` types in the order of the bundle.
+    fn new_columns() -> Vec<Box<dyn AnyColumn>>;
+
+    /// Puts the `Component`s at the `row` of the `table`.
+    /// `ids` are what `register` returned.
+    fn put(self, table: &mut Table, ids: &[ComponentId], row: usize, tick: u32);
+}
+
+impl Bundle for () {
+    fn register(_components: &mut Components) -> Vec<ComponentId> {
+        Vec::new()
+    }
+
+    fn new_columns() -> Vec<Box<dyn AnyColumn>> {
+        Vec::new()
+    }
+
+    fn put(self, _table: &mut Table, _ids: &[ComponentId], _row: usize, _tick: u32) {}
+}
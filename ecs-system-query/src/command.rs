@@ -0,0 +1,89 @@
+use super::query::AccessId;
+use super::{Bundle, Component, ComponentStorage, Entity, QueryState, SystemParam};
+
+/// Deferred change to the storage, see [`Commands`].
+pub type Command = Box<dyn FnOnce(&mut ComponentStorage) + Send + Sync>;
+
+/// Function *System* parameter queueing structural changes such as spawning entities.
+/// Query based *System*s can't change the structure while they're running because queries point into tables.
+/// So that queued commands are applied right after the *System* finishes, see [`ComponentStorage::finish_system`].
+/// Every *System* is a sync point then, and there's no need for explicit flushes like `apply_deferred` in other engines.
+/// Order the *System* seeing the changes after the one queueing them, e.g. by [`SystemConfig::after`](super::SystemConfig::after).
+pub struct Commands<'a> {
+    queue: &'a mut Vec<Command>,
+}
+
+impl<'a> Commands<'a> {
+    /// Queues the `command` which takes the storage mutably.
+    pub fn add(&mut self, command: impl FnOnce(&mut ComponentStorage) + Send + Sync + 'static) -> &mut Self {
+        self.queue.push(Box::new(command));
+        self
+    }
+
+    pub fn spawn(&mut self, bundle: impl Bundle + Send + Sync + 'static) -> &mut Self {
+        self.add(move |storage| {
+            storage.spawn(bundle);
+        })
+    }
+
+    pub fn despawn(&mut self, entity: Entity) -> &mut Self {
+        self.add(move |storage| {
+            storage.despawn(entity);
+        })
+    }
+
+    /// Despawns the `entity` and all its descendants, see [`ComponentStorage::despawn_recursive`].
+    pub fn despawn_recursive(&mut self, entity: Entity) -> &mut Self {
+        self.add(move |storage| {
+            storage.despawn_recursive(entity);
+        })
+    }
+
+    pub fn insert<T: Component>(&mut self, entity: Entity, value: T) -> &mut Self {
+        self.add(move |storage| storage.insert(entity, value))
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: Entity) -> &mut Self {
+        self.add(move |storage| {
+            storage.remove::<T>(entity);
+        })
+    }
+
+    pub fn insert_resource<R: Send + Sync + 'static>(&mut self, value: R) -> &mut Self {
+        self.add(move |storage| storage.insert_resource(value))
+    }
+}
+
+impl<'x> SystemParam for Commands<'x> {
+    type Item<'a> = Commands<'a>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, _state: &mut QueryState) -> Self::Item<'a> {
+        // Safety: The queue is only touched by `apply_commands` after the *System* finishes.
+        let queue = unsafe { &mut *(storage.command_queue() as *mut Vec<Command>) };
+        Commands { queue }
+    }
+
+    /// Commands don't access anything until they're applied.
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
+
+impl ComponentStorage {
+    /// Applies queued commands in the order they're queued.
+    /// Commands queued while applying, e.g. by *System*s run in a command, are applied as well.
+    pub fn apply_commands(&mut self) {
+        while !self.command_queue().is_empty() {
+            for command in std::mem::take(self.command_queue()) {
+                command(self);
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::{Component, ComponentStorage, Entity, FastMap};
+use std::alloc::Layout;
+use std::any::TypeId;
+
+/// Index of a registered `Component` type.
+/// Static `Component`s have their `TypeId`s as well, but dynamic ones only have this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ComponentId(usize);
+
+impl ComponentId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Set of `ComponentId`s as a bitset, so that comparing sets of many `Component`s takes a few word operations.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ComponentSet {
+    // The `n`th bit is set if `ComponentId(n)` is in the set. Trailing zero words are not kept.
+    words: Vec<u64>,
+}
+
+impl ComponentSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: ComponentId) {
+        let (word, bit) = (id.index() / 64, id.index() % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << bit;
+    }
+
+    pub fn contains(&self, id: ComponentId) -> bool {
+        let (word, bit) = (id.index() / 64, id.index() % 64);
+        self.words.get(word).is_some_and(|w| w & (1 << bit) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns true if this set has every element of the `other`.
+    pub fn is_superset(&self, other: &Self) -> bool {
+        other.words.len() <= self.words.len()
+            && other.words.iter().zip(self.words.iter()).all(|(o, s)| o & s == *o)
+    }
+
+    /// Returns true if this set and the `other` have any element in common.
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.words.iter().zip(other.words.iter()).any(|(a, b)| a & b != 0)
+    }
+}
+
+impl FromIterator<ComponentId> for ComponentSet {
+    fn from_iter<I: IntoIterator<Item = ComponentId>>(iter: I) -> Self {
+        let mut set = Self::new();
+        for id in iter {
+            set.insert(id);
+        }
+        set
+    }
+}
+
+/// Function dropping a value in place.
+pub type DropFn = unsafe fn(*mut u8);
+
+/// Function inserting `Component`s required by a `Component`, see [`Component::insert_required`].
+pub type RequiredFn = fn(&mut ComponentStorage, Entity);
+
+/// Information of a registered `Component` type.
+#[derive(Debug, Clone)]
+pub struct ComponentInfo {
+    pub name: String,
+    pub layout: Layout,
+    pub drop: Option<DropFn>,
+    /// `None` for dynamic `Component`s.
+    pub type_id: Option<TypeId>,
+    /// `None` for dynamic `Component`s.
+    pub required: Option<RequiredFn>,
+}
+
+/// `Component` type registry.
+#[derive(Debug, Default)]
+pub struct Components {
+    infos: Vec<ComponentInfo>,
+    // Used only to find out `ComponentId`s of types, tables are indexed by `ComponentId`s.
+    by_type: FastMap<TypeId, ComponentId>,
+}
+
+impl Components {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the `Component` type `T` if it's not registered yet.
+    pub fn register<T: Component>(&mut self) -> ComponentId {
+        if let Some(id) = self.by_type.get(&TypeId::of::<T>()) {
+            return *id;
+        }
+
+        unsafe fn drop_in_place<T>(ptr: *mut u8) {
+            std::ptr::drop_in_place(ptr as *mut T);
+        }
+
+        let id = self.push(ComponentInfo {
+            name: std::any::type_name::<T>().to_owned(),
+            layout: Layout::new::<T>(),
+            drop: std::mem::needs_drop::<T>().then_some(drop_in_place::<T> as DropFn),
+            type_id: Some(TypeId::of::<T>()),
+            required: Some(T::insert_required),
+        });
+        self.by_type.insert(TypeId::of::<T>(), id);
+        id
+    }
+
+    /// Registers a `Component` type that is not known at compile time.
+    /// Values of this type are treated as raw bytes with the `layout`, and dropped by the `drop`.
+    pub fn register_dynamic(
+        &mut self,
+        name: &str,
+        layout: Layout,
+        drop: Option<DropFn>,
+    ) -> ComponentId {
+        self.push(ComponentInfo {
+            name: name.to_owned(),
+            layout,
+            drop,
+            type_id: None,
+            required: None,
+        })
+    }
+
+    /// Registers all `Component` types of the `other` registry.
+    /// Static ones are matched by `TypeId`, and dynamic ones are matched by name and layout.
+    /// Returns ids in this registry indexed by ids in the `other`.
+    pub fn merge(&mut self, other: &Components) -> Vec<ComponentId> {
+        other
+            .infos
+            .iter()
+            .map(|info| match info.type_id {
+                Some(ty) => self.by_type.get(&ty).copied().unwrap_or_else(|| {
+                    let id = self.push(info.clone());
+                    self.by_type.insert(ty, id);
+                    id
+                }),
+                None => self
+                    .infos
+                    .iter()
+                    .position(|x| {
+                        x.type_id.is_none() && x.name == info.name && x.layout == info.layout
+                    })
+                    .map(ComponentId)
+                    .unwrap_or_else(|| self.push(info.clone())),
+            })
+            .collect()
+    }
+
+    pub fn id(&self, ty: &TypeId) -> Option<ComponentId> {
+        self.by_type.get(ty).copied()
+    }
+
+    pub fn info(&self, id: ComponentId) -> &ComponentInfo {
+        &self.infos[id.0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.infos.len()
+    }
+
+    fn push(&mut self, info: ComponentInfo) -> ComponentId {
+        self.infos.push(info);
+        ComponentId(self.infos.len() - 1)
+    }
+}
@@ -0,0 +1,156 @@
+use super::{Bundle, Component, ComponentId, ComponentStorage};
+use std::any::Any;
+
+/// Our *Entity*.
+/// It's just an index to the location of its `Component`s with a generation to detect stale handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+impl Entity {
+    pub(crate) fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// Where an `Entity`'s `Component`s are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityLocation {
+    pub table: usize,
+    pub row: usize,
+}
+
+/// Metadata of an `Entity`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EntityMeta {
+    pub generation: u32,
+    /// `None` if the entity has been despawned.
+    pub location: Option<EntityLocation>,
+}
+
+/// Handle to read an entity, see [`ComponentStorage::entity`].
+#[derive(Clone, Copy)]
+pub struct EntityRef<'a> {
+    storage: &'a ComponentStorage,
+    entity: Entity,
+}
+
+impl<'a> EntityRef<'a> {
+    pub fn id(&self) -> Entity {
+        self.entity
+    }
+
+    pub fn contains<T: Component>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    pub fn get<T: Component>(&self) -> Option<&'a T> {
+        self.storage.get::<T>(self.entity)
+    }
+
+    /// Returns all `Component`s of the entity without knowing their types, see [`ComponentStorage::get_all`].
+    pub fn components(&self) -> impl Iterator<Item = (ComponentId, &'a dyn Any)> {
+        self.storage.get_all(self.entity)
+    }
+}
+
+/// Handle to edit an entity, see [`ComponentStorage::entity_mut`].
+/// Moving the entity between tables is done by the storage, so that you don't need to care about its location.
+///
+/// # Example
+///
+/// ```ignore
+/// storage.entity_mut(entity).unwrap().insert(CompA("A")).insert(CompB("B"));
+/// ```
+pub struct EntityMut<'a> {
+    storage: &'a mut ComponentStorage,
+    entity: Entity,
+}
+
+impl<'a> EntityMut<'a> {
+    pub fn id(&self) -> Entity {
+        self.entity
+    }
+
+    /// Puts the `value` to the entity, see [`ComponentStorage::insert`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entity has been despawned by remove hooks.
+    pub fn insert<T: Component>(&mut self, value: T) -> &mut Self {
+        self.storage.insert(self.entity, value);
+        self
+    }
+
+    /// Puts all `Component`s of the `bundle` to the entity at once, see [`ComponentStorage::insert_bundle`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entity has been despawned by remove hooks.
+    pub fn insert_bundle<B: Bundle>(&mut self, bundle: B) -> &mut Self {
+        self.storage.insert_bundle(self.entity, bundle);
+        self
+    }
+
+    /// Takes the `Component` `T` out of the entity, see [`ComponentStorage::remove`].
+    pub fn remove<T: Component>(&mut self) -> Option<T> {
+        self.storage.remove::<T>(self.entity)
+    }
+
+    pub fn contains<T: Component>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    pub fn get<T: Component>(&self) -> Option<&T> {
+        self.storage.get::<T>(self.entity)
+    }
+
+    pub fn get_mut<T: Component>(&mut self) -> Option<&mut T> {
+        self.storage.get_mut::<T>(self.entity)
+    }
+
+    /// Returns all `Component`s of the entity without knowing their types, see [`ComponentStorage::get_all`].
+    pub fn components(&self) -> impl Iterator<Item = (ComponentId, &dyn Any)> {
+        self.storage.get_all(self.entity)
+    }
+
+    /// Mutable version of [`Self::components`], see [`ComponentStorage::get_all_mut`].
+    pub fn components_mut(&mut self) -> impl Iterator<Item = (ComponentId, &mut dyn Any)> {
+        self.storage.get_all_mut(self.entity)
+    }
+
+    /// Despawns the entity, see [`ComponentStorage::despawn`].
+    pub fn despawn(self) {
+        self.storage.despawn(self.entity);
+    }
+}
+
+impl ComponentStorage {
+    /// Returns a handle to read the `entity`, or `None` if it doesn't exist.
+    pub fn entity(&self, entity: Entity) -> Option<EntityRef<'_>> {
+        self.location(entity)?;
+        Some(EntityRef {
+            storage: self,
+            entity,
+        })
+    }
+
+    /// Returns a handle to edit the `entity`, or `None` if it doesn't exist.
+    pub fn entity_mut(&mut self, entity: Entity) -> Option<EntityMut<'_>> {
+        self.location(entity)?;
+        Some(EntityMut {
+            storage: self,
+            entity,
+        })
+    }
+}
@@ -0,0 +1,186 @@
+use super::{AccessId, Component, ComponentStorage, Entity, Query, QueryId, QueryState, Store};
+use std::cell::Cell;
+use std::iter::{Chain, Copied};
+use std::marker::PhantomData;
+use std::slice::Iter;
+
+/// Our `Event`.
+pub trait Event: Send + Sync + 'static {}
+
+/// An `Event` with its id.
+struct Instance<T> {
+    id: usize,
+    event: T,
+}
+
+/// Double buffered `Event` queue.
+/// Events live for two frames, so that every system can see them once regardless of its position in the schedule.
+/// `ComponentStorage` drops older buffer when it moves on to the next frame.
+pub struct Events<T> {
+    // Events sent during the previous frame.
+    prev: Vec<Instance<T>>,
+    // Events sent during the current frame.
+    cur: Vec<Instance<T>>,
+    // Id of the next event.
+    next_id: usize,
+}
+
+impl<T> Events<T> {
+    pub fn new() -> Self {
+        Self {
+            prev: Vec::new(),
+            cur: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn send(&mut self, event: T) {
+        self.cur.push(Instance {
+            id: self.next_id,
+            event,
+        });
+        self.next_id += 1;
+    }
+
+    /// Drops events of the previous frame and swaps the buffers.
+    pub fn update(&mut self) {
+        std::mem::swap(&mut self.prev, &mut self.cur);
+        self.cur.clear();
+    }
+}
+
+/// Run condition passing if the events `T` have been sent since it was last evaluated, see [`SystemConfig::run_if`].
+/// So that a *System* reacting to rare events such as a window resize doesn't poll every frame.
+/// Each condition has its own cursor, so that make one for each *System*.
+/// Put it last among the conditions, or the cursor moves even if the *System* doesn't run.
+///
+/// [`SystemConfig::run_if`]: super::SystemConfig::run_if
+pub fn on_event<T: Event>() -> impl Fn(&ComponentStorage) -> bool {
+    let seen = Cell::new(0);
+    move |storage| {
+        let next_id = (storage.get_resource::<Events<T>>()).map_or(0, |events| events.next_id);
+        seen.replace(next_id) != next_id
+    }
+}
+
+/// Query parameter to read `Event`s.
+/// Each system has its own cursor, so that it reads an event only once.
+pub struct EventReader<T>(PhantomData<T>);
+
+/// Query parameter to send `Event`s.
+/// Put this in the `Mut`. In the `Ref`, it gives you nothing.
+pub struct EventWriter<T>(PhantomData<T>);
+
+/// Iterator over `Event`s that the system hasn't read yet.
+pub struct EventIter<'a, T> {
+    iter: Chain<Iter<'a, Instance<T>>, Iter<'a, Instance<T>>>,
+    from: usize,
+}
+
+impl<'a, T> EventIter<'a, T> {
+    /// Reads events from `cursor` and moves the `cursor` to the end.
+    pub fn new(events: &'a Events<T>, cursor: &mut usize) -> Self {
+        let from = *cursor;
+        *cursor = events.next_id;
+        Self {
+            iter: events.prev.iter().chain(events.cur.iter()),
+            from,
+        }
+    }
+
+    /// Iterator over no events, which is for events that don't exist yet.
+    pub fn empty() -> Self {
+        Self {
+            iter: [].iter().chain([].iter()),
+            from: 0,
+        }
+    }
+}
+
+impl<'a, T> Iterator for EventIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let from = self.from;
+        self.iter
+            .find(|instance| instance.id >= from)
+            .map(|instance| &instance.event)
+    }
+}
+
+/// `Event` sender.
+pub struct EventSender<'a, T> {
+    events: &'a mut Events<T>,
+}
+
+impl<'a, T: Event> EventSender<'a, T> {
+    pub fn new(events: &'a mut Events<T>) -> Self {
+        Self { events }
+    }
+
+    pub fn send(&mut self, event: T) {
+        self.events.send(event);
+    }
+}
+
+impl<'a, T: Event> Query<'a> for EventReader<T> {
+    type Output = EventIter<'a, T>;
+    type OutputMut = EventIter<'a, T>;
+
+    #[inline]
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output {
+        storage.get_events::<T>(QueryId::read::<Self>(state.system(), state.next_slot()))
+    }
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut {
+        storage.get_events::<T>(QueryId::write::<Self>(state.system(), state.next_slot()))
+    }
+
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        vec![AccessId::of::<Events<T>>()]
+    }
+}
+
+impl<'a, T: Event> Query<'a> for EventWriter<T> {
+    type Output = ();
+    type OutputMut = EventSender<'a, T>;
+
+    #[inline]
+    fn query(_storage: &mut dyn Store, _state: &mut QueryState) -> Self::Output {}
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, _state: &mut QueryState) -> Self::OutputMut {
+        storage.get_events_mut::<T>()
+    }
+
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        vec![AccessId::of::<Events<T>>()]
+    }
+}
+
+/// Query parameter to read entities whose `Component` `T` has been removed or despawned.
+/// Like `EventReader`, each system reads removals of the current and the previous frame only once.
+pub struct RemovedComponents<T>(PhantomData<T>);
+
+impl<'a, T: Component> Query<'a> for RemovedComponents<T> {
+    type Output = Copied<EventIter<'a, Entity>>;
+    type OutputMut = Copied<EventIter<'a, Entity>>;
+
+    #[inline]
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output {
+        storage.get_removed::<T>(QueryId::read::<Self>(state.system(), state.next_slot())).copied()
+    }
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut {
+        storage.get_removed::<T>(QueryId::write::<Self>(state.system(), state.next_slot())).copied()
+    }
+
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        vec![AccessId::of::<RemovedComponents<T>>()]
+    }
+}
@@ -0,0 +1,146 @@
+use super::{ComponentStorage, Entity, Filter, Query, QueryState, RowIter};
+use ecs_system_query_derive::Component;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// Parent of the entity.
+/// Use [`ComponentStorage::attach`] and [`ComponentStorage::detach`] instead of inserting this directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+pub struct Parent(Entity);
+
+impl Parent {
+    pub fn get(&self) -> Entity {
+        self.0
+    }
+}
+
+/// Children of the entity in the order they're attached.
+/// Use [`ComponentStorage::attach`] and [`ComponentStorage::detach`] instead of inserting this directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Component)]
+pub struct Children(Vec<Entity>);
+
+impl Children {
+    pub fn entities(&self) -> &[Entity] {
+        &self.0
+    }
+}
+
+impl ComponentStorage {
+    /// Makes the `child` a child of the `parent`.
+    /// If the `child` already has a parent, it's detached from the parent first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either entity doesn't exist or the `parent` is the `child` or its descendant.
+    pub fn attach(&mut self, child: Entity, parent: Entity) {
+        assert!(self.location(child).is_some(), "child should exist");
+        assert!(self.location(parent).is_some(), "parent should exist");
+        let mut ancestor = Some(parent);
+        while let Some(entity) = ancestor {
+            assert!(entity != child, "attaching makes a cycle");
+            ancestor = self.get::<Parent>(entity).map(Parent::get);
+        }
+
+        self.detach(child);
+        self.insert(child, Parent(parent));
+        match self.get_mut::<Children>(parent) {
+            Some(children) => children.0.push(child),
+            None => self.insert(parent, Children(vec![child])),
+        }
+    }
+
+    /// Makes the `child` a root, and returns its previous parent.
+    pub fn detach(&mut self, child: Entity) -> Option<Entity> {
+        let parent = self.remove::<Parent>(child)?.get();
+        if let Some(children) = self.get_mut::<Children>(parent) {
+            children.0.retain(|e| *e != child);
+            if children.0.is_empty() {
+                self.remove::<Children>(parent);
+            }
+        }
+        Some(parent)
+    }
+
+    /// Despawns the `entity` and all its descendants.
+    /// Returns false if the `entity` doesn't exist.
+    pub fn despawn_recursive(&mut self, entity: Entity) -> bool {
+        if self.location(entity).is_none() {
+            return false;
+        }
+        self.detach(entity);
+        let mut stack = vec![entity];
+        while let Some(entity) = stack.pop() {
+            if let Some(children) = self.get::<Children>(entity) {
+                stack.extend_from_slice(children.entities());
+            }
+            self.despawn(entity);
+        }
+        true
+    }
+
+    /// Returns all entities in depth-first order, so that parents always come before their children.
+    /// Roots are in table order, and children are in the order they're attached.
+    pub fn hierarchy_order(&self) -> Vec<Entity> {
+        let mut order = Vec::new();
+        let mut stack = Vec::new();
+        for table in self.tables() {
+            for root in table.entities() {
+                if self.get::<Parent>(*root).is_some() {
+                    continue;
+                }
+                stack.push(*root);
+                while let Some(entity) = stack.pop() {
+                    order.push(entity);
+                    if let Some(children) = self.get::<Children>(entity) {
+                        // Despawned children without `despawn_recursive` may remain.
+                        let alive = children.entities().iter().rev();
+                        stack.extend(alive.filter(|e| self.location(**e).is_some()));
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
+/// Query parameter to iterate over the `F` in hierarchy order, see [`ComponentStorage::hierarchy_order`].
+/// Each item is `(Entity, Component)`, and parents come before their children.
+/// It's useful for propagating something from parents to children such as transforms.
+pub struct Hierarchy<F>(PhantomData<F>);
+
+impl<'a, F: Filter> Query<'a> for Hierarchy<F> {
+    type Output = std::vec::IntoIter<(Entity, &'a F::Target)>;
+    type OutputMut = std::vec::IntoIter<(Entity, &'a mut F::Target)>;
+
+    fn query(storage: &mut dyn super::Store, state: &mut QueryState) -> Self::Output {
+        let rows = F::query(storage, state).rows();
+        in_hierarchy_order(storage, rows)
+    }
+
+    fn query_mut(storage: &mut dyn super::Store, state: &mut QueryState) -> Self::OutputMut {
+        let rows = F::query_mut(storage, state).rows();
+        in_hierarchy_order(storage, rows)
+    }
+
+    #[inline]
+    fn ids() -> Vec<super::AccessId> {
+        F::ids()
+    }
+}
+
+/// Sorts the `rows` in hierarchy order.
+fn in_hierarchy_order<R: RowIter>(
+    storage: &mut dyn super::Store,
+    mut rows: R,
+) -> std::vec::IntoIter<(Entity, R::Item)> {
+    let mut items = HashMap::new();
+    while let Some((entity, item)) = rows.next_row() {
+        items.insert(entity, item);
+    }
+    storage
+        .hierarchy_order()
+        .into_iter()
+        .filter_map(|entity| items.remove(&entity).map(|item| (entity, item)))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
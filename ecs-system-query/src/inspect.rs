@@ -0,0 +1,91 @@
+use super::{ChangeFilter, ComponentStorage, Filter, Name};
+use std::any::TypeId;
+use std::fmt;
+
+/// Human readable result of [`ComponentStorage::debug_query`].
+#[derive(Debug, Clone)]
+pub struct QueryReport {
+    pub filter: &'static str,
+    pub target: &'static str,
+    pub change: ChangeFilter,
+    /// False if the `Target` has never been inserted, which is a common reason of matching nothing.
+    pub is_target_registered: bool,
+    /// Tables passing the filter, including empty ones.
+    pub tables: Vec<TableReport>,
+}
+
+/// A table in the [`QueryReport`].
+#[derive(Debug, Clone)]
+pub struct TableReport {
+    /// Index to the `ComponentStorage::tables`.
+    pub index: usize,
+    /// Names of all `Component`s of the table.
+    pub components: Vec<String>,
+    pub entities: usize,
+    /// Names of the entities having the `Name`, see [`ComponentStorage::set_name`].
+    pub names: Vec<String>,
+}
+
+impl QueryReport {
+    pub fn entity_count(&self) -> usize {
+        self.tables.iter().map(|table| table.entities).sum()
+    }
+}
+
+impl fmt::Display for QueryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} (target: {}, change: {:?})", self.filter, self.target, self.change)?;
+        if !self.is_target_registered {
+            writeln!(f, "  {} has never been inserted", self.target)?;
+        }
+        for table in self.tables.iter() {
+            writeln!(
+                f,
+                "  table {} [{}]: {} entities",
+                table.index,
+                table.components.join(", "),
+                table.entities
+            )?;
+            if !table.names.is_empty() {
+                writeln!(f, "    named: {}", table.names.join(", "))?;
+            }
+        }
+        write!(f, "  {} entities in {} tables", self.entity_count(), self.tables.len())
+    }
+}
+
+impl ComponentStorage {
+    /// Finds out which tables the filter `F` selects without running it, so that we can see why a query is empty.
+    /// Tables are matched in the same way as queries, but change filters are not evaluated.
+    pub fn debug_query<F: Filter>(&self) -> QueryReport {
+        let components = self.components();
+        let name = components.id(&TypeId::of::<Name>());
+        let target = components.id(&TypeId::of::<F::Target>());
+        let sets = F::sets(components);
+        let tables = (self.tables().iter().enumerate())
+            .filter(|(_, table)| {
+                let set = table.component_set();
+                target.is_some_and(|id| set.contains(id))
+                    && sets.iter().any(|filter| filter.is_match(set))
+            })
+            .map(|(index, table)| TableReport {
+                index,
+                components: (table.ids().iter())
+                    .map(|id| components.info(*id).name.clone())
+                    .collect(),
+                entities: table.len(),
+                names: match name.and_then(|id| table.column::<Name>(id)) {
+                    Some(col) => col.data().iter().map(|name| name.to_string()).collect(),
+                    None => Vec::new(),
+                },
+            })
+            .collect();
+        QueryReport {
+            filter: std::any::type_name::<F>(),
+            target: std::any::type_name::<F::Target>(),
+            change: F::CHANGE,
+            is_target_registered: target.is_some(),
+            tables,
+        }
+    }
+}
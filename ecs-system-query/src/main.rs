@@ -5,36 +5,77 @@
 //! We can use Rust's associated type to solve this problem.
 //! Associated type is an easy approach to show what types are passing to the *System*.
 
+// This is an example, so that some of the APIs are not used.
+#![allow(dead_code)]
+
+mod app;
+mod arena;
+mod bench;
+mod bundle;
+mod command;
+mod component;
+mod entity;
+mod event;
+mod hierarchy;
+mod inspect;
+mod name;
+mod non_send;
+mod persist;
+mod profile;
 mod query;
+mod runner;
+mod schedule;
+mod script;
+mod state;
+mod stats;
 mod storage;
 mod system;
+mod table;
+#[cfg(test)]
+mod tests;
 mod util;
+use app::*;
+use arena::*;
+use bundle::*;
+use command::*;
+use component::*;
+use entity::*;
+use event::*;
+use hierarchy::*;
+use name::*;
+use non_send::*;
+use persist::*;
+use profile::*;
 use query::*;
+use runner::*;
+use schedule::*;
+use script::*;
+use state::*;
+use stats::*;
 use storage::*;
 use system::*;
+use table::*;
 use util::*;
-use std::any::TypeId;
-
-// impl of query::Identify for various tuples.
-impl_identify!(0);
-impl_identify!(1,A);
-impl_identify!(2,A,B);
-impl_identify!(3,A,B,C);
+use ecs_system_query_derive::Component;
+use std::alloc::Layout;
+use std::any::{Any, TypeId};
+use std::sync::Arc;
+use std::time::Duration;
 
-// impl of query::Query for various tuples.
-impl_query!(1,A);
-impl_query!(2,A,B);
-impl_query!(3,A,B,C);
+// impl of query::Identify, query::Query, bundle::Bundle, query::ZipEntities and query::Or for tuples up to 16 elements.
+impl_all_tuples!();
 
 /// Test `Component`.
-#[derive(Debug)]
+#[derive(Debug, Clone, Component)]
 struct CompA(&'static str);
-impl Component for CompA {}
 
 /// Test `Component`.
-#[derive(Debug)]
+#[derive(Debug, Component)]
 struct CompB(&'static str);
-impl Component for CompB {}
+
+/// Test tag `Component`, which has no data.
+#[derive(Debug, Clone, Component)]
+struct Selected;
 
 /// Test `Filter`.
 struct FA;
@@ -45,32 +86,50 @@ impl Filter for FA {
     type FilterNone = ();
 }
 
-/// Test `Filter`.
-struct FB;
-impl Filter for FB {
-    type Target = CompB;
-    type FilterAll = (CompA, CompB);
-    type FilterAny = ();
-    type FilterNone = ();
-}
+// `filter!` writes the same form as above for you.
+filter!(
+    /// Test `Filter`.
+    FB = CompB, all = (CompA, CompB)
+);
+
+filter!(
+    /// Test `Filter`, selects all entities having `CompA`.
+    FAllA = CompA, all = CompA
+);
+
+filter!(
+    /// Test `Filter`, selects `CompB` of entities having `CompA`.
+    /// Not every entity having `CompA` has `CompB`, so that we're going to use this with `Option`.
+    FMaybeB = CompB, all = CompA
+);
+
+/// Test resource.
+struct Paused(bool);
+
+/// Test `Event`.
+struct Ping;
+impl Event for Ping {}
 
 /// Test `System`.
 struct SysA;
 impl System for SysA {
-    type Ref = (FA, FB);
+    // `Ref` and `Mut` can't access the same `Component`, see `Schedule::try_add_system_with`.
+    type Ref = (FB, Local<u32>);
     type Mut = FA;
     
     // Your logic.
     fn run(&self, r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut) {
-        println!("RunA");
-
         // We can see inlay type hint thanks to associated types.
-        let (a, b) = r;
-        for v in a {
-            println!("r.0: {:?}", v);
-        }
+        let (b, count) = r;
+
+        // `Local` keeps its value across runs.
+        *count += 1;
+        println!("RunA #{}", count);
+
+        // Query iterators know how many slices and entities they have.
+        println!("r.0 has {} entities in {} slices", b.entity_count(), b.len());
         for v in b {
-            println!("r.1: {:?}", v);
+            println!("r.0: {:?}", v);
         }
         for v in m {
             println!("m: {:?}", v);
@@ -81,36 +140,782 @@ impl System for SysA {
 /// Test `System`.
 struct SysB;
 impl System for SysB {
-    type Ref = FA;
-    type Mut = (FA, FB);
+    // Same as `FA` without defining a `Filter`.
+    type Ref = Target<CompA, With<CompB>>;
+    type Mut = FB;
     fn run(&self, r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut) {
         println!("RunB");
 
-        // We can see inlay type hint thanks to associated types.
-        for v in r {
-            println!("r: {:?}", v);
+        // Each pair of entities is visited once, which is useful for collision checks.
+        for [x, y] in r.iter_combinations::<2>() {
+            println!("r: pair of {:?} and {:?}", x, y);
         }
-        let (a, b) = m;
-        for v in a {
-            println!("m.0: {:?}", v);
+        for v in m {
+            println!("m: {:?}", v);
         }
-        for v in b {
-            println!("m.1: {:?}", v);
+    }
+}
+
+/// Test `System`.
+struct SysC;
+impl System for SysC {
+    type Ref = (EntityId<FAllA>, Option<FMaybeB>);
+    type Mut = FAllA;
+    fn run(&self, r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut) {
+        println!("RunC");
+
+        // We can iterate per entity as well.
+        // `b` is `None` for the entity that doesn't have `CompB`.
+        let (e, b) = r;
+        for (e, b, a) in (e.rows(), b.rows(), m.rows()).zip_entities() {
+            println!("{:?}: (r, m): ({:?}, {:?})", e, b, a);
+        }
+    }
+}
+
+/// Test fallible *System*, which expects only one entity having `CompA`.
+/// It fails on the storage having many of them, and the error goes to the error handler of the `Schedule`.
+struct SysE;
+impl PipeSystem for SysE {
+    type In = ();
+    type Out = Result<(), SystemError>;
+    type Ref = FAllA;
+    type Mut = ();
+    fn run(&self, _input: (), r: <Self::Ref as Query>::Output, _m: ()) -> Self::Out {
+        println!("RunE: {:?}", r.single()?);
+        Ok(())
+    }
+}
+
+/// Test read-only `System`, which doesn't write anything.
+struct SysF;
+impl System for SysF {
+    type Ref = (EntityId<FAllA>, Option<FMaybeB>);
+    type Mut = ();
+    fn run(&self, (e, b): <Self::Ref as Query>::Output, _m: ()) {
+        for (e, b) in (e.rows(), b.rows()).zip_entities() {
+            println!("RunF {:?}: {:?}", e, b);
+        }
+    }
+}
+
+/// Test `System` reading events that may not be registered.
+struct SysPing;
+impl System for SysPing {
+    type Ref = EventReader<Ping>;
+    type Mut = ();
+    fn run(&self, pings: EventIter<Ping>, _m: ()) {
+        println!("RunPing: {} pings", pings.count());
+    }
+}
+
+/// Test double of the `Store`, which counts queries and forwards them to the storage.
+struct CountingStore<'a> {
+    inner: &'a mut ComponentStorage,
+    queries: usize,
+}
+
+impl StoreRead for CountingStore<'_> {
+    fn chunks_shared<'s>(
+        &self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_optional: bool,
+    ) -> &'s mut [Chunk] {
+        self.inner.chunks_shared(state, filter, is_optional)
+    }
+}
+
+impl Store for CountingStore<'_> {
+    fn chunks<'s>(
+        &mut self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_mut: bool,
+        is_optional: bool,
+    ) -> &'s mut [Chunk] {
+        self.queries += 1;
+        self.inner.chunks(state, filter, is_mut, is_optional)
+    }
+
+    fn resource_mut(&mut self, ty: TypeId) -> Option<&mut (dyn Any + Send + Sync)> {
+        self.inner.resource_mut(ty)
+    }
+
+    fn event_cursor(&mut self, id: QueryId) -> &mut usize {
+        self.inner.event_cursor(id)
+    }
+
+    fn removed(&mut self, ty: TypeId) -> Option<&Events<Entity>> {
+        self.inner.removed(ty)
+    }
+
+    fn hierarchy_order(&mut self) -> Vec<Entity> {
+        self.inner.hierarchy_order()
+    }
+
+    fn local(
+        &mut self,
+        id: QueryId,
+        init: fn() -> Box<dyn Any + Send + Sync>,
+    ) -> &mut (dyn Any + Send + Sync) {
+        self.inner.local(id, init)
+    }
+
+    fn take_query_state(&mut self, system: TypeId) -> QueryState {
+        self.inner.take_query_state(system)
+    }
+
+    fn put_query_state(&mut self, state: QueryState) {
+        self.inner.put_query_state(state)
+    }
+
+    fn finish_system(&mut self, system: TypeId) {
+        self.inner.finish_system(system)
+    }
+}
+
+/// Test `ExclusiveSystem`.
+struct SysD;
+impl ExclusiveSystem for SysD {
+    fn run(&self, storage: &mut ComponentStorage) {
+        println!("RunD");
+
+        // Structural changes are allowed here.
+        let a = storage.component_id::<CompA>().unwrap();
+        let b = storage.component_id::<CompB>().unwrap();
+        let lacks_b: Vec<Entity> = storage
+            .tables()
+            .iter()
+            .filter(|table| table.contains(a) && !table.contains(b))
+            .flat_map(|table| table.entities().iter().copied())
+            .collect();
+        for entity in lacks_b {
+            println!("{:?} gets CompB", entity);
+            storage.insert(entity, CompB("B(5)"));
         }
     }
 }
 
+/// Test `Executor` showing which systems can run at the same time.
+/// A parallel executor would run each batch on multiple threads instead.
+struct LogExecutor;
+impl Executor for LogExecutor {
+    fn execute(
+        &mut self,
+        storage: &mut ComponentStorage,
+        batch: &[&dyn Invokable],
+    ) -> Vec<(&'static str, SystemError)> {
+        let names = batch.iter().map(|system| system.name()).collect::<Vec<_>>();
+        println!("Batch: {}", names.join(", "));
+        SingleThreaded.execute(storage, batch)
+    }
+}
+
+/// Test `ExclusiveSystem` running at a fixed rate.
+struct Step;
+impl ExclusiveSystem for Step {
+    fn run(&self, storage: &mut ComponentStorage) {
+        let time = storage.get_resource::<FixedTime>().unwrap();
+        println!("Step by {:?}", time.dt);
+    }
+}
+
+/// Test `PipeSystem` returning the number of entities having `CompA`.
+struct CountA;
+impl PipeSystem for CountA {
+    type In = ();
+    type Out = usize;
+    type Ref = FAllA;
+    type Mut = ();
+    fn run(&self, _input: (), r: <Self::Ref as Query>::Output, _m: ()) -> usize {
+        r.entity_count()
+    }
+}
+
+/// Test `PipeSystem` taking the output of `CountA`.
+struct Report;
+impl PipeSystem for Report {
+    type In = usize;
+    type Out = ();
+    type Ref = ();
+    type Mut = ();
+    fn run(&self, count: usize, _r: (), _m: ()) {
+        println!("{} entities have CompA", count);
+    }
+}
+
+/// Test function *System*, whose queries are its parameters.
+fn print_a(a: Read<FAllA>, mut count: Write<Local<u32>>) {
+    **count += 1;
+    println!("print_a #{}: {} entities have CompA", **count, a.entity_count());
+}
+
+/// Test function *System* with various parameters.
+/// It renames `CompA` of entities having `CompB` as well, and spawns an entity on the second frame.
+fn rename(q: QueryParam<(&mut CompA, &CompB)>, frames: Res<Frames>, mut commands: Commands) {
+    for (a, b) in q {
+        println!("rename {:?} with {:?}", a, b);
+        a.0 = "Renamed A";
+    }
+    if frames.0 == 2 {
+        commands.spawn((CompA("Commanded A"), CompB("Commanded B")));
+    }
+}
+
+/// Test helper taking the narrowest query it needs.
+fn names_of(q: QueryParam<&CompA>) -> Vec<&'static str> {
+    q.into_iter().map(|a| a.0).collect()
+}
+
+/// Test function *System* handing its broader query to the helper through a lens.
+fn list_names(q: QueryParam<(&mut CompA, &CompB)>) {
+    println!("Names having CompB: {:?}", names_of(q.lens::<&CompA, _>()));
+}
+
+/// Test non-send resource, which is like a handle of a JS object.
+struct Canvas(std::rc::Rc<std::cell::Cell<u32>>);
+
+/// Test function *System* touching the non-send resource, which runs on the main thread.
+fn draw(canvas: NonSendMut<Canvas>) {
+    canvas.0.set(canvas.0.get() + 1);
+    println!("Drawn {} times on the main thread", canvas.0.get());
+}
+
+/// Test function *System* with a query written by `query!`, which prints `CompA` of entities without `CompB`.
+fn print_lonely_a(q: query!(&CompA, without = CompB)) {
+    for a in q {
+        println!("{:?} doesn't have CompB", a);
+    }
+}
+
+/// Test resource counting frames of the `App`.
+struct Frames(u32);
+
+/// Test `ExclusiveSystem` stopping the `App` after the given number of frames.
+struct Quit(u32);
+impl ExclusiveSystem for Quit {
+    fn run(&self, storage: &mut ComponentStorage) {
+        let frames = storage.get_resource_mut::<Frames>().unwrap();
+        frames.0 += 1;
+        if frames.0 == self.0 {
+            println!("Quit after {} frames", frames.0);
+            storage.insert_resource(AppExit);
+        }
+    }
+}
+
+/// Test `Component` inserted by default along with a `Sprite`.
+#[derive(Debug, Default, PartialEq, Component)]
+struct Transform(i32, i32);
+
+/// Saved as "x,y" in JSON, and as 8 bytes in the binary format.
+impl Persist for Transform {
+    fn save(&self) -> String {
+        format!("{},{}", self.0, self.1)
+    }
+
+    fn load(text: &str) -> Option<Self> {
+        let (x, y) = text.split_once(',')?;
+        Some(Self(x.parse().ok()?, y.parse().ok()?))
+    }
+
+    fn save_bytes(&self) -> Vec<u8> {
+        [self.0.to_le_bytes(), self.1.to_le_bytes()].concat()
+    }
+
+    fn load_bytes(bytes: &[u8]) -> Option<Self> {
+        let x = i32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let y = i32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+        Some(Self(x, y))
+    }
+}
+
+/// Test `Component` requiring the `Transform`.
+#[derive(Debug, Component)]
+#[component(require(Transform))]
+struct Sprite(&'static str);
+
+/// Test state machine switching groups of *System*s.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum GameState {
+    Menu,
+    Playing,
+}
+
+/// Test function *System* in the menu, which starts the game.
+fn start_game(mut states: ResMut<States<GameState>>) {
+    println!("Start game in {:?}", states.current());
+    states.set(GameState::Playing);
+}
+
+/// Test function *System* while playing, which counts frames.
+fn play(mut frames: ResMut<Frames>) {
+    frames.0 += 1;
+    println!("Playing frame #{}", frames.0);
+}
+
+/// Test `Plugin` packaging the resource and the system it needs.
+struct QuitPlugin {
+    frames: u32,
+}
+impl Plugin for QuitPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Frames(0))
+            .add_exclusive_system(Quit(self.frames));
+    }
+}
+
 fn main() {
+    // `cargo run --release -- bench` measures hot paths instead.
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        bench::run();
+        return;
+    }
+
     // Test storage
     let mut storage = ComponentStorage::new();
-    storage.insert(TypeId::of::<CompA>(), Box::new(vec![CompA("A(0)"), CompA("A(1)")]));
-    storage.insert(TypeId::of::<CompB>(), Box::new(vec![CompB("B(2)"), CompB("B(3)")]));
+    for (a, b) in [("A(0)", "B(2)"), ("A(1)", "B(3)")] {
+        storage.spawn((CompA(a), CompB(b)));
+    }
+    let entity = storage.spawn((CompA("A(4)"),));
+
+    // `Schedule` keeps heterogeneous functions using object safe trait `Invokable`.
+    let mut schedule = Schedule::new();
+    schedule
+        .add_system_with(Stage::Update, SysB, SystemConfig::new().in_set("logic"))
+        .configure_set(
+            "logic",
+            SetConfig::new().run_if(|storage| !storage.get_resource::<Paused>().unwrap().0),
+        )
+        .add_system(Stage::PreUpdate, SysA)
+        .add_system(Stage::PreUpdate, SysE)
+        .set_error_handler(log_error)
+        .add_exclusive_system(Stage::PostUpdate, SysD)
+        .add_system_with(
+            Stage::PostUpdate,
+            SysC,
+            SystemConfig::new().before(Label::of::<SysD>()),
+        );
 
-    // We can have a list including heterogeneous functions using object safe trait `Invokable`.
-    let list: Vec<Box<dyn Invokable>> = vec![Box::new(SysA), Box::new(SysB)];
+    // Conflicting queries are rejected when the system is added.
+    struct SysConflict;
+    impl System for SysConflict {
+        type Ref = FA;
+        type Mut = FAllA;
+        fn run(&self, _r: <Self::Ref as Query>::Output, _m: <Self::Mut as Query>::OutputMut) {}
+    }
+    let e = Schedule::new().try_add_system_with(Stage::Update, SysConflict, SystemConfig::new()).err();
+    assert!(matches!(e, Some(ScheduleError::Conflict { .. })));
+    println!("{}", e.unwrap());
 
-    // Let's invoke each function.
-    for item in list.iter() {
-        item.invoke(&mut storage);
+    // Writing the same type doesn't conflict if the filters can't select the same entities,
+    // so that these two systems are in the same batch.
+    struct SysPaired;
+    impl System for SysPaired {
+        type Ref = ();
+        type Mut = FA;
+        fn run(&self, _r: (), _m: <Self::Mut as Query>::OutputMut) {}
+    }
+    struct SysLonely;
+    impl System for SysLonely {
+        type Ref = ();
+        type Mut = Target<CompA, Without<CompB>>;
+        fn run(&self, _r: (), _m: <Self::Mut as Query>::OutputMut) {}
     }
+    let mut disjoint = Schedule::new();
+    disjoint.add_system(Stage::Update, SysPaired).add_system(Stage::Update, SysLonely);
+    disjoint.build().unwrap();
+    disjoint.run_with(&mut storage, &mut LogExecutor);
+    let lonely = AccessId::filtered::<Target<CompA, Without<CompB>>>();
+    assert!(!AccessId::filtered::<FA>().conflicts(&lonely));
+    assert!(AccessId::filtered::<FA>().conflicts(&AccessId::filtered::<FAllA>()));
+
+    // A system writing what another one reads runs first regardless of insertion order,
+    // so that explicit constraints are needed only to break ties. `AccessOrder::Insertion` opts out of it.
+    let mut inferred = Schedule::new();
+    inferred.add_system(Stage::Update, print_lonely_a).add_system(Stage::Update, SysLonely);
+    inferred.build().unwrap();
+    let listed = inferred.to_string();
+    assert!(listed.find("SysLonely") < listed.find("print_lonely_a"));
+    inferred.set_access_order(AccessOrder::Insertion).build().unwrap();
+    let listed = inferred.to_string();
+    assert!(listed.find("print_lonely_a") < listed.find("SysLonely"));
+
+    // A broader query can be narrowed to pass it to helpers, and the narrowed access is checked at compile time.
+    // Narrowing into `&mut CompB` here doesn't compile because the query only reads it.
+    let mut lenses = Schedule::new();
+    lenses.add_system(Stage::Update, list_names).add_system(
+        Stage::Update,
+        |q: QueryParam<(&mut CompA, &CompB)>| {
+            for (b, a) in q.lens::<(&CompB, &mut CompA), _>() {
+                assert!(b.0.starts_with('B') && a.0.starts_with('A'));
+            }
+        },
+    );
+    lenses.run_systems(&mut storage);
+
+    // Systems are inserted or removed through their handles at the next frame, e.g. when a mod is loaded or unloaded.
+    let mut modded = Schedule::new();
+    let base = modded.register(Stage::Update, list_names, SystemConfig::new());
+    let loaded = modded.insert_after(base, |q: QueryParam<&CompB>| {
+        println!("Mod sees {} CompB", q.into_iter().count());
+    });
+    modded.run_systems(&mut storage);
+    modded.remove(loaded);
+    modded.run_systems(&mut storage);
+
+    // Systems writing the same data without ordering are reported, because only insertion order decides which goes first.
+    let mut ambiguous = Schedule::new();
+    ambiguous
+        .add_system(Stage::Update, SysLonely)
+        .add_system(Stage::Update, |q: QueryParam<&mut CompA>| {
+            for a in q {
+                a.0 = "Reset A";
+            }
+        });
+    let ambiguities = ambiguous.check_ambiguities().unwrap();
+    assert_eq!(ambiguities.len(), 1);
+    println!("{}", ambiguities[0]);
+    // Inferred ordering resolves the pair of a reader and a writer.
+    assert_eq!(inferred.check_ambiguities().unwrap().len(), 1);
+    inferred.set_access_order(AccessOrder::WritersFirst);
+    assert!(inferred.check_ambiguities().unwrap().is_empty());
+
+    // Let's invoke each function, SysA runs first because it's in the earlier stage.
+    // And SysC runs before SysD due to the ordering constraint.
+    // SysB runs only if it's not paused, which is the condition of its set.
+    storage.insert_resource(Paused(false));
+    schedule.build().unwrap();
+    print!("{}", schedule);
+    schedule.run(&mut storage);
+
+    // The same systems can run on another storage, which has its own query caches and `Local`s.
+    // So that SysA counts from the beginning here, and SysB doesn't run because only the client is paused.
+    let mut client = ComponentStorage::new();
+    client.spawn((CompA("Client A(0)"), CompB("Client B(0)")));
+    client.insert_resource(Paused(true));
+    schedule.run(&mut client);
+
+    // Spawning entities can reallocate columns, so that cached query results are made again.
+    let epoch = storage.epoch();
+    // `spawn_batch` is faster than `spawn` in a loop for many entities.
+    storage.spawn_batch([("A(5)", "B(6)"), ("A(7)", "B(8)")].map(|(a, b)| (CompA(a), CompB(b))));
+    assert_ne!(epoch, storage.epoch());
+    // Profiling tells how long each *System* took in the last frame.
+    schedule.set_profiler(Some(std_clock));
+    schedule.run_with(&mut storage, &mut LogExecutor);
+    println!("{}", storage.get_resource::<FrameReport>().unwrap());
+    schedule.set_profiler(None);
+
+    // Simulation can run at a fixed rate regardless of the frame rate.
+    // 25ms has passed, so that the fixed schedule runs twice and the remaining 5ms is carried over.
+    let mut fixed = Schedule::new();
+    fixed.add_exclusive_system(Stage::Update, Step);
+    let mut runner = Runner::new(fixed, Schedule::new(), Duration::from_millis(10));
+    assert_eq!(runner.advance(&mut storage, Duration::from_millis(25)), 2);
+    assert_eq!(runner.advance(&mut storage, Duration::from_millis(5)), 1);
+    // `Time` tells systems how much time has passed.
+    let time = storage.get_resource::<Time>().unwrap();
+    assert_eq!(time.delta, Duration::from_millis(5));
+    assert_eq!((time.elapsed, time.frame_count), (Duration::from_millis(30), 2));
+
+    // `Component`s can be defined at run-time as well, like ones from scripts.
+    let health = storage.register_dynamic("Health", Layout::new::<u32>(), None);
+    let value = 100_u32;
+    // Safety: `value` is a valid `u32` that doesn't need to be dropped.
+    unsafe { storage.insert_raw(entity, health, &value as *const u32 as *const u8) };
+    for chunk in storage.query_raw(health) {
+        println!("Health of {:?}: {:?}", chunk.entities, chunk.bytes);
+    }
+
+    // Columns of dynamic `Component`s can live in an arena, which is released at once when the storage drops.
+    let arena = Arc::new(BumpArena::new(1 << 16));
+    let mut scripted = ComponentStorage::new();
+    scripted.set_column_alloc(arena.clone());
+    let mana = scripted.register_dynamic("Mana", Layout::new::<u32>(), None);
+    for value in 0..10_u32 {
+        let entity = scripted.spawn(());
+        // Safety: `value` is a valid `u32` that doesn't need to be dropped.
+        unsafe { scripted.insert_raw(entity, mana, &value as *const u32 as *const u8) };
+    }
+    assert!(arena.used() >= 10 * std::mem::size_of::<u32>());
+    drop(scripted);
+
+    // Queries can be built at run-time as well, like ones from inspectors.
+    let query = DynamicQuery::new(vec![TypeId::of::<CompA>()], vec![TypeId::of::<CompB>()]);
+    for chunk in storage.query_dynamic(&query) {
+        // Safety: We know the type of the first read here, inspectors would find it out by `Components::info`.
+        let first = unsafe { &*(chunk.reads[0] as *const CompA) };
+        println!("{} entities from {:?} have CompA and CompB", chunk.entities.len(), first);
+    }
+
+    // Scripts register *System*s over `ComponentId`s, which are scheduled along with native ones.
+    let add_health = |amount: u32| {
+        move |chunks: &mut [DynamicChunk<'_>]| -> Result<(), SystemError> {
+            for chunk in chunks {
+                // Safety: `Health` is a `u32`, scripting layers would check it by `Components::info`.
+                let values = unsafe {
+                    std::slice::from_raw_parts_mut(chunk.writes[0] as *mut u32, chunk.entities.len())
+                };
+                values.iter_mut().for_each(|value| *value += amount);
+            }
+            Ok(())
+        }
+    };
+    let a = storage.register::<CompA>();
+    let regen = DynamicSystem::new("regen", storage.components(), vec![a], vec![health], add_health(5));
+    let heal = DynamicSystem::new("heal", storage.components(), vec![], vec![health], add_health(10));
+    let mut scripts = Schedule::new();
+    scripts.add_system(Stage::Update, regen).add_system(Stage::Update, heal);
+    let ambiguities = scripts.check_ambiguities().unwrap();
+    assert_eq!(ambiguities[0].components, ["Health"]);
+    scripts.run(&mut storage);
+    // Safety: `Health` of the `entity` is a `u32`.
+    assert_eq!(unsafe { *(storage.get_raw(entity, health).unwrap() as *const u32) }, 115);
+
+    // `Or` selects entities passing any of the filters, so that every entity having `CompA` is selected here.
+    type AnyA = Or<(FA, Target<CompA, Without<CompB>>)>;
+    let any_a = storage.query::<AnyA>(TypeId::of::<AnyA>());
+    println!("{} entities have CompA with or without CompB", any_a.entity_count());
+
+    // Names make entities recognizable in reports, and we can look them up.
+    storage.set_name(entity, "player");
+    assert_eq!(storage.entity_by_name("player"), Some(entity));
+    println!("Found {}", storage.describe(entity));
+
+    // When a query selects nothing unexpectedly, the report shows which tables the filter matches.
+    println!("{}", storage.debug_query::<FA>());
+
+    // Entities built in another storage, like a scene or a prefab, can be moved into.
+    let mut scene = ComponentStorage::new();
+    scene.spawn((CompA("A(9)"), CompB("B(9)")));
+    for (old, new) in storage.append(scene) {
+        println!("{:?} in the scene is appended as {:?}", old, new);
+    }
+
+    // Entities can make a hierarchy, which is despawned together.
+    storage.on_add::<Children>(|_storage, entity| println!("{:?} becomes a parent", entity));
+    let root = storage.spawn((CompA("Root"),));
+    let child = storage.spawn((CompA("Child"),));
+    storage.attach(child, root);
+    println!("Children of {:?}: {:?}", root, storage.get::<Children>(root).unwrap());
+    storage.despawn_recursive(root);
+    assert!(storage.location(child).is_none());
+
+    // *System*s despawn hierarchies through `Commands` as well.
+    let root = storage.spawn((CompA("Root"),));
+    let child = storage.spawn((CompA("Child"), CompB("Child B")));
+    storage.attach(child, root);
+    let mut despawner = Schedule::new();
+    despawner.add_system(Stage::Update, move |mut commands: Commands| {
+        commands.despawn_recursive(root);
+    });
+    despawner.run(&mut storage);
+    assert!(storage.location(root).is_none() && storage.location(child).is_none());
+
+    // `EntityMut` edits an entity without passing it to every call.
+    let handle = storage.spawn((CompA("A(10)"),));
+    let mut edit = storage.entity_mut(handle).unwrap();
+    edit.insert(CompB("B(10)")).remove::<CompA>();
+    assert!(edit.contains::<CompB>() && !edit.contains::<CompA>());
+    edit.despawn();
+    assert!(storage.entity_mut(handle).is_none());
+
+    // Hooks let us release resources related to removed `Component`s.
+    storage.on_remove::<CompB>(|_storage, entity| println!("{:?} loses CompB", entity));
+    storage.remove::<CompB>(entity);
+    storage.despawn(entity);
+    assert!(storage.location(entity).is_none());
+
+    // Required `Component`s are inserted with their defaults if they're missing, so that entities are never half-made.
+    let hero = storage.spawn((Sprite("Hero"),));
+    assert_eq!(storage.get::<Transform>(hero), Some(&Transform(0, 0)));
+    let placed = storage.spawn((Sprite("Tree"), Transform(3, 4)));
+    assert_eq!(storage.get::<Transform>(placed), Some(&Transform(3, 4)));
+    storage.despawn(hero);
+    storage.despawn(placed);
+
+    // Entities are cloned like prefabs, once each `Component` type is registered as cloneable or skipped.
+    let prefab = storage.spawn((CompA("Prefab A"), CompB("Unique B")));
+    storage.register_clone::<CompA>();
+    assert!(storage.clone_entity(prefab).is_none());
+    storage.skip_clone::<CompB>();
+    let copy = storage.clone_entity(prefab).unwrap();
+    assert_eq!(storage.get::<CompA>(copy).unwrap().0, "Prefab A");
+    assert!(storage.get::<CompB>(copy).is_none());
+    // Worlds are saved in a compact binary format with `Component` names instead of ids, e.g. for save games.
+    let mut world = ComponentStorage::new();
+    world.register_persist::<Transform>("Transform");
+    let gone = world.spawn((Transform(1, 2),));
+    let kept = world.spawn((Transform(-3, 4), CompA("Not saved")));
+    world.despawn(gone);
+    let bytes = world.save_binary();
+    println!("Saved {} bytes in binary, {} bytes in JSON", bytes.len(), world.save().len());
+    let mut loaded = ComponentStorage::new();
+    loaded.register_persist::<Transform>("Transform");
+    loaded.load_binary(&bytes).unwrap();
+    assert_eq!(loaded.get::<Transform>(kept), Some(&Transform(-3, 4)));
+    assert!(loaded.location(gone).is_none());
+    let mut newer = bytes.clone();
+    newer[4] = FORMAT_VERSION as u8 + 1;
+    let error = ComponentStorage::new().load_binary(&newer).err();
+    assert_eq!(error, Some(PersistError::UnsupportedVersion(FORMAT_VERSION + 1)));
+
+    // Inspectors visit all `Component`s of an entity without knowing their types.
+    let inspected = storage.entity(prefab).unwrap();
+    assert_eq!(inspected.components().count(), 2);
+    let names: Vec<_> = (inspected.components())
+        .filter_map(|(_, value)| value.downcast_ref::<CompA>())
+        .map(|a| a.0)
+        .collect();
+    assert_eq!(names, ["Prefab A"]);
+    for (_, value) in storage.entity_mut(copy).unwrap().components_mut() {
+        if let Some(a) = value.downcast_mut::<CompA>() {
+            a.0 = "Copied A";
+        }
+    }
+    assert_eq!(storage.get::<CompA>(copy).unwrap().0, "Copied A");
+    storage.despawn(prefab);
+    storage.despawn(copy);
+
+    // `App` runs its schedule every frame until `AppExit` is inserted.
+    // Features can be packaged as `Plugin`s.
+    // And the output of a *System* can be piped into another one.
+    // Functions can be *System*s as well.
+    let mut app = App::new();
+    app.storage_mut().spawn((CompA("App A(0)"),));
+    app.add_plugin(QuitPlugin { frames: 3 })
+        .add_system(SysA)
+        .add_system(CountA.pipe(Report))
+        .add_system(print_a)
+        .add_system(rename)
+        .add_system(print_lonely_a)
+        .run();
+
+    // State machines switch groups of *System*s without manual booleans.
+    // Transitions take place at the start of the next frame, running `OnExit` and `OnEnter` schedules.
+    let mut game = App::new();
+    game.insert_resource(Frames(0))
+        .add_state(GameState::Menu)
+        .on_exit_mut(GameState::Menu)
+        .add_system(Stage::Update, |frames: Res<Frames>| println!("Leave menu at frame #{}", frames.0));
+    game.on_enter_mut(GameState::Playing)
+        .add_system(Stage::Update, |frames: Res<Frames>| println!("Enter game at frame #{}", frames.0));
+    game.schedule_mut()
+        .add_system_with(Stage::Update, start_game, SystemConfig::new().in_set("menu"))
+        .configure_set("menu", SetConfig::new().run_if(in_state(GameState::Menu)))
+        .add_system_with(Stage::Update, play, SystemConfig::new().run_if(in_state(GameState::Playing)));
+    for _ in 0..3 {
+        game.update();
+    }
+    assert_eq!(game.storage().get_resource::<Frames>().unwrap().0, 2);
+
+    // Despawning moves the last row into the hole, so that queries stay dense.
+    // And the index is reused with a new generation, so that the old handle doesn't point to the new entity.
+    let mut dense = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)", "D(3)"].map(|name| dense.spawn((CompA(name),)));
+    dense.despawn(d[1]);
+    let reused = dense.spawn((CompA("D(4)"),));
+    assert_eq!(reused.index(), d[1].index());
+    assert!(dense.location(d[1]).is_none() && dense.entity_mut(d[1]).is_none());
+    dense.despawn(d[0]);
+    let names = dense.query::<Target<CompA>>(TypeId::of::<CompA>())
+        .rows()
+        .map(|a| a.0)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["D(4)", "D(3)", "D(2)"]);
+
+    // Tags don't take any storage per entity, but they filter entities as usual.
+    dense.insert(reused, Selected);
+    let selected = dense.query::<Target<CompA, With<Selected>>>(TypeId::of::<Selected>())
+        .rows()
+        .map(|a| a.0)
+        .collect::<Vec<_>>();
+    assert_eq!(selected, ["D(4)"]);
+    assert_eq!(dense.query::<Added<Target<Selected>>>(TypeId::of::<Selected>()).entity_count(), 1);
+
+    // Snapshots roll the storage back, e.g. when a predicted frame turns out to be wrong.
+    dense.register_snapshot::<CompA>();
+    dense.register_snapshot::<Selected>();
+    let snapshot = dense.snapshot().unwrap();
+    dense.despawn(reused);
+    dense.spawn((CompA("D(5)"),));
+    dense.restore(snapshot);
+    assert_eq!(dense.get::<CompA>(reused).unwrap().0, "D(4)");
+    assert_eq!(dense.query::<Target<CompA>>(TypeId::of::<CompA>()).entity_count(), 3);
+
+    // The deterministic mode visits tables by their `Component` names and rows by entity index.
+    // So that the order doesn't depend on how the storage got here, e.g. by despawns or by loading.
+    dense.set_deterministic(true);
+    let names = dense.query::<Target<CompA>>(TypeId::of::<CompA>())
+        .rows()
+        .map(|a| a.0)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["D(2)", "D(3)", "D(4)"]);
+
+    // Queries make pointers to values from a shared borrow of the column, see `SyncCell`.
+    // So that the cached result stays valid after a value is borrowed elsewhere, which `cargo miri run` checks.
+    dense.get_mut::<CompA>(reused).unwrap().0 = "D(6)";
+    let names = dense.query::<Target<CompA>>(TypeId::of::<CompA>())
+        .rows()
+        .map(|a| a.0)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["D(2)", "D(3)", "D(6)"]);
+
+    // Matched values can be split into fixed-size slices for SIMD or batched work items.
+    let sizes = dense.query::<Target<CompA>>(TypeId::of::<CompA>())
+        .chunks(2)
+        .map(|(_, values)| values.len())
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, [2, 1]);
+
+    // Read-only systems take the storage shared, so that they run at the same time on scoped threads.
+    // Systems touching non-send resources stay on this thread.
+    dense.insert_non_send(Canvas(Default::default()));
+    let mut readers = Schedule::new();
+    readers
+        .add_system(Stage::Update, ReadOnly(SysE))
+        .add_system(Stage::Update, ReadOnly(SysF))
+        .add_system(Stage::Update, draw)
+        .set_error_handler(log_error);
+    readers.build().unwrap();
+    readers.run_with(&mut dense, &mut ScopedThreads);
+    assert_eq!(dense.get_non_send::<Canvas>().unwrap().0.get(), 1);
+
+    // *System*s query through `dyn Store`, so that they run on any storage such as a test double.
+    let mut counting = CountingStore {
+        inner: &mut dense,
+        queries: 0,
+    };
+    SysF.run_on(&mut counting);
+    assert_eq!(counting.queries, 2);
+
+    // Events that haven't been registered read as empty, and `try_` getters tell why.
+    SysPing.run_on(&mut dense);
+    let store: &mut dyn Store = &mut dense;
+    let error = store.try_get_events::<Ping>(QueryId::read::<Ping>(TypeId::of::<Ping>(), 0)).err();
+    assert_eq!(error, Some(QueryError::UnregisteredEvents(std::any::type_name::<Ping>())));
+
+    // Event-triggered systems run only if the events have been sent since they were checked.
+    dense.add_event::<Ping>();
+    let mut reactions = Schedule::new();
+    reactions.add_system_with(Stage::Update, SysPing, SystemConfig::new().run_if(on_event::<Ping>()));
+    reactions.run(&mut dense);
+    dense.get_resource_mut::<Events<Ping>>().unwrap().send(Ping);
+    reactions.run(&mut dense);
+    reactions.run(&mut dense);
+
+    // Tables left empty by removals can be dropped, and the rows are sorted by entity index.
+    let tables = storage.tables().len();
+    storage.compact();
+    println!("Compacted {} tables into {}", tables, storage.tables().len());
+
+    // `WorldStats` is refreshed at the end of every frame once it's inserted, so that memory growth can be monitored.
+    dense.insert_resource(WorldStats::default());
+    reactions.run(&mut dense);
+    let entities = dense.query::<Target<CompA>>(TypeId::of::<CompA>()).entity_count();
+    let stats = dense.get_resource::<WorldStats>().unwrap();
+    assert_eq!(stats.entities, entities);
+    println!("{}", stats);
 }
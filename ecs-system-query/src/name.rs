@@ -0,0 +1,144 @@
+use super::{ComponentStorage, Entity};
+use ecs_system_query_derive::Component;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Optional name of the entity for debugging, which is shown by inspectors instead of its index.
+/// Names don't need to be unique, see [`ComponentStorage::entity_by_name`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Component)]
+pub struct Name(String);
+
+impl Name {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Resource mapping names to entities, which is kept up to date by hooks of the `Name`.
+#[derive(Debug, Default)]
+struct NameIndex {
+    entities: HashMap<String, Vec<Entity>>,
+    // Number of entities in the `entities`.
+    len: usize,
+}
+
+impl NameIndex {
+    fn insert(&mut self, name: &str, entity: Entity) {
+        self.entities
+            .entry(name.to_owned())
+            .or_default()
+            .push(entity);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, name: &str, entity: Entity) {
+        let Some(entities) = self.entities.get_mut(name) else {
+            return;
+        };
+        let len = entities.len();
+        entities.retain(|e| *e != entity);
+        self.len -= len - entities.len();
+        if entities.is_empty() {
+            self.entities.remove(name);
+        }
+    }
+}
+
+impl ComponentStorage {
+    /// Gives the `entity` the `name`, replacing its previous one.
+    /// Use this instead of inserting the `Name` directly when the `entity` already has one,
+    /// so that [`Self::entity_by_name`] finds it fast.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `entity` doesn't exist.
+    pub fn set_name(&mut self, entity: Entity, name: impl Into<String>) {
+        assert!(self.location(entity).is_some(), "entity should exist");
+        self.init_name_index();
+        if let Some(old) = self.get::<Name>(entity).cloned() {
+            let index = self.get_resource_mut::<NameIndex>().unwrap();
+            index.remove(old.as_str(), entity);
+            let name = Name::new(name);
+            index.insert(name.as_str(), entity);
+            *self.get_mut::<Name>(entity).unwrap() = name;
+        } else {
+            // The add hook puts it into the index.
+            self.insert(entity, Name::new(name));
+        }
+    }
+
+    /// Returns an entity having the `name`, or any of them if there are many.
+    /// It's a hash lookup once [`Self::set_name`] has been called, and a scan over all entities before that.
+    pub fn entity_by_name(&self, name: &str) -> Option<Entity> {
+        let has_name = |entity: &Entity| {
+            self.get::<Name>(*entity)
+                .is_some_and(|n| n.as_str() == name)
+        };
+        if let Some(index) = self.get_resource::<NameIndex>() {
+            // Names changed without `set_name` make the index stale, then we fall back to the scan.
+            let found = index
+                .entities
+                .get(name)
+                .map(|entities| entities.iter().find(|e| has_name(e)));
+            match found {
+                Some(Some(entity)) => return Some(*entity),
+                None if index.len == self.named_entities().count() => return None,
+                _ => {}
+            }
+        }
+        self.named_entities().find(has_name)
+    }
+
+    /// Returns the name of the `entity`, or its `Debug` representation if it doesn't have a `Name`.
+    pub fn describe(&self, entity: Entity) -> String {
+        match self.get::<Name>(entity) {
+            Some(name) => format!("{} ({:?})", name, entity),
+            None => format!("{:?}", entity),
+        }
+    }
+
+    /// Returns all entities having the `Name`.
+    fn named_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        let id = self.components().id(&std::any::TypeId::of::<Name>());
+        (self.tables().iter())
+            .filter(move |table| id.is_some_and(|id| table.contains(id)))
+            .flat_map(|table| table.entities().iter().copied())
+    }
+
+    /// Builds the `NameIndex` from existing names, and registers hooks keeping it up to date.
+    fn init_name_index(&mut self) {
+        if self.get_resource::<NameIndex>().is_some() {
+            return;
+        }
+        let mut index = NameIndex::default();
+        for entity in self.named_entities() {
+            index.insert(self.get::<Name>(entity).unwrap().as_str(), entity);
+        }
+        self.insert_resource(index);
+
+        self.on_add::<Name>(|storage, entity| {
+            let name = storage.get::<Name>(entity).unwrap().as_str().to_owned();
+            storage
+                .get_resource_mut::<NameIndex>()
+                .unwrap()
+                .insert(&name, entity);
+        });
+        self.on_remove::<Name>(|storage, entity| {
+            let name = storage.get::<Name>(entity).unwrap().as_str().to_owned();
+            storage
+                .get_resource_mut::<NameIndex>()
+                .unwrap()
+                .remove(&name, entity);
+        });
+    }
+}
@@ -0,0 +1,164 @@
+use super::{AccessId, ComponentStorage, QueryState, SystemParam};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::thread::{self, ThreadId};
+
+/// Resource that isn't `Send` nor `Sync` such as a handle of a JS object on the web, see [`ComponentStorage::insert_non_send`].
+/// It's kept with the thread that inserted it, the main thread, and accessing it from another thread panics.
+/// So that the storage is still shared by threads, and *System*s touching it are pinned to the main thread.
+struct NonSendCell<R> {
+    thread: ThreadId,
+    value: ManuallyDrop<R>,
+}
+
+// Safety: The `value` is accessed and dropped only on the `thread`.
+unsafe impl<R> Send for NonSendCell<R> {}
+unsafe impl<R> Sync for NonSendCell<R> {}
+
+impl<R> NonSendCell<R> {
+    fn new(value: R) -> Self {
+        Self {
+            thread: thread::current().id(),
+            value: ManuallyDrop::new(value),
+        }
+    }
+
+    fn is_main_thread(&self) -> bool {
+        thread::current().id() == self.thread
+    }
+
+    fn get(&self) -> &R {
+        assert!(self.is_main_thread(), "non-send resource should be accessed on the main thread");
+        &self.value
+    }
+
+    fn get_mut(&mut self) -> &mut R {
+        assert!(self.is_main_thread(), "non-send resource should be accessed on the main thread");
+        &mut self.value
+    }
+}
+
+/// The value is leaked if the storage is dropped on another thread, because it can't be dropped there.
+impl<R> Drop for NonSendCell<R> {
+    fn drop(&mut self) {
+        if self.is_main_thread() {
+            // Safety: The `value` is not used after this.
+            unsafe { ManuallyDrop::drop(&mut self.value) };
+        }
+    }
+}
+
+impl ComponentStorage {
+    /// Inserts the resource `R` which is not `Send` nor `Sync`.
+    /// The current thread becomes its main thread, and it can be accessed only there.
+    pub fn insert_non_send<R: 'static>(&mut self, value: R) {
+        self.insert_resource(NonSendCell::new(value));
+    }
+
+    /// # Panics
+    ///
+    /// Panics if it's not the main thread of the resource.
+    pub fn get_non_send<R: 'static>(&self) -> Option<&R> {
+        self.get_resource::<NonSendCell<R>>().map(NonSendCell::get)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if it's not the main thread of the resource.
+    pub fn get_non_send_mut<R: 'static>(&mut self) -> Option<&mut R> {
+        self.get_resource_mut::<NonSendCell<R>>().map(NonSendCell::get_mut)
+    }
+}
+
+/// Function *System* parameter reading the non-send resource `T`, see [`ComponentStorage::insert_non_send`].
+/// The *System* runs on the main thread, see [`Invokable::is_main_thread_only`](super::Invokable::is_main_thread_only).
+///
+/// # Panics
+///
+/// The *System* panics if the resource doesn't exist.
+pub struct NonSend<'a, T>(&'a T);
+
+/// Function *System* parameter writing the non-send resource `T`, see [`NonSend`].
+///
+/// # Panics
+///
+/// The *System* panics if the resource doesn't exist.
+pub struct NonSendMut<'a, T>(&'a mut T);
+
+impl<'x, T: 'static> SystemParam for NonSend<'x, T> {
+    type Item<'a> = NonSend<'a, T>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, _state: &mut QueryState) -> Self::Item<'a> {
+        let value = storage
+            .get_non_send::<T>()
+            .unwrap_or_else(|| panic!("resource {} should be inserted", std::any::type_name::<T>()));
+        // Safety: Same as `Res`.
+        NonSend(unsafe { &*(value as *const T) })
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        vec![AccessId::of::<NonSendCell<T>>()]
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn is_main_thread_only() -> bool {
+        true
+    }
+}
+
+impl<'x, T: 'static> SystemParam for NonSendMut<'x, T> {
+    type Item<'a> = NonSendMut<'a, T>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, _state: &mut QueryState) -> Self::Item<'a> {
+        let value = storage
+            .get_non_send_mut::<T>()
+            .unwrap_or_else(|| panic!("resource {} should be inserted", std::any::type_name::<T>()));
+        // Safety: Same as `Res`.
+        NonSendMut(unsafe { &mut *(value as *mut T) })
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        vec![AccessId::of::<NonSendCell<T>>()]
+    }
+
+    #[inline]
+    fn is_main_thread_only() -> bool {
+        true
+    }
+}
+
+impl<'a, T> Deref for NonSend<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a, T> Deref for NonSendMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a, T> DerefMut for NonSendMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
@@ -0,0 +1,453 @@
+//! Saving and loading `ComponentStorage` as JSON or as compact binary.
+//! Real projects would use `serde` with `postcard` or `bincode`, but this example doesn't depend on any crates.
+//! So that `Persist` plays the role of `Serialize` and `Deserialize` with a tiny JSON reader and writer,
+//! and a binary format written by hand.
+
+use super::{Component, ComponentId, ComponentStorage, Entity};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+/// A `Component` that can be saved and loaded.
+pub trait Persist: Component + Sized {
+    /// Serializes itself to text.
+    fn save(&self) -> String;
+
+    /// Deserializes from the text made by `save`.
+    fn load(text: &str) -> Option<Self>;
+
+    /// Serializes itself to bytes for the binary format, which is the text made by `save` by default.
+    /// Override it together with `load_bytes` for compact data.
+    fn save_bytes(&self) -> Vec<u8> {
+        self.save().into_bytes()
+    }
+
+    /// Deserializes from the bytes made by `save_bytes`.
+    fn load_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::load(std::str::from_utf8(bytes).ok()?)
+    }
+}
+
+/// Type erased functions of a `Persist` type.
+#[derive(Clone, Copy)]
+pub struct PersistFns {
+    pub save: fn(&ComponentStorage, Entity) -> Option<String>,
+    pub load: fn(&mut ComponentStorage, Entity, &str) -> bool,
+    pub save_bytes: fn(&ComponentStorage, Entity) -> Option<Vec<u8>>,
+    pub load_bytes: fn(&mut ComponentStorage, Entity, &[u8]) -> bool,
+}
+
+impl PersistFns {
+    pub fn of<T: Persist>() -> Self {
+        Self {
+            save: |storage, entity| storage.get::<T>(entity).map(T::save),
+            load: |storage, entity, text| {
+                T::load(text)
+                    .map(|value| storage.insert(entity, value))
+                    .is_some()
+            },
+            save_bytes: |storage, entity| storage.get::<T>(entity).map(T::save_bytes),
+            load_bytes: |storage, entity, bytes| {
+                T::load_bytes(bytes)
+                    .map(|value| storage.insert(entity, value))
+                    .is_some()
+            },
+        }
+    }
+}
+
+/// Persistable `Component` types with their stable names.
+/// Names are written to the saved data instead of `TypeId`s, which differ between builds.
+#[derive(Default)]
+pub struct PersistRegistry {
+    by_id: HashMap<ComponentId, (String, PersistFns)>,
+    by_name: HashMap<String, PersistFns>,
+}
+
+impl PersistRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, id: ComponentId, name: &str, fns: PersistFns) {
+        self.by_id.insert(id, (name.to_owned(), fns));
+        self.by_name.insert(name.to_owned(), fns);
+    }
+
+    pub fn get(&self, id: ComponentId) -> Option<(&str, PersistFns)> {
+        self.by_id.get(&id).map(|(name, fns)| (name.as_str(), *fns))
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<PersistFns> {
+        self.by_name.get(name).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PersistError {
+    /// Input is not a valid JSON or doesn't have expected shape.
+    Syntax(String),
+    /// `Component` name that is not registered.
+    UnknownComponent(String),
+    /// `Persist::load` failed.
+    InvalidValue { component: String, text: String },
+    /// Storage must be empty to load entities with their identities.
+    NotEmpty,
+    /// Binary data written by a newer format, see [`FORMAT_VERSION`].
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            Self::UnknownComponent(name) => write!(f, "unknown component: {}", name),
+            Self::InvalidValue { component, text } => {
+                write!(f, "invalid value of {}: {}", component, text)
+            }
+            Self::NotEmpty => write!(f, "storage is not empty"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version: {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PersistError {}
+
+/// An entity in the saved data.
+/// `V` is the text made by `Persist::save`, or the bytes made by `Persist::save_bytes` in the binary format.
+pub struct SavedEntity<V = String> {
+    pub index: u32,
+    pub generation: u32,
+    /// (Component name, Value).
+    pub components: Vec<(String, V)>,
+}
+
+/// Writes entities as JSON like
+/// `{"entities":[{"index":0,"generation":0,"components":{"CompA":"..."}}]}`.
+pub fn to_json(entities: &[SavedEntity]) -> String {
+    let mut out = String::from("{\"entities\":[");
+    for (i, entity) in entities.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"index\":{},\"generation\":{},\"components\":{{",
+            entity.index, entity.generation
+        )
+        .unwrap();
+        for (j, (name, text)) in entity.components.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            write_str(&mut out, name);
+            out.push(':');
+            write_str(&mut out, text);
+        }
+        out.push_str("}}");
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Reads JSON written by `to_json`.
+pub fn from_json(text: &str) -> Result<Vec<SavedEntity>, PersistError> {
+    let mut reader = Reader {
+        s: text.as_bytes(),
+        pos: 0,
+    };
+    let mut entities = Vec::new();
+    reader.expect(b'{')?;
+    reader.expect_key("entities")?;
+    reader.expect(b'[')?;
+    if !reader.eat(b']') {
+        loop {
+            reader.expect(b'{')?;
+            reader.expect_key("index")?;
+            let index = reader.number()?;
+            reader.expect(b',')?;
+            reader.expect_key("generation")?;
+            let generation = reader.number()?;
+            reader.expect(b',')?;
+            reader.expect_key("components")?;
+            reader.expect(b'{')?;
+            let mut components = Vec::new();
+            if !reader.eat(b'}') {
+                loop {
+                    let name = reader.string()?;
+                    reader.expect(b':')?;
+                    components.push((name, reader.string()?));
+                    if reader.eat(b'}') {
+                        break;
+                    }
+                    reader.expect(b',')?;
+                }
+            }
+            reader.expect(b'}')?;
+            entities.push(SavedEntity {
+                index,
+                generation,
+                components,
+            });
+            if reader.eat(b']') {
+                break;
+            }
+            reader.expect(b',')?;
+        }
+    }
+    reader.expect(b'}')?;
+    reader.skip_ws();
+    if reader.pos != reader.s.len() {
+        return Err(reader.error("trailing characters"));
+    }
+    Ok(entities)
+}
+
+/// Leading bytes of the binary format.
+pub const MAGIC: [u8; 4] = *b"ECSW";
+
+/// Number of despawned indices a loaded storage can have in addition to its entities.
+/// Indices beyond are rejected, so that untrusted input such as network state can't make the storage allocate
+/// metadata of billions of entities by a few bytes.
+pub const MAX_FREE_INDICES: usize = 1 << 16;
+
+/// Version of the binary format, which is bumped whenever the layout changes.
+/// Data of older versions can still be read, and newer ones are rejected.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Writes entities in the binary format.
+///
+/// The layout is the `MAGIC`, the `FORMAT_VERSION` in little endian, the table of `Component` names,
+/// and then entities, where each `Component` refers to its name by the position in the table.
+/// So that names are written only once however many entities have them.
+/// Integers other than the version are LEB128 varints like `postcard`, and names and values are prefixed
+/// with their lengths.
+pub fn to_binary(entities: &[SavedEntity<Vec<u8>>]) -> Vec<u8> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut name_index: HashMap<&str, u32> = HashMap::new();
+    for (name, _) in entities.iter().flat_map(|entity| &entity.components) {
+        name_index.entry(name).or_insert_with(|| {
+            names.push(name);
+            names.len() as u32 - 1
+        });
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    write_varint(&mut out, names.len() as u32);
+    for name in names {
+        write_bytes(&mut out, name.as_bytes());
+    }
+    write_varint(&mut out, entities.len() as u32);
+    for entity in entities {
+        write_varint(&mut out, entity.index);
+        write_varint(&mut out, entity.generation);
+        write_varint(&mut out, entity.components.len() as u32);
+        for (name, value) in &entity.components {
+            write_varint(&mut out, name_index[name.as_str()]);
+            write_bytes(&mut out, value);
+        }
+    }
+    out
+}
+
+/// Reads the binary format written by `to_binary`.
+pub fn from_binary(bytes: &[u8]) -> Result<Vec<SavedEntity<Vec<u8>>>, PersistError> {
+    let mut reader = BinaryReader { s: bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.error("invalid magic"));
+    }
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version > FORMAT_VERSION {
+        return Err(PersistError::UnsupportedVersion(version));
+    }
+
+    let mut names = Vec::new();
+    for _ in 0..reader.varint()? {
+        let name = reader.bytes()?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| reader.error("invalid utf-8"))?;
+        names.push(name);
+    }
+    let mut entities = Vec::new();
+    for _ in 0..reader.varint()? {
+        let index = reader.varint()?;
+        let generation = reader.varint()?;
+        let mut components = Vec::new();
+        for _ in 0..reader.varint()? {
+            let name = names
+                .get(reader.varint()? as usize)
+                .ok_or_else(|| reader.error("invalid name index"))?;
+            components.push((name.clone(), reader.bytes()?.to_vec()));
+        }
+        entities.push(SavedEntity {
+            index,
+            generation,
+            components,
+        });
+    }
+    if reader.pos != reader.s.len() {
+        return Err(reader.error("trailing bytes"));
+    }
+    Ok(entities)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Reader of the binary format, see [`to_binary`].
+struct BinaryReader<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn error(&self, msg: &str) -> PersistError {
+        PersistError::Syntax(format!("{} at byte {}", msg, self.pos))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PersistError> {
+        let bytes = (self.s.get(self.pos..self.pos + len)).ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u32, PersistError> {
+        let mut value = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("varint overflow"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], PersistError> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// JSON reader just enough for `from_json`.
+struct Reader<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, msg: &str) -> PersistError {
+        PersistError::Syntax(format!("{} at {}", msg, self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.s.len() && self.s[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_ws();
+        if self.s.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), PersistError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c as char)))
+        }
+    }
+
+    fn expect_key(&mut self, key: &str) -> Result<(), PersistError> {
+        if self.string()? != key {
+            return Err(self.error(&format!("expected key \"{}\"", key)));
+        }
+        self.expect(b':')
+    }
+
+    fn number(&mut self) -> Result<u32, PersistError> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.s.len() && self.s[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.s[start..self.pos])
+            .unwrap()
+            .parse()
+            .map_err(|_| self.error("expected number"))
+    }
+
+    fn string(&mut self) -> Result<String, PersistError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&c) = self.s.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&e) = self.s.get(self.pos) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    match e {
+                        b'"' | b'\\' | b'/' => bytes.push(e),
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'u' => {
+                            let hex = self
+                                .s
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|h| std::str::from_utf8(h).ok())
+                                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid escape"))?;
+                            self.pos += 4;
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(hex.encode_utf8(&mut buf).as_bytes());
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("invalid utf-8"))
+    }
+}
@@ -0,0 +1,131 @@
+use super::{AccessId, ComponentStorage, Invokable, QueryState, SystemError};
+use std::any::TypeId;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Function returning the current time from any fixed origin, which times *System*s, see [`Schedule::set_profiler`].
+/// `Instant` is not available on the web, so that use a clock calling `performance.now()` there like
+/// `|| Duration::from_secs_f64(performance.now() / 1000.0)`.
+///
+/// [`Schedule::set_profiler`]: super::Schedule::set_profiler
+pub type Clock = fn() -> Duration;
+
+/// `Clock` based on `Instant`, whose origin is the first call.
+pub fn std_clock() -> Duration {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    ORIGIN.get_or_init(Instant::now).elapsed()
+}
+
+/// Resource having how long each *System* took in the last frame.
+/// It's inserted by a `Schedule` that has a profiler, and the frame ends at `ComponentStorage::update_events`.
+/// A *System* run multiple times in a frame, e.g. in a fixed schedule, appears as many times.
+#[derive(Debug, Clone, Default)]
+pub struct FrameReport {
+    pub systems: Vec<(&'static str, Duration)>,
+    // Timings of the current frame.
+    recording: Vec<(&'static str, Duration)>,
+}
+
+impl FrameReport {
+    pub fn total(&self) -> Duration {
+        self.systems.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    /// Returns the slowest *System* in the last frame.
+    pub fn slowest(&self) -> Option<(&'static str, Duration)> {
+        self.systems.iter().copied().max_by_key(|(_, elapsed)| *elapsed)
+    }
+
+    fn finish_frame(&mut self) {
+        self.systems = std::mem::take(&mut self.recording);
+    }
+}
+
+/// Lists *System*s from the slowest.
+impl fmt::Display for FrameReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut systems = self.systems.clone();
+        systems.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+        for (name, elapsed) in systems {
+            writeln!(f, "{:>12?} {}", elapsed, name)?;
+        }
+        write!(f, "{:>12?} total", self.total())
+    }
+}
+
+impl ComponentStorage {
+    /// Appends a timing of the *System* `name` to the `FrameReport` of the current frame.
+    pub fn record_timing(&mut self, name: &'static str, elapsed: Duration) {
+        if self.get_resource::<FrameReport>().is_none() {
+            self.insert_resource(FrameReport::default());
+        }
+        let report = self.get_resource_mut::<FrameReport>().unwrap();
+        report.recording.push((name, elapsed));
+    }
+
+    /// Makes timings recorded so far the report of the last frame, see [`Self::update_events`].
+    pub(crate) fn finish_frame_report(&mut self) {
+        if let Some(report) = self.get_resource_mut::<FrameReport>() {
+            report.finish_frame();
+        }
+    }
+}
+
+/// *System* timing the wrapped one, so that executors can run it as usual even on multiple threads.
+/// Timings are collected into the `out`, and the `Schedule` moves them into the storage after the batch.
+pub(crate) struct Timed<'a> {
+    pub system: &'a dyn Invokable,
+    pub clock: Clock,
+    pub out: &'a Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl Invokable for Timed<'_> {
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError> {
+        let start = (self.clock)();
+        let result = self.system.invoke(storage);
+        let elapsed = (self.clock)().saturating_sub(start);
+        self.out.lock().unwrap().push((self.system.name(), elapsed));
+        result
+    }
+
+    fn reads(&self) -> Vec<AccessId> {
+        self.system.reads()
+    }
+
+    fn writes(&self) -> Vec<AccessId> {
+        self.system.writes()
+    }
+
+    fn id(&self) -> TypeId {
+        self.system.id()
+    }
+
+    fn name(&self) -> &'static str {
+        self.system.name()
+    }
+
+    fn is_exclusive(&self) -> bool {
+        self.system.is_exclusive()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.system.is_read_only()
+    }
+
+    fn is_main_thread_only(&self) -> bool {
+        self.system.is_main_thread_only()
+    }
+
+    fn invoke_shared(
+        &self,
+        storage: &ComponentStorage,
+        state: &mut QueryState,
+    ) -> Result<(), SystemError> {
+        let start = (self.clock)();
+        let result = self.system.invoke_shared(storage, state);
+        let elapsed = (self.clock)().saturating_sub(start);
+        self.out.lock().unwrap().push((self.system.name(), elapsed));
+        result
+    }
+}
@@ -1,7 +1,7 @@
-use super::util::{downcast_slice, downcast_mut_slice};
-use super::{Component, Store};
+use super::util::{downcast_mut_slice, downcast_slice, ErasedSlice};
+use super::{Component, ComponentId, ComponentSet, Components, Entity, QueryState, Store, StoreRead};
 use std::slice::{Iter, IterMut};
-use std::{any::TypeId, marker::PhantomData};
+use std::{any::TypeId, fmt, marker::PhantomData};
 use std::ptr::NonNull;
 
 /// A filter to select slices of `Component`.
@@ -10,11 +10,13 @@ use std::ptr::NonNull;
 /// `FilterAll` is a tuple of `Component`s to choose entities that have all these `Component`s.
 /// `FilterAny` is a tuple of `Component`s to choose entities that have any of these `Component`s.
 /// `FilterNone` is a tuple of `Component`s not to choose entities that have any of these `Component`s.
+/// `CHANGE` narrows the `Target` down to recently added or modified ones, see [`Added`] and [`Changed`].
 pub trait Filter: 'static {
     type Target: Component;
     type FilterAll: Identify;
     type FilterAny: Identify;
     type FilterNone: Identify;
+    const CHANGE: ChangeFilter = ChangeFilter::None;
 
     #[allow(clippy::type_complexity)]
     #[inline]
@@ -45,6 +47,415 @@ pub trait Filter: 'static {
             <Self::FilterNone as Identify>::as_slice(&all_any_none.2),
         ]
     }
+
+    /// `Component` types selecting entities apart from the `Target`.
+    /// Entities passing any of them are selected, so that an empty result selects nothing.
+    /// By default, it's made of `FilterAll`, `FilterAny`, and `FilterNone`. [`Or`] collects ones of its filters instead.
+    fn types() -> Vec<FilterTypes> {
+        let all_any_none = Self::all_any_none();
+        let [all, any, none] = Self::as_slice(&all_any_none);
+        vec![FilterTypes::new(all, any, none)]
+    }
+
+    /// Compiles the [`Filter::types`] into `FilterSets` against the registered `components`.
+    fn sets(components: &Components) -> Vec<FilterSets> {
+        FilterTypes::compile(&Self::types(), components)
+    }
+}
+
+/// `FilterAll`, `FilterAny`, and `FilterNone` of a `Filter` as `TypeId`s.
+/// Unlike `FilterSets`, they don't depend on a storage, so that a `Schedule` can compare them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterTypes {
+    pub all: Vec<TypeId>,
+    pub any: Vec<TypeId>,
+    pub none: Vec<TypeId>,
+}
+
+impl FilterTypes {
+    pub fn new(all: &[TypeId], any: &[TypeId], none: &[TypeId]) -> Self {
+        Self {
+            all: all.to_vec(),
+            any: any.to_vec(),
+            none: none.to_vec(),
+        }
+    }
+
+    /// Returns true if no entity can pass both, which is when either one requires what the other excludes.
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        let excludes = |a: &Self, b: &Self| {
+            a.all.iter().any(|ty| b.none.contains(ty))
+                || (!a.any.is_empty() && a.any.iter().all(|ty| b.none.contains(ty)))
+        };
+        excludes(self, other) || excludes(other, self)
+    }
+
+    /// Compiles the `types` into `FilterSets`, skipping ones that no table can pass.
+    pub fn compile(types: &[Self], components: &Components) -> Vec<FilterSets> {
+        (types.iter())
+            .filter_map(|t| FilterSets::new(&t.all, &t.any, &t.none, components))
+            .collect()
+    }
+}
+
+/// Type erased `Filter`, so that a `Store` can take any filter without being generic over it.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterInfo {
+    pub filter: TypeId,
+    pub target: TypeId,
+    pub change: ChangeFilter,
+    /// [`Filter::types`] of the filter.
+    pub types: fn() -> Vec<FilterTypes>,
+}
+
+impl FilterInfo {
+    pub fn of<F: Filter>() -> Self {
+        Self {
+            filter: TypeId::of::<F>(),
+            target: TypeId::of::<F::Target>(),
+            change: F::CHANGE,
+            types: F::types,
+        }
+    }
+}
+
+/// `FilterAll`, `FilterAny`, and `FilterNone` of a `Filter` as `ComponentSet`s.
+/// Matching a table is then a few bit operations whatever the number of `Component` types is.
+#[derive(Debug, Clone, Default)]
+pub struct FilterSets {
+    pub all: ComponentSet,
+    pub any: ComponentSet,
+    pub none: ComponentSet,
+}
+
+impl FilterSets {
+    /// Returns `None` if no table can pass, which happens when some of the `all` or every of the `any` are not registered.
+    /// `Component`s that have never been inserted are not registered, no tables have them.
+    pub fn new(all: &[TypeId], any: &[TypeId], none: &[TypeId], components: &Components) -> Option<Self> {
+        let all = all
+            .iter()
+            .map(|ty| components.id(ty))
+            .collect::<Option<ComponentSet>>()?;
+        let any_ids = any.iter().filter_map(|ty| components.id(ty)).collect::<ComponentSet>();
+        if !any.is_empty() && any_ids.is_empty() {
+            return None;
+        }
+        let none = none.iter().filter_map(|ty| components.id(ty)).collect();
+        Some(Self {
+            all,
+            any: any_ids,
+            none,
+        })
+    }
+
+    pub fn is_match(&self, set: &ComponentSet) -> bool {
+        set.is_superset(&self.all)
+            && (self.any.is_empty() || set.intersects(&self.any))
+            && !set.intersects(&self.none)
+    }
+}
+
+/// Change detection condition of a `Filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeFilter {
+    /// Takes all `Target`s.
+    None,
+    /// Takes `Target`s added since the last run of the *System*.
+    Added,
+    /// Takes `Target`s added or mutably queried since the last run of the *System*.
+    Changed,
+}
+
+/// Wraps a `Filter` to select only `Target`s added since the last run of the *System*.
+/// The first run of the *System* selects all `Target`s.
+/// For instance, `type Ref = Added<FA>;`
+pub struct Added<F>(PhantomData<F>);
+
+impl<F: Filter> Filter for Added<F> {
+    type Target = F::Target;
+    type FilterAll = F::FilterAll;
+    type FilterAny = F::FilterAny;
+    type FilterNone = F::FilterNone;
+    const CHANGE: ChangeFilter = ChangeFilter::Added;
+
+    #[inline]
+    fn types() -> Vec<FilterTypes> {
+        F::types()
+    }
+}
+
+/// Wraps a `Filter` to select only `Target`s added or modified since the last run of the *System*.
+/// We can't see what's written through `&mut`, so that `Target`s are regarded as modified when they're queried mutably.
+pub struct Changed<F>(PhantomData<F>);
+
+impl<F: Filter> Filter for Changed<F> {
+    type Target = F::Target;
+    type FilterAll = F::FilterAll;
+    type FilterAny = F::FilterAny;
+    type FilterNone = F::FilterNone;
+    const CHANGE: ChangeFilter = ChangeFilter::Changed;
+
+    #[inline]
+    fn types() -> Vec<FilterTypes> {
+        F::types()
+    }
+}
+
+/// A `Filter` without defining a named struct.
+/// `T` is the `Target`, and it can be narrowed by `With` and `Without`.
+/// For instance, `type Ref = Target<CompA, With<CompB>, Without<CompC>>;`
+pub struct Target<T, W = With<()>, N = Without<()>>(PhantomData<(T, W, N)>);
+
+/// Selects entities having all of the `Component`s in the `T` for `Target`.
+pub struct With<T>(PhantomData<T>);
+
+/// Doesn't select entities having any of the `Component`s in the `T` for `Target`.
+pub struct Without<T>(PhantomData<T>);
+
+impl<T, W, N> Filter for Target<T, With<W>, Without<N>>
+where
+    T: Component,
+    W: Identify + 'static,
+    N: Identify + 'static,
+{
+    type Target = T;
+    type FilterAll = W;
+    type FilterAny = ();
+    type FilterNone = N;
+}
+
+/// `Target<T, Without<N>>` as a shorthand of `Target<T, With<()>, Without<N>>`.
+impl<T, N> Filter for Target<T, Without<N>, Without<()>>
+where
+    T: Component,
+    N: Identify + 'static,
+{
+    type Target = T;
+    type FilterAll = ();
+    type FilterAny = ();
+    type FilterNone = N;
+}
+
+/// Selects entities that pass any of the filters in the tuple `T`, such as `Or<(FA, Target<CompA, Without<CompB>>)>`.
+/// All filters must have the same `Target`, and their change filters are ignored.
+/// Wrap it with [`Added`] or [`Changed`] to detect changes.
+/// Tuples are implemented by `impl_or!`.
+pub struct Or<T>(PhantomData<T>);
+
+/// Data of each entity yielded by `QueryParam`, which is `&T`, `&mut T`, or a tuple of them.
+/// Tuples are implemented by `impl_query_data!`.
+pub trait QueryData {
+    /// Per-entity iterator over the data.
+    type Iter<'a>: Iterator;
+
+    /// The data with the `'static` lifetime, which identifies the data regardless of the lifetime.
+    type Static: QueryData + 'static;
+
+    /// Gets the data of entities chosen by the `S` with the `state` of the *System*.
+    fn iter<'a, S: DataSelector>(storage: &mut dyn Store, state: &mut QueryState) -> Self::Iter<'a>;
+
+    /// Per-element iterators that [`Narrow`] takes out one by one.
+    type Parts<'a>;
+
+    /// Splits the iterator into the per-element ones.
+    fn into_parts(iter: Self::Iter<'_>) -> Self::Parts<'_>;
+
+    /// Pushes `TypeId`s of the `Component`s in the data.
+    fn components(ids: &mut Vec<TypeId>);
+
+    /// Types read by the data, see [`Query::ids`].
+    fn reads() -> Vec<AccessId>;
+
+    /// Types written by the data, see [`Query::ids`].
+    fn writes() -> Vec<AccessId>;
+}
+
+/// An element of the tuple `QueryData`, which is `&T` or `&mut T`.
+pub trait QueryElement {
+    type Rows<'a>: RowIter + Iterator;
+
+    /// The element with the `'static` lifetime, see [`QueryData::Static`].
+    type Static: QueryElement + 'static;
+
+    /// Gets the element of entities chosen by the `S` with the `state` of the *System*.
+    fn rows<'a, S: DataSelector>(storage: &mut dyn Store, state: &mut QueryState) -> Self::Rows<'a>;
+
+    /// The `Component` of the element.
+    fn component() -> AccessId;
+
+    /// Whether the element is `&mut T`.
+    fn is_mut() -> bool;
+}
+
+impl<T: Component> QueryElement for &T {
+    type Rows<'a> = Rows<'a, T>;
+    type Static = &'static T;
+
+    #[inline]
+    fn rows<'a, S: DataSelector>(storage: &mut dyn Store, state: &mut QueryState) -> Self::Rows<'a> {
+        DataFilter::<T, S>::query(storage, state).rows()
+    }
+
+    #[inline]
+    fn component() -> AccessId {
+        AccessId::of::<T>()
+    }
+
+    #[inline]
+    fn is_mut() -> bool {
+        false
+    }
+}
+
+impl<T: Component> QueryElement for &mut T {
+    type Rows<'a> = RowsMut<'a, T>;
+    type Static = &'static mut T;
+
+    #[inline]
+    fn rows<'a, S: DataSelector>(storage: &mut dyn Store, state: &mut QueryState) -> Self::Rows<'a> {
+        DataFilter::<T, S>::query_mut(storage, state).rows()
+    }
+
+    #[inline]
+    fn component() -> AccessId {
+        AccessId::of::<T>()
+    }
+
+    #[inline]
+    fn is_mut() -> bool {
+        true
+    }
+}
+
+impl<E: QueryElement> QueryData for E {
+    type Iter<'a> = E::Rows<'a>;
+    type Static = E::Static;
+
+    #[inline]
+    fn iter<'a, S: DataSelector>(storage: &mut dyn Store, state: &mut QueryState) -> Self::Iter<'a> {
+        E::rows::<S>(storage, state)
+    }
+
+    type Parts<'a> = Option<E::Rows<'a>>;
+
+    #[inline]
+    fn into_parts(iter: Self::Iter<'_>) -> Self::Parts<'_> {
+        Some(iter)
+    }
+
+    #[inline]
+    fn components(ids: &mut Vec<TypeId>) {
+        ids.push(E::component().id);
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        element_access::<E>(false)
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        element_access::<E>(true)
+    }
+}
+
+/// Returns the `AccessId` of the `E` if it's `&mut T` and `is_mut` is true, or if it's `&T` and `is_mut` is false.
+pub fn element_access<E: QueryElement>(is_mut: bool) -> Vec<AccessId> {
+    if E::is_mut() == is_mut {
+        vec![E::component()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// `QueryData` that can be reinterpreted as the `T` whose access is a subset of this one's, see [`QueryParam::lens`].
+/// `&mut A` narrows into `&A` or itself, `&A` into itself, and tuples into any of their elements or tuples of them.
+/// So that narrowing `&A` into `&mut A` or picking a `Component` that isn't in the data doesn't compile.
+/// `I` tells where the `T` comes from, which is inferred.
+/// Tuples are implemented by `impl_narrow!`.
+///
+/// # Panics
+///
+/// Picking the same element twice, such as narrowing `(&A, &B)` into `(&A, &A)`, panics.
+///
+/// [`QueryParam::lens`]: super::QueryParam::lens
+pub trait Narrow<T: QueryData, I>: QueryData {
+    /// Takes the iterator of the `T` out of the `parts`.
+    fn narrow<'a>(parts: &mut Self::Parts<'a>) -> T::Iter<'a>;
+}
+
+/// Index of [`Narrow`] picking the element itself.
+pub struct Here;
+
+/// Index of [`Narrow`] picking the `N`th element of a tuple, and the `I` of the element.
+pub struct Nth<const N: usize, I>(PhantomData<I>);
+
+/// Index of [`Narrow`] making a tuple of elements picked by each of the `I`.
+pub struct Each<I>(PhantomData<I>);
+
+impl<T: Component> Narrow<&T, Here> for &T {
+    #[inline]
+    fn narrow<'a>(parts: &mut Self::Parts<'a>) -> Rows<'a, T> {
+        take_part(parts)
+    }
+}
+
+impl<T: Component> Narrow<&T, Here> for &mut T {
+    #[inline]
+    fn narrow<'a>(parts: &mut Self::Parts<'a>) -> Rows<'a, T> {
+        take_part(parts).into_shared()
+    }
+}
+
+impl<T: Component> Narrow<&mut T, Here> for &mut T {
+    #[inline]
+    fn narrow<'a>(parts: &mut Self::Parts<'a>) -> RowsMut<'a, T> {
+        take_part(parts)
+    }
+}
+
+fn take_part<T>(part: &mut Option<T>) -> T {
+    part.take().expect("query element should be picked once")
+}
+
+/// Entities that a `QueryData` visits, see [`Select`].
+pub trait DataSelector: 'static {
+    /// `Component` types selecting entities like [`Filter::types`].
+    fn types() -> Vec<FilterTypes>;
+}
+
+/// `DataSelector` choosing entities having all `Component`s of the `D` and the `W`, but none of the `N`.
+/// `W` and `N` are `Component`s or tuples of them like the ones of `Target`.
+pub struct Select<D, W = With<()>, N = Without<()>>(PhantomData<(D, W, N)>);
+
+impl<D, W, N> DataSelector for Select<D, With<W>, Without<N>>
+where
+    D: QueryData + 'static,
+    W: Identify + 'static,
+    N: Identify + 'static,
+{
+    fn types() -> Vec<FilterTypes> {
+        let mut all = Vec::new();
+        D::components(&mut all);
+        let (with, without) = (W::ids(), N::ids());
+        all.extend_from_slice(W::as_slice(&with));
+        vec![FilterTypes::new(&all, &[], N::as_slice(&without))]
+    }
+}
+
+/// `Filter` selecting the `T` of entities chosen by the `S`.
+/// Every element of a `QueryData` uses this with the same `S`, so that they visit the same entities in the same order.
+pub struct DataFilter<T, S>(PhantomData<(T, S)>);
+
+impl<T: Component, S: DataSelector> Filter for DataFilter<T, S> {
+    type Target = T;
+    type FilterAll = ();
+    type FilterAny = ();
+    type FilterNone = ();
+
+    fn types() -> Vec<FilterTypes> {
+        S::types()
+    }
 }
 
 /// A trait to get `TypeId`s of elements inside a tuple.
@@ -59,65 +470,943 @@ pub trait Query<'a> {
     type Output;
     type OutputMut;
 
-    fn query(storage: &mut impl Store, s_id: TypeId) -> Self::Output;
-    fn query_mut(storage: &mut impl Store, s_id: TypeId) -> Self::OutputMut;
-    fn ids() -> Vec<TypeId>;
+    /// Reads data with the cached results in the `state` of the *System*, see [`QueryState`].
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output;
+
+    /// Writes data with the cached results in the `state` of the *System*, see [`QueryState`].
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut;
+
+    /// Types accessed by the query, which are checked for conflicts when the *System* is added to a `Schedule`.
+    fn ids() -> Vec<AccessId>;
+}
+
+/// `Query` that only reads, so that it works on a shared storage, see [`StoreRead`](super::StoreRead).
+/// Filters, `Option`s of them, `EntityId`s, and tuples of those are read-only.
+pub trait ReadOnlyQuery<'a>: Query<'a> {
+    /// Reads data like [`Query::query`].
+    fn query_shared(storage: &dyn StoreRead, state: &mut QueryState) -> Self::Output;
 }
 
-pub struct QueryMutTypeIdSalt;
+/// `TypeId` of the type accessed by a query, with its name for error messages.
+/// Accesses to `Component`s may carry the filter narrowing down entities, see [`AccessId::conflicts`].
+#[derive(Debug, Clone, Copy)]
+pub struct AccessId {
+    pub id: TypeId,
+    pub name: &'static str,
+    /// Entities accessed if it's not all of them.
+    pub filter: Option<fn() -> Vec<FilterTypes>>,
+    /// Dynamic `Component` accessed, which doesn't have its own `TypeId`, see [`AccessId::dynamic`].
+    pub dynamic: Option<ComponentId>,
+}
+
+impl AccessId {
+    pub fn of<T: ?Sized + 'static>() -> Self {
+        Self {
+            id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+            filter: None,
+            dynamic: None,
+        }
+    }
+
+    /// Access to the dynamic `Component` `id` named the `name`.
+    /// All dynamic `Component`s share the same `TypeId`, and they're told apart by their ids.
+    pub fn dynamic(id: ComponentId, name: &'static str) -> Self {
+        Self {
+            name,
+            dynamic: Some(id),
+            ..Self::of::<ComponentId>()
+        }
+    }
+
+    /// Access to the `Target` of entities passing the `F`.
+    pub fn filtered<F: Filter>() -> Self {
+        Self::of::<F::Target>().narrow(F::types)
+    }
+
+    /// Limits the access to entities passing the `filter`.
+    pub fn narrow(self, filter: fn() -> Vec<FilterTypes>) -> Self {
+        Self {
+            filter: Some(filter),
+            ..self
+        }
+    }
+
+    /// Returns true if both access the same type of the same entities.
+    /// Accesses to the same `Component` don't conflict if their filters are disjoint,
+    /// like `Target<CompA, With<CompB>>` and `Target<CompA, Without<CompB>>`.
+    pub fn conflicts(&self, other: &Self) -> bool {
+        let is_disjoint = match (self.filter, other.filter) {
+            (Some(a), Some(b)) => {
+                let (a, b) = (a(), b());
+                a.iter().all(|a| b.iter().all(|b| a.is_disjoint(b)))
+            }
+            _ => false,
+        };
+        self == other && !is_disjoint
+    }
+}
+
+impl PartialEq for AccessId {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.dynamic == other.dynamic
+    }
+}
+
+impl Eq for AccessId {}
+
+/// Query built from `TypeId`s at run-time instead of `Filter` types.
+/// It's for editors, inspectors, or scripts, see [`ComponentStorage::query_dynamic`](super::ComponentStorage::query_dynamic).
+#[derive(Debug, Clone, Default)]
+pub struct DynamicQuery {
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>,
+}
+
+impl DynamicQuery {
+    /// Selects entities having all of the `reads` and the `writes`.
+    pub fn new(reads: Vec<TypeId>, writes: Vec<TypeId>) -> Self {
+        Self { reads, writes }
+    }
+
+    pub fn reads(&self) -> &[TypeId] {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &[TypeId] {
+        &self.writes
+    }
+}
+
+/// Whether a query is in the `Ref` or the `Mut` of a *System*.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Identifies a query of a *System*.
+/// Storage keeps state of each query with this such as event cursors and `Local`s, while cached results are in the [`QueryState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryId {
+    /// `TypeId` of the *System*.
+    pub system: TypeId,
+    /// `TypeId` of the query such as `F`, `Option<F>`, or `EventReader<T>`.
+    pub filter: TypeId,
+    /// The same query can be in both of the `Ref` and the `Mut`, and they're different.
+    pub access: Access,
+    /// Position of the query in the *System*, see [`QueryState::next_slot`].
+    /// The same query can appear several times, such as `(Local<u32>, Local<u32>)`, and each has its own state.
+    pub slot: usize,
+}
+
+impl QueryId {
+    /// Id of the query `Q` at the `slot` in the `Ref` of the `system`.
+    pub fn read<Q: 'static>(system: TypeId, slot: usize) -> Self {
+        Self {
+            system,
+            filter: TypeId::of::<Q>(),
+            access: Access::Read,
+            slot,
+        }
+    }
+
+    /// Id of the query `Q` at the `slot` in the `Mut` of the `system`.
+    pub fn write<Q: 'static>(system: TypeId, slot: usize) -> Self {
+        Self {
+            system,
+            filter: TypeId::of::<Q>(),
+            access: Access::Write,
+            slot,
+        }
+    }
+}
+
+/// A piece of the query result.
+/// It's a part of a column with its owner entities.
+/// `components` is `None` only for optional queries when the entities don't have the `Component`.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    pub components: Option<ErasedSlice>,
+    pub entities: NonNull<[Entity]>,
+}
+
+// Safety: It points to `Component`s and entities in the storage, which are `Send` and `Sync`.
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+/// Error of `single` and `single_mut` when the query doesn't have exactly one item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuerySingleError {
+    NoEntities,
+    MultipleEntities,
+}
+
+impl fmt::Display for QuerySingleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoEntities => write!(f, "no entities match the query"),
+            Self::MultipleEntities => write!(f, "multiple entities match the query"),
+        }
+    }
+}
+
+impl std::error::Error for QuerySingleError {}
+
+/// Error of the fallible getters of the `Store`, such as `try_get_events`.
+/// Queries of `Component`s don't fail, types that have never been inserted just select no entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryError {
+    /// The events haven't been registered by `add_event`.
+    UnregisteredEvents(&'static str),
+    /// The value kept for the type is of another type.
+    TypeMismatch(&'static str),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnregisteredEvents(name) => write!(f, "events {} should be registered by add_event", name),
+            Self::TypeMismatch(name) => write!(f, "value for {} has another type", name),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Takes the only item from the `iter`.
+fn take_single<I: Iterator>(mut iter: I) -> Result<I::Item, QuerySingleError> {
+    let item = iter.next().ok_or(QuerySingleError::NoEntities)?;
+    match iter.next() {
+        Some(_) => Err(QuerySingleError::MultipleEntities),
+        None => Ok(item),
+    }
+}
 
 pub struct QueryIter<'a, T> {
-    iter: Iter<'a, NonNull<[()]>>,
+    iter: Iter<'a, Chunk>,
     _marker: PhantomData<T>,
 }
 
-impl<'a, T> QueryIter<'a, T> {
+impl<'a, T: 'static> QueryIter<'a, T> {
     /// # Safety
     /// 
     /// Borrow check breaks here.
     /// Caller should guarantee that `v` is invariant during its usage.
     /// Plus, generic parameter `T` should match with the original type of the `v`.
-    pub unsafe fn new(v: &Vec<NonNull<[()]>>) -> Self {
+    pub unsafe fn new(v: &[Chunk]) -> Self {
         Self {
-            iter: (*(v as *const Vec<NonNull<[()]>>)).iter(),
+            iter: (*(v as *const [Chunk])).iter(),
             _marker: PhantomData,
         }
     }
+
+    /// Returns the next slice with its owner entities.
+    pub fn next_chunk(&mut self) -> Option<(&'a [Entity], &'a [T])> {
+        // Safety: Downcasting will be guaranteed by the caller(See comment at the constructor).
+        self.iter.next().map(|chunk| unsafe { Self::to_slices(chunk) })
+    }
+
+    /// Returns the last slice with its owner entities.
+    pub fn next_chunk_back(&mut self) -> Option<(&'a [Entity], &'a [T])> {
+        // Safety: Same as `next_chunk`.
+        self.iter.next_back().map(|chunk| unsafe { Self::to_slices(chunk) })
+    }
+
+    unsafe fn to_slices(chunk: &Chunk) -> (&'a [Entity], &'a [T]) {
+        (
+            &*(chunk.entities.as_ptr() as *const [Entity]),
+            downcast_slice(chunk.components.unwrap()),
+        )
+    }
+
+    /// Returns true if there're no slices left.
+    pub fn is_empty(&self) -> bool {
+        self.iter.len() == 0
+    }
+
+    /// Returns the number of entities in the remaining slices.
+    /// Use this to preallocate per-entity buffers, `len` is the number of slices.
+    pub fn entity_count(&self) -> usize {
+        self.iter.as_slice().iter().map(|chunk| chunk.entities.len()).sum()
+    }
+
+    /// Turns into per-entity iterator.
+    pub fn rows(self) -> Rows<'a, T> {
+        Rows {
+            chunks: self,
+            entities: [].iter(),
+            values: [].iter(),
+        }
+    }
+
+    /// Returns the `Component` when exactly one entity is expected, such as the player or the camera.
+    pub fn single(self) -> Result<&'a T, QuerySingleError> {
+        take_single(self.rows())
+    }
+
+    /// Splits the slices into ones of `n` entities with their owner entities,
+    /// so that each can be processed by SIMD or handed to a thread pool as a work item.
+    /// Slices start at multiples of `n` from the start of each slice of the query, and don't cross tables.
+    /// So that the last one of each table can be shorter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn chunks(self, n: usize) -> Chunks<'a, T> {
+        assert!(n > 0, "chunk size should be non-zero");
+        Chunks {
+            slices: self,
+            n,
+            entities: &[],
+            values: &[],
+        }
+    }
+
+    /// Turns into an iterator over unique combinations of `K` entities, such as pairs for collision checks.
+    /// Each combination is yielded once regardless of the order, and an entity doesn't appear twice in it.
+    pub fn iter_combinations<const K: usize>(self) -> Combinations<'a, T, K> {
+        Combinations::new(self.rows().collect())
+    }
 }
 
-impl<'a, T: 'a> Iterator for QueryIter<'a, T> {
+impl<'a, T: 'static> Iterator for QueryIter<'a, T> {
     type Item = &'a [T];
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter
-            .next()
-            .map(|ptr| 
-                // Safety: Downcasting will be guaranteed by the caller(See comment at the constructor).
-                unsafe { downcast_slice(ptr.as_ptr()) }
-            )
+        self.next_chunk().map(|(_, values)| values)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
+impl<'a, T: 'static> DoubleEndedIterator for QueryIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_chunk_back().map(|(_, values)| values)
+    }
+}
+
+impl<'a, T: 'static> ExactSizeIterator for QueryIter<'a, T> {}
+
 pub struct QueryIterMut<'a, T> {
-    iter: IterMut<'a, NonNull<[()]>>,
+    iter: IterMut<'a, Chunk>,
     _marker: PhantomData<T>,
 }
 
-impl<'a, T> QueryIterMut<'a, T> {
-    pub unsafe fn new(v: &mut Vec<NonNull<[()]>>) -> Self {
+impl<'a, T: 'static> QueryIterMut<'a, T> {
+    pub unsafe fn new(v: &mut [Chunk]) -> Self {
         Self {
-            iter: (*(v as *mut Vec<NonNull<[()]>>)).iter_mut(),
+            iter: (*(v as *mut [Chunk])).iter_mut(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the next slice with its owner entities.
+    pub fn next_chunk(&mut self) -> Option<(&'a [Entity], &'a mut [T])> {
+        self.iter.next().map(|chunk| unsafe { Self::to_slices(chunk) })
+    }
+
+    /// Returns the last slice with its owner entities.
+    pub fn next_chunk_back(&mut self) -> Option<(&'a [Entity], &'a mut [T])> {
+        self.iter.next_back().map(|chunk| unsafe { Self::to_slices(chunk) })
+    }
+
+    unsafe fn to_slices(chunk: &Chunk) -> (&'a [Entity], &'a mut [T]) {
+        (
+            &*(chunk.entities.as_ptr() as *const [Entity]),
+            downcast_mut_slice(chunk.components.unwrap()),
+        )
+    }
+
+    /// Returns true if there're no slices left.
+    pub fn is_empty(&self) -> bool {
+        self.iter.len() == 0
+    }
+
+    /// Returns the number of entities in the remaining slices.
+    /// Use this to preallocate per-entity buffers, `len` is the number of slices.
+    pub fn entity_count(&self) -> usize {
+        self.iter.as_slice().iter().map(|chunk| chunk.entities.len()).sum()
+    }
+
+    /// Turns into per-entity iterator.
+    pub fn rows(self) -> RowsMut<'a, T> {
+        RowsMut {
+            chunks: self,
+            entities: [].iter(),
+            values: [].iter_mut(),
+        }
+    }
+
+    /// Gives up writing, see [`Narrow`].
+    pub fn into_shared(self) -> QueryIter<'a, T> {
+        QueryIter {
+            iter: self.iter.into_slice().iter(),
             _marker: PhantomData,
         }
     }
+
+    /// Returns the `Component` when exactly one entity is expected, such as the player or the camera.
+    pub fn single_mut(self) -> Result<&'a mut T, QuerySingleError> {
+        take_single(self.rows())
+    }
+
+    /// Mutable version of [`QueryIter::chunks`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn chunks_mut(self, n: usize) -> ChunksMut<'a, T> {
+        assert!(n > 0, "chunk size should be non-zero");
+        ChunksMut {
+            slices: self,
+            n,
+            entities: &[],
+            values: &mut [],
+        }
+    }
+
+    /// Mutable version of [`QueryIter::iter_combinations`].
+    /// It can't be an `Iterator` because a combination borrows the entities until the next one is fetched.
+    pub fn iter_combinations_mut<const K: usize>(self) -> CombinationsMut<'a, T, K> {
+        CombinationsMut::new(self.rows().map(NonNull::from).collect())
+    }
 }
 
-impl<'a, T: 'a> Iterator for QueryIterMut<'a, T> {
+impl<'a, T: 'static> Iterator for QueryIterMut<'a, T> {
     type Item = &'a mut [T];
 
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|(_, values)| values)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T: 'static> DoubleEndedIterator for QueryIterMut<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_chunk_back().map(|(_, values)| values)
+    }
+}
+
+impl<'a, T: 'static> ExactSizeIterator for QueryIterMut<'a, T> {}
+
+/// Iterator over the result of `Query::query` for `Option<F>`.
+/// It yields `None` for entities that pass the filter but don't have the `Target`.
+pub struct OptionIter<'a, T> {
+    iter: Iter<'a, Chunk>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: 'static> OptionIter<'a, T> {
+    /// # Safety
+    ///
+    /// See [`QueryIter::new`].
+    pub unsafe fn new(v: &[Chunk]) -> Self {
+        Self {
+            iter: (*(v as *const [Chunk])).iter(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the next slice with its owner entities.
+    pub fn next_chunk(&mut self) -> Option<(&'a [Entity], Option<&'a [T]>)> {
+        self.iter.next().map(|chunk| unsafe {
+            (
+                &*(chunk.entities.as_ptr() as *const [Entity]),
+                chunk.components.map(|slice| downcast_slice(slice)),
+            )
+        })
+    }
+
+    /// Turns into per-entity iterator.
+    pub fn rows(self) -> OptionRows<'a, T> {
+        OptionRows {
+            chunks: self,
+            entities: [].iter(),
+            values: None,
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for OptionIter<'a, T> {
+    type Item = Option<&'a [T]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|(_, values)| values)
+    }
+}
+
+/// Iterator over the result of `Query::query_mut` for `Option<F>`.
+pub struct OptionIterMut<'a, T> {
+    iter: IterMut<'a, Chunk>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: 'static> OptionIterMut<'a, T> {
+    /// # Safety
+    ///
+    /// See [`QueryIter::new`].
+    pub unsafe fn new(v: &mut [Chunk]) -> Self {
+        Self {
+            iter: (*(v as *mut [Chunk])).iter_mut(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the next slice with its owner entities.
+    pub fn next_chunk(&mut self) -> Option<(&'a [Entity], Option<&'a mut [T]>)> {
+        self.iter.next().map(|chunk| unsafe {
+            (
+                &*(chunk.entities.as_ptr() as *const [Entity]),
+                chunk.components.map(|slice| downcast_mut_slice(slice)),
+            )
+        })
+    }
+
+    /// Turns into per-entity iterator.
+    pub fn rows(self) -> OptionRowsMut<'a, T> {
+        OptionRowsMut {
+            chunks: self,
+            entities: [].iter(),
+            values: None,
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for OptionIterMut<'a, T> {
+    type Item = Option<&'a mut [T]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|(_, values)| values)
+    }
+}
+
+/// Iterator that knows the owner entity of each item.
+pub trait RowIter {
+    type Item;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)>;
+}
+
+/// Per-entity iterator over the result of `Query::query`.
+pub struct Rows<'a, T> {
+    chunks: QueryIter<'a, T>,
+    entities: Iter<'a, Entity>,
+    values: Iter<'a, T>,
+}
+
+impl<'a, T: 'static> RowIter for Rows<'a, T> {
+    type Item = &'a T;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)> {
+        loop {
+            if let (Some(entity), Some(value)) = (self.entities.next(), self.values.next()) {
+                return Some((*entity, value));
+            }
+            let (entities, values) = self.chunks.next_chunk()?;
+            self.entities = entities.iter();
+            self.values = values.iter();
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for Rows<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|(_, value)| value)
+    }
+}
+
+/// Per-entity iterator over the result of `Query::query_mut`.
+pub struct RowsMut<'a, T> {
+    chunks: QueryIterMut<'a, T>,
+    entities: Iter<'a, Entity>,
+    values: IterMut<'a, T>,
+}
+
+impl<'a, T: 'static> RowIter for RowsMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)> {
+        loop {
+            if let (Some(entity), Some(value)) = (self.entities.next(), self.values.next()) {
+                return Some((*entity, value));
+            }
+            let (entities, values) = self.chunks.next_chunk()?;
+            self.entities = entities.iter();
+            self.values = values.iter_mut();
+        }
+    }
+}
+
+impl<'a, T: 'static> RowsMut<'a, T> {
+    /// Gives up writing, see [`Narrow`].
+    pub fn into_shared(self) -> Rows<'a, T> {
+        Rows {
+            chunks: self.chunks.into_shared(),
+            entities: self.entities,
+            values: self.values.into_slice().iter(),
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for RowsMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|(_, value)| value)
+    }
+}
+
+/// Iterator over slices of at most `n` entities, see [`QueryIter::chunks`].
+pub struct Chunks<'a, T> {
+    slices: QueryIter<'a, T>,
+    n: usize,
+    entities: &'a [Entity],
+    values: &'a [T],
+}
+
+impl<'a, T: 'static> Iterator for Chunks<'a, T> {
+    type Item = (&'a [Entity], &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.values.is_empty() {
+            (self.entities, self.values) = self.slices.next_chunk()?;
+        }
+        let n = self.n.min(self.values.len());
+        let (entities, rest_entities) = self.entities.split_at(n);
+        let (values, rest_values) = self.values.split_at(n);
+        self.entities = rest_entities;
+        self.values = rest_values;
+        Some((entities, values))
+    }
+}
+
+/// Iterator over mutable slices of at most `n` entities, see [`QueryIterMut::chunks_mut`].
+pub struct ChunksMut<'a, T> {
+    slices: QueryIterMut<'a, T>,
+    n: usize,
+    entities: &'a [Entity],
+    values: &'a mut [T],
+}
+
+impl<'a, T: 'static> Iterator for ChunksMut<'a, T> {
+    type Item = (&'a [Entity], &'a mut [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.values.is_empty() {
+            (self.entities, self.values) = self.slices.next_chunk()?;
+        }
+        let n = self.n.min(self.values.len());
+        let (entities, rest_entities) = self.entities.split_at(n);
+        let (values, rest_values) = std::mem::take(&mut self.values).split_at_mut(n);
+        self.entities = rest_entities;
+        self.values = rest_values;
+        Some((entities, values))
+    }
+}
+
+/// Returns the next indices of `K` out of `n` in lexicographic order, or `None` after the last ones.
+fn next_combination<const K: usize>(mut indices: [usize; K], n: usize) -> Option<[usize; K]> {
+    // Finds the rightmost index that can move forward, and puts the following ones right after it.
+    let i = (0..K).rev().find(|&i| indices[i] < n - K + i)?;
+    indices[i] += 1;
+    for j in i + 1..K {
+        indices[j] = indices[j - 1] + 1;
+    }
+    Some(indices)
+}
+
+/// Iterator over unique combinations of `K` entities, see [`QueryIter::iter_combinations`].
+pub struct Combinations<'a, T, const K: usize> {
+    values: Vec<&'a T>,
+    // `None` if there're no combinations left.
+    indices: Option<[usize; K]>,
+}
+
+impl<'a, T, const K: usize> Combinations<'a, T, K> {
+    fn new(values: Vec<&'a T>) -> Self {
+        let indices = (K <= values.len()).then(|| std::array::from_fn(|i| i));
+        Self { values, indices }
+    }
+}
+
+impl<'a, T: 'a, const K: usize> Iterator for Combinations<'a, T, K> {
+    type Item = [&'a T; K];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let indices = self.indices?;
+        self.indices = next_combination(indices, self.values.len());
+        Some(indices.map(|i| self.values[i]))
+    }
+}
+
+/// Lending iterator over unique combinations of `K` entities, see [`QueryIterMut::iter_combinations_mut`].
+///
+/// ```ignore
+/// let mut pairs = m.iter_combinations_mut::<2>();
+/// while let Some([a, b]) = pairs.fetch_next() {
+///     // Both `a` and `b` are writable here.
+/// }
+/// ```
+pub struct CombinationsMut<'a, T, const K: usize> {
+    values: Vec<NonNull<T>>,
+    // `None` if there're no combinations left.
+    indices: Option<[usize; K]>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T, const K: usize> CombinationsMut<'a, T, K> {
+    fn new(values: Vec<NonNull<T>>) -> Self {
+        let indices = (K <= values.len()).then(|| std::array::from_fn(|i| i));
+        Self {
+            values,
+            indices,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the next combination, which is borrowed until this is called again.
+    pub fn fetch_next(&mut self) -> Option<[&mut T; K]> {
+        let indices = self.indices?;
+        self.indices = next_combination(indices, self.values.len());
+
+        // `&mut`s to the same entity must not be made together.
+        assert!(
+            indices.windows(2).all(|w| w[0] < w[1]),
+            "a combination has the same entity twice"
+        );
+        // Safety: Pointers came from `&'a mut`s to distinct entities, and the indices are distinct as checked above.
+        // Returned references borrow `self`, so that they're dropped before the next combination.
+        Some(indices.map(|i| unsafe { &mut *self.values[i].as_ptr() }))
+    }
+}
+
+/// Per-entity iterator over the result of `Query::query` for `Option<F>`.
+pub struct OptionRows<'a, T> {
+    chunks: OptionIter<'a, T>,
+    entities: Iter<'a, Entity>,
+    // `None` if the current chunk doesn't have the `Target`.
+    values: Option<Iter<'a, T>>,
+}
+
+impl<'a, T: 'static> RowIter for OptionRows<'a, T> {
+    type Item = Option<&'a T>;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)> {
+        loop {
+            if let Some(entity) = self.entities.next() {
+                let value = self.values.as_mut().and_then(|values| values.next());
+                return Some((*entity, value));
+            }
+            let (entities, values) = self.chunks.next_chunk()?;
+            self.entities = entities.iter();
+            self.values = values.map(|values| values.iter());
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for OptionRows<'a, T> {
+    type Item = Option<&'a T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|(_, value)| value)
+    }
+}
+
+/// Per-entity iterator over the result of `Query::query_mut` for `Option<F>`.
+pub struct OptionRowsMut<'a, T> {
+    chunks: OptionIterMut<'a, T>,
+    entities: Iter<'a, Entity>,
+    // `None` if the current chunk doesn't have the `Target`.
+    values: Option<IterMut<'a, T>>,
+}
+
+impl<'a, T: 'static> RowIter for OptionRowsMut<'a, T> {
+    type Item = Option<&'a mut T>;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)> {
+        loop {
+            if let Some(entity) = self.entities.next() {
+                let value = self.values.as_mut().and_then(|values| values.next());
+                return Some((*entity, value));
+            }
+            let (entities, values) = self.chunks.next_chunk()?;
+            self.entities = entities.iter();
+            self.values = values.map(|values| values.iter_mut());
+        }
+    }
+}
+
+impl<'a, T: 'static> Iterator for OptionRowsMut<'a, T> {
+    type Item = Option<&'a mut T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|(_, value)| value)
+    }
+}
+
+/// Nothing, for *System*s that don't need the `Ref` or the `Mut`.
+impl<'a> Query<'a> for () {
+    type Output = ();
+    type OutputMut = ();
+
+    #[inline]
+    fn query(_storage: &mut dyn Store, _state: &mut QueryState) -> Self::Output {}
+
+    #[inline]
+    fn query_mut(_storage: &mut dyn Store, _state: &mut QueryState) -> Self::OutputMut {}
+
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
+
+impl<'a> ReadOnlyQuery<'a> for () {
+    #[inline]
+    fn query_shared(_storage: &dyn StoreRead, _state: &mut QueryState) -> Self::Output {}
+}
+
+/// Optional `Target` of the `F`.
+/// Entities that pass the filters of the `F` are selected even if they don't have the `Target`.
+impl<'a, F: Filter> Query<'a> for Option<F> {
+    type Output = OptionIter<'a, F::Target>;
+    type OutputMut = OptionIterMut<'a, F::Target>;
+
+    #[inline]
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output {
+        storage.get_optional::<F>(state)
+    }
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut {
+        storage.get_optional_mut::<F>(state)
+    }
+
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        vec![AccessId::filtered::<F>()]
+    }
+}
+
+impl<'a, F: Filter> ReadOnlyQuery<'a> for Option<F> {
+    #[inline]
+    fn query_shared(storage: &dyn StoreRead, state: &mut QueryState) -> Self::Output {
+        storage.get_optional_shared::<F>(state)
+    }
+}
+
+/// Pseudo filter that gives you entities instead of `Component`s.
+/// It selects the same entities as the `F` in the same order,
+/// so that you can find out which entity each row of the `F` belongs to.
+/// For instance, `type Ref = (EntityId<FA>, FA);`
+pub struct EntityId<F>(PhantomData<F>);
+
+impl<'a, F: Filter> Query<'a> for EntityId<F> {
+    type Output = EntityIter<'a>;
+    type OutputMut = EntityIter<'a>;
+
+    #[inline]
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output {
+        storage.get_entities::<F>(state)
+    }
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut {
+        storage.get_entities::<F>(state)
+    }
+
+    /// Entities are not `Component`s, so that `EntityId` doesn't access any `Component`s.
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
+
+impl<'a, F: Filter> ReadOnlyQuery<'a> for EntityId<F> {
+    #[inline]
+    fn query_shared(storage: &dyn StoreRead, state: &mut QueryState) -> Self::Output {
+        storage.get_entities_shared::<F>(state)
+    }
+}
+
+/// Iterator over slices of entities, see [`EntityId`].
+pub struct EntityIter<'a> {
+    iter: Iter<'a, Chunk>,
+}
+
+impl<'a> EntityIter<'a> {
+    /// # Safety
+    ///
+    /// Borrow check breaks here.
+    /// Caller should guarantee that `v` is invariant during its usage.
+    pub unsafe fn new(v: &[Chunk]) -> Self {
+        Self {
+            iter: (*(v as *const [Chunk])).iter(),
+        }
+    }
+
+    /// Turns into per-entity iterator.
+    pub fn rows(self) -> EntityRows<'a> {
+        EntityRows {
+            chunks: self,
+            entities: [].iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for EntityIter<'a> {
+    type Item = &'a [Entity];
+
     fn next(&mut self) -> Option<Self::Item> {
         self.iter
             .next()
-            .map(|ptr| unsafe { downcast_mut_slice(ptr.as_ptr()) })
+            .map(|chunk| unsafe { &*(chunk.entities.as_ptr() as *const [Entity]) })
     }
 }
+
+/// Per-entity iterator over entities, see [`EntityId`].
+pub struct EntityRows<'a> {
+    chunks: EntityIter<'a>,
+    entities: Iter<'a, Entity>,
+}
+
+impl<'a> RowIter for EntityRows<'a> {
+    type Item = Entity;
+
+    fn next_row(&mut self) -> Option<(Entity, Self::Item)> {
+        loop {
+            if let Some(entity) = self.entities.next() {
+                return Some((*entity, *entity));
+            }
+            self.entities = self.chunks.next()?.iter();
+        }
+    }
+}
+
+impl<'a> Iterator for EntityRows<'a> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|(entity, _)| entity)
+    }
+}
+
+/// Zips per-entity iterators in a tuple such as `(r.rows(), m.rows())`.
+/// It yields items of the same entity together like `(&A, &mut B)`.
+/// Zipped iterators must visit the same entities in the same order,
+/// which is true if their filters have the same `FilterAll`, `FilterAny`, and `FilterNone`.
+/// Otherwise, it panics.
+pub trait ZipEntities: Sized {
+    fn zip_entities(self) -> Zipped<Self>;
+}
+
+/// See [`ZipEntities`].
+pub struct Zipped<T>(pub T);
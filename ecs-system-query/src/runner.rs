@@ -0,0 +1,123 @@
+use super::{ComponentStorage, Schedule};
+use std::time::{Duration, Instant};
+
+/// Resource telling the fixed timestep to systems.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTime {
+    /// Fixed delta time of the fixed schedule.
+    pub dt: Duration,
+    /// Remaining time not yet simulated by the fixed schedule, over `dt`.
+    /// It's in [0, 1), use it to interpolate rendering between fixed steps.
+    pub overstep: f32,
+}
+
+/// Resource telling how time passes frame by frame, which `Runner` and `App` update at the start of each frame.
+/// Read it by `Res<Time>` to move things by the `delta`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    /// Time elapsed since the last frame, which is zero in the first frame.
+    pub delta: Duration,
+    /// Time elapsed since the first frame.
+    pub elapsed: Duration,
+    /// Number of frames started so far, including the current one.
+    pub frame_count: u64,
+}
+
+impl Time {
+    pub fn delta_secs(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    pub fn elapsed_secs(&self) -> f64 {
+        self.elapsed.as_secs_f64()
+    }
+
+    /// Starts a new frame after the `delta`.
+    pub fn advance(&mut self, delta: Duration) {
+        self.delta = delta;
+        self.elapsed += delta;
+        self.frame_count += 1;
+    }
+}
+
+impl ComponentStorage {
+    /// Starts a new frame of the `Time` after the `delta`, which is inserted at the first call.
+    pub fn advance_time(&mut self, delta: Duration) {
+        if self.get_resource::<Time>().is_none() {
+            self.insert_resource(Time::default());
+        }
+        self.get_resource_mut::<Time>().unwrap().advance(delta);
+    }
+}
+
+/// Runs a fixed schedule at a fixed rate alongside a variable rate schedule.
+/// Each `tick` adds elapsed wall-clock time to an accumulator and runs the fixed schedule as many times as `dt` fits in it.
+/// So that the fixed schedule sees the same `dt` every time, which makes simulation deterministic.
+pub struct Runner {
+    fixed: Schedule,
+    update: Schedule,
+    dt: Duration,
+    accumulator: Duration,
+    last: Option<Instant>,
+}
+
+impl Runner {
+    /// `fixed` runs every `dt`, and `update` runs once every `tick`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dt` is zero.
+    pub fn new(fixed: Schedule, update: Schedule, dt: Duration) -> Self {
+        assert!(!dt.is_zero(), "dt should not be zero");
+        Self {
+            fixed,
+            update,
+            dt,
+            accumulator: Duration::ZERO,
+            last: None,
+        }
+    }
+
+    /// Runs a frame with the wall-clock time elapsed since the last `tick`.
+    /// The first `tick` runs only the `update` because no time has elapsed yet.
+    /// The elapsed time is the `delta` of the `Time` as well.
+    pub fn tick(&mut self, storage: &mut ComponentStorage) {
+        let now = Instant::now();
+        let elapsed = self.last.map(|last| now - last).unwrap_or_default();
+        self.last = Some(now);
+        self.advance(storage, elapsed);
+    }
+
+    /// Runs a frame as if the `elapsed` time has passed.
+    /// The fixed schedule runs zero or more times, and then the `update` runs once.
+    /// Returns how many times the fixed schedule ran.
+    pub fn advance(&mut self, storage: &mut ComponentStorage, elapsed: Duration) -> u32 {
+        storage.advance_time(elapsed);
+        self.accumulator += elapsed;
+        let mut steps = 0;
+        while self.accumulator >= self.dt {
+            self.accumulator -= self.dt;
+            storage.insert_resource(self.fixed_time());
+            self.fixed.run_systems(storage);
+            steps += 1;
+        }
+        storage.insert_resource(self.fixed_time());
+        self.update.run(storage);
+        steps
+    }
+
+    pub fn fixed_mut(&mut self) -> &mut Schedule {
+        &mut self.fixed
+    }
+
+    pub fn update_mut(&mut self) -> &mut Schedule {
+        &mut self.update
+    }
+
+    fn fixed_time(&self) -> FixedTime {
+        FixedTime {
+            dt: self.dt,
+            overstep: self.accumulator.as_secs_f32() / self.dt.as_secs_f32(),
+        }
+    }
+}
@@ -0,0 +1,1027 @@
+use super::{
+    AccessId, Clock, ComponentStorage, Exclusive, ExclusiveSystem, IntoSystem, Invokable, SystemError, Timed,
+};
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Stages of a `Schedule`.
+/// Stages are executed in the order declared here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    PreUpdate,
+    Update,
+    PostUpdate,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::PreUpdate, Stage::Update, Stage::PostUpdate];
+}
+
+/// A label to point out *System*s.
+/// Every *System* has its type and its handle as labels, and you can give it more names.
+/// Names of system sets are labels as well, see [`SystemConfig::in_set`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Label {
+    Type(TypeId),
+    Name(&'static str),
+    System(SystemHandle),
+}
+
+impl Label {
+    /// Label of the *System* type `S`.
+    pub fn of<S: 'static>() -> Self {
+        Self::Type(TypeId::of::<S>())
+    }
+}
+
+impl From<&'static str> for Label {
+    fn from(value: &'static str) -> Self {
+        Self::Name(value)
+    }
+}
+
+impl From<SystemHandle> for Label {
+    fn from(value: SystemHandle) -> Self {
+        Self::System(value)
+    }
+}
+
+/// A *System* added to a `Schedule`, which is returned by [`Schedule::register`].
+/// It stays valid while the *System* is in the schedule, and it's never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SystemHandle {
+    stage: Stage,
+    id: u64,
+}
+
+impl SystemHandle {
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+}
+
+/// How *System*s in the same `Stage` accessing the same data are ordered, see [`Schedule::set_access_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessOrder {
+    /// A *System* writing what another one reads runs before it, so that the reader sees data of the current frame.
+    /// Explicit ordering constraints take precedence, and they're needed only to break ties
+    /// such as *System*s writing the same data, which keep insertion order.
+    #[default]
+    WritersFirst,
+    /// *System*s run in insertion order unless they have explicit ordering constraints.
+    Insertion,
+}
+
+/// A run condition, see [`SystemConfig::run_if`].
+pub type Condition = Box<dyn Fn(&ComponentStorage) -> bool>;
+
+/// Configuration of a *System* such as labels, ordering constraints in the same `Stage`, and run conditions.
+///
+/// # Example
+///
+/// ```ignore
+/// schedule.add_system_with(
+///     Stage::Update,
+///     SysB,
+///     SystemConfig::new().label("b").after(Label::of::<SysA>()).run_if(|s| !s.get_resource::<Paused>().unwrap().0),
+/// );
+/// ```
+#[derive(Default)]
+pub struct SystemConfig {
+    labels: Vec<Label>,
+    sets: Vec<Label>,
+    before: Vec<Label>,
+    after: Vec<Label>,
+    conditions: Vec<Condition>,
+}
+
+impl SystemConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives a label to the *System*.
+    pub fn label(mut self, label: impl Into<Label>) -> Self {
+        self.labels.push(label.into());
+        self
+    }
+
+    /// Puts the *System* into the `set`.
+    /// A set is a group of *System*s sharing its name as a label, and it can be configured collectively by [`Schedule::configure_set`].
+    pub fn in_set(mut self, set: impl Into<Label>) -> Self {
+        self.sets.push(set.into());
+        self
+    }
+
+    /// The *System* runs before *System*s having the `label`.
+    pub fn before(mut self, label: impl Into<Label>) -> Self {
+        self.before.push(label.into());
+        self
+    }
+
+    /// The *System* runs after *System*s having the `label`.
+    pub fn after(mut self, label: impl Into<Label>) -> Self {
+        self.after.push(label.into());
+        self
+    }
+
+    /// The *System* runs only if the `condition` returns true.
+    /// Conditions are evaluated every frame right before the *System* runs.
+    /// If there're multiple conditions, all of them should be true.
+    pub fn run_if(mut self, condition: impl Fn(&ComponentStorage) -> bool + 'static) -> Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+}
+
+/// Configuration shared by all *System*s in a set.
+///
+/// # Example
+///
+/// ```ignore
+/// schedule.configure_set("physics", SetConfig::new().after("input"));
+/// ```
+#[derive(Default)]
+pub struct SetConfig {
+    before: Vec<Label>,
+    after: Vec<Label>,
+    conditions: Vec<Condition>,
+}
+
+impl SetConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// *System*s in the set run before *System*s having the `label`.
+    pub fn before(mut self, label: impl Into<Label>) -> Self {
+        self.before.push(label.into());
+        self
+    }
+
+    /// *System*s in the set run after *System*s having the `label`.
+    pub fn after(mut self, label: impl Into<Label>) -> Self {
+        self.after.push(label.into());
+        self
+    }
+
+    /// *System*s in the set run only if the `condition` returns true.
+    /// It's evaluated for each *System* in the set, right before the *System* runs.
+    pub fn run_if(mut self, condition: impl Fn(&ComponentStorage) -> bool + 'static) -> Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// Ordering constraints make a cycle among these systems.
+    Cycle {
+        stage: Stage,
+        systems: Vec<&'static str>,
+    },
+    /// The system writes the type while it reads or writes the same type elsewhere in its queries.
+    Conflict {
+        system: &'static str,
+        component: &'static str,
+    },
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle { stage, systems } => {
+                write!(f, "ordering cycle in {:?} among {}", stage, systems.join(", "))
+            }
+            Self::Conflict { system, component } => {
+                write!(f, "{} writes {} while it accesses the same elsewhere", system, component)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// A pair of *System*s in the same stage accessing the same data without ordering between them,
+/// see [`Schedule::check_ambiguities`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ambiguity {
+    pub stage: Stage,
+    pub systems: [&'static str; 2],
+    /// Types written by either *System* and accessed by the other.
+    pub components: Vec<&'static str>,
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} and {} in {:?} access {} without ordering",
+            self.systems[0],
+            self.systems[1],
+            self.stage,
+            self.components.join(", ")
+        )
+    }
+}
+
+/// Checks that the `system` doesn't write a type it reads or writes elsewhere,
+/// which makes aliasing references to the same `Component`s.
+fn check_access(system: &dyn Invokable) -> Result<(), ScheduleError> {
+    let (reads, writes) = (system.reads(), system.writes());
+    for (i, write) in writes.iter().enumerate() {
+        let conflicts = |access: &AccessId| access.conflicts(write);
+        if reads.iter().any(conflicts) || writes[..i].iter().any(conflicts) {
+            return Err(ScheduleError::Conflict {
+                system: system.name(),
+                component: write.name,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A *System* with its configuration.
+struct SystemEntry {
+    system: Box<dyn Invokable>,
+    config: SystemConfig,
+    handle: SystemHandle,
+}
+
+impl SystemEntry {
+    fn has_label(&self, label: &Label) -> bool {
+        *label == Label::Type(self.system.id())
+            || *label == Label::System(self.handle)
+            || self.config.labels.contains(label)
+            || self.config.sets.contains(label)
+    }
+
+    /// Configurations of the sets that the *System* belongs to.
+    fn set_configs<'a>(
+        &'a self,
+        sets: &'a HashMap<Label, SetConfig>,
+    ) -> impl Iterator<Item = &'a SetConfig> {
+        self.config.sets.iter().filter_map(|set| sets.get(set))
+    }
+
+    /// Returns true if either *System* writes what the other one accesses, see [`AccessId::conflicts`].
+    fn conflicts_with(&self, other: &Self) -> bool {
+        let (reads, writes) = (self.system.reads(), self.system.writes());
+        let (other_reads, other_writes) = (other.system.reads(), other.system.writes());
+        self.system.is_exclusive()
+            || other.system.is_exclusive()
+            || overlaps(&writes, &other_reads)
+            || overlaps(&writes, &other_writes)
+            || overlaps(&other_writes, &reads)
+    }
+
+    /// Returns names of types that either *System* writes and the other one accesses.
+    fn conflicting_accesses(&self, other: &Self) -> Vec<&'static str> {
+        let (reads, writes) = (self.system.reads(), self.system.writes());
+        let (other_reads, other_writes) = (other.system.reads(), other.system.writes());
+        let other_accesses = other_reads.iter().chain(other_writes.iter());
+        let pairs = (writes.iter().flat_map(|w| other_accesses.clone().map(move |a| (w, a))))
+            .chain(other_writes.iter().flat_map(|w| reads.iter().map(move |a| (w, a))));
+        let mut names = Vec::new();
+        for (write, access) in pairs {
+            if write.conflicts(access) && !names.contains(&write.name) {
+                names.push(write.name);
+            }
+        }
+        names
+    }
+
+    /// Returns true if the *System* writes what the `other` reads, and the `other` doesn't write what it accesses.
+    /// Then it runs first by `AccessOrder::WritersFirst`.
+    fn feeds(&self, other: &Self) -> bool {
+        let (reads, writes) = (self.system.reads(), self.system.writes());
+        let (other_reads, other_writes) = (other.system.reads(), other.system.writes());
+        !self.system.is_exclusive()
+            && !other.system.is_exclusive()
+            && overlaps(&writes, &other_reads)
+            && !overlaps(&writes, &other_writes)
+            && !overlaps(&other_writes, &reads)
+    }
+
+    /// Returns true if the *System* runs before the `other` due to its own or its sets' constraints.
+    fn is_before(&self, other: &Self, sets: &HashMap<Label, SetConfig>) -> bool {
+        let own_sets = self.set_configs(sets);
+        let other_sets = other.set_configs(sets);
+        let mut before = (self.config.before.iter()).chain(own_sets.flat_map(|c| c.before.iter()));
+        let mut after = (other.config.after.iter()).chain(other_sets.flat_map(|c| c.after.iter()));
+        before.any(|l| other.has_label(l)) || after.any(|l| self.has_label(l))
+    }
+
+    fn should_run(
+        &self,
+        storage: &ComponentStorage,
+        sets: &HashMap<Label, SetConfig>,
+        disabled: &HashSet<Label>,
+    ) -> bool {
+        !disabled.iter().any(|label| self.has_label(label))
+            && self.config.conditions.iter().all(|cond| cond(storage))
+            && self
+                .set_configs(sets)
+                .all(|c| c.conditions.iter().all(|cond| cond(storage)))
+    }
+}
+
+/// Returns true if any of `a` conflicts with any of `b`, see [`AccessId::conflicts`].
+fn overlaps(a: &[AccessId], b: &[AccessId]) -> bool {
+    a.iter().any(|a| b.iter().any(|b| a.conflicts(b)))
+}
+
+/// Returns true if the `to` is reachable from the `from` along the `edges`.
+fn reaches(edges: &[Vec<usize>], from: usize, to: usize) -> bool {
+    let mut visited = vec![false; edges.len()];
+    let mut stack = vec![from];
+    while let Some(i) = stack.pop() {
+        if i == to {
+            return true;
+        }
+        if !std::mem::replace(&mut visited[i], true) {
+            stack.extend(edges[i].iter().copied());
+        }
+    }
+    false
+}
+
+/// *System*s of a `Stage`.
+#[derive(Default)]
+struct StageSystems {
+    entries: Vec<SystemEntry>,
+    // Execution order, indices to the `entries`.
+    // Empty if it's not built yet.
+    sorted: Vec<usize>,
+    // Consecutive runs of the `sorted` that can run at the same time.
+    batches: Vec<Vec<usize>>,
+    // `edges[i]` has entries running right after the entry `i`, which are explicit or inferred ordering.
+    edges: Vec<Vec<usize>>,
+}
+
+impl StageSystems {
+    /// Sorts the entries topologically with respect to the ordering constraints,
+    /// and the accesses if the `order` is `AccessOrder::WritersFirst`.
+    /// Ties are broken by insertion order.
+    fn build(
+        &mut self,
+        stage: Stage,
+        sets: &HashMap<Label, SetConfig>,
+        order: AccessOrder,
+    ) -> Result<(), ScheduleError> {
+        let n = self.entries.len();
+        let mut edges = vec![Vec::new(); n];
+        let mut in_degree = vec![0; n];
+        for (i, entry) in self.entries.iter().enumerate() {
+            for (j, other) in self.entries.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                // i -> j if i runs before j.
+                if entry.is_before(other, sets) {
+                    edges[i].push(j);
+                    in_degree[j] += 1;
+                }
+            }
+        }
+        if order == AccessOrder::WritersFirst {
+            for (i, entry) in self.entries.iter().enumerate() {
+                for (j, other) in self.entries.iter().enumerate() {
+                    // Inferred edges never go against explicit ones, even transitively.
+                    if i != j && entry.feeds(other) && !reaches(&edges, j, i) && !edges[i].contains(&j) {
+                        edges[i].push(j);
+                        in_degree[j] += 1;
+                    }
+                }
+            }
+        }
+
+        let mut sorted = Vec::with_capacity(n);
+        let mut ready = (0..n).filter(|i| in_degree[*i] == 0).collect::<VecDeque<_>>();
+        while let Some(i) = ready.pop_front() {
+            sorted.push(i);
+            for &j in edges[i].iter() {
+                in_degree[j] -= 1;
+                if in_degree[j] == 0 {
+                    // Keeps insertion order as much as possible.
+                    let pos = ready.partition_point(|&k| k < j);
+                    ready.insert(pos, j);
+                }
+            }
+        }
+
+        if sorted.len() < n {
+            return Err(ScheduleError::Cycle {
+                stage,
+                systems: (0..n)
+                    .filter(|i| in_degree[*i] > 0)
+                    .map(|i| self.entries[i].system.name())
+                    .collect(),
+            });
+        }
+        // Splits the order into batches of systems that don't conflict or depend on each other.
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        for &i in sorted.iter() {
+            let entry = &self.entries[i];
+            let fits = batches.last().is_some_and(|batch| {
+                batch.iter().all(|&j| {
+                    let other = &self.entries[j];
+                    !entry.conflicts_with(other) && !other.is_before(entry, sets)
+                })
+            });
+            match batches.last_mut() {
+                Some(batch) if fits => batch.push(i),
+                _ => batches.push(vec![i]),
+            }
+        }
+
+        self.sorted = sorted;
+        self.batches = batches;
+        self.edges = edges;
+        Ok(())
+    }
+
+    /// Returns pairs of conflicting entries that aren't ordered even transitively.
+    fn ambiguities(&self, stage: Stage) -> Vec<Ambiguity> {
+        let mut ambiguities = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            for (j, other) in self.entries.iter().enumerate().skip(i + 1) {
+                if reaches(&self.edges, i, j) || reaches(&self.edges, j, i) {
+                    continue;
+                }
+                let components = entry.conflicting_accesses(other);
+                if !components.is_empty() {
+                    ambiguities.push(Ambiguity {
+                        stage,
+                        systems: [entry.system.name(), other.system.name()],
+                        components,
+                    });
+                }
+            }
+        }
+        ambiguities
+    }
+}
+
+fn access_names(ids: &[AccessId]) -> String {
+    if ids.is_empty() {
+        "-".to_owned()
+    } else {
+        ids.iter().map(|id| id.name).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Handles an error returned by the *System* named the first argument, see [`Schedule::set_error_handler`].
+pub type ErrorHandler = fn(&'static str, SystemError);
+
+/// `ErrorHandler` panicking, which is the default one.
+pub fn panic_on_error(system: &'static str, error: SystemError) {
+    panic!("{} failed: {}", system, error);
+}
+
+/// `ErrorHandler` printing the error to the stderr.
+pub fn log_error(system: &'static str, error: SystemError) {
+    eprintln!("{} failed: {}", system, error);
+}
+
+/// `ErrorHandler` ignoring the error.
+pub fn skip_error(_system: &'static str, _error: SystemError) {}
+
+/// Runs a batch of *System*s, see [`Schedule::run_with`].
+/// *System*s in a batch don't write what others in the batch access, and they don't have ordering constraints among them.
+/// So that a parallel executor can run them at the same time, e.g. on web workers sharing the wasm memory.
+/// Exclusive *System*s are always alone in their batches.
+pub trait Executor {
+    /// Returns errors of failed *System*s with their names, which are handed to the error handler of the `Schedule`.
+    fn execute(
+        &mut self,
+        storage: &mut ComponentStorage,
+        batch: &[&dyn Invokable],
+    ) -> Vec<(&'static str, SystemError)>;
+}
+
+/// `Executor` running *System*s one by one on the current thread.
+pub struct SingleThreaded;
+
+impl Executor for SingleThreaded {
+    fn execute(
+        &mut self,
+        storage: &mut ComponentStorage,
+        batch: &[&dyn Invokable],
+    ) -> Vec<(&'static str, SystemError)> {
+        batch
+            .iter()
+            .filter_map(|system| system.invoke(storage).err().map(|e| (system.name(), e)))
+            .collect()
+    }
+}
+
+/// `Executor` running read-only *System*s of a batch at the same time on scoped threads, see [`ReadOnly`].
+/// The other *System*s of the batch, such as ones that write or touch non-send resources,
+/// run one by one on the calling thread afterwards like `SingleThreaded`.
+pub struct ScopedThreads;
+
+impl Executor for ScopedThreads {
+    fn execute(
+        &mut self,
+        storage: &mut ComponentStorage,
+        batch: &[&dyn Invokable],
+    ) -> Vec<(&'static str, SystemError)> {
+        let (parallel, rest): (Vec<&dyn Invokable>, Vec<&dyn Invokable>) = (batch.iter())
+            .partition(|system| system.is_read_only() && !system.is_main_thread_only());
+        if parallel.len() < 2 {
+            return SingleThreaded.execute(storage, batch);
+        }
+
+        storage.prepare_shared_queries();
+        let mut states: Vec<_> = (parallel.iter())
+            .map(|system| storage.take_query_state(system.id()))
+            .collect();
+        let shared = &*storage;
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (parallel.iter().zip(states.iter_mut()))
+                .map(|(system, state)| scope.spawn(move || system.invoke_shared(shared, state)))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        for (system, state) in parallel.iter().zip(states) {
+            storage.put_query_state(state);
+            storage.finish_system(system.id());
+        }
+        let mut errors: Vec<_> = (parallel.iter().zip(results))
+            .filter_map(|(system, result)| result.err().map(|e| (system.name(), e)))
+            .collect();
+
+        // The rest run one by one on this thread, which is the main thread of non-send resources.
+        errors.extend(SingleThreaded.execute(storage, &rest));
+        errors
+    }
+}
+
+/// `Executor` like `ScopedThreads`, but running read-only *System*s of a batch on web workers
+/// that share the wasm memory, see the `wasm-worker` crate.
+/// It waits for the workers by spinning because `Atomics.wait` isn't allowed on the browser's main thread,
+/// so that it works there too, though it's better to run the `Schedule` on a worker.
+/// Build with shared memory, e.g. `-C target-feature=+atomics,+bulk-memory`, so that workers see the storage.
+#[cfg(target_arch = "wasm32")]
+pub struct WebWorkers {
+    workers: Vec<wasm_worker::Worker>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl WebWorkers {
+    /// Spawns `count` web workers, which are terminated when this is dropped.
+    pub fn new(count: usize) -> Result<Self, wasm_bindgen::JsValue> {
+        let workers = (0..count.max(1))
+            .map(|id| wasm_worker::Worker::spawn(&format!("ecs-worker-{}", id), id))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { workers })
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Executor for WebWorkers {
+    fn execute(
+        &mut self,
+        storage: &mut ComponentStorage,
+        batch: &[&dyn Invokable],
+    ) -> Vec<(&'static str, SystemError)> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (parallel, rest): (Vec<&dyn Invokable>, Vec<&dyn Invokable>) = (batch.iter())
+            .partition(|system| system.is_read_only() && !system.is_main_thread_only());
+        if parallel.len() < 2 {
+            return SingleThreaded.execute(storage, batch);
+        }
+
+        storage.prepare_shared_queries();
+        let mut states: Vec<_> = (parallel.iter())
+            .map(|system| storage.take_query_state(system.id()))
+            .collect();
+        let shared = &*storage;
+        let results: Vec<Mutex<Option<Result<(), SystemError>>>> =
+            parallel.iter().map(|_| Mutex::new(None)).collect();
+        let remaining = AtomicUsize::new(parallel.len());
+        let jobs = parallel.iter().zip(states.iter_mut()).zip(results.iter());
+        for (i, ((system, state), result)) in jobs.enumerate() {
+            let remaining = &remaining;
+            let job = move |_worker_id| {
+                *result.lock().unwrap() = Some(system.invoke_shared(shared, state));
+                remaining.fetch_sub(1, Ordering::Release);
+            };
+            // Jobs borrow the storage, which is fine because this doesn't return until all of them are done.
+            let worker = &self.workers[i % self.workers.len()];
+            if let Err(e) = worker.run_one_shot(job) {
+                let e = format!("failed to send to a web worker: {:?}", e);
+                *result.lock().unwrap() = Some(Err(e.into()));
+                remaining.fetch_sub(1, Ordering::Release);
+            }
+        }
+        while remaining.load(Ordering::Acquire) > 0 {
+            std::hint::spin_loop();
+        }
+
+        for (system, state) in parallel.iter().zip(states) {
+            storage.put_query_state(state);
+            storage.finish_system(system.id());
+        }
+        let mut errors: Vec<_> = (parallel.iter().zip(results))
+            .filter_map(|(system, result)| {
+                let result = result.into_inner().unwrap().expect("every job is done");
+                result.err().map(|e| (system.name(), e))
+            })
+            .collect();
+
+        // The rest run one by one on this thread, which is the main thread of non-send resources.
+        errors.extend(SingleThreaded.execute(storage, &rest));
+        errors
+    }
+}
+
+/// Change to the *System*s queued until the next build, see [`Schedule::remove`].
+enum Edit {
+    Remove(SystemHandle),
+    InsertAfter(SystemHandle, SystemEntry),
+}
+
+/// A list of *System*s grouped by `Stage`.
+/// All systems in a stage complete before the next stage starts.
+/// It doesn't belong to any storage, so that the same `Schedule` can run on multiple storages in turn.
+pub struct Schedule {
+    stages: Vec<(Stage, StageSystems)>,
+    // Configurations of system sets.
+    sets: HashMap<Label, SetConfig>,
+    // *System*s having any of these labels don't run.
+    disabled: HashSet<Label>,
+    // Called with errors of fallible *System*s.
+    error_handler: ErrorHandler,
+    // Times *System*s if it's set.
+    profiler: Option<Clock>,
+    // Orders *System*s accessing the same data, see `Schedule::set_access_order`.
+    access_order: AccessOrder,
+    // Id of the next `SystemHandle`.
+    next_handle: u64,
+    // Changes applied at the next build.
+    edits: Vec<Edit>,
+    is_built: bool,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self {
+            stages: Stage::ALL
+                .iter()
+                .map(|stage| (*stage, StageSystems::default()))
+                .collect(),
+            sets: HashMap::new(),
+            disabled: HashSet::new(),
+            error_handler: panic_on_error,
+            profiler: None,
+            access_order: AccessOrder::default(),
+            next_handle: 0,
+            edits: Vec::new(),
+            is_built: true,
+        }
+    }
+
+    /// Appends the `system` to the `stage`.
+    /// It can be a *System* type or a function taking *System* parameters, see [`IntoSystem`].
+    /// Systems in the same stage are executed in insertion order unless they have ordering constraints,
+    /// or one writes what another reads, see [`AccessOrder`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `system` has conflicting queries. See [`Self::try_add_system_with`].
+    pub fn add_system<M>(&mut self, stage: Stage, system: impl IntoSystem<M>) -> &mut Self {
+        self.add_system_with(stage, system, SystemConfig::new())
+    }
+
+    /// Appends the `system` to the `stage` with its configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `system` has conflicting queries. See [`Self::try_add_system_with`].
+    pub fn add_system_with<M>(
+        &mut self,
+        stage: Stage,
+        system: impl IntoSystem<M>,
+        config: SystemConfig,
+    ) -> &mut Self {
+        if let Err(e) = self.try_add_system_with(stage, system, config) {
+            panic!("{}", e);
+        }
+        self
+    }
+
+    /// Appends the `system` to the `stage` with its configuration.
+    /// It fails if the `Mut` of the `system` accesses a `Component` that the `Ref` or another query of the `Mut` accesses too.
+    pub fn try_add_system_with<M>(
+        &mut self,
+        stage: Stage,
+        system: impl IntoSystem<M>,
+        config: SystemConfig,
+    ) -> Result<&mut Self, ScheduleError> {
+        let entry = self.new_entry(stage, system, config)?;
+        self.stage_mut(stage).entries.push(entry);
+        self.is_built = false;
+        Ok(self)
+    }
+
+    /// Appends the `system` to the `stage` like [`Self::add_system_with`], and returns its handle.
+    /// The handle lets us remove the `system` or insert another one next to it later, e.g. when a mod is loaded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `system` has conflicting queries. See [`Self::try_add_system_with`].
+    pub fn register<M>(
+        &mut self,
+        stage: Stage,
+        system: impl IntoSystem<M>,
+        config: SystemConfig,
+    ) -> SystemHandle {
+        let entry = self.new_entry(stage, system, config).unwrap_or_else(|e| panic!("{}", e));
+        let handle = entry.handle;
+        self.stage_mut(stage).entries.push(entry);
+        self.is_built = false;
+        handle
+    }
+
+    /// Removes the *System* of the `handle` at the next build, which `run` does at the start of the next frame.
+    /// So that it's fine to call this between frames of a running application.
+    /// Nothing happens if it has been removed already.
+    pub fn remove(&mut self, handle: SystemHandle) -> &mut Self {
+        self.edits.push(Edit::Remove(handle));
+        self.is_built = false;
+        self
+    }
+
+    /// Inserts the `system` right after the *System* of the `handle` at the next build like [`Self::remove`].
+    /// The `system` runs after it as well, and it's appended to the stage if the *System* has been removed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `system` has conflicting queries. See [`Self::try_add_system_with`].
+    pub fn insert_after<M>(&mut self, handle: SystemHandle, system: impl IntoSystem<M>) -> SystemHandle {
+        let config = SystemConfig::new().after(handle);
+        let entry = self.new_entry(handle.stage, system, config).unwrap_or_else(|e| panic!("{}", e));
+        let new_handle = entry.handle;
+        self.edits.push(Edit::InsertAfter(handle, entry));
+        self.is_built = false;
+        new_handle
+    }
+
+    fn new_entry<M>(
+        &mut self,
+        stage: Stage,
+        system: impl IntoSystem<M>,
+        config: SystemConfig,
+    ) -> Result<SystemEntry, ScheduleError> {
+        let system = system.into_system();
+        check_access(&system)?;
+        let handle = SystemHandle {
+            stage,
+            id: self.next_handle,
+        };
+        self.next_handle += 1;
+        Ok(SystemEntry {
+            system: Box::new(system),
+            config,
+            handle,
+        })
+    }
+
+    /// Applies changes queued by [`Self::remove`] and [`Self::insert_after`].
+    fn apply_edits(&mut self) {
+        for edit in std::mem::take(&mut self.edits) {
+            match edit {
+                Edit::Remove(handle) => {
+                    (self.stage_mut(handle.stage).entries).retain(|entry| entry.handle != handle);
+                }
+                Edit::InsertAfter(handle, entry) => {
+                    let entries = &mut self.stage_mut(handle.stage).entries;
+                    let pos = (entries.iter().position(|entry| entry.handle == handle))
+                        .map_or(entries.len(), |i| i + 1);
+                    entries.insert(pos, entry);
+                }
+            }
+        }
+    }
+
+    /// Appends the exclusive `system` to the `stage`.
+    /// It runs alone, no other systems run at the same time.
+    pub fn add_exclusive_system(
+        &mut self,
+        stage: Stage,
+        system: impl ExclusiveSystem,
+    ) -> &mut Self {
+        self.add_system(stage, Exclusive(system))
+    }
+
+    /// Configures all systems in the `set` collectively, see [`SystemConfig::in_set`].
+    /// It replaces the previous configuration of the `set`.
+    /// Ordering constraints take effect among systems in the same stage like ones of each system.
+    pub fn configure_set(&mut self, set: impl Into<Label>, config: SetConfig) -> &mut Self {
+        self.sets.insert(set.into(), config);
+        self.is_built = false;
+        self
+    }
+
+    /// Enables or disables systems having the `label`, such as all systems in a set.
+    /// Disabled systems are skipped by `run` until they're enabled again.
+    pub fn set_enabled(&mut self, label: impl Into<Label>, enabled: bool) -> &mut Self {
+        if enabled {
+            self.disabled.remove(&label.into());
+        } else {
+            self.disabled.insert(label.into());
+        }
+        self
+    }
+
+    /// Replaces how errors of fallible *System*s are handled, such as [`log_error`] or [`skip_error`].
+    /// The default one is [`panic_on_error`].
+    pub fn set_error_handler(&mut self, handler: ErrorHandler) -> &mut Self {
+        self.error_handler = handler;
+        self
+    }
+
+    /// Times every *System* with the `clock`, and records them in the `FrameReport` resource.
+    /// Pass `None` to stop profiling.
+    pub fn set_profiler(&mut self, clock: Option<Clock>) -> &mut Self {
+        self.profiler = clock;
+        self
+    }
+
+    /// Replaces how systems in the same stage accessing the same data are ordered.
+    /// The default one is [`AccessOrder::WritersFirst`].
+    pub fn set_access_order(&mut self, order: AccessOrder) -> &mut Self {
+        self.access_order = order;
+        self.is_built = false;
+        self
+    }
+
+    /// Sorts systems in each stage with respect to their ordering constraints.
+    /// `run` does this automatically, but you can call this to handle the error.
+    /// Changes queued by [`Self::remove`] and [`Self::insert_after`] are applied first.
+    pub fn build(&mut self) -> Result<(), ScheduleError> {
+        if !self.is_built {
+            self.apply_edits();
+            for (stage, systems) in self.stages.iter_mut() {
+                systems.build(*stage, &self.sets, self.access_order)?;
+            }
+            self.is_built = true;
+        }
+        Ok(())
+    }
+
+    /// Builds the schedule, and returns pairs of systems in the same stage where either writes what the other accesses
+    /// while no ordering constraint, explicit or inferred, puts one before the other.
+    /// Such pairs run in insertion order, which silently changes when they are added in another order, e.g. by plugins.
+    /// Exclusive systems are not reported because they run alone in insertion order by design.
+    pub fn check_ambiguities(&mut self) -> Result<Vec<Ambiguity>, ScheduleError> {
+        self.build()?;
+        Ok((self.stages.iter())
+            .flat_map(|(stage, systems)| systems.ambiguities(*stage))
+            .collect())
+    }
+
+    /// Runs all stages once.
+    /// This is a frame, so that `storage` drops old events at the end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if ordering constraints make a cycle. See [`Self::build`].
+    pub fn run(&mut self, storage: &mut ComponentStorage) {
+        self.run_systems(storage);
+        storage.update_events();
+    }
+
+    /// Runs all stages once without moving on to the next frame.
+    /// Use this to run the schedule multiple times in a frame, see `Runner`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if ordering constraints make a cycle. See [`Self::build`].
+    pub fn run_systems(&mut self, storage: &mut ComponentStorage) {
+        if let Err(e) = self.build() {
+            panic!("{}", e);
+        }
+        for (_, systems) in self.stages.iter() {
+            for &i in systems.sorted.iter() {
+                let entry = &systems.entries[i];
+                if entry.should_run(storage, &self.sets, &self.disabled) {
+                    let result = match self.profiler {
+                        Some(clock) => {
+                            let start = clock();
+                            let result = entry.system.invoke(storage);
+                            storage.record_timing(entry.system.name(), clock().saturating_sub(start));
+                            result
+                        }
+                        None => entry.system.invoke(storage),
+                    };
+                    if let Err(e) = result {
+                        (self.error_handler)(entry.system.name(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs all stages once like `run`, but hands batches of *System*s to the `executor`.
+    /// Run conditions are evaluated right before each batch is handed over.
+    ///
+    /// # Panics
+    ///
+    /// Panics if ordering constraints make a cycle. See [`Self::build`].
+    pub fn run_with(&mut self, storage: &mut ComponentStorage, executor: &mut impl Executor) {
+        if let Err(e) = self.build() {
+            panic!("{}", e);
+        }
+        for (_, systems) in self.stages.iter() {
+            for batch in systems.batches.iter() {
+                let batch = batch
+                    .iter()
+                    .map(|&i| &systems.entries[i])
+                    .filter(|entry| entry.should_run(storage, &self.sets, &self.disabled))
+                    .map(|entry| entry.system.as_ref())
+                    .collect::<Vec<_>>();
+                if batch.is_empty() {
+                    continue;
+                }
+                let timings = Mutex::new(Vec::new());
+                let timed = match self.profiler {
+                    Some(clock) => (batch.iter())
+                        .map(|system| Timed {
+                            system: *system,
+                            clock,
+                            out: &timings,
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                let batch = if timed.is_empty() {
+                    batch
+                } else {
+                    timed.iter().map(|system| system as &dyn Invokable).collect()
+                };
+                for (system, e) in executor.execute(storage, &batch) {
+                    (self.error_handler)(system, e);
+                }
+                for (system, elapsed) in timings.into_inner().unwrap() {
+                    storage.record_timing(system, elapsed);
+                }
+            }
+        }
+        storage.update_events();
+    }
+
+    fn stage_mut(&mut self, stage: Stage) -> &mut StageSystems {
+        // `stages` always has all stages, see `Schedule::new`.
+        let (_, systems) = self.stages.iter_mut().find(|(s, _)| *s == stage).unwrap();
+        systems
+    }
+}
+
+/// Shows *System*s of each stage in execution order with their accesses, e.g.
+///
+/// ```text
+/// Update
+///   0: SysA (reads: CompB; writes: CompA)
+///   0: SysB (reads: CompB; writes: -)
+///   1: SysC (reads: -; writes: CompB) [disabled]
+/// ```
+///
+/// Numbers are batches, *System*s in the same batch can run at the same time.
+/// Before the schedule is built, they're in insertion order without batches.
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (stage, systems) in self.stages.iter() {
+            writeln!(f, "{:?}", stage)?;
+            let batches = if self.is_built {
+                systems.batches.clone()
+            } else {
+                writeln!(f, "  (not built yet)")?;
+                (0..systems.entries.len()).map(|i| vec![i]).collect()
+            };
+            for (b, batch) in batches.iter().enumerate() {
+                for entry in batch.iter().map(|&i| &systems.entries[i]) {
+                    let batch = if self.is_built { format!("{}: ", b) } else { String::new() };
+                    write!(
+                        f,
+                        "  {}{} (reads: {}; writes: {})",
+                        batch,
+                        entry.system.name(),
+                        access_names(&entry.system.reads()),
+                        access_names(&entry.system.writes())
+                    )?;
+                    if self.disabled.iter().any(|label| entry.has_label(label)) {
+                        write!(f, " [disabled]")?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
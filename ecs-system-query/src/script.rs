@@ -0,0 +1,90 @@
+use super::{AccessId, ComponentId, ComponentStorage, Components, DynamicChunk, Invokable, SystemError};
+use std::any::TypeId;
+
+/// Type erased body of a [`DynamicSystem`].
+/// It receives columns of the accesses grouped by table, see [`ComponentStorage::query_columns`].
+pub type DynamicRunFn =
+    Box<dyn Fn(&mut [DynamicChunk<'_>]) -> Result<(), SystemError> + Send + Sync>;
+
+/// Object-safe *System* built at run-time, such as one defined by a Lua or JS script.
+/// It declares accesses as `ComponentId`s instead of types, so that it reaches dynamic `Component`s too.
+/// And the `Schedule` orders and parallelizes it along with native *System*s by the same accesses.
+///
+/// All `DynamicSystem`s have the same [`Invokable::id`], so that refer to one by its [`SystemHandle`]
+/// rather than by its type.
+///
+/// [`SystemHandle`]: super::SystemHandle
+pub struct DynamicSystem {
+    name: &'static str,
+    reads: Vec<ComponentId>,
+    writes: Vec<ComponentId>,
+    read_access: Vec<AccessId>,
+    write_access: Vec<AccessId>,
+    run: DynamicRunFn,
+}
+
+impl DynamicSystem {
+    /// Makes a *System* reading the `reads` and writing the `writes` registered in the `components`.
+    /// The `run` gets a chunk per table having all of them, where columns are in the same order as the ids.
+    /// Names are leaked because `AccessId`s keep `&'static str`, which is fine for *System*s made at load time.
+    pub fn new(
+        name: &str,
+        components: &Components,
+        reads: Vec<ComponentId>,
+        writes: Vec<ComponentId>,
+        run: impl Fn(&mut [DynamicChunk<'_>]) -> Result<(), SystemError> + Send + Sync + 'static,
+    ) -> Self {
+        let access = |ids: &[ComponentId]| -> Vec<AccessId> {
+            (ids.iter())
+                .map(|id| {
+                    let info = components.info(*id);
+                    let name = leak(&info.name);
+                    match info.type_id {
+                        Some(ty) => AccessId {
+                            id: ty,
+                            name,
+                            filter: None,
+                            dynamic: None,
+                        },
+                        None => AccessId::dynamic(*id, name),
+                    }
+                })
+                .collect()
+        };
+        Self {
+            name: leak(name),
+            read_access: access(&reads),
+            write_access: access(&writes),
+            reads,
+            writes,
+            run: Box::new(run),
+        }
+    }
+}
+
+fn leak(name: &str) -> &'static str {
+    Box::leak(name.to_owned().into_boxed_str())
+}
+
+impl Invokable for DynamicSystem {
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError> {
+        let mut chunks = storage.query_columns(&self.reads, &self.writes);
+        (self.run)(&mut chunks)
+    }
+
+    fn reads(&self) -> Vec<AccessId> {
+        self.read_access.clone()
+    }
+
+    fn writes(&self) -> Vec<AccessId> {
+        self.write_access.clone()
+    }
+
+    fn id(&self) -> TypeId {
+        TypeId::of::<Self>()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
@@ -0,0 +1,120 @@
+use super::{ComponentStorage, Schedule};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Value of a state machine such as `enum GameState { Menu, Playing }`.
+pub trait StateValue: Debug + Clone + Eq + Hash + Send + Sync + 'static {}
+
+impl<T: Debug + Clone + Eq + Hash + Send + Sync + 'static> StateValue for T {}
+
+/// Resource having the current state of the state machine `S`.
+/// A transition requested by [`States::set`] takes place at the start of the next frame, see [`StateTransitions`].
+/// So that every *System* in a frame sees the same state.
+#[derive(Debug, Clone)]
+pub struct States<S> {
+    current: S,
+    next: Option<S>,
+}
+
+impl<S: StateValue> States<S> {
+    pub fn new(initial: S) -> Self {
+        Self {
+            current: initial,
+            next: None,
+        }
+    }
+
+    pub fn current(&self) -> &S {
+        &self.current
+    }
+
+    /// Requests a transition to the `next` state, which replaces the previous request.
+    pub fn set(&mut self, next: S) {
+        self.next = Some(next);
+    }
+}
+
+/// Run condition passing while the state machine `S` is in the `state`, see [`SystemConfig::run_if`].
+/// Give it to a set by [`SetConfig::run_if`] to switch a whole group of *System*s.
+///
+/// [`SystemConfig::run_if`]: super::SystemConfig::run_if
+/// [`SetConfig::run_if`]: super::SetConfig::run_if
+pub fn in_state<S: StateValue>(state: S) -> impl Fn(&ComponentStorage) -> bool {
+    move |storage| {
+        (storage.get_resource::<States<S>>()).is_some_and(|states| *states.current() == state)
+    }
+}
+
+/// `OnEnter` and `OnExit` schedules of the state machine `S`, which run when the state changes.
+/// `App` applies transitions at the start of every frame, see [`App::add_state`](super::App::add_state).
+/// Without the `App`, call [`Self::apply`] at the start of every frame.
+pub struct StateTransitions<S> {
+    on_enter: HashMap<S, Schedule>,
+    on_exit: HashMap<S, Schedule>,
+    // The state whose `OnEnter` has run last, `None` before the first transition.
+    entered: Option<S>,
+}
+
+impl<S: StateValue> StateTransitions<S> {
+    pub fn new() -> Self {
+        Self {
+            on_enter: HashMap::new(),
+            on_exit: HashMap::new(),
+            entered: None,
+        }
+    }
+
+    /// Schedule running when the state machine enters the `state`, including the initial state.
+    pub fn on_enter_mut(&mut self, state: S) -> &mut Schedule {
+        self.on_enter.entry(state).or_insert_with(Schedule::new)
+    }
+
+    /// Schedule running when the state machine leaves the `state`.
+    pub fn on_exit_mut(&mut self, state: S) -> &mut Schedule {
+        self.on_exit.entry(state).or_insert_with(Schedule::new)
+    }
+
+    /// Takes the requested transition, and runs `OnExit` of the previous state and `OnEnter` of the new one.
+    /// `OnEnter` of the initial state runs at the first call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `States<S>` has not been inserted.
+    pub fn apply(&mut self, storage: &mut ComponentStorage) {
+        let states = storage
+            .get_resource_mut::<States<S>>()
+            .unwrap_or_else(|| panic!("resource {} should be inserted", std::any::type_name::<States<S>>()));
+        if let Some(next) = states.next.take() {
+            states.current = next;
+        }
+        let current = states.current.clone();
+        if self.entered.as_ref() == Some(&current) {
+            return;
+        }
+        if let Some(schedule) = (self.entered.take()).and_then(|prev| self.on_exit.get_mut(&prev)) {
+            schedule.run_systems(storage);
+        }
+        if let Some(schedule) = self.on_enter.get_mut(&current) {
+            schedule.run_systems(storage);
+        }
+        self.entered = Some(current);
+    }
+}
+
+/// Type erased `StateTransitions` kept by the `App`.
+pub(crate) trait AnyTransitions {
+    fn apply(&mut self, storage: &mut ComponentStorage);
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<S: StateValue> AnyTransitions for StateTransitions<S> {
+    fn apply(&mut self, storage: &mut ComponentStorage) {
+        StateTransitions::apply(self, storage);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,103 @@
+use super::{Chunk, ComponentStorage};
+use std::fmt;
+
+/// Counters of the storage to monitor memory growth of a long-running application, see [`ComponentStorage::stats`].
+/// Insert `WorldStats::default()` as a resource, then the storage refreshes it at the end of every frame.
+#[derive(Debug, Clone, Default)]
+pub struct WorldStats {
+    pub entities: usize,
+    /// Tables having any entities, empty ones are only counted by `empty_tables`.
+    pub tables: Vec<TableStats>,
+    pub empty_tables: usize,
+    /// Size of all `Component` values, see [`TableStats::bytes`].
+    pub component_bytes: usize,
+    /// Number of *System*s having query caches.
+    pub query_states: usize,
+    /// Number of slices cached by queries, and their size.
+    pub cached_chunks: usize,
+    pub cached_bytes: usize,
+    pub resources: usize,
+}
+
+/// A table in the [`WorldStats`], which is an archetype.
+#[derive(Debug, Clone)]
+pub struct TableStats {
+    /// Index to the `ComponentStorage::tables`.
+    pub index: usize,
+    /// Names of all `Component`s of the table.
+    pub components: Vec<String>,
+    pub entities: usize,
+    /// Size of the values, which doesn't count spare capacity and change ticks.
+    pub bytes: usize,
+}
+
+/// Lists tables from the largest.
+impl fmt::Display for WorldStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entities in {} tables ({} empty tables)",
+            self.entities,
+            self.tables.len(),
+            self.empty_tables
+        )?;
+        writeln!(f, "{} bytes of components", self.component_bytes)?;
+        writeln!(
+            f,
+            "{} chunks ({} bytes) cached by {} systems",
+            self.cached_chunks, self.cached_bytes, self.query_states
+        )?;
+        write!(f, "{} resources", self.resources)?;
+        let mut tables = self.tables.iter().collect::<Vec<_>>();
+        tables.sort_by_key(|table| std::cmp::Reverse(table.bytes));
+        for table in tables {
+            write!(
+                f,
+                "\n  table {} [{}]: {} entities, {} bytes",
+                table.index,
+                table.components.join(", "),
+                table.entities,
+                table.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ComponentStorage {
+    /// Counts entities, tables, memory of `Component`s, and query caches right now.
+    pub fn stats(&self) -> WorldStats {
+        let mut stats = WorldStats::default();
+        for (index, table) in self.tables().iter().enumerate() {
+            if table.len() == 0 {
+                stats.empty_tables += 1;
+                continue;
+            }
+            let infos = table.ids().iter().map(|id| self.components().info(*id));
+            let row_size = infos.clone().map(|info| info.layout.size()).sum::<usize>();
+            let table = TableStats {
+                index,
+                components: infos.map(|info| info.name.clone()).collect(),
+                entities: table.len(),
+                bytes: row_size * table.len(),
+            };
+            stats.entities += table.entities;
+            stats.component_bytes += table.bytes;
+            stats.tables.push(table);
+        }
+        let (query_states, cached_chunks) = self.query_cache_len();
+        stats.query_states = query_states;
+        stats.cached_chunks = cached_chunks;
+        stats.cached_bytes = cached_chunks * std::mem::size_of::<Chunk>();
+        stats.resources = self.resource_count();
+        stats
+    }
+
+    /// Replaces the `WorldStats` resource with the current one if it exists, see [`Self::update_events`].
+    pub(crate) fn refresh_stats(&mut self) {
+        if self.get_resource::<WorldStats>().is_some() {
+            let stats = self.stats();
+            *self.get_resource_mut::<WorldStats>().unwrap() = stats;
+        }
+    }
+}
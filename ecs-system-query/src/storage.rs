@@ -1,96 +1,1668 @@
-use super::{upcast_slice, Filter, QueryIter, QueryIterMut};
+use super::{
+    AnyColumn, Bundle, ChangeFilter, Chunk, Column, ComponentId, Components, DropFn, DynamicQuery, Entity, FastMap, FilterInfo, FilterTypes, QueryError,
+    EntityIter, EntityLocation, EntityMeta, Event, EventIter, EventSender, Events, Filter,
+    OptionIter, OptionIterMut, Persist, PersistError, PersistFns, PersistRegistry, QueryId, QueryIter,
+    QueryIterMut, RawColumn, SavedEntity, Table, Command, CloneFn, RowCloneFn, ColumnAlloc, Global, Query,
+};
+use super::persist;
+use std::alloc::Layout;
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::ops::Range;
 use std::ptr::NonNull;
+use std::sync::Arc;
 
 /// Our `Component`.
-pub trait Component: 'static {}
+/// It should be `Send` and `Sync`, so that the storage can be shared by threads of a parallel executor.
+pub trait Component: Send + Sync + 'static {
+    /// Inserts `Component`s that this one requires to the `entity` if it doesn't have them yet.
+    /// It's called right after this is added to the `entity`, before add hooks.
+    /// Derive it by `#[component(require(..))]` rather than implementing it by hand.
+    fn insert_required(_storage: &mut ComponentStorage, _entity: Entity) {}
+}
+
+/// Result of a query in a `QueryState`.
+#[derive(Default)]
+struct QueryCache {
+    // `TypeId` of the `Filter` with whether it's optional, which the `chunks` are made for.
+    query: Option<(TypeId, bool)>,
+    // `epoch` of the storage when the `chunks` were made, `None` if they've never been made.
+    epoch: Option<u64>,
+    chunks: Vec<Chunk>,
+    // (Table index, Rows) of the `chunks` that have `Component`s, to stamp ticks again.
+    ranges: Vec<(usize, Range<usize>)>,
+}
+
+/// Results of the queries of a *System*, which are reused until tables change.
+/// Queries take their caches in the order they appear in the *System*, so that they don't need to be looked up.
+/// The storage keeps one for each *System*, see [`ComponentStorage::take_query_state`].
+pub struct QueryState {
+    system: TypeId,
+    caches: Vec<QueryCache>,
+    // Index to the `caches` for the next query.
+    next: usize,
+    // Slot for the next query keeping state in the storage, see `next_slot`.
+    slot: usize,
+}
+
+impl QueryState {
+    pub fn new(system: TypeId) -> Self {
+        Self {
+            system,
+            caches: Vec::new(),
+            next: 0,
+            slot: 0,
+        }
+    }
+
+    /// `TypeId` of the *System*.
+    pub fn system(&self) -> TypeId {
+        self.system
+    }
+
+    /// Returns the slot of the next query that keeps state in the storage such as `Local`s and event cursors.
+    /// Like caches, slots are given in the order queries appear in the *System*,
+    /// so that each query gets the same slot every run.
+    pub fn next_slot(&mut self) -> usize {
+        self.slot += 1;
+        self.slot - 1
+    }
+
+    /// Returns the cache of the next query, which is reset if it was made for another query.
+    fn next_cache(&mut self, filter: TypeId, is_optional: bool) -> &mut QueryCache {
+        if self.next == self.caches.len() {
+            self.caches.push(QueryCache::default());
+        }
+        let cache = &mut self.caches[self.next];
+        self.next += 1;
+        let query = Some((filter, is_optional));
+        if cache.query != query {
+            *cache = QueryCache {
+                query,
+                ..QueryCache::default()
+            };
+        }
+        cache
+    }
+}
+
+/// A hook called right before a `Component` is removed from the entity, see [`ComponentStorage::on_remove`].
+pub type RemoveHook = Arc<dyn Fn(&mut ComponentStorage, Entity) + Send + Sync>;
+
+/// A hook called right after a `Component` is added to the entity, see [`ComponentStorage::on_add`].
+pub type AddHook = Arc<dyn Fn(&mut ComponentStorage, Entity) + Send + Sync>;
+
+/// Values of a dynamic `Component` in a table, see [`ComponentStorage::query_raw`].
+pub struct RawChunk<'a> {
+    pub entities: &'a [Entity],
+    pub layout: Layout,
+    pub bytes: &'a mut [u8],
+}
+
+/// Columns of a table selected by a `DynamicQuery`, see [`ComponentStorage::query_dynamic`].
+/// Each pointer points to the first value of the column, and the stride is the size of the type.
+pub struct DynamicChunk<'a> {
+    pub entities: &'a [Entity],
+    /// Columns of the `reads` in the same order.
+    pub reads: Vec<*const u8>,
+    /// Columns of the `writes` in the same order.
+    pub writes: Vec<*mut u8>,
+}
+
+/// Ticks when a `Component` was added and last changed.
+#[derive(Debug, Clone, Copy)]
+pub struct ComponentTicks {
+    pub added: u32,
+    pub changed: u32,
+}
+
+impl ComponentTicks {
+    pub fn new(tick: u32) -> Self {
+        Self {
+            added: tick,
+            changed: tick,
+        }
+    }
+
+    /// Determines whether the `Component` has been added or changed since the `last_run` of a *System* running at the `this_run`.
+    /// Everything is new to the *System* that has never run, whose `last_run` is `None`.
+    #[inline]
+    pub fn is_match(&self, filter: ChangeFilter, last_run: Option<u32>, this_run: u32) -> bool {
+        // Compares distances from the `this_run` so that it keeps working after the tick wraps around.
+        let is_newer = |tick: u32| {
+            last_run.is_none_or(|last_run| this_run.wrapping_sub(tick) < this_run.wrapping_sub(last_run))
+        };
+        match filter {
+            ChangeFilter::None => true,
+            ChangeFilter::Added => is_newer(self.added),
+            ChangeFilter::Changed => is_newer(self.changed),
+        }
+    }
+}
+
+/// Copy of entities and their `Component`s at some point, see [`ComponentStorage::snapshot`].
+pub struct Snapshot {
+    entities: Vec<EntityMeta>,
+    free: Vec<u32>,
+    tables: Vec<Table>,
+    table_index: FastMap<Vec<ComponentId>, usize>,
+    tick: u32,
+    last_runs: FastMap<TypeId, u32>,
+}
 
 /// A super simple `Component`s storage.
+/// `Component`s are stored in `Table`s according to the set of `Component` types of each entity.
+/// Everything a *System* leaves behind, such as query caches, `Local`s, and event cursors, is kept here.
+/// So that we can have multiple independent storages, e.g. a server world and a client prediction world.
 pub struct ComponentStorage {
-    data: HashMap<TypeId, Box<dyn Any>>,
-    // `query_buffer` keeps the results of queries.
-    query_buffer: HashMap<(TypeId, (TypeId, TypeId)), Vec<NonNull<[()]>>>,
+    // Entity metadata indexed by `Entity::index`.
+    entities: Vec<EntityMeta>,
+    // Indices of despawned entities to be reused, whose generations have been increased.
+    free: Vec<u32>,
+    // The first table is for entities without any `Component`s.
+    tables: Vec<Table>,
+    // Registered `Component` types.
+    components: Components,
+    // Sorted `ComponentId`s -> index to the `tables`.
+    table_index: FastMap<Vec<ComponentId>, usize>,
+    // Current tick, which is increased whenever a *System* finishes.
+    tick: u32,
+    // Ticks when *System*s last ran, which are compared with `ComponentTicks` for change detection.
+    last_runs: FastMap<TypeId, u32>,
+    // Increased whenever columns may be reallocated or rows may move, which makes cached pointers dangle.
+    epoch: u64,
+    // Results of queries of each *System*.
+    query_states: FastMap<TypeId, QueryState>,
+    // Unique data that don't belong to any entities such as `Events`.
+    resources: FastMap<TypeId, Box<dyn Any + Send + Sync>>,
+    // Persistable `Component` types.
+    persist: PersistRegistry,
+    // Functions cloning columns of `Component` types registered by `register_snapshot`.
+    clone_fns: HashMap<ComponentId, CloneFn>,
+    // Functions cloning `Component`s for `clone_entity`, `None` for types opted out by `skip_clone`.
+    row_clone_fns: HashMap<ComponentId, Option<RowCloneFn>>,
+    // Functions updating registered `Events` every frame.
+    event_updaters: Vec<fn(&mut Self)>,
+    // Read cursors of `EventReader`s and `RemovedComponents`.
+    event_cursors: FastMap<QueryId, usize>,
+    // State of `Local`s.
+    locals: FastMap<QueryId, Box<dyn Any + Send + Sync>>,
+    // Hooks called when `Component`s are removed.
+    remove_hooks: HashMap<ComponentId, Vec<RemoveHook>>,
+    // Hooks called when `Component`s are added.
+    add_hooks: HashMap<ComponentId, Vec<AddHook>>,
+    // Entities whose `Component`s have been removed, which are updated every frame like `Events`.
+    removed: HashMap<ComponentId, Events<Entity>>,
+    // (Entity, Component, Whether hooks have been called) being removed.
+    removing: Vec<(Entity, ComponentId, bool)>,
+    // Commands queued by *System*s, see `Commands`.
+    commands: Vec<Command>,
+    // Allocator of columns of dynamic `Component`s, see `set_column_alloc`.
+    column_alloc: ColumnAlloc,
+    // Whether queries visit tables and rows in a stable order, see `set_deterministic`.
+    deterministic: bool,
+    // Indices to the `tables` sorted by names of their `Component`s, which is valid only in the deterministic mode.
+    table_order: Vec<usize>,
+    // Epoch when the `table_order` and rows were sorted.
+    ordered_epoch: Option<u64>,
 }
 
 impl ComponentStorage {
     pub fn new() -> Self {
         Self {
-            data: HashMap::new(),
-            query_buffer: HashMap::new(),
+            entities: Vec::new(),
+            free: Vec::new(),
+            tables: vec![Table::new(Vec::new(), HashMap::new())],
+            components: Components::new(),
+            table_index: FastMap::from_iter([(Vec::new(), 0)]),
+            tick: 0,
+            last_runs: FastMap::default(),
+            epoch: 0,
+            query_states: FastMap::default(),
+            resources: FastMap::default(),
+            persist: PersistRegistry::new(),
+            clone_fns: HashMap::new(),
+            row_clone_fns: HashMap::new(),
+            event_updaters: Vec::new(),
+            event_cursors: FastMap::default(),
+            locals: FastMap::default(),
+            remove_hooks: HashMap::new(),
+            add_hooks: HashMap::new(),
+            removed: HashMap::new(),
+            removing: Vec::new(),
+            commands: Vec::new(),
+            column_alloc: Arc::new(Global),
+            deterministic: false,
+            table_order: Vec::new(),
+            ordered_epoch: None,
+        }
+    }
+
+    /// Makes a new entity with the `bundle`.
+    /// Pass `()` to make an entity without any `Component`s.
+    pub fn spawn(&mut self, bundle: impl Bundle) -> Entity {
+        let entity = self.alloc_entity();
+        let row = self.tables[0].push_entity(entity);
+        self.epoch += 1;
+        self.entities[entity.index() as usize].location = Some(EntityLocation { table: 0, row });
+        self.insert_bundle(entity, bundle);
+        entity
+    }
+
+    /// Makes a new entity without location, reusing the index of a despawned entity if any.
+    /// The reused index has a new generation, so that handles to the despawned entity stay invalid.
+    fn alloc_entity(&mut self) -> Entity {
+        if let Some(index) = self.free.pop() {
+            return Entity::new(index, self.entities[index as usize].generation);
+        }
+        self.entities.push(EntityMeta {
+            generation: 0,
+            location: None,
+        });
+        Entity::new(self.entities.len() as u32 - 1, 0)
+    }
+
+    /// Makes new entities with the bundles of the `iter`, and returns them in the same order.
+    /// Unlike calling `spawn` in a loop, it finds out the table only once and reserves memory in advance.
+    /// Add hooks are called after all entities are spawned.
+    pub fn spawn_batch<B: Bundle>(&mut self, iter: impl IntoIterator<Item = B>) -> Vec<Entity> {
+        let ids = B::register(&mut self.components);
+        let mut sorted = ids.clone();
+        sorted.sort();
+        sorted.dedup();
+        let table = match self.table_index.get(&sorted) {
+            Some(table) => *table,
+            None => {
+                let columns = ids.iter().copied().zip(B::new_columns()).collect();
+                self.add_table(sorted.clone(), columns)
+            }
+        };
+
+        let iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
+        self.tables[table].reserve(lower);
+        self.entities.reserve(lower);
+        self.epoch += 1;
+        let tick = self.tick;
+        let mut spawned = Vec::with_capacity(lower);
+        for bundle in iter {
+            let entity = self.alloc_entity();
+            let row = self.tables[table].push_entity(entity);
+            bundle.put(&mut self.tables[table], &ids, row, tick);
+            self.entities[entity.index() as usize].location = Some(EntityLocation { table, row });
+            spawned.push(entity);
+        }
+        for entity in spawned.iter() {
+            self.call_add_hooks(*entity, &sorted);
+        }
+        spawned
+    }
+
+    /// Returns where the `entity`'s `Component`s are.
+    pub fn location(&self, entity: Entity) -> Option<EntityLocation> {
+        self.entities
+            .get(entity.index() as usize)
+            .filter(|meta| meta.generation == entity.generation())
+            .and_then(|meta| meta.location)
+    }
+
+    /// Removes the `entity` with all its `Component`s.
+    /// Remove hooks of the `Component`s are called first.
+    /// The last row of the table takes the place of the removed row, so that tables stay dense without holes.
+    /// And the index of the `entity` is reused by the next spawn.
+    /// Returns false if the `entity` doesn't exist.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        let Some(loc) = self.location(entity) else {
+            return false;
+        };
+        let ids = self.tables[loc.table].ids().to_vec();
+        self.call_remove_hooks(entity, &ids);
+
+        // Hooks may have changed or despawned the entity.
+        let Some(loc) = self.location(entity) else {
+            return true;
+        };
+        self.epoch += 1;
+        for id in self.tables[loc.table].ids() {
+            self.removed.entry(*id).or_insert_with(Events::new).send(entity);
+        }
+        let moved = self.tables[loc.table].remove_row(loc.row);
+        let meta = &mut self.entities[entity.index() as usize];
+        meta.location = None;
+        meta.generation = meta.generation.wrapping_add(1);
+        self.free.push(entity.index());
+        if let Some(moved) = moved {
+            self.set_row(moved, loc.row);
+        }
+        true
+    }
+
+    /// Maintenance pass after heavy removals.
+    /// It drops empty tables, sorts rows of each table by entity index, and releases unused memory.
+    /// Locations of entities are fixed up, and cached query results are made again.
+    pub fn compact(&mut self) {
+        let tables = std::mem::take(&mut self.tables);
+        self.table_index.clear();
+        for (i, mut table) in tables.into_iter().enumerate() {
+            // The table without any `Component`s stays at 0, see `spawn`.
+            if i != 0 && table.len() == 0 {
+                continue;
+            }
+            table.sort_by_entity();
+            table.shrink_to_fit();
+            let index = self.tables.len();
+            for (row, entity) in table.entities().iter().enumerate() {
+                self.entities[entity.index() as usize].location =
+                    Some(EntityLocation { table: index, row });
+            }
+            self.table_index.insert(table.ids().to_vec(), index);
+            self.tables.push(table);
+        }
+        self.epoch += 1;
+        self.query_states.clear();
+    }
+
+    /// Moves all entities and their `Component`s of the `other` into this storage.
+    /// Entities get new identities, and returns the map from old ones to new ones.
+    /// Note that entities kept in `Component`s, if any, are not remapped, use the returned map to fix them.
+    /// Resources, events, and hooks of the `other` are dropped.
+    pub fn append(&mut self, mut other: ComponentStorage) -> HashMap<Entity, Entity> {
+        let id_map = self.components.merge(&other.components);
+        let mut entity_map = HashMap::new();
+        for (index, meta) in other.entities.iter().enumerate() {
+            if meta.location.is_some() {
+                let entity = self.alloc_entity();
+                entity_map.insert(Entity::new(index as u32, meta.generation), entity);
+            }
+        }
+        self.epoch += 1;
+
+        let tick = self.tick;
+        let mut added = Vec::new();
+        for table in other.tables.iter_mut().filter(|table| table.len() > 0) {
+            let mut ids = table.ids().iter().map(|id| id_map[id.index()]).collect::<Vec<_>>();
+            ids.sort();
+            let dst = self.table_index.get(&ids).copied().unwrap_or_else(|| {
+                let columns = table
+                    .empty_columns()
+                    .into_iter()
+                    .map(|(id, col)| (id_map[id.index()], col))
+                    .collect();
+                self.add_table(ids, columns)
+            });
+            let start = self.tables[dst].len();
+            self.tables[dst].append(table, |id| id_map[id.index()], |e| entity_map[&e], tick);
+
+            // Fixes up locations.
+            for row in start..self.tables[dst].len() {
+                let entity = self.tables[dst].entities()[row];
+                self.entities[entity.index() as usize].location =
+                    Some(EntityLocation { table: dst, row });
+                added.push((entity, dst));
+            }
+        }
+        for (entity, table) in added {
+            let ids = self.tables[table].ids().to_vec();
+            self.call_add_hooks(entity, &ids);
+        }
+        entity_map
+    }
+
+    /// Takes the `Component` `T` out of the `entity`.
+    /// The remove hooks of the `T` are called first.
+    /// The `entity` moves to another table that doesn't have the `T`.
+    /// Returns `None` if the `entity` doesn't have the `T`, or hooks have removed it already.
+    pub fn remove<T: Component>(&mut self, entity: Entity) -> Option<T> {
+        let id = self.component_id::<T>()?;
+        if !self.tables[self.location(entity)?.table].contains(id) {
+            return None;
+        }
+        self.call_remove_hooks(entity, &[id]);
+
+        // Hooks may have changed the entity.
+        let loc = self.location(entity)?;
+        if !self.tables[loc.table].contains(id) {
+            return None;
+        }
+        self.epoch += 1;
+        let mut ids = self.tables[loc.table].ids().to_vec();
+        ids.retain(|x| *x != id);
+        let dst = self.table_index.get(&ids).copied().unwrap_or_else(|| {
+            let mut columns = self.tables[loc.table].empty_columns();
+            columns.remove(&id);
+            self.add_table(ids, columns)
+        });
+        let mut taken = Column::<T>::new();
+        let (src_table, dst_table) = get_two_mut(&mut self.tables, loc.table, dst);
+        let moved = src_table.move_row_with(loc.row, dst_table, |col_id, col| {
+            if col_id == id {
+                col.move_row(loc.row, &mut taken);
+            } else {
+                col.swap_remove(loc.row);
+            }
+        });
+        let new_loc = EntityLocation {
+            table: dst,
+            row: dst_table.len() - 1,
+        };
+
+        // Fixes up locations.
+        self.entities[entity.index() as usize].location = Some(new_loc);
+        if let Some(moved) = moved {
+            self.set_row(moved, loc.row);
+        }
+        self.removed.entry(id).or_insert_with(Events::new).send(entity);
+        taken.pop()
+    }
+
+    /// Registers the `hook` called right before the `Component` `T` is removed from an entity, or the entity is despawned.
+    /// The `Component` is still accessible in the `hook`, so that you can release resources related to it.
+    pub fn on_remove<T: Component>(
+        &mut self,
+        hook: impl Fn(&mut Self, Entity) + Send + Sync + 'static,
+    ) {
+        let id = self.components.register::<T>();
+        self.remove_hooks.entry(id).or_default().push(Arc::new(hook));
+    }
+
+    /// Registers the `hook` called right after the `Component` `T` is added to an entity.
+    /// It's not called when the `Component` is replaced.
+    /// Use this to initialize resources related to the `Component`, e.g. GPU buffers of a mesh.
+    pub fn on_add<T: Component>(
+        &mut self,
+        hook: impl Fn(&mut Self, Entity) + Send + Sync + 'static,
+    ) {
+        let id = self.components.register::<T>();
+        self.add_hooks.entry(id).or_default().push(Arc::new(hook));
+    }
+
+    /// Calls add hooks of the `ids` until the `entity` is despawned by hooks.
+    /// `Component`s required by the `ids` are inserted first, so that hooks see them.
+    fn call_add_hooks(&mut self, entity: Entity, ids: &[ComponentId]) {
+        for id in ids {
+            if let Some(insert_required) = self.components.info(*id).required {
+                if self.location(entity).is_none() {
+                    return;
+                }
+                insert_required(self, entity);
+            }
+        }
+        for id in ids {
+            // Hooks can register other hooks, so that we clone them first.
+            let hooks = self.add_hooks.get(id).cloned().unwrap_or_default();
+            for hook in hooks {
+                if self.location(entity).is_none() {
+                    return;
+                }
+                hook(self, entity);
+            }
+        }
+    }
+
+    /// Calls remove hooks of the `ids` once each, even if hooks remove the `Component`s again.
+    fn call_remove_hooks(&mut self, entity: Entity, ids: &[ComponentId]) {
+        let is_removing = |removing: &[(Entity, ComponentId, bool)], id: ComponentId| {
+            removing.iter().position(|(e, i, _)| (*e, *i) == (entity, id))
+        };
+        let claimed = ids
+            .iter()
+            .copied()
+            .filter(|id| is_removing(&self.removing, *id).is_none())
+            .collect::<Vec<_>>();
+        self.removing
+            .extend(claimed.iter().map(|id| (entity, *id, false)));
+
+        for id in ids {
+            // Nested removal from hooks may have called them already.
+            match is_removing(&self.removing, *id) {
+                Some(i) if !self.removing[i].2 => self.removing[i].2 = true,
+                _ => continue,
+            }
+            // Hooks can register other hooks, so that we clone them first.
+            let hooks = self.remove_hooks.get(id).cloned().unwrap_or_default();
+            for hook in hooks {
+                hook(self, entity);
+            }
+        }
+        self.removing
+            .retain(|(e, i, _)| *e != entity || !claimed.contains(i));
+    }
+
+    /// Fixes up the row of the `entity`, which has been moved in the same table.
+    fn set_row(&mut self, entity: Entity, row: usize) {
+        if let Some(loc) = self.entities[entity.index() as usize].location.as_mut() {
+            loc.row = row;
+        }
+    }
+
+    /// Puts the `value` to the `entity`.
+    /// If the `entity` already has the same type of `Component`, it's replaced.
+    /// Otherwise, the `entity` moves to another table that has the `Component` type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `entity` doesn't exist.
+    pub fn insert<T: Component>(&mut self, entity: Entity, value: T) {
+        let id = self.components.register::<T>();
+        let tick = self.tick;
+        let (loc, added) = self.prepare_insert(entity, &[id], |_| Box::new(Column::<T>::new()));
+        let col = self.tables[loc.table].column_mut::<T>(id).unwrap();
+        col.put(loc.row, value, tick);
+        self.call_add_hooks(entity, &added);
+    }
+
+    /// Puts all `Component`s of the `bundle` to the `entity`.
+    /// The `entity` moves to the table having all of them at once.
+    /// `Component`s that the `entity` already has are replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `entity` doesn't exist.
+    pub fn insert_bundle<B: Bundle>(&mut self, entity: Entity, bundle: B) {
+        let ids = B::register(&mut self.components);
+        let mut columns = B::new_columns().into_iter().map(Some).collect::<Vec<_>>();
+        let tick = self.tick;
+        let (loc, added) = self.prepare_insert(entity, &ids, |id| {
+            let i = ids.iter().position(|x| *x == id).unwrap();
+            columns[i].take().unwrap()
+        });
+        bundle.put(&mut self.tables[loc.table], &ids, loc.row, tick);
+        self.call_add_hooks(entity, &added);
+    }
+
+    /// Puts the value of the dynamic `Component` `id` to the `entity`.
+    /// See [`Self::register_dynamic`].
+    ///
+    /// # Safety
+    ///
+    /// `value` must point to a valid value of the `Component` `id`.
+    /// The value is moved into the storage, so that caller must not drop it.
+    /// Plus, it must be safe to send and share between threads like other `Component`s.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `entity` doesn't exist or the `id` is not a dynamic `Component`.
+    pub unsafe fn insert_raw(&mut self, entity: Entity, id: ComponentId, value: *const u8) {
+        let info = self.components.info(id);
+        assert!(info.type_id.is_none(), "{} is not a dynamic component", info.name);
+        let (layout, drop) = (info.layout, info.drop);
+        let tick = self.tick;
+        let alloc = self.column_alloc.clone();
+        let (loc, added) = self.prepare_insert(entity, &[id], |_| {
+            Box::new(RawColumn::new_in(layout, drop, alloc.clone()))
+        });
+        let col = self.tables[loc.table].raw_column_mut(id).unwrap();
+        if loc.row == col.len() {
+            col.push(value, ComponentTicks::new(tick));
+        } else {
+            let ptr = col.get_ptr(loc.row);
+            if let Some(drop) = drop {
+                drop(ptr);
+            }
+            std::ptr::copy_nonoverlapping(value, ptr, layout.size());
+            col.set_changed(loc.row, tick);
+        }
+        self.call_add_hooks(entity, &added);
+    }
+
+    /// Moves the `entity` to the table having all `Component` `ids`.
+    /// `new_column` makes an empty column of an id when a new table is needed.
+    /// Returns the new location and ids that the `entity` didn't have.
+    /// Columns the `entity` didn't have are one row shorter than others, so that caller should push the `Component`s into them.
+    fn prepare_insert(
+        &mut self,
+        entity: Entity,
+        ids: &[ComponentId],
+        mut new_column: impl FnMut(ComponentId) -> Box<dyn AnyColumn>,
+    ) -> (EntityLocation, Vec<ComponentId>) {
+        let loc = self.location(entity).expect("entity should exist");
+        let mut new_ids = ids
+            .iter()
+            .filter(|id| !self.tables[loc.table].contains(**id))
+            .copied()
+            .collect::<Vec<_>>();
+        if new_ids.is_empty() {
+            return (loc, new_ids);
+        }
+        new_ids.sort();
+        new_ids.dedup();
+        self.epoch += 1;
+
+        // Moves to another table.
+        let mut dst_ids = self.tables[loc.table].ids().to_vec();
+        dst_ids.extend_from_slice(&new_ids);
+        dst_ids.sort();
+        let dst = self.table_index.get(&dst_ids).copied().unwrap_or_else(|| {
+            let mut columns = self.tables[loc.table].empty_columns();
+            for id in new_ids.iter() {
+                columns.insert(*id, new_column(*id));
+            }
+            self.add_table(dst_ids, columns)
+        });
+        let (src_table, dst_table) = get_two_mut(&mut self.tables, loc.table, dst);
+        let moved = src_table.move_row(loc.row, dst_table);
+        let new_loc = EntityLocation {
+            table: dst,
+            row: dst_table.len() - 1,
+        };
+
+        // Fixes up locations.
+        self.entities[entity.index() as usize].location = Some(new_loc);
+        if let Some(moved) = moved {
+            self.set_row(moved, loc.row);
+        }
+        (new_loc, new_ids)
+    }
+
+    pub fn get<T: Component>(&self, entity: Entity) -> Option<&T> {
+        let loc = self.location(entity)?;
+        let id = self.component_id::<T>()?;
+        self.tables[loc.table]
+            .column::<T>(id)
+            .map(|col| &col.data()[loc.row])
+    }
+
+    pub fn get_mut<T: Component>(&mut self, entity: Entity) -> Option<&mut T> {
+        let loc = self.location(entity)?;
+        let id = self.component_id::<T>()?;
+        let tick = self.tick;
+        self.tables[loc.table].column_mut::<T>(id).map(|col| {
+            col.set_changed(loc.row..loc.row + 1, tick);
+            &mut col.data_mut()[loc.row]
+        })
+    }
+
+    /// Returns all `Component`s of the `entity` as `Any` with their ids, so that inspectors and serializers can visit them
+    /// without knowing their types. Dynamic `Component`s are left out, see [`Self::get_raw`].
+    /// It's empty if the `entity` doesn't exist.
+    pub fn get_all(&self, entity: Entity) -> impl Iterator<Item = (ComponentId, &dyn Any)> {
+        let loc = self.location(entity);
+        loc.into_iter().flat_map(|loc| self.tables[loc.table].row_any(loc.row))
+    }
+
+    /// Mutable version of [`Self::get_all`], which marks all the `Component`s as changed.
+    pub fn get_all_mut(&mut self, entity: Entity) -> impl Iterator<Item = (ComponentId, &mut dyn Any)> {
+        let tick = self.tick;
+        let loc = self.location(entity);
+        let table = loc.map(|loc| (&mut self.tables[loc.table], loc.row));
+        table.into_iter().flat_map(move |(table, row)| table.row_any_mut(row, tick))
+    }
+
+    /// Returns pointer to the value of the dynamic `Component` `id` of the `entity`.
+    pub fn get_raw(&self, entity: Entity, id: ComponentId) -> Option<*mut u8> {
+        let loc = self.location(entity)?;
+        self.tables[loc.table]
+            .raw_column(id)
+            .map(|col| col.get_ptr(loc.row))
+    }
+
+    /// Returns all values of the dynamic `Component` `id` as raw bytes, grouped by table.
+    /// Each chunk has `entities.len()` values, the size of a value is `layout.size()`.
+    /// Values are regarded as changed.
+    pub fn query_raw(&mut self, id: ComponentId) -> Vec<RawChunk<'_>> {
+        let tick = self.tick;
+        self.tables
+            .iter_mut()
+            .filter(|table| table.len() > 0)
+            .filter_map(|table| {
+                let entities = NonNull::from(table.entities());
+                let col = table.raw_column_mut(id)?;
+                col.ticks.iter_mut().for_each(|t| t.changed = tick);
+                Some(RawChunk {
+                    // Safety: Entities are not changed while `self` is borrowed.
+                    entities: unsafe { entities.as_ref() },
+                    layout: col.layout(),
+                    bytes: col.as_bytes_mut(),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns type erased columns selected by the `query`, grouped by table.
+    /// Values of the `writes` are regarded as changed.
+    /// Use [`Components::info`] to find out the layout of each type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `query` writes a type that it reads or writes elsewhere.
+    pub fn query_dynamic(&mut self, query: &DynamicQuery) -> Vec<DynamicChunk<'_>> {
+        let (reads, writes) = (query.reads(), query.writes());
+        for (i, write) in writes.iter().enumerate() {
+            assert!(
+                !reads.contains(write) && !writes[..i].contains(write),
+                "dynamic query writes a type that it accesses elsewhere"
+            );
+        }
+
+        // `Component`s that have never been inserted are not registered, no tables have them.
+        let to_ids = |types: &[TypeId]| -> Option<Vec<ComponentId>> {
+            types.iter().map(|ty| self.components.id(ty)).collect()
+        };
+        let (Some(reads), Some(writes)) = (to_ids(reads), to_ids(writes)) else {
+            return Vec::new();
+        };
+        self.query_columns(&reads, &writes)
+    }
+
+    /// Returns type erased columns of the `reads` and the `writes` like [`Self::query_dynamic`],
+    /// but it takes `ComponentId`s, so that dynamic `Component`s can be selected as well.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it writes a `Component` that it reads or writes elsewhere.
+    pub fn query_columns(&mut self, reads: &[ComponentId], writes: &[ComponentId]) -> Vec<DynamicChunk<'_>> {
+        for (i, write) in writes.iter().enumerate() {
+            assert!(
+                !reads.contains(write) && !writes[..i].contains(write),
+                "dynamic query writes a type that it accesses elsewhere"
+            );
+        }
+
+        let tick = self.tick;
+        self.tables
+            .iter_mut()
+            .filter(|table| table.len() > 0)
+            .filter(|table| reads.iter().chain(writes.iter()).all(|id| table.contains(*id)))
+            .map(|table| {
+                let entities = NonNull::from(table.entities());
+                let reads = (reads.iter())
+                    .map(|id| table.any_column_mut(*id).unwrap().as_mut_ptr() as *const u8)
+                    .collect();
+                let writes = (writes.iter())
+                    .map(|id| {
+                        let col = table.any_column_mut(*id).unwrap();
+                        col.ticks_mut().iter_mut().for_each(|t| t.changed = tick);
+                        col.as_mut_ptr()
+                    })
+                    .collect();
+                DynamicChunk {
+                    // Safety: Entities are not changed while `self` is borrowed.
+                    entities: unsafe { entities.as_ref() },
+                    reads,
+                    writes,
+                }
+            })
+            .collect()
+    }
+
+    /// Registers the `Component` type `T`.
+    /// You don't need to call this because `insert` does it.
+    pub fn register<T: Component>(&mut self) -> ComponentId {
+        self.components.register::<T>()
+    }
+
+    /// Registers a `Component` type that is not known at compile time, such as ones from scripts.
+    /// Values of the type are raw bytes with the `layout`, and they're dropped by the `drop`.
+    /// Use [`Self::insert_raw`], [`Self::get_raw`], and [`Self::query_raw`] to access them.
+    pub fn register_dynamic(
+        &mut self,
+        name: &str,
+        layout: Layout,
+        drop: Option<DropFn>,
+    ) -> ComponentId {
+        self.components.register_dynamic(name, layout, drop)
+    }
+
+    /// Makes columns of dynamic `Component`s allocate from the `alloc`, such as a [`BumpArena`](super::BumpArena).
+    /// Only columns made after this call are affected.
+    /// Static `Component`s keep using `Vec`, which can't take an allocator on stable Rust.
+    pub fn set_column_alloc(&mut self, alloc: ColumnAlloc) {
+        self.column_alloc = alloc;
+    }
+
+    /// Returns `ComponentId` of the `T` if it's registered.
+    pub fn component_id<T: Component>(&self) -> Option<ComponentId> {
+        self.components.id(&TypeId::of::<T>())
+    }
+
+    pub fn components(&self) -> &Components {
+        &self.components
+    }
+
+    pub fn tables(&self) -> &[Table] {
+        &self.tables
+    }
+
+    fn add_table(
+        &mut self,
+        ids: Vec<ComponentId>,
+        columns: HashMap<ComponentId, Box<dyn AnyColumn>>,
+    ) -> usize {
+        self.tables.push(Table::new(ids.clone(), columns));
+        self.table_index.insert(ids, self.tables.len() - 1);
+        self.tables.len() - 1
+    }
+
+    /// Registers the `Component` type `T` to be saved and loaded with the `name`.
+    /// `name` should be stable across builds.
+    pub fn register_persist<T: Persist>(&mut self, name: &str) {
+        let id = self.components.register::<T>();
+        self.persist.register(id, name, PersistFns::of::<T>());
+    }
+
+    /// Saves all entities and their registered `Component`s as JSON.
+    /// `Component`s that are not registered by [`Self::register_persist`] are skipped.
+    pub fn save(&self) -> String {
+        persist::to_json(&self.saved_entities(|fns, entity| (fns.save)(self, entity)))
+    }
+
+    /// Saves all entities and their registered `Component`s in the compact binary format, see [`persist::to_binary`].
+    /// It suits save games and network state transfer better than [`Self::save`].
+    pub fn save_binary(&self) -> Vec<u8> {
+        persist::to_binary(&self.saved_entities(|fns, entity| (fns.save_bytes)(self, entity)))
+    }
+
+    fn saved_entities<V>(
+        &self,
+        save: impl Fn(PersistFns, Entity) -> Option<V>,
+    ) -> Vec<SavedEntity<V>> {
+        self.entities
+            .iter()
+            .enumerate()
+            .filter_map(|(index, meta)| Some((index, meta.generation, meta.location?)))
+            .map(|(index, generation, loc)| {
+                let entity = Entity::new(index as u32, generation);
+                let components = self.tables[loc.table]
+                    .ids()
+                    .iter()
+                    .filter_map(|id| self.persist.get(*id))
+                    .filter_map(|(name, fns)| save(fns, entity).map(|value| (name.to_owned(), value)))
+                    .collect();
+                SavedEntity {
+                    index: entity.index(),
+                    generation: entity.generation(),
+                    components,
+                }
+            })
+            .collect()
+    }
+
+    /// Loads entities saved by [`Self::save`].
+    /// Entities keep their identities, so that the storage must not have any entities.
+    /// Indices of despawned entities are reused by later spawns,
+    /// and up to [`persist::MAX_FREE_INDICES`] of them are accepted.
+    /// The input is validated before loading, and the storage is left empty if a value fails to load anyway.
+    pub fn load(&mut self, json: &str) -> Result<(), PersistError> {
+        if !self.entities.is_empty() {
+            return Err(PersistError::NotEmpty);
+        }
+        let saved = persist::from_json(json)?;
+        self.load_saved(saved, |fns, storage, entity, text| (fns.load)(storage, entity, text))
+    }
+
+    /// Loads entities saved by [`Self::save_binary`] like [`Self::load`].
+    pub fn load_binary(&mut self, bytes: &[u8]) -> Result<(), PersistError> {
+        if !self.entities.is_empty() {
+            return Err(PersistError::NotEmpty);
+        }
+        let saved = persist::from_binary(bytes)?;
+        self.load_saved(saved, |fns, storage, entity, bytes| (fns.load_bytes)(storage, entity, bytes))
+    }
+
+    fn load_saved<V: AsRef<[u8]>>(
+        &mut self,
+        mut saved: Vec<SavedEntity<V>>,
+        load: impl Fn(PersistFns, &mut Self, Entity, &V) -> bool,
+    ) -> Result<(), PersistError> {
+        saved.sort_by_key(|entity| entity.index);
+        if let Some(pair) = saved.windows(2).find(|pair| pair[0].index == pair[1].index) {
+            return Err(PersistError::Syntax(format!(
+                "duplicate entity index {}",
+                pair[0].index
+            )));
+        }
+        let limit = saved.len() + persist::MAX_FREE_INDICES;
+        if let Some(entity) = saved.last().filter(|entity| entity.index as usize >= limit) {
+            return Err(PersistError::Syntax(format!(
+                "entity index {} is out of range",
+                entity.index
+            )));
+        }
+        let mut names = saved.iter().flat_map(|entity| &entity.components);
+        if let Some((name, _)) = names.find(|(name, _)| self.persist.get_by_name(name).is_none()) {
+            return Err(PersistError::UnknownComponent(name.clone()));
+        }
+
+        let mut loaded = Vec::with_capacity(saved.len());
+        let result = self.load_entities(saved, load, &mut loaded);
+        if result.is_err() {
+            // Puts the storage back to empty, which it was before loading.
+            for entity in loaded {
+                self.despawn(entity);
+            }
+            self.entities.clear();
+            self.free.clear();
+            self.removed.clear();
         }
+        result
     }
-    
-    pub fn insert(&mut self, key: TypeId, value: Box<dyn Any>) {
-        self.data.insert(key, value);
+
+    /// Loads the validated `saved` entities, and records them to the `loaded` as they're spawned.
+    fn load_entities<V: AsRef<[u8]>>(
+        &mut self,
+        saved: Vec<SavedEntity<V>>,
+        load: impl Fn(PersistFns, &mut Self, Entity, &V) -> bool,
+        loaded: &mut Vec<Entity>,
+    ) -> Result<(), PersistError> {
+        for entity in saved {
+            while self.entities.len() < entity.index as usize {
+                self.free.push(self.entities.len() as u32);
+                self.entities.push(EntityMeta {
+                    generation: 0,
+                    location: None,
+                });
+            }
+            let e = Entity::new(entity.index, entity.generation);
+            let row = self.tables[0].push_entity(e);
+            self.epoch += 1;
+            self.entities.push(EntityMeta {
+                generation: entity.generation,
+                location: Some(EntityLocation { table: 0, row }),
+            });
+            loaded.push(e);
+            for (name, value) in entity.components {
+                let fns = self.persist.get_by_name(&name).unwrap();
+                if !load(fns, self, e, &value) {
+                    return Err(PersistError::InvalidValue {
+                        component: name,
+                        text: String::from_utf8_lossy(value.as_ref()).into_owned(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers the `Component` type `T` to be copied by [`Self::snapshot`].
+    pub fn register_snapshot<T: Component + Clone>(&mut self) {
+        let id = self.components.register::<T>();
+        self.clone_fns.insert(id, Column::<T>::clone_any);
+    }
+
+    /// Copies all entities and their `Component`s, which can be put back by [`Self::restore`], e.g. for rollback or undo.
+    /// Static `Component` types must be registered by [`Self::register_snapshot`], and dynamic ones must not need drop.
+    /// Returns `None` if there's a `Component` that can't be copied.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        let tables = (self.tables.iter())
+            .map(|table| {
+                table.try_clone(|id, col| match self.clone_fns.get(&id) {
+                    Some(clone) => Some(clone(col)),
+                    None => col
+                        .as_any()
+                        .downcast_ref::<RawColumn>()?
+                        .try_clone()
+                        .map(|col| Box::new(col) as Box<dyn AnyColumn>),
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Snapshot {
+            entities: self.entities.clone(),
+            free: self.free.clone(),
+            tables,
+            table_index: self.table_index.clone(),
+            tick: self.tick,
+            last_runs: self.last_runs.clone(),
+        })
+    }
+
+    /// Registers the `Component` type `T` to be cloned by [`Self::clone_entity`].
+    pub fn register_clone<T: Component + Clone>(&mut self) {
+        let id = self.components.register::<T>();
+        self.row_clone_fns.insert(id, Some(Column::<T>::clone_row));
+    }
+
+    /// Makes [`Self::clone_entity`] leave out the `Component` type `T`, e.g. one that is unique to the original.
+    pub fn skip_clone<T: Component>(&mut self) {
+        let id = self.components.register::<T>();
+        self.row_clone_fns.insert(id, None);
+    }
+
+    /// Makes a new entity having copies of the `src`'s `Component`s, e.g. to instantiate a prefab or duplicate selection.
+    /// Static `Component` types must be registered by [`Self::register_clone`] or opted out by [`Self::skip_clone`],
+    /// and dynamic ones must not need drop.
+    /// The copies are regarded as added, and add hooks are called for them.
+    /// Returns `None` if the `src` doesn't exist or it has a `Component` that can't be copied.
+    pub fn clone_entity(&mut self, src: Entity) -> Option<Entity> {
+        let loc = self.location(src)?;
+        let table = &self.tables[loc.table];
+        let mut columns = Vec::new();
+        for id in table.ids() {
+            let col = table.any_column(*id).unwrap();
+            let clone = match self.row_clone_fns.get(id) {
+                Some(Some(clone)) => clone(col, loc.row),
+                Some(None) => continue,
+                None => Box::new(col.as_any().downcast_ref::<RawColumn>()?.try_clone_row(loc.row)?),
+            };
+            columns.push((*id, clone));
+        }
+
+        let entity = self.spawn(());
+        let ids = columns.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let tick = self.tick;
+        let (loc, added) = self.prepare_insert(entity, &ids, |id| {
+            let (_, col) = columns.iter().find(|(i, _)| *i == id).unwrap();
+            col.empty()
+        });
+        for (id, mut col) in columns {
+            let dst = self.tables[loc.table].any_column_mut(id).unwrap();
+            dst.append(col.as_mut(), tick);
+        }
+        self.call_add_hooks(entity, &added);
+        Some(entity)
+    }
+
+    /// Puts entities and their `Component`s back to the `snapshot`, including the tick for change detection.
+    /// Resources, events, and hooks stay as they are, and hooks are not called for `Component`s dropped here.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.entities = snapshot.entities;
+        self.free = snapshot.free;
+        self.tables = snapshot.tables;
+        self.table_index = snapshot.table_index;
+        self.tick = snapshot.tick;
+        self.last_runs = snapshot.last_runs;
+        self.epoch += 1;
+    }
+
+    pub fn insert_resource<R: Send + Sync + 'static>(&mut self, value: R) {
+        self.resources.insert(TypeId::of::<R>(), Box::new(value));
+    }
+
+    pub fn get_resource<R: 'static>(&self) -> Option<&R> {
+        self.resources
+            .get(&TypeId::of::<R>())
+            .and_then(|r| r.downcast_ref::<R>())
+    }
+
+    pub fn get_resource_mut<R: 'static>(&mut self) -> Option<&mut R> {
+        self.resources
+            .get_mut(&TypeId::of::<R>())
+            .and_then(|r| r.downcast_mut::<R>())
+    }
+
+    /// Registers `Events<T>` as a resource.
+    /// Registered events are updated by [`Self::update_events`].
+    pub fn add_event<T: Event>(&mut self) {
+        if self.get_resource::<Events<T>>().is_none() {
+            self.insert_resource(Events::<T>::new());
+            self.event_updaters.push(|storage| {
+                storage.get_resource_mut::<Events<T>>().unwrap().update();
+            });
+        }
+    }
+
+    /// Drops old events of all registered `Events` and old removals for `RemovedComponents`.
+    /// And timings of *System*s so far become the `FrameReport` of the last frame.
+    /// This should be called once a frame.
+    pub fn update_events(&mut self) {
+        for i in 0..self.event_updaters.len() {
+            (self.event_updaters[i])(self);
+        }
+        for removed in self.removed.values_mut() {
+            removed.update();
+        }
+        self.finish_frame_report();
+        self.refresh_stats();
+    }
+
+    /// Returns the number of query states and chunks cached in them, see [`WorldStats`](super::WorldStats).
+    pub(crate) fn query_cache_len(&self) -> (usize, usize) {
+        let chunks = (self.query_states.values())
+            .flat_map(|state| state.caches.iter())
+            .map(|cache| cache.chunks.len())
+            .sum();
+        (self.query_states.len(), chunks)
+    }
+
+    pub(crate) fn resource_count(&self) -> usize {
+        self.resources.len()
+    }
+
+    /// Returns current tick.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Returns current epoch, which is increased whenever the structure of the storage changes.
+    /// Cached query results are reused while it stays the same.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Moves on to the next tick.
+    /// Changes made after this are newer than ones made before, see [`Self::finish_system`].
+    pub fn increase_tick(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+    }
+
+    /// Returns the tick when the *System* `system` last ran, or `None` if it has never run on this storage.
+    pub fn last_run(&self, system: TypeId) -> Option<u32> {
+        self.last_runs.get(&system).copied()
+    }
+
+    /// Records that the *System* `system` has run at the current tick, and moves on to the next tick.
+    /// Commands queued by the *System* are applied here.
+    /// `Added` and `Changed` in its next run select `Component`s stamped after this run.
+    /// So that changes made by *System*s running later are detected even if they run in the next frame.
+    pub fn finish_system(&mut self, system: TypeId) {
+        self.apply_commands();
+        self.last_runs.insert(system, self.tick);
+        self.increase_tick();
+    }
+
+    /// Takes the `QueryState` of the *System* `system` to run it, which is made at the first run.
+    /// Put it back by [`Self::put_query_state`] after the *System* finishes, so that the next run reuses the results.
+    pub fn take_query_state(&mut self, system: TypeId) -> QueryState {
+        let mut state = (self.query_states.remove(&system))
+            .unwrap_or_else(|| QueryState::new(system));
+        state.next = 0;
+        state.slot = 0;
+        state
+    }
+
+    pub fn put_query_state(&mut self, state: QueryState) {
+        self.query_states.insert(state.system, state);
+    }
+
+    /// Reads the query `Q` outside of *System*s as if the *System* `system` reads it.
+    pub fn query<'a, Q: Query<'a>>(&mut self, system: TypeId) -> Q::Output {
+        let mut state = self.take_query_state(system);
+        let output = Q::query(self, &mut state);
+        self.put_query_state(state);
+        output
+    }
+
+    /// Writes the query `Q` outside of *System*s as if the *System* `system` writes it.
+    pub fn query_mut<'a, Q: Query<'a>>(&mut self, system: TypeId) -> Q::OutputMut {
+        let mut state = self.take_query_state(system);
+        let output = Q::query_mut(self, &mut state);
+        self.put_query_state(state);
+        output
+    }
+
+    /// Makes queries visit tables sorted by names of their `Component`s, and rows sorted by entity index.
+    /// By default, tables are visited in the order they're made, and rows move around by removals.
+    /// Those depend on the history of the storage, e.g. a loaded one has different order from the saved one.
+    /// This mode makes the order depend only on what the storage has, which is required by lockstep simulations.
+    /// It costs sorting tables changed since the last query.
+    /// Dynamic queries, such as `query_dynamic`, are not affected.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+        self.ordered_epoch = None;
+    }
+
+    /// Sorts the `table_order` and rows of tables in the deterministic mode.
+    /// Rows move only here and in the structural changes, which don't happen while a *System* is running.
+    /// So that every query of a *System* sees the same positions.
+    fn sort_for_determinism(&mut self) {
+        if !self.deterministic || self.ordered_epoch == Some(self.epoch) {
+            return;
+        }
+        let mut moved = false;
+        for (t, table) in self.tables.iter_mut().enumerate() {
+            if table.entities().is_sorted_by_key(|entity| entity.index()) {
+                continue;
+            }
+            table.sort_by_entity();
+            for (row, entity) in table.entities().iter().enumerate() {
+                self.entities[entity.index() as usize].location = Some(EntityLocation { table: t, row });
+            }
+            moved = true;
+        }
+        if moved {
+            self.epoch += 1;
+        }
+        let names = |table: &Table| {
+            (table.ids().iter())
+                .map(|id| self.components.info(*id).name.clone())
+                .collect::<Vec<_>>()
+        };
+        self.table_order = (0..self.tables.len()).collect();
+        self.table_order.sort_by_cached_key(|t| names(&self.tables[*t]));
+        self.ordered_epoch = Some(self.epoch);
+    }
+
+    /// Returns the queue of commands, see [`Commands`](super::Commands).
+    pub(crate) fn command_queue(&mut self) -> &mut Vec<Command> {
+        &mut self.commands
+    }
+
+    /// Puts slices of the `Target` that pass the `filter` into the next cache of the `state`, and returns the cache.
+    /// If `is_mut` is true, the slices are regarded as changed.
+    /// If `is_optional` is true, entities without the `Target` are also selected.
+    fn query_slices<'s>(
+        &mut self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_mut: bool,
+        is_optional: bool,
+    ) -> &'s mut QueryCache {
+        let system = state.system;
+        self.sort_for_determinism();
+        let target = self.components.id(&filter.target);
+
+        // Borrow rules of a *System* are checked when it's added to a `Schedule`, see `check_access`.
+        // But direct `query_mut` calls outside a `Schedule` are not checked, and their results can alias.
+
+        // Note that the pointers can differ from the past if the columns have been resized.
+        // So we reuse the cached chunks only if the epoch hasn't changed since they were made.
+        // Change filters depend on ticks as well, so that they're resolved every time.
+        let tick = self.tick;
+        let cache = state.next_cache(filter.filter, is_optional);
+        if cache.epoch == Some(self.epoch) && filter.change == ChangeFilter::None {
+            if is_mut {
+                for (t, range) in cache.ranges.iter() {
+                    let col = self.tables[*t].any_column_mut(target.unwrap()).unwrap();
+                    col.set_changed_rows(range.clone(), tick);
+                }
+            }
+            return cache;
+        }
+
+        cache.chunks.clear();
+        cache.ranges.clear();
+        for (t, rows) in self.match_rows(filter, system, is_optional) {
+            let table = &mut self.tables[t];
+            let entities = NonNull::from(table.entities());
+            let Some(range) = rows else {
+                // Optional query on the table without the `Target`.
+                cache.chunks.push(Chunk {
+                    components: None,
+                    entities,
+                });
+                continue;
+            };
+            let id = target.unwrap();
+            if is_mut {
+                let col = table.any_column_mut(id).unwrap();
+                col.set_changed_rows(range.clone(), tick);
+            }
+            // Values are borrowed shared, so that the pointer doesn't invalidate ones cached by other queries.
+            let col = table.any_column(id).unwrap();
+            // Safety: `range` is in bounds of the `entities`.
+            let entities = unsafe { NonNull::from(&entities.as_ref()[range.clone()]) };
+            cache.chunks.push(Chunk {
+                components: Some(col.rows_ptr(range.clone())),
+                entities,
+            });
+            cache.ranges.push((t, range));
+        }
+        cache.epoch = Some(self.epoch);
+        cache
+    }
+
+    /// Same as `query_slices` without `is_mut`, but it works on a shared storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the deterministic mode if tables haven't been sorted, see [`Self::prepare_shared_queries`].
+    fn query_slices_shared<'s>(
+        &self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_optional: bool,
+    ) -> &'s mut QueryCache {
+        assert!(
+            !self.deterministic || self.ordered_epoch == Some(self.epoch),
+            "call prepare_shared_queries before querying a shared storage in the deterministic mode"
+        );
+        let system = state.system;
+        let cache = state.next_cache(filter.filter, is_optional);
+        if cache.epoch == Some(self.epoch) && filter.change == ChangeFilter::None {
+            return cache;
+        }
+
+        let target = self.components.id(&filter.target);
+        cache.chunks.clear();
+        cache.ranges.clear();
+        for (t, rows) in self.match_rows(filter, system, is_optional) {
+            let table = &self.tables[t];
+            let entities = NonNull::from(table.entities());
+            let Some(range) = rows else {
+                cache.chunks.push(Chunk {
+                    components: None,
+                    entities,
+                });
+                continue;
+            };
+            let col = table.any_column(target.unwrap()).unwrap();
+            // Safety: `range` is in bounds of the `entities`.
+            let entities = unsafe { NonNull::from(&entities.as_ref()[range.clone()]) };
+            cache.chunks.push(Chunk {
+                components: Some(col.rows_ptr(range.clone())),
+                entities,
+            });
+            cache.ranges.push((t, range));
+        }
+        cache.epoch = Some(self.epoch);
+        cache
+    }
+
+    /// Finds rows of the `Target` passing the `filter` for the *System* `system` as (Table index, Rows).
+    /// Rows are `None` for tables without the `Target`, which are selected only if `is_optional` is true.
+    fn match_rows(
+        &self,
+        filter: &FilterInfo,
+        system: TypeId,
+        is_optional: bool,
+    ) -> Vec<(usize, Option<Range<usize>>)> {
+        let target = self.components.id(&filter.target);
+        let tick = self.tick;
+        let last_run = self.last_runs.get(&system).copied();
+        // A `Target` that has never been inserted is in no table.
+        if target.is_none() && !is_optional {
+            return Vec::new();
+        }
+        let sets = FilterTypes::compile(&(filter.types)(), &self.components);
+        let mut matched = Vec::new();
+        for i in 0..self.tables.len() {
+            let t = if self.deterministic { self.table_order[i] } else { i };
+            let table = &self.tables[t];
+            let set = table.component_set();
+            let is_match = (is_optional || target.is_some_and(|id| set.contains(id)))
+                && sets.iter().any(|filter| filter.is_match(set));
+            if !is_match || table.len() == 0 {
+                continue;
+            }
+
+            let Some(col) = target.and_then(|id| table.any_column(id)) else {
+                matched.push((t, None));
+                continue;
+            };
+
+            // Splits the column into runs of matched rows.
+            let change = filter.change;
+            if change == ChangeFilter::None {
+                matched.push((t, Some(0..col.len())));
+            } else {
+                let mut start = 0;
+                while start < col.len() {
+                    if !col.is_match(start, change, last_run, tick) {
+                        start += 1;
+                        continue;
+                    }
+                    let mut end = start + 1;
+                    while end < col.len() && col.is_match(end, change, last_run, tick) {
+                        end += 1;
+                    }
+                    matched.push((t, Some(start..end)));
+                    start = end;
+                }
+            }
+        }
+        matched
+    }
+
+    /// Does what queries on a shared storage can't do, such as sorting tables in the deterministic mode.
+    /// Call this before running read-only *System*s on a shared storage, see [`StoreRead`].
+    pub fn prepare_shared_queries(&mut self) {
+        self.sort_for_determinism();
+    }
+}
+
+/// Borrows two different elements mutably at the same time.
+fn get_two_mut<T>(v: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    assert_ne!(a, b);
+    if a < b {
+        let (left, right) = v.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = v.split_at_mut(a);
+        (&mut right[0], &mut left[b])
     }
 }
 
-/// Interface of the `ComponentStorage`.
+// The storage can be shared by threads of a parallel executor.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ComponentStorage>();
+};
+
+/// Interface of the `ComponentStorage`, which *System*s query through.
+/// It's object safe, so that *System*s can run on any storage behind `&mut dyn Store`, such as a test double.
+/// Methods here are type erased, and typed ones such as `get` are provided by `dyn Store` on top of them.
 /// `Store` should be able to borrow multiple internal data pieces at the same time.
 /// To do that, maybe we can use interior mutability, but we use raw pointer in this example.
 /// It's dangerous but easy to implement.
 /// Plus, you can see the lifetimes between input and output are decoupled by explicit 'a.
-pub trait Store {
-    fn get<'a, F: Filter>(&mut self, q_id: (TypeId, TypeId)) -> QueryIter<'a, F::Target>;
-    fn get_mut<'a, F: Filter>(&mut self, q_id: (TypeId, TypeId)) -> QueryIterMut<'a, F::Target>;
+pub trait Store: StoreRead {
+    /// Returns chunks of the `Target` passing the `filter`, which are put into the next cache of the `state`.
+    /// A `Target` that has never been inserted selects nothing, so that there are no chunks rather than an error.
+    /// If `is_mut` is true, the chunks are regarded as changed.
+    /// If `is_optional` is true, entities without the `Target` are also selected without their values.
+    fn chunks<'s>(
+        &mut self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_mut: bool,
+        is_optional: bool,
+    ) -> &'s mut [Chunk];
+
+    /// Returns the resource of the type `ty`.
+    fn resource_mut(&mut self, ty: TypeId) -> Option<&mut (dyn Any + Send + Sync)>;
+
+    /// Returns the cursor of the reader `id`, which points to the next event to read.
+    fn event_cursor(&mut self, id: QueryId) -> &mut usize;
+
+    /// Returns entities that lost the `Component` of the type `ty`, or `None` if it has never been inserted.
+    fn removed(&mut self, ty: TypeId) -> Option<&Events<Entity>>;
+
+    /// See [`ComponentStorage::hierarchy_order`].
+    fn hierarchy_order(&mut self) -> Vec<Entity>;
+
+    /// Returns the `Local` of the query `id`, which is made by the `init` at the first call.
+    fn local(
+        &mut self,
+        id: QueryId,
+        init: fn() -> Box<dyn Any + Send + Sync>,
+    ) -> &mut (dyn Any + Send + Sync);
+
+    /// See [`ComponentStorage::take_query_state`].
+    fn take_query_state(&mut self, system: TypeId) -> QueryState;
+
+    fn put_query_state(&mut self, state: QueryState);
+
+    /// See [`ComponentStorage::finish_system`].
+    fn finish_system(&mut self, system: TypeId);
+}
+
+/// Read half of the `Store`, which works on a shared storage.
+/// Read-only *System*s query through this, so that they can run at the same time, see [`ReadOnly`](super::ReadOnly).
+/// Unlike the `Store`, reading doesn't need a unique borrow, so that there are no events or `Local`s which move cursors.
+pub trait StoreRead {
+    /// Same as [`Store::chunks`] without `is_mut`.
+    fn chunks_shared<'s>(
+        &self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_optional: bool,
+    ) -> &'s mut [Chunk];
+}
+
+impl dyn Store + '_ {
+    pub fn get<'a, F: Filter>(&mut self, state: &mut QueryState) -> QueryIter<'a, F::Target> {
+        let chunks = self.chunks(state, &FilterInfo::of::<F>(), false, false);
+
+        // Safety: Each query of a *System* has its own cache in the `state`, which only the *System* can take.
+        // As a result, we can guarantee that the chunks are invariant during its usage because no one else touches them.
+        // Also, the cache is made for the `F`, so that downcasting is valid.
+        unsafe { QueryIter::new(chunks) }
+    }
+
+    pub fn get_mut<'a, F: Filter>(&mut self, state: &mut QueryState) -> QueryIterMut<'a, F::Target> {
+        let chunks = self.chunks(state, &FilterInfo::of::<F>(), true, false);
+
+        unsafe { QueryIterMut::new(chunks) }
+    }
+
+    pub fn get_optional<'a, F: Filter>(&mut self, state: &mut QueryState) -> OptionIter<'a, F::Target> {
+        let chunks = self.chunks(state, &FilterInfo::of::<F>(), false, true);
+
+        // Safety: Same as `get`.
+        unsafe { OptionIter::new(chunks) }
+    }
+
+    pub fn get_optional_mut<'a, F: Filter>(
+        &mut self,
+        state: &mut QueryState,
+    ) -> OptionIterMut<'a, F::Target> {
+        let chunks = self.chunks(state, &FilterInfo::of::<F>(), true, true);
+
+        unsafe { OptionIterMut::new(chunks) }
+    }
+
+    pub fn get_entities<'a, F: Filter>(&mut self, state: &mut QueryState) -> EntityIter<'a> {
+        let chunks = self.chunks(state, &FilterInfo::of::<F>(), false, false);
+
+        // Safety: Same as `get`.
+        unsafe { EntityIter::new(chunks) }
+    }
+
+    /// Reads the events `T` for the reader `id`.
+    /// Events that haven't been registered yet read as empty, see [`Self::try_get_events`] to tell them apart.
+    pub fn get_events<'a, T: Event>(&mut self, id: QueryId) -> EventIter<'a, T> {
+        match self.try_get_events(id) {
+            Ok(events) => events,
+            Err(QueryError::UnregisteredEvents(_)) => EventIter::empty(),
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    pub fn try_get_events<'a, T: Event>(&mut self, id: QueryId) -> Result<EventIter<'a, T>, QueryError> {
+        let events = self.try_events_ptr::<T>()?;
+
+        // Safety: `Events` are not removed or moved while systems are running.
+        // Plus, no one writes the `Events` at the same time if borrow rule is kept by the `Ref` and `Mut`.
+        let events = unsafe { &*events };
+        Ok(EventIter::new(events, self.event_cursor(id)))
+    }
+
+    /// Writes the events `T`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the events haven't been registered, see [`Self::try_get_events_mut`].
+    pub fn get_events_mut<'a, T: Event>(&mut self) -> EventSender<'a, T> {
+        self.try_get_events_mut()
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_get_events_mut<'a, T: Event>(&mut self) -> Result<EventSender<'a, T>, QueryError> {
+        let events = self.try_events_ptr::<T>()?;
+
+        // Safety: Same as `try_get_events`.
+        Ok(EventSender::new(unsafe { &mut *events }))
+    }
+
+    fn try_events_ptr<T: Event>(&mut self) -> Result<*mut Events<T>, QueryError> {
+        let name = std::any::type_name::<T>();
+        self.resource_mut(TypeId::of::<Events<T>>())
+            .ok_or(QueryError::UnregisteredEvents(name))?
+            .downcast_mut::<Events<T>>()
+            .map(|events| events as *mut Events<T>)
+            .ok_or(QueryError::TypeMismatch(name))
+    }
+
+    pub fn get_removed<'a, T: Component>(&mut self, id: QueryId) -> EventIter<'a, Entity> {
+        // Nothing has been removed if `T` has never been inserted.
+        let Some(removed) = self.removed(TypeId::of::<T>()) else {
+            return EventIter::empty();
+        };
+
+        // Safety: Removals are made only by exclusive systems or outside of the schedule, not while this is borrowed.
+        let removed = unsafe { &*(removed as *const Events<Entity>) };
+        EventIter::new(removed, self.event_cursor(id))
+    }
+
+    pub fn get_local<'a, T: Default + Send + Sync + 'static>(&mut self, id: QueryId) -> &'a mut T {
+        let local = self
+            .local(id, || Box::new(T::default()))
+            .downcast_mut::<T>()
+            .unwrap_or_else(|| panic!("{}", QueryError::TypeMismatch(std::any::type_name::<T>())));
+
+        // Safety: Only the query of the `id` accesses it, and it's boxed so that it doesn't move.
+        unsafe { &mut *(local as *mut T) }
+    }
+}
+
+impl dyn StoreRead + '_ {
+    pub fn get_shared<'a, F: Filter>(&self, state: &mut QueryState) -> QueryIter<'a, F::Target> {
+        let chunks = self.chunks_shared(state, &FilterInfo::of::<F>(), false);
+
+        // Safety: Same as `Store::get`.
+        unsafe { QueryIter::new(chunks) }
+    }
+
+    pub fn get_optional_shared<'a, F: Filter>(&self, state: &mut QueryState) -> OptionIter<'a, F::Target> {
+        let chunks = self.chunks_shared(state, &FilterInfo::of::<F>(), true);
+
+        // Safety: Same as `Store::get`.
+        unsafe { OptionIter::new(chunks) }
+    }
+
+    pub fn get_entities_shared<'a, F: Filter>(&self, state: &mut QueryState) -> EntityIter<'a> {
+        let chunks = self.chunks_shared(state, &FilterInfo::of::<F>(), false);
+
+        // Safety: Same as `Store::get`.
+        unsafe { EntityIter::new(chunks) }
+    }
+}
+
+impl StoreRead for ComponentStorage {
+    fn chunks_shared<'s>(
+        &self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_optional: bool,
+    ) -> &'s mut [Chunk] {
+        &mut self.query_slices_shared(state, filter, is_optional).chunks
+    }
 }
 
 impl Store for ComponentStorage {
-    fn get<'a, F: Filter>(&mut self, q_id: (TypeId, TypeId)) -> QueryIter<'a, F::Target> {
-        let all_any_none = F::all_any_none();
-        let _filters = F::as_slice(&all_any_none);
-
-        // Didn't implement with respect to filter.
-        // Didn't check borrow rule for now, so that data race can occur.
-
-        let v = self
-            .data
-            .get_mut(&TypeId::of::<F::Target>())
-            .unwrap()
-            .downcast_mut::<Vec<F::Target>>()
-            .unwrap()
-            .as_mut_slice();
-
-        let query_key = (TypeId::of::<F>(), q_id);
-        self.query_buffer.entry(query_key)
-            .and_modify(|prev| {
-                // Adjust the capacity and length of the `prev` in practical usage.
-                // But here we just put the new pointer in the `prev`.
-                // Note that the pointer of `v` can differ from the past if it's been resized.
-                prev[0] = NonNull::new(upcast_slice(v)).unwrap();
-            })
-            .or_insert(
-                vec![NonNull::new(upcast_slice(v)).unwrap()]
-            );
+    fn chunks<'s>(
+        &mut self,
+        state: &'s mut QueryState,
+        filter: &FilterInfo,
+        is_mut: bool,
+        is_optional: bool,
+    ) -> &'s mut [Chunk] {
+        &mut self.query_slices(state, filter, is_mut, is_optional).chunks
+    }
 
-        // Safety: `k` is unique of all *System-Query-Filter* combinations.
-        // As a result, we can guarantee that `v` is invariant during its usage because no one can generate the same `k` except itself.
-        // Also, It means downcasting is valid.
-        unsafe { QueryIter::new(self.query_buffer.get(&query_key).unwrap()) }
+    fn resource_mut(&mut self, ty: TypeId) -> Option<&mut (dyn Any + Send + Sync)> {
+        self.resources.get_mut(&ty).map(|resource| resource.as_mut())
     }
 
-    fn get_mut<'a, F: Filter>(&mut self, q_id: (TypeId, TypeId)) -> QueryIterMut<'a, F::Target> {
-        let all_any_none = F::all_any_none();
-        let _filters = F::as_slice(&all_any_none);
+    fn event_cursor(&mut self, id: QueryId) -> &mut usize {
+        self.event_cursors.entry(id).or_default()
+    }
 
-        let v = self
-            .data
-            .get_mut(&TypeId::of::<F::Target>())
-            .unwrap()
-            .downcast_mut::<Vec<F::Target>>()
-            .unwrap()
-            .as_mut_slice();
+    fn removed(&mut self, ty: TypeId) -> Option<&Events<Entity>> {
+        let id = self.components.id(&ty)?;
+        Some(self.removed.entry(id).or_insert_with(Events::new))
+    }
 
-        let query_key = (TypeId::of::<F>(), q_id);
-        self.query_buffer.entry(query_key)
-            .and_modify(|prev| {
-                prev[0] = NonNull::new(upcast_slice(v)).unwrap();
-            })
-            .or_insert(
-                vec![NonNull::new(upcast_slice(v)).unwrap()]
-            );
+    fn hierarchy_order(&mut self) -> Vec<Entity> {
+        ComponentStorage::hierarchy_order(self)
+    }
+
+    fn local(
+        &mut self,
+        id: QueryId,
+        init: fn() -> Box<dyn Any + Send + Sync>,
+    ) -> &mut (dyn Any + Send + Sync) {
+        self.locals.entry(id).or_insert_with(init).as_mut()
+    }
+
+    fn take_query_state(&mut self, system: TypeId) -> QueryState {
+        ComponentStorage::take_query_state(self, system)
+    }
+
+    fn put_query_state(&mut self, state: QueryState) {
+        ComponentStorage::put_query_state(self, state)
+    }
 
-        unsafe { QueryIterMut::new(self.query_buffer.get_mut(&query_key).unwrap()) }
+    fn finish_system(&mut self, system: TypeId) {
+        ComponentStorage::finish_system(self, system)
     }
 }
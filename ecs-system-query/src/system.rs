@@ -1,36 +1,652 @@
-use super::query::Query;
-use super::ComponentStorage;
+use super::query::{
+    AccessId, DataSelector, Narrow, Query, QueryData, QueryId, ReadOnlyQuery, Select, With, Without,
+};
+use super::{ComponentStorage, QueryState, Store};
 use std::any::TypeId;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 
-pub trait Invokable {
-    fn invoke(&self, storage: &mut ComponentStorage); // Depends on DataPool for object safety.
-    fn reads(&self) -> Vec<TypeId>; // For parallel execution later.
-    fn writes(&self) -> Vec<TypeId>; // For parallel execution later.
+/// It's `Send` and `Sync`, so that executors can run *System*s on other threads, see `Executor`.
+pub trait Invokable: Send + Sync {
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError>; // Depends on DataPool for object safety.
+    fn reads(&self) -> Vec<AccessId>; // For conflict check and parallel execution later.
+    fn writes(&self) -> Vec<AccessId>; // For conflict check and parallel execution later.
+
+    /// `TypeId` of the *System*, which identifies the *System* in a `Schedule`.
+    fn id(&self) -> TypeId;
+
+    /// Type name of the *System* for debugging.
+    fn name(&self) -> &'static str;
+
+    /// Exclusive one accesses the whole storage, so that it should run alone.
+    fn is_exclusive(&self) -> bool {
+        false
+    }
+
+    /// Read-only one can be invoked on a shared storage by [`Invokable::invoke_shared`].
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Main-thread-only one touches non-send resources, so that executors should invoke it on the thread calling them.
+    fn is_main_thread_only(&self) -> bool {
+        false
+    }
+
+    /// Runs the *System* on a shared storage, so that read-only *System*s can run at the same time.
+    /// Unlike [`Invokable::invoke`], caller should take the `state` of the *System* from the storage,
+    /// and put it back and call [`ComponentStorage::finish_system`] afterwards, see [`ComponentStorage::take_query_state`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the *System* is not read-only.
+    fn invoke_shared(
+        &self,
+        _storage: &ComponentStorage,
+        _state: &mut QueryState,
+    ) -> Result<(), SystemError> {
+        panic!("{} is not read-only", self.name());
+    }
 }
 
-impl<T: System> Invokable for T {
+impl<T: PipeSystem<In = ()>> Invokable for T
+where
+    T::Out: SystemOutput,
+{
     #[inline]
-    fn invoke(&self, storage: &mut ComponentStorage) {
-        self.run(
-            <T::Ref as Query>::query(storage, TypeId::of::<T>()),
-            <T::Mut as Query>::query_mut(storage, TypeId::of::<T>()),
-        );
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError> {
+        self.run_on(storage).into_result()
     }
 
     #[inline]
-    fn reads(&self) -> Vec<TypeId> {
+    fn reads(&self) -> Vec<AccessId> {
         <T::Ref as Query>::ids()
     }
 
     #[inline]
-    fn writes(&self) -> Vec<TypeId> {
+    fn writes(&self) -> Vec<AccessId> {
         <T::Mut as Query>::ids()
     }
+
+    #[inline]
+    fn id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    #[inline]
+    fn name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
 }
 
-pub trait System: 'static {
+pub trait System: Send + Sync + 'static {
     type Ref: for<'a> Query<'a>;
     type Mut: for<'a> Query<'a>;
 
     fn run(&self, r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut);
 }
+
+/// A *System* taking the output of the previous *System* as the `In`, and returning the `Out` to the next one.
+/// They're chained by [`PipeSystem::pipe`], and the chain can be added to a `Schedule` if it takes `()` and returns a [`SystemOutput`].
+/// Every `System` is a `PipeSystem` taking and returning `()`.
+pub trait PipeSystem: Send + Sync + 'static {
+    type In;
+    type Out;
+    type Ref: for<'a> Query<'a>;
+    type Mut: for<'a> Query<'a>;
+
+    fn run(
+        &self,
+        input: Self::In,
+        r: <Self::Ref as Query>::Output,
+        m: <Self::Mut as Query>::OutputMut,
+    ) -> Self::Out;
+
+    /// Makes a *System* passing the output of `self` to the `next`.
+    /// The chain runs as a single *System*, so that its queries must not conflict as a whole.
+    fn pipe<B: PipeSystem<In = Self::Out>>(self, next: B) -> Pipe<Self, B>
+    where
+        Self: Sized,
+    {
+        Pipe(self, next)
+    }
+
+    /// Runs the *System* on the `store` as an `Invokable` does, and returns its output.
+    /// The `store` can be any storage, such as a test double wrapping the `ComponentStorage`.
+    fn run_on(&self, store: &mut dyn Store) -> Self::Out
+    where
+        Self: PipeSystem<In = ()>,
+    {
+        let system = TypeId::of::<Self>();
+        let mut state = store.take_query_state(system);
+        let output = self.run(
+            (),
+            <Self::Ref as Query>::query(store, &mut state),
+            <Self::Mut as Query>::query_mut(store, &mut state),
+        );
+        store.put_query_state(state);
+        store.finish_system(system);
+        output
+    }
+}
+
+impl<T: System> PipeSystem for T {
+    type In = ();
+    type Out = ();
+    type Ref = T::Ref;
+    type Mut = T::Mut;
+
+    #[inline]
+    fn run(&self, _input: (), r: <Self::Ref as Query>::Output, m: <Self::Mut as Query>::OutputMut) {
+        System::run(self, r, m)
+    }
+}
+
+/// Chain of two *System*s, see [`PipeSystem::pipe`].
+pub struct Pipe<A, B>(A, B);
+
+impl<A: PipeSystem, B: PipeSystem<In = A::Out>> PipeSystem for Pipe<A, B> {
+    type In = A::In;
+    type Out = B::Out;
+    type Ref = (A::Ref, B::Ref);
+    type Mut = (A::Mut, B::Mut);
+
+    #[inline]
+    fn run(
+        &self,
+        input: Self::In,
+        (ra, rb): <Self::Ref as Query>::Output,
+        (ma, mb): <Self::Mut as Query>::OutputMut,
+    ) -> Self::Out {
+        let output = self.0.run(input, ra, ma);
+        self.1.run(output, rb, mb)
+    }
+}
+
+/// Error returned by a fallible *System*, which is handled by the error handler of the `Schedule`.
+/// Any error can be turned into this by `?`.
+#[derive(Debug)]
+pub struct SystemError(Box<dyn std::error::Error + Send + Sync>);
+
+impl<E: Into<Box<dyn std::error::Error + Send + Sync>>> From<E> for SystemError {
+    fn from(error: E) -> Self {
+        Self(error.into())
+    }
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Output of a *System* that can be added to a `Schedule`.
+/// It's `()`, or `Result<(), SystemError>` for fallible *System*s.
+pub trait SystemOutput {
+    fn into_result(self) -> Result<(), SystemError>;
+}
+
+impl SystemOutput for () {
+    #[inline]
+    fn into_result(self) -> Result<(), SystemError> {
+        Ok(())
+    }
+}
+
+impl SystemOutput for Result<(), SystemError> {
+    #[inline]
+    fn into_result(self) -> Result<(), SystemError> {
+        self
+    }
+}
+
+/// Conversion into an `Invokable` *System*, so that `Schedule::add_system` takes functions as well as *System* types.
+/// `Marker` tells implementations apart, which is `()` for `Invokable`s and `fn(Params) -> Out` for functions.
+pub trait IntoSystem<Marker> {
+    type System: Invokable + 'static;
+
+    fn into_system(self) -> Self::System;
+}
+
+impl<T: Invokable + 'static> IntoSystem<()> for T {
+    type System = T;
+
+    #[inline]
+    fn into_system(self) -> Self::System {
+        self
+    }
+}
+
+/// Parameter of a function *System*.
+/// Accessed types of the function are inferred from its parameters.
+///
+/// - [`QueryParam`] for `Component`s of each entity such as `QueryParam<(&mut Pos, &Vel)>`.
+/// - [`Read`] and [`Write`] for any `Query` types such as `Filter`s, `EventReader`s, and `Local`s.
+/// - [`Res`] and [`ResMut`] for resources.
+/// - [`NonSend`](super::NonSend) and [`NonSendMut`](super::NonSendMut) for resources that are not `Send`.
+/// - [`Commands`](super::Commands) for structural changes.
+///
+/// ```ignore
+/// fn movement(q: QueryParam<(&mut Pos, &Vel)>, time: Res<Time>, mut count: Write<Local<u32>>) { ... }
+/// ```
+pub trait SystemParam {
+    /// The parameter type with the lifetime of the data in the storage.
+    type Item<'a>;
+
+    /// Gets data for the *System* `system`, see [`Query::query`].
+    fn fetch<'a>(storage: &mut ComponentStorage, state: &mut QueryState) -> Self::Item<'a>;
+
+    /// Types read by the parameter.
+    fn reads() -> Vec<AccessId>;
+
+    /// Types written by the parameter.
+    fn writes() -> Vec<AccessId>;
+
+    /// True if the parameter can be fetched only on the main thread, such as [`NonSend`](super::NonSend).
+    fn is_main_thread_only() -> bool {
+        false
+    }
+}
+
+/// Function *System* parameter reading the query `Q` like the `Ref` of a `System`.
+/// It dereferences to the output of the query.
+///
+/// ```ignore
+/// fn movement(v: Read<Target<Velocity>>, p: Write<Target<Position>>) { ... }
+/// schedule.add_system(Stage::Update, movement);
+/// ```
+pub struct Read<'a, Q: Query<'a>>(pub Q::Output);
+
+/// Function *System* parameter writing the query `Q` like the `Mut` of a `System`.
+/// It dereferences to the output of the query.
+pub struct Write<'a, Q: Query<'a>>(pub Q::OutputMut);
+
+impl<'x, Q: for<'a> Query<'a>> SystemParam for Read<'x, Q> {
+    type Item<'a> = Read<'a, Q>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, state: &mut QueryState) -> Self::Item<'a> {
+        Read(Q::query(storage, state))
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        Q::ids()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
+
+impl<'x, Q: for<'a> Query<'a>> SystemParam for Write<'x, Q> {
+    type Item<'a> = Write<'a, Q>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, state: &mut QueryState) -> Self::Item<'a> {
+        Write(Q::query_mut(storage, state))
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        Q::ids()
+    }
+}
+
+impl<'a, Q: Query<'a>> Deref for Read<'a, Q> {
+    type Target = Q::Output;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, Q: Query<'a>> DerefMut for Read<'a, Q> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'a, Q: Query<'a>> IntoIterator for Read<'a, Q>
+where
+    Q::Output: IntoIterator,
+{
+    type Item = <Q::Output as IntoIterator>::Item;
+    type IntoIter = <Q::Output as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, Q: Query<'a>> Deref for Write<'a, Q> {
+    type Target = Q::OutputMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, Q: Query<'a>> DerefMut for Write<'a, Q> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'a, Q: Query<'a>> IntoIterator for Write<'a, Q>
+where
+    Q::OutputMut: IntoIterator,
+{
+    type Item = <Q::OutputMut as IntoIterator>::Item;
+    type IntoIter = <Q::OutputMut as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Function *System* parameter yielding the `D` of each entity having all `Component`s in the `D`.
+/// Entities can be narrowed down by `W` and `N` like `Target`, or write them by [`query!`](crate::query!).
+///
+/// ```ignore
+/// fn movement(q: QueryParam<(&mut Pos, &Vel), With<Player>, Without<Frozen>>) {
+///     for (pos, vel) in q {
+///         pos.0 += vel.0;
+///     }
+/// }
+/// ```
+pub struct QueryParam<'a, D: QueryData, W = With<()>, N = Without<()>>(
+    D::Iter<'a>,
+    PhantomData<fn() -> (W, N)>,
+);
+
+impl<'x, D: QueryData, W: 'static, N: 'static> SystemParam for QueryParam<'x, D, W, N>
+where
+    Select<D::Static, W, N>: DataSelector,
+{
+    type Item<'a> = QueryParam<'a, D, W, N>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, state: &mut QueryState) -> Self::Item<'a> {
+        QueryParam(D::iter::<Select<D::Static, W, N>>(storage, state), PhantomData)
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        let select = <Select<D::Static, W, N> as DataSelector>::types;
+        D::reads().into_iter().map(|access| access.narrow(select)).collect()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        let select = <Select<D::Static, W, N> as DataSelector>::types;
+        D::writes().into_iter().map(|access| access.narrow(select)).collect()
+    }
+}
+
+impl<'a, D: QueryData, W, N> QueryParam<'a, D, W, N> {
+    /// Reinterprets the query as the narrower `T`, e.g. `(&mut Pos, &Vel)` as `&Pos`, to pass it to a helper function.
+    /// The `T` must be a subset of the `D` at compile time, see [`Narrow`]. The index `I` is inferred by `lens::<T, _>()`.
+    /// The lens visits the same entities, and its type doesn't have the filters, so that helpers take `QueryParam<&Pos>`.
+    ///
+    /// ```ignore
+    /// fn movement(q: QueryParam<(&mut Pos, &Vel)>) {
+    ///     print_positions(q.lens::<&Pos, _>());
+    /// }
+    /// ```
+    pub fn lens<T: QueryData, I>(self) -> QueryParam<'a, T>
+    where
+        D: Narrow<T, I>,
+    {
+        let mut parts = D::into_parts(self.0);
+        QueryParam(D::narrow(&mut parts), PhantomData)
+    }
+}
+
+impl<'a, D: QueryData, W, N> IntoIterator for QueryParam<'a, D, W, N> {
+    type Item = <D::Iter<'a> as Iterator>::Item;
+    type IntoIter = D::Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0
+    }
+}
+
+/// Function *System* parameter reading the resource `T`.
+///
+/// # Panics
+///
+/// The *System* panics if the resource doesn't exist.
+pub struct Res<'a, T>(&'a T);
+
+/// Function *System* parameter writing the resource `T`.
+///
+/// # Panics
+///
+/// The *System* panics if the resource doesn't exist.
+pub struct ResMut<'a, T>(&'a mut T);
+
+impl<'x, T: Send + Sync + 'static> SystemParam for Res<'x, T> {
+    type Item<'a> = Res<'a, T>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, _state: &mut QueryState) -> Self::Item<'a> {
+        let value = storage
+            .get_resource::<T>()
+            .unwrap_or_else(|| panic!("resource {} should be inserted", std::any::type_name::<T>()));
+        // Safety: Resources are boxed, so that they don't move while the *System* is running.
+        // And the conflict check guarantees that no one writes it at the same time.
+        Res(unsafe { &*(value as *const T) })
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        vec![AccessId::of::<T>()]
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
+
+impl<'x, T: Send + Sync + 'static> SystemParam for ResMut<'x, T> {
+    type Item<'a> = ResMut<'a, T>;
+
+    #[inline]
+    fn fetch<'a>(storage: &mut ComponentStorage, _state: &mut QueryState) -> Self::Item<'a> {
+        let value = storage
+            .get_resource_mut::<T>()
+            .unwrap_or_else(|| panic!("resource {} should be inserted", std::any::type_name::<T>()));
+        // Safety: Same as `Res`.
+        ResMut(unsafe { &mut *(value as *mut T) })
+    }
+
+    #[inline]
+    fn reads() -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn writes() -> Vec<AccessId> {
+        vec![AccessId::of::<T>()]
+    }
+}
+
+impl<'a, T> Deref for Res<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a, T> Deref for ResMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a, T> DerefMut for ResMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+/// *System* made of the function `F` taking the parameters `P`, see [`IntoSystem`].
+/// Its identity is the type of the `F`, which is unique for each function and closure.
+pub struct FnSystem<F, P> {
+    pub(crate) func: F,
+    _marker: PhantomData<fn() -> P>,
+}
+
+impl<F, P> FnSystem<F, P> {
+    pub fn new(func: F) -> Self {
+        Self {
+            func,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Wrapper of a *System* whose `Mut` is `()` to make it read-only `Invokable`.
+/// Read-only *System*s are invoked with a shared storage, see [`Invokable::invoke_shared`].
+pub struct ReadOnly<T>(pub T);
+
+impl<T: PipeSystem<In = (), Mut = ()>> Invokable for ReadOnly<T>
+where
+    T::Out: SystemOutput,
+    T::Ref: for<'a> ReadOnlyQuery<'a>,
+{
+    #[inline]
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError> {
+        storage.prepare_shared_queries();
+        let mut state = storage.take_query_state(TypeId::of::<T>());
+        let output = self.invoke_shared(storage, &mut state);
+        storage.put_query_state(state);
+        storage.finish_system(TypeId::of::<T>());
+        output
+    }
+
+    #[inline]
+    fn reads(&self) -> Vec<AccessId> {
+        <T::Ref as Query>::ids()
+    }
+
+    #[inline]
+    fn writes(&self) -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    #[inline]
+    fn name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    #[inline]
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn invoke_shared(
+        &self,
+        storage: &ComponentStorage,
+        state: &mut QueryState,
+    ) -> Result<(), SystemError> {
+        let r = <T::Ref as ReadOnlyQuery>::query_shared(storage, state);
+        self.0.run((), r, ()).into_result()
+    }
+}
+
+/// A *System* that takes the whole storage mutably.
+/// Use this for structural changes such as spawning entities or inserting `Component`s,
+/// which the query based `System` can't do.
+pub trait ExclusiveSystem: Send + Sync + 'static {
+    fn run(&self, storage: &mut ComponentStorage);
+}
+
+/// Wrapper of the `ExclusiveSystem` to make it `Invokable`.
+pub struct Exclusive<T>(pub T);
+
+impl<T: ExclusiveSystem> Invokable for Exclusive<T> {
+    #[inline]
+    fn invoke(&self, storage: &mut ComponentStorage) -> Result<(), SystemError> {
+        self.0.run(storage);
+        storage.finish_system(TypeId::of::<T>());
+        Ok(())
+    }
+
+    /// It can read anything, see [`Invokable::is_exclusive`].
+    #[inline]
+    fn reads(&self) -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    /// It can write anything, see [`Invokable::is_exclusive`].
+    #[inline]
+    fn writes(&self) -> Vec<AccessId> {
+        Vec::new()
+    }
+
+    #[inline]
+    fn id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    #[inline]
+    fn name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    #[inline]
+    fn is_exclusive(&self) -> bool {
+        true
+    }
+
+    /// It can touch non-send resources as well.
+    #[inline]
+    fn is_main_thread_only(&self) -> bool {
+        true
+    }
+}
+
+/// Query parameter for private state of a *System* that persists across runs, such as counters or caches.
+/// It starts from `T::default()`, and it's mutable in both the `Ref` and the `Mut` because no one else can see it.
+/// Each `Local` has its own state, even ones of the same type in the `Ref` and the `Mut` or in piped *System*s.
+pub struct Local<T>(PhantomData<T>);
+
+impl<'a, T: Default + Send + Sync + 'static> Query<'a> for Local<T> {
+    type Output = &'a mut T;
+    type OutputMut = &'a mut T;
+
+    #[inline]
+    fn query(storage: &mut dyn Store, state: &mut QueryState) -> Self::Output {
+        storage.get_local::<T>(QueryId::read::<Self>(state.system(), state.next_slot()))
+    }
+
+    #[inline]
+    fn query_mut(storage: &mut dyn Store, state: &mut QueryState) -> Self::OutputMut {
+        storage.get_local::<T>(QueryId::write::<Self>(state.system(), state.next_slot()))
+    }
+
+    /// It doesn't access any shared data.
+    #[inline]
+    fn ids() -> Vec<AccessId> {
+        Vec::new()
+    }
+}
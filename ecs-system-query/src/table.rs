@@ -0,0 +1,820 @@
+use super::{ChangeFilter, Component, ComponentId, ComponentSet, ComponentTicks, DropFn, Entity, ErasedSlice, SyncCell};
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::any::Any;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+/// A column of a `Table`.
+/// Keeps `Component`s of the same type with their ticks.
+///
+/// Tags, `Component`s of zero size such as `Selected`, are kept as a length only.
+/// `Vec` of them doesn't allocate, and they don't keep ticks either, so that their presence is just the table they're in.
+/// As a result, `Added` and `Changed` of a tag pass every entity having it.
+///
+/// Values are kept in `SyncCell`s, so that queries can write them through a shared borrow, see [`AnyColumn::rows_ptr`].
+pub struct Column<T> {
+    cells: Vec<SyncCell<T>>,
+    pub ticks: Vec<ComponentTicks>,
+}
+
+impl<T: Component> Column<T> {
+    const IS_TAG: bool = std::mem::size_of::<T>() == 0;
+
+    pub fn new() -> Self {
+        Self {
+            cells: Vec::new(),
+            ticks: Vec::new(),
+        }
+    }
+
+    /// Values of the column.
+    pub fn data(&self) -> &[T] {
+        // Safety: Values are written only through `&mut self` or by the *System* holding the write access,
+        // which doesn't run while the storage is shared, see `SyncCell`.
+        unsafe { &*SyncCell::slice_ptr(&self.cells) }
+    }
+
+    /// Number of values the column can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.cells.capacity()
+    }
+
+    /// Values of the column.
+    /// The slice is made from the cells without borrowing them uniquely, so that pointers made by queries stay valid.
+    pub fn data_mut(&mut self) -> &mut [T] {
+        // Safety: `self` is borrowed uniquely, so that no one else accesses the values.
+        unsafe { &mut *SyncCell::slice_ptr(&self.cells) }
+    }
+
+    /// Removes the last value.
+    pub fn pop(&mut self) -> Option<T> {
+        self.ticks.pop();
+        self.cells.pop().map(SyncCell::into_inner)
+    }
+
+    pub fn push(&mut self, value: T, ticks: ComponentTicks) {
+        self.cells.push(SyncCell::new(value));
+        if !Self::IS_TAG {
+            self.ticks.push(ticks);
+        }
+    }
+
+    /// Marks the `rows` as changed at the `tick`.
+    pub fn set_changed(&mut self, rows: Range<usize>, tick: u32) {
+        if !Self::IS_TAG {
+            self.ticks[rows].iter_mut().for_each(|t| t.changed = tick);
+        }
+    }
+
+    /// Determines whether the value at the `row` passes the change `filter`.
+    /// Tags always pass because they don't have ticks.
+    pub fn is_match(
+        &self,
+        row: usize,
+        filter: ChangeFilter,
+        last_run: Option<u32>,
+        this_run: u32,
+    ) -> bool {
+        Self::IS_TAG || self.ticks[row].is_match(filter, last_run, this_run)
+    }
+
+    /// Replaces the value at the `row` as changed one.
+    /// If the `row` is right after the end, which is a row waiting for its `Component`s, pushes the value as added one.
+    pub fn put(&mut self, row: usize, value: T, tick: u32) {
+        if row < self.cells.len() {
+            self.data_mut()[row] = value;
+            self.set_changed(row..row + 1, tick);
+        } else {
+            debug_assert_eq!(row, self.cells.len());
+            self.push(value, ComponentTicks::new(tick));
+        }
+    }
+}
+
+impl<T: Component + Clone> Column<T> {
+    /// Clones the type erased `column` of this type, see [`CloneFn`].
+    pub fn clone_any(column: &dyn AnyColumn) -> Box<dyn AnyColumn> {
+        let column = column.as_any().downcast_ref::<Column<T>>().unwrap();
+        Box::new(Column {
+            cells: column.data().iter().cloned().map(SyncCell::new).collect(),
+            ticks: column.ticks.clone(),
+        })
+    }
+
+    /// Clones the value at the `row` of the type erased `column` of this type, see [`RowCloneFn`].
+    pub fn clone_row(column: &dyn AnyColumn, row: usize) -> Box<dyn AnyColumn> {
+        let column = column.as_any().downcast_ref::<Column<T>>().unwrap();
+        let mut clone = Column::new();
+        clone.push(column.data()[row].clone(), ComponentTicks::new(0));
+        Box::new(clone)
+    }
+}
+
+/// Function cloning a type erased `Column`, which is made by `Column::clone_any`.
+pub type CloneFn = fn(&dyn AnyColumn) -> Box<dyn AnyColumn>;
+
+/// Function cloning a row of a type erased `Column` into a new column, which is made by `Column::clone_row`.
+pub type RowCloneFn = fn(&dyn AnyColumn, usize) -> Box<dyn AnyColumn>;
+
+/// Type erased `Column`.
+/// `Table` moves rows between columns through this without knowing the type.
+pub trait AnyColumn: Any + Send + Sync {
+    fn len(&self) -> usize;
+
+    /// Makes a new empty column of the same type.
+    fn empty(&self) -> Box<dyn AnyColumn>;
+
+    /// Removes the row by swapping it with the last row, and drops it.
+    fn swap_remove(&mut self, row: usize);
+
+    /// Removes the row by swapping it with the last row, and pushes it into the `dst`.
+    /// `dst` must be a column of the same type.
+    fn move_row(&mut self, row: usize, dst: &mut dyn AnyColumn);
+
+    /// Moves all rows of the `other` to the end, and regards them as added at the `tick`.
+    /// `other` must be a column of the same type.
+    fn append(&mut self, other: &mut dyn AnyColumn, tick: u32);
+
+    /// Returns pointer to the first value.
+    /// Values are contiguous, and the stride is the size of the type.
+    fn as_mut_ptr(&mut self) -> *mut u8;
+
+    fn ticks_mut(&mut self) -> &mut [ComponentTicks];
+
+    /// Returns the values at the `rows` as a type erased slice of the same length, which queries cast back to the type.
+    /// It's made from a shared borrow, but the values can be written through it under the invariants of `SyncCell`.
+    fn rows_ptr(&self, rows: Range<usize>) -> ErasedSlice;
+
+    /// Marks the `rows` as changed at the `tick`.
+    fn set_changed_rows(&mut self, rows: Range<usize>, tick: u32);
+
+    /// Determines whether the value at the `row` passes the change `filter`.
+    /// Tags always pass because they don't have ticks.
+    fn is_match(&self, row: usize, filter: ChangeFilter, last_run: Option<u32>, this_run: u32) -> bool;
+
+    /// Swaps two rows.
+    fn swap(&mut self, a: usize, b: usize);
+
+    /// Releases unused capacity.
+    fn shrink_to_fit(&mut self);
+
+    /// Reserves capacity for at least `additional` more rows.
+    fn reserve(&mut self, additional: usize);
+
+    /// Returns the value at the `row` as `Any`, or `None` for dynamic `Component`s which don't have types.
+    fn get_any(&self, row: usize) -> Option<&dyn Any>;
+
+    /// Returns the value at the `row` as `Any` like [`Self::get_any`], and marks it as changed at the `tick`.
+    fn get_any_mut(&mut self, row: usize, tick: u32) -> Option<&mut dyn Any>;
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Component> AnyColumn for Column<T> {
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn empty(&self) -> Box<dyn AnyColumn> {
+        Box::new(Column::<T>::new())
+    }
+
+    fn swap_remove(&mut self, row: usize) {
+        self.cells.swap_remove(row);
+        if !Self::IS_TAG {
+            self.ticks.swap_remove(row);
+        }
+    }
+
+    fn move_row(&mut self, row: usize, dst: &mut dyn AnyColumn) {
+        let dst = dst.as_any_mut().downcast_mut::<Column<T>>().unwrap();
+        let ticks = if Self::IS_TAG {
+            ComponentTicks::new(0)
+        } else {
+            self.ticks.swap_remove(row)
+        };
+        dst.push(self.cells.swap_remove(row).into_inner(), ticks);
+    }
+
+    fn append(&mut self, other: &mut dyn AnyColumn, tick: u32) {
+        let other = other.as_any_mut().downcast_mut::<Column<T>>().unwrap();
+        if !Self::IS_TAG {
+            self.ticks.extend(other.cells.iter().map(|_| ComponentTicks::new(tick)));
+        }
+        self.cells.append(&mut other.cells);
+        other.ticks.clear();
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.cells.as_mut_ptr() as *mut u8
+    }
+
+    fn ticks_mut(&mut self) -> &mut [ComponentTicks] {
+        &mut self.ticks
+    }
+
+    fn rows_ptr(&self, rows: Range<usize>) -> ErasedSlice {
+        ErasedSlice::new(SyncCell::slice_ptr(&self.cells[rows]))
+    }
+
+    fn set_changed_rows(&mut self, rows: Range<usize>, tick: u32) {
+        self.set_changed(rows, tick);
+    }
+
+    fn is_match(&self, row: usize, filter: ChangeFilter, last_run: Option<u32>, this_run: u32) -> bool {
+        Column::is_match(self, row, filter, last_run, this_run)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.cells.swap(a, b);
+        if !Self::IS_TAG {
+            self.ticks.swap(a, b);
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.cells.shrink_to_fit();
+        self.ticks.shrink_to_fit();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.cells.reserve(additional);
+        if !Self::IS_TAG {
+            self.ticks.reserve(additional);
+        }
+    }
+
+    fn get_any(&self, row: usize) -> Option<&dyn Any> {
+        Some(&self.data()[row])
+    }
+
+    fn get_any_mut(&mut self, row: usize, tick: u32) -> Option<&mut dyn Any> {
+        self.set_changed(row..row + 1, tick);
+        Some(&mut self.data_mut()[row])
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Allocator of `RawColumn`s.
+/// It's the stable `GlobalAlloc` trait, so that any allocator written for `#[global_allocator]` works, see [`BumpArena`](super::BumpArena).
+pub type ColumnAlloc = Arc<dyn GlobalAlloc + Send + Sync>;
+
+/// `GlobalAlloc` forwarding to the global allocator, which is the default of `RawColumn`s.
+pub struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        alloc::realloc(ptr, layout, new_size)
+    }
+}
+
+/// A column of a dynamic `Component` type, which is not known at compile time.
+/// Values are kept as raw bytes with the `Layout` of the type.
+/// Tags of zero size are kept as a length only like `Column`.
+pub struct RawColumn {
+    layout: Layout,
+    drop: Option<DropFn>,
+    alloc: ColumnAlloc,
+    data: NonNull<u8>,
+    capacity: usize,
+    len: usize,
+    pub ticks: Vec<ComponentTicks>,
+}
+
+impl RawColumn {
+    pub fn new(layout: Layout, drop: Option<DropFn>) -> Self {
+        Self::new_in(layout, drop, Arc::new(Global))
+    }
+
+    /// Makes a column whose memory comes from the `alloc`.
+    pub fn new_in(layout: Layout, drop: Option<DropFn>, alloc: ColumnAlloc) -> Self {
+        Self {
+            layout,
+            drop,
+            alloc,
+            data: dangling(layout),
+            capacity: if layout.size() == 0 { usize::MAX } else { 0 },
+            len: 0,
+            ticks: Vec::new(),
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn is_tag(&self) -> bool {
+        self.layout.size() == 0
+    }
+
+    /// Marks the value at the `row` as changed at the `tick`.
+    pub fn set_changed(&mut self, row: usize, tick: u32) {
+        if !self.is_tag() {
+            self.ticks[row].changed = tick;
+        }
+    }
+
+    /// Returns pointer to the value at the `row`.
+    pub fn get_ptr(&self, row: usize) -> *mut u8 {
+        assert!(row < self.len);
+        // Safety: `row` is in bounds.
+        unsafe { self.data.as_ptr().add(row * self.layout.size()) }
+    }
+
+    /// Returns all values as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `data` has `len` initialized values.
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.len * self.layout.size()) }
+    }
+
+    /// Returns all values as bytes.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // Safety: `data` has `len` initialized values.
+        unsafe {
+            std::slice::from_raw_parts_mut(self.data.as_ptr(), self.len * self.layout.size())
+        }
+    }
+
+    /// Appends a value by copying bytes from the `value`.
+    ///
+    /// # Safety
+    ///
+    /// `value` must point to a valid value of the `layout`.
+    /// The value is moved into the column, so that caller must not drop it.
+    pub unsafe fn push(&mut self, value: *const u8, ticks: ComponentTicks) {
+        if self.len == self.capacity {
+            self.grow();
+        }
+        let size = self.layout.size();
+        std::ptr::copy_nonoverlapping(value, self.data.as_ptr().add(self.len * size), size);
+        self.len += 1;
+        if !self.is_tag() {
+            self.ticks.push(ticks);
+        }
+    }
+
+    /// Copies all values as bytes.
+    /// Returns `None` if the type needs drop, whose values may own resources that can't be copied.
+    pub fn try_clone(&self) -> Option<RawColumn> {
+        if self.drop.is_some() {
+            return None;
+        }
+        let mut column = RawColumn::new_in(self.layout, None, self.alloc.clone());
+        column.reserve(self.len);
+        for row in 0..self.len {
+            let ticks = self.ticks.get(row).copied().unwrap_or(ComponentTicks::new(0));
+            // Safety: The value is in bounds, and copying it is fine because it doesn't need drop.
+            unsafe { column.push(self.get_ptr(row), ticks) };
+        }
+        Some(column)
+    }
+
+    /// Copies the value at the `row` into a new column like [`Self::try_clone`].
+    pub fn try_clone_row(&self, row: usize) -> Option<RawColumn> {
+        if self.drop.is_some() {
+            return None;
+        }
+        let mut column = RawColumn::new_in(self.layout, None, self.alloc.clone());
+        // Safety: Same as `try_clone`.
+        unsafe { column.push(self.get_ptr(row), ComponentTicks::new(0)) };
+        Some(column)
+    }
+
+    /// Removes the row by moving the last row into it without dropping anything.
+    /// Caller should take or drop the value at the `row` first.
+    unsafe fn swap_remove_forget(&mut self, row: usize) {
+        let size = self.layout.size();
+        let last = self.len - 1;
+        if row != last {
+            std::ptr::copy_nonoverlapping(
+                self.data.as_ptr().add(last * size),
+                self.data.as_ptr().add(row * size),
+                size,
+            );
+        }
+        self.len -= 1;
+        if !self.is_tag() {
+            self.ticks.swap_remove(row);
+        }
+    }
+
+    fn grow(&mut self) {
+        self.grow_to((self.capacity * 2).max(4));
+    }
+
+    fn grow_to(&mut self, new_cap: usize) {
+        let new_layout = array_layout(self.layout, new_cap);
+        let ptr = if self.capacity == 0 {
+            // Safety: `new_layout` has non-zero size.
+            unsafe { self.alloc.alloc(new_layout) }
+        } else {
+            let old_layout = array_layout(self.layout, self.capacity);
+            // Safety: `data` was allocated with the `old_layout`.
+            unsafe { self.alloc.realloc(self.data.as_ptr(), old_layout, new_layout.size()) }
+        };
+        self.data = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(new_layout));
+        self.capacity = new_cap;
+    }
+}
+
+// Safety: It owns the values like `Vec`, and dynamic `Component`s are required to be thread safe, see `insert_raw`.
+unsafe impl Send for RawColumn {}
+unsafe impl Sync for RawColumn {}
+
+impl Drop for RawColumn {
+    fn drop(&mut self) {
+        if let Some(drop) = self.drop {
+            for row in 0..self.len {
+                // Safety: Values in bounds are initialized.
+                unsafe { drop(self.get_ptr(row)) };
+            }
+        }
+        if self.layout.size() > 0 && self.capacity > 0 {
+            // Safety: `data` was allocated with this layout.
+            unsafe { self.alloc.dealloc(self.data.as_ptr(), array_layout(self.layout, self.capacity)) };
+        }
+    }
+}
+
+impl AnyColumn for RawColumn {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn empty(&self) -> Box<dyn AnyColumn> {
+        Box::new(RawColumn::new_in(self.layout, self.drop, self.alloc.clone()))
+    }
+
+    fn swap_remove(&mut self, row: usize) {
+        assert!(row < self.len);
+        // Safety: `row` is in bounds, and the value is dropped before it's overwritten.
+        unsafe {
+            if let Some(drop) = self.drop {
+                drop(self.get_ptr(row));
+            }
+            self.swap_remove_forget(row);
+        }
+    }
+
+    fn move_row(&mut self, row: usize, dst: &mut dyn AnyColumn) {
+        let dst = dst.as_any_mut().downcast_mut::<RawColumn>().unwrap();
+        assert_eq!(self.layout, dst.layout);
+        // Safety: The value is moved to the `dst`, so that we don't drop it here.
+        unsafe {
+            let ticks = self.ticks.get(row).copied().unwrap_or(ComponentTicks::new(0));
+            dst.push(self.get_ptr(row), ticks);
+            self.swap_remove_forget(row);
+        }
+    }
+
+    fn append(&mut self, other: &mut dyn AnyColumn, tick: u32) {
+        let other = other.as_any_mut().downcast_mut::<RawColumn>().unwrap();
+        assert_eq!(self.layout, other.layout);
+        for row in 0..other.len {
+            // Safety: Values are moved to the `self`, and `other` forgets them below.
+            unsafe { self.push(other.get_ptr(row), ComponentTicks::new(tick)) };
+        }
+        other.len = 0;
+        other.ticks.clear();
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.data.as_ptr()
+    }
+
+    fn ticks_mut(&mut self) -> &mut [ComponentTicks] {
+        &mut self.ticks
+    }
+
+    fn rows_ptr(&self, rows: Range<usize>) -> ErasedSlice {
+        assert!(rows.start <= rows.end && rows.end <= self.len);
+        // Safety: `rows` are in bounds.
+        let ptr = unsafe { self.data.as_ptr().add(rows.start * self.layout.size()) };
+        ErasedSlice::untyped(std::ptr::slice_from_raw_parts_mut(ptr as *mut (), rows.len()))
+    }
+
+    fn set_changed_rows(&mut self, rows: Range<usize>, tick: u32) {
+        for row in rows {
+            self.set_changed(row, tick);
+        }
+    }
+
+    fn is_match(&self, row: usize, filter: ChangeFilter, last_run: Option<u32>, this_run: u32) -> bool {
+        self.is_tag() || self.ticks[row].is_match(filter, last_run, this_run)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        if a != b {
+            // Safety: Both rows are in bounds, and they're different so that they don't overlap.
+            unsafe {
+                std::ptr::swap_nonoverlapping(self.get_ptr(a), self.get_ptr(b), self.layout.size())
+            };
+        }
+        if !self.is_tag() {
+            self.ticks.swap(a, b);
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        if self.layout.size() == 0 || self.capacity == self.len {
+            self.ticks.shrink_to_fit();
+            return;
+        }
+        let old_layout = array_layout(self.layout, self.capacity);
+        if self.len == 0 {
+            // Safety: `data` was allocated with the `old_layout`.
+            unsafe { self.alloc.dealloc(self.data.as_ptr(), old_layout) };
+            self.data = dangling(self.layout);
+        } else {
+            let new_layout = array_layout(self.layout, self.len);
+            // Safety: `data` was allocated with the `old_layout`, and the new size is not zero.
+            let ptr = unsafe { self.alloc.realloc(self.data.as_ptr(), old_layout, new_layout.size()) };
+            self.data = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(new_layout));
+        }
+        self.capacity = self.len;
+        self.ticks.shrink_to_fit();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).unwrap();
+        if needed > self.capacity {
+            self.grow_to(needed.max(self.capacity * 2));
+        }
+        if !self.is_tag() {
+            self.ticks.reserve(additional);
+        }
+    }
+
+    fn get_any(&self, _row: usize) -> Option<&dyn Any> {
+        None
+    }
+
+    fn get_any_mut(&mut self, _row: usize, _tick: u32) -> Option<&mut dyn Any> {
+        None
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Aligned dangling pointer for the `layout`.
+fn dangling(layout: Layout) -> NonNull<u8> {
+    // Safety: Alignment is not zero.
+    unsafe { NonNull::new_unchecked(layout.align() as *mut u8) }
+}
+
+/// Layout of the `n` values of the `layout`.
+fn array_layout(layout: Layout, n: usize) -> Layout {
+    Layout::from_size_align(layout.size().checked_mul(n).unwrap(), layout.align()).unwrap()
+}
+
+/// A table of `Component`s, known as *Archetype*.
+/// All entities in a table have the same set of `Component` types.
+/// Each row is an `Entity`, and each column is a `Component` type.
+pub struct Table {
+    // Sorted `ComponentId`s.
+    ids: Vec<ComponentId>,
+    // Columns in the same order as the `ids`.
+    columns: Vec<Box<dyn AnyColumn>>,
+    // `ComponentId::index` -> index to the `columns`, so that finding a column doesn't need hashing.
+    // Ids registered after the table was made are out of bounds.
+    sparse: Vec<Option<usize>>,
+    // The same as the `ids`, for filters to match tables by bit operations.
+    set: ComponentSet,
+    entities: Vec<Entity>,
+}
+
+impl Table {
+    pub fn new(ids: Vec<ComponentId>, mut columns: HashMap<ComponentId, Box<dyn AnyColumn>>) -> Self {
+        debug_assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        let mut sparse = vec![None; ids.last().map_or(0, |id| id.index() + 1)];
+        for (i, id) in ids.iter().enumerate() {
+            sparse[id.index()] = Some(i);
+        }
+        let columns = ids.iter().map(|id| columns.remove(id).unwrap()).collect();
+        let set = ids.iter().copied().collect();
+        Self {
+            ids,
+            columns,
+            sparse,
+            set,
+            entities: Vec::new(),
+        }
+    }
+
+    /// Clones the table by cloning each column with the `clone`, which returns `None` if it can't.
+    pub fn try_clone(
+        &self,
+        clone: impl Fn(ComponentId, &dyn AnyColumn) -> Option<Box<dyn AnyColumn>>,
+    ) -> Option<Table> {
+        let columns = (self.ids.iter().zip(self.columns.iter()))
+            .map(|(id, col)| clone(*id, col.as_ref()))
+            .collect::<Option<_>>()?;
+        Some(Self {
+            ids: self.ids.clone(),
+            columns,
+            sparse: self.sparse.clone(),
+            set: self.set.clone(),
+            entities: self.entities.clone(),
+        })
+    }
+
+    #[inline]
+    fn column_index(&self, id: ComponentId) -> Option<usize> {
+        self.sparse.get(id.index()).copied().flatten()
+    }
+
+    #[inline]
+    pub fn any_column(&self, id: ComponentId) -> Option<&dyn AnyColumn> {
+        self.column_index(id).map(|i| self.columns[i].as_ref())
+    }
+
+    pub fn ids(&self) -> &[ComponentId] {
+        &self.ids
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    #[inline]
+    pub fn component_set(&self) -> &ComponentSet {
+        &self.set
+    }
+
+    pub fn contains(&self, id: ComponentId) -> bool {
+        self.column_index(id).is_some()
+    }
+
+    /// Returns the column of the `id`.
+    /// `T` must be the type of the `id`, otherwise it returns `None`.
+    pub fn column<T: Component>(&self, id: ComponentId) -> Option<&Column<T>> {
+        self.any_column(id)
+            .and_then(|col| col.as_any().downcast_ref::<Column<T>>())
+    }
+
+    /// Returns the column of the `id`.
+    /// `T` must be the type of the `id`, otherwise it returns `None`.
+    pub fn column_mut<T: Component>(&mut self, id: ComponentId) -> Option<&mut Column<T>> {
+        self.any_column_mut(id)
+            .and_then(|col| col.as_any_mut().downcast_mut::<Column<T>>())
+    }
+
+    /// Returns the column of the dynamic `Component` `id`.
+    pub fn raw_column(&self, id: ComponentId) -> Option<&RawColumn> {
+        self.any_column(id)
+            .and_then(|col| col.as_any().downcast_ref::<RawColumn>())
+    }
+
+    /// Returns the column of the dynamic `Component` `id`.
+    pub fn raw_column_mut(&mut self, id: ComponentId) -> Option<&mut RawColumn> {
+        self.any_column_mut(id)
+            .and_then(|col| col.as_any_mut().downcast_mut::<RawColumn>())
+    }
+
+    /// Returns all values at the `row` with their `ComponentId`s, see [`AnyColumn::get_any`].
+    pub fn row_any(&self, row: usize) -> impl Iterator<Item = (ComponentId, &dyn Any)> {
+        (self.ids.iter().zip(self.columns.iter()))
+            .filter_map(move |(id, col)| Some((*id, col.get_any(row)?)))
+    }
+
+    /// Returns all values at the `row` with their `ComponentId`s, and marks them as changed at the `tick`.
+    pub fn row_any_mut(&mut self, row: usize, tick: u32) -> impl Iterator<Item = (ComponentId, &mut dyn Any)> {
+        (self.ids.iter().zip(self.columns.iter_mut()))
+            .filter_map(move |(id, col)| Some((*id, col.get_any_mut(row, tick)?)))
+    }
+
+    /// Returns the type erased column of the `id`.
+    #[inline]
+    pub fn any_column_mut(&mut self, id: ComponentId) -> Option<&mut dyn AnyColumn> {
+        self.column_index(id).map(|i| self.columns[i].as_mut())
+    }
+
+    /// Makes empty columns of the same types.
+    pub fn empty_columns(&self) -> HashMap<ComponentId, Box<dyn AnyColumn>> {
+        (self.ids.iter())
+            .zip(self.columns.iter())
+            .map(|(id, col)| (*id, col.empty()))
+            .collect()
+    }
+
+    /// Appends the `entity` without its `Component`s.
+    /// Caller should push `Component`s into all columns.
+    pub fn push_entity(&mut self, entity: Entity) -> usize {
+        self.entities.push(entity);
+        self.entities.len() - 1
+    }
+
+    /// Moves the row to the `dst` table, and returns the entity that took the place of the moved row.
+    /// `Component`s that `dst` doesn't have are dropped.
+    /// Caller should push `Component`s that only the `dst` has.
+    pub fn move_row(&mut self, row: usize, dst: &mut Table) -> Option<Entity> {
+        self.move_row_with(row, dst, |_, col| col.swap_remove(row))
+    }
+
+    /// Same as `move_row`, but `Component`s that `dst` doesn't have are handed to the `remove`.
+    /// `remove` must remove the row from the given column by swapping it with the last row.
+    pub fn move_row_with(
+        &mut self,
+        row: usize,
+        dst: &mut Table,
+        mut remove: impl FnMut(ComponentId, &mut dyn AnyColumn),
+    ) -> Option<Entity> {
+        for (id, col) in self.ids.iter().zip(self.columns.iter_mut()) {
+            if let Some(dst_col) = dst.any_column_mut(*id) {
+                col.move_row(row, dst_col);
+            } else {
+                remove(*id, col.as_mut());
+            }
+        }
+        dst.entities.push(self.entities.swap_remove(row));
+        self.entities.get(row).copied()
+    }
+
+    /// Moves all rows of the `other` to the end.
+    /// `id_map` maps `ComponentId`s of the `other` to ones of this table, and `entity_map` does the same for entities.
+    /// Moved `Component`s are regarded as added at the `tick`.
+    pub fn append(
+        &mut self,
+        other: &mut Table,
+        id_map: impl Fn(ComponentId) -> ComponentId,
+        entity_map: impl Fn(Entity) -> Entity,
+        tick: u32,
+    ) {
+        for (id, col) in other.ids.iter().zip(other.columns.iter_mut()) {
+            let dst = self.any_column_mut(id_map(*id)).unwrap();
+            dst.append(col.as_mut(), tick);
+        }
+        self.entities
+            .extend(other.entities.drain(..).map(entity_map));
+    }
+
+    /// Sorts rows by entity index.
+    pub fn sort_by_entity(&mut self) {
+        let mut order = (0..self.len()).collect::<Vec<_>>();
+        order.sort_by_key(|row| self.entities[*row].index());
+        // Row `order[i]` goes to `i`. Previous swaps moved the row at `j < i` to `order[j]`, so we follow it.
+        for i in 0..order.len() {
+            let mut j = order[i];
+            while j < i {
+                j = order[j];
+            }
+            for col in self.columns.iter_mut() {
+                col.swap(i, j);
+            }
+            self.entities.swap(i, j);
+        }
+    }
+
+    /// Reserves capacity for at least `additional` more rows.
+    pub fn reserve(&mut self, additional: usize) {
+        for col in self.columns.iter_mut() {
+            col.reserve(additional);
+        }
+        self.entities.reserve(additional);
+    }
+
+    /// Releases unused capacity of the columns.
+    pub fn shrink_to_fit(&mut self) {
+        for col in self.columns.iter_mut() {
+            col.shrink_to_fit();
+        }
+        self.entities.shrink_to_fit();
+    }
+
+    /// Drops the row by swapping it with the last row, and returns the entity that took the place of the removed row.
+    pub fn remove_row(&mut self, row: usize) -> Option<Entity> {
+        for col in self.columns.iter_mut() {
+            col.swap_remove(row);
+        }
+        self.entities.swap_remove(row);
+        self.entities.get(row).copied()
+    }
+}
@@ -0,0 +1,313 @@
+//! Tests of query caches and entity bookkeeping.
+//! They touch the pointers that queries cache, so that run them under Miri as well by `cargo +nightly miri test`.
+
+use super::*;
+
+/// Test *System* id that queries are cached for.
+struct Cached;
+
+/// Test `Component` numbering entities, so that tests don't need a name for each.
+#[derive(Debug, Component)]
+struct Num(usize);
+
+/// Reads names of `CompA` through the query cache of the *System* `system`, in the order the query visits them.
+fn names(storage: &mut ComponentStorage, system: TypeId) -> Vec<&'static str> {
+    storage.query::<Target<CompA>>(system).rows().map(|a| a.0).collect()
+}
+
+/// Reads `Num`s through the query cache of the *System* `system`, in the order the query visits them.
+fn nums(storage: &mut ComponentStorage, system: TypeId) -> Vec<usize> {
+    storage.query::<Target<Num>>(system).rows().map(|n| n.0).collect()
+}
+
+/// Returns the column of `Num` in the table of the `entity`.
+fn column_of(storage: &ComponentStorage, entity: Entity) -> &Column<Num> {
+    let id = storage.components().id(&TypeId::of::<Num>()).unwrap();
+    let table = storage.location(entity).unwrap().table;
+    storage.tables()[table].column::<Num>(id).unwrap()
+}
+
+#[test]
+fn cached_query_sees_rows_after_column_grows() {
+    let mut storage = ComponentStorage::new();
+    let first = storage.spawn((Num(0),));
+    let system = TypeId::of::<Cached>();
+    assert_eq!(nums(&mut storage, system), [0]);
+
+    // Spawns until the column reallocates, so that the cached pointer would dangle.
+    let capacity = column_of(&storage, first).capacity();
+    for i in 1..=capacity {
+        storage.spawn((Num(i),));
+    }
+    assert!(column_of(&storage, first).capacity() > capacity);
+
+    assert_eq!(nums(&mut storage, system), (0..=capacity).collect::<Vec<_>>());
+}
+
+/// `Num`s in ascending order, whatever order the query visits them in.
+fn sorted_nums(storage: &mut ComponentStorage) -> Vec<usize> {
+    let mut nums = nums(storage, TypeId::of::<Cached>());
+    nums.sort();
+    nums
+}
+
+#[test]
+fn despawn_reuses_index_with_new_generation() {
+    let mut storage = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)", "D(3)"].map(|name| storage.spawn((CompA(name),)));
+    storage.despawn(d[1]);
+    let reused = storage.spawn((CompA("D(4)"),));
+    assert_eq!(reused.index(), d[1].index());
+    assert_ne!(reused.generation(), d[1].generation());
+    storage.despawn(d[0]);
+    assert_eq!(names(&mut storage, TypeId::of::<Cached>()), ["D(4)", "D(3)", "D(2)"]);
+}
+
+#[test]
+fn despawn_last_row() {
+    let mut storage = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)"].map(|name| storage.spawn((CompA(name),)));
+    assert!(storage.despawn(d[2]));
+
+    // Nothing moves into the hole, so that the others keep their rows.
+    assert_eq!(storage.location(d[0]).unwrap().row, 0);
+    assert_eq!(storage.location(d[1]).unwrap().row, 1);
+    assert_eq!(names(&mut storage, TypeId::of::<Cached>()), ["D(0)", "D(1)"]);
+
+    // The next spawn takes the last row and the index back.
+    let reused = storage.spawn((CompA("D(3)"),));
+    assert_eq!(reused.index(), d[2].index());
+    assert_eq!(storage.location(reused).unwrap().row, 2);
+    assert_eq!(names(&mut storage, TypeId::of::<Cached>()), ["D(0)", "D(1)", "D(3)"]);
+
+    // Down to empty.
+    for entity in [d[0], d[1], reused] {
+        assert!(storage.despawn(entity));
+    }
+    assert!(names(&mut storage, TypeId::of::<Cached>()).is_empty());
+}
+
+#[test]
+fn stale_handle_is_rejected() {
+    let mut storage = ComponentStorage::new();
+    let stale = storage.spawn((CompA("D(0)"),));
+    storage.despawn(stale);
+    let reused = storage.spawn((CompA("D(1)"),));
+    assert_eq!(reused.index(), stale.index());
+
+    assert!(storage.location(stale).is_none());
+    assert!(storage.get::<CompA>(stale).is_none());
+    assert!(storage.get_mut::<CompA>(stale).is_none());
+    assert!(storage.entity_mut(stale).is_none());
+    // Despawning by the stale handle doesn't touch the entity reusing the index.
+    assert!(!storage.despawn(stale));
+    assert_eq!(storage.get::<CompA>(reused).unwrap().0, "D(1)");
+    assert_eq!(names(&mut storage, TypeId::of::<Cached>()), ["D(1)"]);
+}
+
+#[test]
+fn interleaved_spawn_and_despawn() {
+    let mut storage = ComponentStorage::new();
+    let mut alive: Vec<(Entity, usize)> = Vec::new();
+    let mut dead: Vec<Entity> = Vec::new();
+
+    // Despawns from the front, the middle and the back in turn, with spawns in between.
+    for i in 0..40 {
+        if i % 3 == 2 && !alive.is_empty() {
+            let at = [0, alive.len() / 2, alive.len() - 1][i / 3 % 3];
+            let (entity, _) = alive.remove(at);
+            assert!(storage.despawn(entity));
+            dead.push(entity);
+        } else {
+            alive.push((storage.spawn((Num(i),)), i));
+        }
+
+        for (entity, num) in alive.iter() {
+            assert_eq!(storage.get::<Num>(*entity).unwrap().0, *num);
+        }
+        for entity in dead.iter() {
+            assert!(storage.get::<Num>(*entity).is_none());
+            assert!(!storage.despawn(*entity));
+        }
+        let mut expected: Vec<_> = alive.iter().map(|(_, num)| *num).collect();
+        expected.sort();
+        assert_eq!(sorted_nums(&mut storage), expected);
+    }
+}
+
+#[test]
+fn cached_query_after_get_mut() {
+    let mut storage = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)"].map(|name| storage.spawn((CompA(name),)));
+    let system = TypeId::of::<Cached>();
+    assert_eq!(names(&mut storage, system), ["D(0)", "D(1)", "D(2)"]);
+
+    // `get_mut` doesn't change the structure, so that the next query reuses the cached pointers.
+    let epoch = storage.epoch();
+    storage.get_mut::<CompA>(d[1]).unwrap().0 = "D(3)";
+    assert_eq!(epoch, storage.epoch());
+    assert_eq!(names(&mut storage, system), ["D(0)", "D(3)", "D(2)"]);
+
+    // Writing through a query and then through `get_mut` again.
+    for a in storage.query_mut::<Target<CompA>>(system).rows() {
+        a.0 = "D(4)";
+    }
+    storage.get_mut::<CompA>(d[2]).unwrap().0 = "D(5)";
+    assert_eq!(names(&mut storage, system), ["D(4)", "D(4)", "D(5)"]);
+}
+
+/// Another test *System* id.
+struct Other;
+
+#[test]
+fn shared_queries_overlap_column() {
+    let mut storage = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)"].map(|name| storage.spawn((CompA(name),)));
+    let mut a = QueryState::new(TypeId::of::<Cached>());
+    let mut b = QueryState::new(TypeId::of::<Other>());
+    storage.prepare_shared_queries();
+    let shared = &storage;
+
+    // Both results point to the same column and are alive at the same time.
+    let first = <Target<CompA> as ReadOnlyQuery>::query_shared(shared, &mut a);
+    let second = <Target<CompA> as ReadOnlyQuery>::query_shared(shared, &mut b);
+    let names = first.rows().zip(second.rows()).map(|(x, y)| [x.0, y.0]).collect::<Vec<_>>();
+    assert_eq!(names, [["D(0)"; 2], ["D(1)"; 2], ["D(2)"; 2]]);
+    // And so does a plain shared borrow of a value.
+    assert_eq!(shared.get::<CompA>(d[1]).unwrap().0, "D(1)");
+
+    // Cached results are reused by the next queries of the same states.
+    storage.put_query_state(a);
+    storage.put_query_state(b);
+    let mut a = storage.take_query_state(TypeId::of::<Cached>());
+    let mut b = storage.take_query_state(TypeId::of::<Other>());
+    let shared = &storage;
+    let first = <Target<CompA> as ReadOnlyQuery>::query_shared(shared, &mut a);
+    let second = <Target<CompA> as ReadOnlyQuery>::query_shared(shared, &mut b);
+    assert!(first.rows().zip(second.rows()).all(|(x, y)| x.0 == y.0));
+}
+
+/// Rows of `CompA` counted by `CountRows`.
+static COUNTED: [std::sync::atomic::AtomicUsize; 2] =
+    [std::sync::atomic::AtomicUsize::new(0), std::sync::atomic::AtomicUsize::new(0)];
+
+/// Test read-only *System* reading all `CompA` and counting them to `COUNTED[N]`.
+struct CountRows<const N: usize>;
+impl<const N: usize> System for CountRows<N> {
+    type Ref = FAllA;
+    type Mut = ();
+    fn run(&self, r: <Self::Ref as Query>::Output, _m: ()) {
+        let names = r.rows().map(|a| a.0).collect::<Vec<_>>();
+        assert!(names.iter().all(|name| name.starts_with("D(")));
+        COUNTED[N].fetch_add(names.len(), std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn reads_on_scoped_threads() {
+    let mut storage = ComponentStorage::new();
+    let d = ["D(0)", "D(1)", "D(2)"].map(|name| storage.spawn((CompA(name),)));
+    let mut schedule = Schedule::new();
+    schedule
+        .add_system(Stage::Update, ReadOnly(CountRows::<0>))
+        .add_system(Stage::Update, ReadOnly(CountRows::<1>));
+
+    schedule.run_with(&mut storage, &mut ScopedThreads);
+    // The second run reuses cached results, after a value is written in between.
+    storage.get_mut::<CompA>(d[0]).unwrap().0 = "D(3)";
+    schedule.run_with(&mut storage, &mut ScopedThreads);
+    for counted in COUNTED.iter() {
+        assert_eq!(counted.load(std::sync::atomic::Ordering::Relaxed), 6);
+    }
+}
+
+#[test]
+fn locals_of_the_same_type_are_separate() {
+    let mut storage = ComponentStorage::new();
+    let system = TypeId::of::<Cached>();
+    let (a, b) = storage.query::<(Local<u32>, Local<u32>)>(system);
+    assert!(!std::ptr::eq(a, b));
+    *a += 1;
+    *b += 2;
+
+    // Each keeps its own state across runs.
+    let (a, b) = storage.query::<(Local<u32>, Local<u32>)>(system);
+    assert_eq!((*a, *b), (1, 2));
+}
+
+/// Test `Event`.
+struct Tick;
+impl Event for Tick {}
+
+#[test]
+fn event_readers_of_the_same_type_have_separate_cursors() {
+    let mut storage = ComponentStorage::new();
+    storage.add_event::<Tick>();
+    storage.get_resource_mut::<Events<Tick>>().unwrap().send(Tick);
+    let system = TypeId::of::<Cached>();
+    let (a, b) = storage.query::<(EventReader<Tick>, EventReader<Tick>)>(system);
+    assert_eq!((a.count(), b.count()), (1, 1));
+
+    // Both have read it, and the next run reads nothing.
+    let (a, b) = storage.query::<(EventReader<Tick>, EventReader<Tick>)>(system);
+    assert_eq!((a.count(), b.count()), (0, 0));
+}
+
+/// `Num`s read by `ReadNums`.
+static READ_NUMS: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(Vec::new());
+
+/// Test read-only *System* collecting `Num`s in the order it reads them.
+struct ReadNums;
+impl System for ReadNums {
+    type Ref = Target<Num>;
+    type Mut = ();
+    fn run(&self, r: <Self::Ref as Query>::Output, _m: ()) {
+        READ_NUMS.lock().unwrap().extend(r.rows().map(|n| n.0));
+    }
+}
+
+#[test]
+fn read_only_system_in_deterministic_mode() {
+    let mut storage = ComponentStorage::new();
+    storage.set_deterministic(true);
+    storage.spawn((Num(0),));
+    let mut schedule = Schedule::new();
+    schedule.add_system(Stage::Update, ReadOnly(ReadNums));
+
+    // Runs one by one on the storage borrowed mutably, not through an `Executor` sharing it.
+    schedule.run(&mut storage);
+    storage.spawn((Num(1),));
+    schedule.run(&mut storage);
+    assert_eq!(*READ_NUMS.lock().unwrap(), [0, 0, 1]);
+}
+
+#[test]
+fn load_rejects_far_entity_index() {
+    let mut storage = ComponentStorage::new();
+    storage.register_persist::<Transform>("Transform");
+    let far = r#"{"entities":[{"index":4294967295,"generation":0,"components":{"Transform":"1,2"}}]}"#;
+    assert!(matches!(storage.load(far), Err(PersistError::Syntax(_))));
+
+    // Nothing has been loaded, so that the storage can load another.
+    let near = r#"{"entities":[{"index":3,"generation":0,"components":{"Transform":"1,2"}}]}"#;
+    storage.load(near).unwrap();
+    assert_eq!(storage.get::<Transform>(Entity::new(3, 0)), Some(&Transform(1, 2)));
+}
+
+#[test]
+fn failed_load_leaves_storage_empty() {
+    let mut storage = ComponentStorage::new();
+    storage.register_persist::<Transform>("Transform");
+    let invalid = r#"{"entities":[
+        {"index":0,"generation":0,"components":{"Transform":"1,2"}},
+        {"index":1,"generation":0,"components":{"Transform":"oops"}}
+    ]}"#;
+    assert!(matches!(storage.load(invalid), Err(PersistError::InvalidValue { .. })));
+    assert!(storage.location(Entity::new(0, 0)).is_none());
+
+    let valid = r#"{"entities":[{"index":1,"generation":2,"components":{"Transform":"3,4"}}]}"#;
+    storage.load(valid).unwrap();
+    assert_eq!(storage.get::<Transform>(Entity::new(1, 2)), Some(&Transform(3, 4)));
+    assert!(storage.location(Entity::new(0, 0)).is_none());
+    assert_eq!(storage.spawn(()), Entity::new(0, 0));
+}
@@ -1,16 +1,155 @@
-#[inline]
-pub fn upcast_slice<T>(v: &mut [T]) -> *mut [()] {
-    v as *mut [T] as *mut [()]
+use std::any::TypeId;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::ptr::NonNull;
+
+/// `UnsafeCell` that can be shared by threads, which is the unstable `SyncUnsafeCell` of std.
+/// Values of `Column`s live in these, so that queries make pointers to them from a shared borrow of the column.
+/// Unlike pointers made from `&mut [T]`, such pointers stay valid when another shared borrow of the column is made,
+/// and writing through them is allowed as long as the invariants below are kept.
+///
+/// # Invariants
+///
+/// - A value is written by only one *System* at a time, the one holding the write access checked by the `Schedule`,
+///   or through `&mut ComponentStorage` outside of *System*s.
+/// - While a value is written, no one else reads it.
+/// - Pointers are valid until the column is resized, which happens only through `&mut ComponentStorage`.
+#[repr(transparent)]
+pub struct SyncCell<T>(UnsafeCell<T>);
+
+// Safety: Accesses from multiple threads are serialized by the invariants above.
+unsafe impl<T: Send + Sync> Sync for SyncCell<T> {}
+
+impl<T> SyncCell<T> {
+    #[inline]
+    pub fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+
+    /// Returns pointer to the values in the `cells`, which can be written under the invariants.
+    #[inline]
+    pub fn slice_ptr(cells: &[Self]) -> *mut [T] {
+        // `SyncCell<T>` has the same layout as `T`, and `UnsafeCell` allows writing through a shared borrow.
+        cells as *const [Self] as *mut [T]
+    }
+}
+
+/// Type erased slice in a `Chunk`, see [`AnyColumn::rows_ptr`](super::AnyColumn::rows_ptr).
+/// Debug builds keep the `TypeId` of the values as well, so that downcasting to another type panics
+/// instead of reading garbage, e.g. when a query cache entry is keyed wrongly.
+#[derive(Debug, Clone, Copy)]
+pub struct ErasedSlice {
+    ptr: NonNull<[()]>,
+    // `None` for values of dynamic `Component`s, which don't have types to check.
+    #[cfg(debug_assertions)]
+    ty: Option<TypeId>,
+}
+
+impl ErasedSlice {
+    /// Erases the type of the values `T` that the `ptr` points to.
+    pub fn new<T: 'static>(ptr: *mut [T]) -> Self {
+        Self {
+            ptr: NonNull::new(ptr as *mut [()]).unwrap(),
+            #[cfg(debug_assertions)]
+            ty: Some(TypeId::of::<T>()),
+        }
+    }
+
+    /// Erases the slice of values whose type is unknown, which can't be checked at downcasting.
+    pub fn untyped(ptr: *mut [()]) -> Self {
+        Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            #[cfg(debug_assertions)]
+            ty: None,
+        }
+    }
+
+    #[inline]
+    fn cast<T: 'static>(self) -> *mut [T] {
+        #[cfg(debug_assertions)]
+        if let Some(ty) = self.ty {
+            assert!(
+                ty == TypeId::of::<T>(),
+                "type erased slice is downcast to {}, which is not its original type",
+                std::any::type_name::<T>()
+            );
+        }
+        self.ptr.as_ptr() as *mut [T]
+    }
 }
 
+/// Casts the type erased slice in a `Chunk` back to `&[T]`.
+/// Debug builds check that `T` is the original type, see [`ErasedSlice`].
+///
+/// # Safety
+///
+/// `T` must be the original type, and no one writes the values while the returned slice is alive.
 #[inline]
-pub unsafe fn downcast_slice<'a, T>(ptr: *mut [()]) -> &'a [T] {
-    &*(ptr as *const [T])
+pub unsafe fn downcast_slice<'a, T: 'static>(slice: ErasedSlice) -> &'a [T] {
+    &*(slice.cast::<T>() as *const [T])
 }
 
+/// Casts the type erased slice in a `Chunk` back to `&mut [T]`.
+/// Debug builds check that `T` is the original type, see [`ErasedSlice`].
+///
+/// # Safety
+///
+/// `T` must be the original type, and no one else accesses the values while the returned slice is alive.
+/// Writing through the slice must be allowed, such as one from [`SyncCell::slice_ptr`].
 #[inline]
-pub unsafe fn downcast_mut_slice<'a, T>(ptr: *mut [()]) -> &'a mut [T] {
-    &mut *(ptr as *mut [T])
+pub unsafe fn downcast_mut_slice<'a, T: 'static>(slice: ErasedSlice) -> &'a mut [T] {
+    &mut *slice.cast::<T>()
+}
+
+/// `HashMap` with `FastHasher`, for the maps looked up on hot paths such as the query buffer.
+pub type FastMap<K, V> = HashMap<K, V, BuildHasherDefault<FastHasher>>;
+
+/// Hasher of FxHash used in rustc, which is much faster than SipHash for small keys.
+/// It's not resistant to DoS attacks, but keys here are `TypeId`s and `ComponentId`s that users can't choose.
+#[derive(Default)]
+pub struct FastHasher(u64);
+
+impl FastHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    #[inline]
+    fn add(&mut self, word: u64) {
+        self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+impl Hasher for FastHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in chunks.by_ref() {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        for byte in chunks.remainder() {
+            self.add(*byte as u64);
+        }
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    #[inline]
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
 #[macro_export]
@@ -70,48 +209,394 @@ macro_rules! impl_query {
             type OutputMut = $crate::query::QueryIterMut<'a, $id::Target>;
             
             #[inline]
-            fn query(storage: &mut impl $crate::storage::Store, s_id: std::any::TypeId) -> Self::Output {
-                storage.get::<$id>((std::any::TypeId::of::<Self>(), s_id))
+            fn query(storage: &mut dyn $crate::storage::Store, state: &mut $crate::storage::QueryState) -> Self::Output {
+                storage.get::<$id>(state)
             }
 
             #[inline]
-            fn query_mut(storage: &mut impl $crate::storage::Store, s_id: std::any::TypeId) -> Self::OutputMut {
-                storage.get_mut::<$id>((std::any::TypeId::of::<Self>(), s_id))
+            fn query_mut(storage: &mut dyn $crate::storage::Store, state: &mut $crate::storage::QueryState) -> Self::OutputMut {
+                storage.get_mut::<$id>(state)
             }
             
             #[inline]
-            fn ids() -> std::vec::Vec<std::any::TypeId> {
-                vec![std::any::TypeId::of::<$id::Target>()]
+            fn ids() -> std::vec::Vec<$crate::query::AccessId> {
+                vec![$crate::query::AccessId::filtered::<$id>()]
+            }
+        }
+
+        impl<'a, $id: $crate::query::Filter> $crate::query::ReadOnlyQuery<'a> for $id {
+            #[inline]
+            fn query_shared(storage: &dyn $crate::storage::StoreRead, state: &mut $crate::storage::QueryState) -> Self::Output {
+                storage.get_shared::<$id>(state)
             }
         }
     };
     ($n:tt, $($id:ident),+) => {
-        impl<'a, $($id: $crate::query::Filter),+> $crate::query::Query<'a> for ( $($id),+ ) {
-            type Output = ( $($crate::query::QueryIter<'a, $id::Target>),+ );
-            type OutputMut = ( $($crate::query::QueryIterMut<'a, $id::Target>),+ );
+        impl<'a, $($id: $crate::query::Query<'a>),+> $crate::query::Query<'a> for ( $($id),+ ) {
+            type Output = ( $($id::Output),+ );
+            type OutputMut = ( $($id::OutputMut),+ );
             
             #[inline]
-            fn query(storage: &mut impl $crate::storage::Store, s_id: std::any::TypeId) -> Self::Output {
-                ( 
-                    $( storage.get::<$id>((std::any::TypeId::of::<Self>(), s_id)) ),+
+            fn query(storage: &mut dyn $crate::storage::Store, state: &mut $crate::storage::QueryState) -> Self::Output {
+                (
+                    $( $id::query(storage, state) ),+
                 )
             }
 
             #[inline]
-            fn query_mut(storage: &mut impl $crate::storage::Store, s_id: std::any::TypeId) -> Self::OutputMut {
-                ( 
-                    $(
-                        storage.get_mut::<$id>((
-                        std::any::TypeId::of::<(Self, $crate::query::QueryMutTypeIdSalt)>(), 
-                        s_id)) 
-                    ),+
+            fn query_mut(storage: &mut dyn $crate::storage::Store, state: &mut $crate::storage::QueryState) -> Self::OutputMut {
+                (
+                    $( $id::query_mut(storage, state) ),+
                 )
             }
             
             #[inline]
-            fn ids() -> std::vec::Vec<std::any::TypeId> {
-                vec![$(std::any::TypeId::of::<$id::Target>()),+]
+            fn ids() -> std::vec::Vec<$crate::query::AccessId> {
+                let mut ids = std::vec::Vec::new();
+                $( ids.extend($id::ids()); )+
+                ids
+            }
+        }
+
+        impl<'a, $($id: $crate::query::ReadOnlyQuery<'a>),+> $crate::query::ReadOnlyQuery<'a> for ( $($id),+ ) {
+            #[inline]
+            fn query_shared(storage: &dyn $crate::storage::StoreRead, state: &mut $crate::storage::QueryState) -> Self::Output {
+                (
+                    $( $id::query_shared(storage, state) ),+
+                )
             }
         }
     }
 }
+
+#[macro_export]
+macro_rules! impl_zip_entities {
+    ($n:tt, $($id:ident),+) => {
+        impl<$($id: $crate::query::RowIter),+> $crate::query::ZipEntities for ( $($id),+ ) {
+            #[inline]
+            fn zip_entities(self) -> $crate::query::Zipped<Self> {
+                $crate::query::Zipped(self)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($id: $crate::query::RowIter),+> Iterator for $crate::query::Zipped<( $($id),+ )> {
+            type Item = ( $($id::Item),+ );
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let ( $($id),+ ) = &mut self.0;
+                let mut entity = None;
+                $(
+                    let $id = match $id.next_row() {
+                        Some((e, v)) => {
+                            assert!(
+                                *entity.get_or_insert(e) == e,
+                                "zipped queries visit different entities"
+                            );
+                            v
+                        }
+                        None => {
+                            assert!(entity.is_none(), "zipped queries have different lengths");
+                            return None;
+                        }
+                    };
+                )+
+                Some(( $($id),+ ))
+            }
+        }
+    };
+}
+
+/// Defines a unit struct implementing `Filter`.
+/// Filter sets that are not given are `()`.
+///
+/// ```ignore
+/// filter!(FA = CompA, all = (CompA, CompB));
+/// filter!(pub FNotB = CompA, none = CompB);
+/// ```
+#[macro_export]
+macro_rules! filter {
+    (@or) => { () };
+    (@or $ty:ty) => { $ty };
+    (
+        $(#[$meta:meta])* $vis:vis $name:ident = $target:ty
+        $(, all = $all:ty)? $(, any = $any:ty)? $(, none = $none:ty)? $(,)?
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+        impl $crate::query::Filter for $name {
+            type Target = $target;
+            type FilterAll = $crate::filter!(@or $($all)?);
+            type FilterAny = $crate::filter!(@or $($any)?);
+            type FilterNone = $crate::filter!(@or $($none)?);
+        }
+    };
+}
+
+/// Writes the type of a `QueryParam` for function *System*s.
+/// `with` and `without` are `Component`s or tuples of them, and they're `()` if not given.
+///
+/// ```ignore
+/// fn movement(q: query!((&mut Pos, &Vel), with = Player, without = Frozen)) { ... }
+/// ```
+#[macro_export]
+macro_rules! query {
+    ($data:ty $(, with = $with:ty)? $(, without = $without:ty)? $(,)?) => {
+        $crate::system::QueryParam<
+            $data,
+            $crate::query::With<$crate::filter!(@or $($with)?)>,
+            $crate::query::Without<$crate::filter!(@or $($without)?)>,
+        >
+    };
+}
+
+#[macro_export]
+macro_rules! impl_bundle {
+    ($($id:ident $i:tt),+) => {
+        impl<$($id: $crate::storage::Component),+> $crate::bundle::Bundle for ( $($id,)+ ) {
+            fn register(components: &mut $crate::component::Components) -> std::vec::Vec<$crate::component::ComponentId> {
+                vec![$( components.register::<$id>() ),+]
+            }
+
+            fn new_columns() -> std::vec::Vec<std::boxed::Box<dyn $crate::table::AnyColumn>> {
+                vec![$( std::boxed::Box::new($crate::table::Column::<$id>::new()) ),+]
+            }
+
+            fn put(self, table: &mut $crate::table::Table, ids: &[$crate::component::ComponentId], row: usize, tick: u32) {
+                $(
+                    table
+                        .column_mut::<$id>(ids[$i])
+                        .unwrap()
+                        .put(row, self.$i, tick);
+                )+
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! impl_or {
+    ($first:ident, $($id:ident),+) => {
+        impl<$first: $crate::query::Filter, $($id: $crate::query::Filter<Target = $first::Target>),+> $crate::query::Filter
+            for $crate::query::Or<( $first, $($id),+ )>
+        {
+            type Target = $first::Target;
+            type FilterAll = ();
+            type FilterAny = ();
+            type FilterNone = ();
+
+            #[inline]
+            fn types() -> Vec<$crate::query::FilterTypes> {
+                let mut types = $first::types();
+                $( types.extend($id::types()); )+
+                types
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! impl_query_data {
+    ($($id:ident),+) => {
+        impl<$($id: $crate::query::QueryElement),+> $crate::query::QueryData for ( $($id),+ ) {
+            type Iter<'a> = $crate::query::Zipped<( $($id::Rows<'a>),+ )>;
+            type Static = ( $($id::Static),+ );
+
+            #[inline]
+            fn iter<'a, S: $crate::query::DataSelector>(
+                storage: &mut dyn $crate::storage::Store,
+                state: &mut $crate::storage::QueryState,
+            ) -> Self::Iter<'a> {
+                $crate::query::Zipped(( $($id::rows::<S>(storage, state)),+ ))
+            }
+
+            type Parts<'a> = ( $(<$id as $crate::query::QueryData>::Parts<'a>),+ );
+
+            #[allow(non_snake_case)]
+            #[inline]
+            fn into_parts(iter: Self::Iter<'_>) -> Self::Parts<'_> {
+                let $crate::query::Zipped(( $($id),+ )) = iter;
+                ( $(<$id as $crate::query::QueryData>::into_parts($id)),+ )
+            }
+
+            #[inline]
+            fn components(ids: &mut std::vec::Vec<std::any::TypeId>) {
+                $( ids.push($id::component().id); )+
+            }
+
+            #[inline]
+            fn reads() -> std::vec::Vec<$crate::query::AccessId> {
+                let mut ids = std::vec::Vec::new();
+                $( ids.extend($crate::query::element_access::<$id>(false)); )+
+                ids
+            }
+
+            #[inline]
+            fn writes() -> std::vec::Vec<$crate::query::AccessId> {
+                let mut ids = std::vec::Vec::new();
+                $( ids.extend($crate::query::element_access::<$id>(true)); )+
+                ids
+            }
+        }
+    };
+}
+
+/// Implements `Narrow` for tuples picking one of their elements, and for tuples of picked elements.
+#[macro_export]
+macro_rules! impl_narrow {
+    ($($id:ident $i:tt),+) => {
+        $crate::impl_narrow!(@pick ($($id),+); $($id $i),+);
+        $crate::impl_narrow!(
+            @each [] [I0 I1 I2 I3 I4 I5 I6 I7 I8 I9 I10 I11 I12 I13 I14 I15]; $($id),+
+        );
+    };
+    // Picks the element `$id` at `$i` of the tuple of all `$all`.
+    (@pick ($($all:ident),+); ) => {};
+    (@pick ($($all:ident),+); $id:ident $i:tt $(, $rest:ident $ri:tt)*) => {
+        impl<$($all: $crate::query::QueryElement,)+ Narrowed: $crate::query::QueryData, Index>
+            $crate::query::Narrow<Narrowed, $crate::query::Nth<$i, Index>> for ( $($all),+ )
+        where
+            $id: $crate::query::Narrow<Narrowed, Index>,
+        {
+            #[inline]
+            fn narrow<'a>(parts: &mut Self::Parts<'a>) -> Narrowed::Iter<'a> {
+                <$id as $crate::query::Narrow<Narrowed, Index>>::narrow(&mut parts.$i)
+            }
+        }
+        $crate::impl_narrow!(@pick ($($all),+); $($rest $ri),*);
+    };
+    // Pairs each element `$t` with its index type `$x`, then makes the tuple of them.
+    (@each [$($t:ident $x:ident)*] [$($free:ident)*]; ) => {
+        impl<S: $crate::query::QueryData, $($t: $crate::query::QueryElement, $x),*>
+            $crate::query::Narrow<( $($t),* ), $crate::query::Each<( $($x),* )>> for S
+        where
+            $( S: $crate::query::Narrow<$t, $x>, )*
+        {
+            #[inline]
+            fn narrow<'a>(parts: &mut S::Parts<'a>) -> <( $($t),* ) as $crate::query::QueryData>::Iter<'a> {
+                $crate::query::Zipped(( $(<S as $crate::query::Narrow<$t, $x>>::narrow(parts)),* ))
+            }
+        }
+    };
+    (@each [$($acc:ident)*] [$x:ident $($free:ident)*]; $id:ident $(, $rest:ident)*) => {
+        $crate::impl_narrow!(@each [$($acc)* $id $x] [$($free)*]; $($rest),*);
+    };
+}
+
+/// Implements `IntoSystem` for functions taking the parameters, and `Invokable` for the `FnSystem` of them.
+#[macro_export]
+macro_rules! impl_into_system {
+    ($($p:ident),*) => {
+        impl<Func, Out, $($p: $crate::system::SystemParam + 'static),*> $crate::system::IntoSystem<fn($($p),*) -> Out> for Func
+        where
+            Func: Send + Sync + 'static,
+            // Functions are called with the parameters that have the lifetime of the data.
+            // Taking `&Func` helps the compiler infer the types of closures.
+            for<'a> &'a Func: Fn($($p),*) -> Out + Fn($($p::Item<'a>),*) -> Out,
+            Out: $crate::system::SystemOutput,
+        {
+            type System = $crate::system::FnSystem<Func, ($($p,)*)>;
+
+            #[inline]
+            fn into_system(self) -> Self::System {
+                $crate::system::FnSystem::new(self)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<Func, Out, $($p: $crate::system::SystemParam + 'static),*> $crate::system::Invokable
+            for $crate::system::FnSystem<Func, ($($p,)*)>
+        where
+            Func: Send + Sync + 'static,
+            for<'a> &'a Func: Fn($($p),*) -> Out + Fn($($p::Item<'a>),*) -> Out,
+            Out: $crate::system::SystemOutput,
+        {
+            fn invoke(&self, storage: &mut $crate::storage::ComponentStorage) -> Result<(), $crate::system::SystemError> {
+                // Picks the `Fn` taking the items out of two `Fn`s.
+                #[allow(clippy::too_many_arguments)]
+                fn call<Out, $($p),*>(func: impl Fn($($p),*) -> Out, $($p: $p),*) -> Out {
+                    func($($p),*)
+                }
+
+                let system = std::any::TypeId::of::<Func>();
+                #[allow(unused_mut, unused_variables)]
+                let mut state = storage.take_query_state(system);
+                $( let $p = $p::fetch(storage, &mut state); )*
+                let output = call(&self.func, $($p),*);
+                storage.put_query_state(state);
+                storage.finish_system(system);
+                $crate::system::SystemOutput::into_result(output)
+            }
+
+            fn reads(&self) -> std::vec::Vec<$crate::query::AccessId> {
+                #[allow(unused_mut)]
+                let mut ids = std::vec::Vec::new();
+                $( ids.extend($p::reads()); )*
+                ids
+            }
+
+            fn writes(&self) -> std::vec::Vec<$crate::query::AccessId> {
+                #[allow(unused_mut)]
+                let mut ids = std::vec::Vec::new();
+                $( ids.extend($p::writes()); )*
+                ids
+            }
+
+            #[inline]
+            fn id(&self) -> std::any::TypeId {
+                std::any::TypeId::of::<Func>()
+            }
+
+            #[inline]
+            fn name(&self) -> &'static str {
+                std::any::type_name::<Func>()
+            }
+
+            #[inline]
+            fn is_main_thread_only(&self) -> bool {
+                false $( || $p::is_main_thread_only() )*
+            }
+        }
+    };
+}
+
+/// Implements `Identify`, `Query`, `Bundle`, `ZipEntities`, `Or`, `QueryData` and `IntoSystem` for tuples up to 16 elements.
+/// Invoke this once, then we don't need to invoke the macros above for each arity.
+///
+/// ```ignore
+/// impl_all_tuples!();
+/// ```
+#[macro_export]
+macro_rules! impl_all_tuples {
+    () => {
+        $crate::impl_identify!(0);
+        $crate::impl_into_system!();
+        $crate::impl_all_tuples!(
+            @next [];
+            A 0 1, B 1 2, C 2 3, D 3 4, E 4 5, F 5 6, G 6 7, H 7 8,
+            I 8 9, J 9 10, K 10 11, L 11 12, M 12 13, N 13 14, O 14 15, P 15 16
+        );
+    };
+    // Each element has its type parameter, its index and the arity of the tuple ending with it.
+    (@next [$($prev:ident $pi:tt),*];) => {};
+    (@next [$($prev:ident $pi:tt),*]; $id:ident $i:tt $n:tt $(, $rest:ident $ri:tt $rn:tt)*) => {
+        $crate::impl_all_tuples!(@arity $n; $($prev $pi,)* $id $i);
+        $crate::impl_all_tuples!(@next [$($prev $pi,)* $id $i]; $($rest $ri $rn),*);
+    };
+    (@arity 1; $id:ident $i:tt) => {
+        $crate::impl_identify!(1, $id);
+        $crate::impl_query!(1, $id);
+        $crate::impl_bundle!($id $i);
+        $crate::impl_into_system!($id);
+    };
+    (@arity $n:tt; $($id:ident $i:tt),+) => {
+        $crate::impl_identify!($n, $($id),+);
+        $crate::impl_query!($n, $($id),+);
+        $crate::impl_bundle!($($id $i),+);
+        $crate::impl_zip_entities!($n, $($id),+);
+        $crate::impl_or!($($id),+);
+        $crate::impl_query_data!($($id),+);
+        $crate::impl_narrow!($($id $i),+);
+        $crate::impl_into_system!($($id),+);
+    };
+}